@@ -0,0 +1,142 @@
+//! Time per `process` call for the example plugins, one iteration is one
+//! block of `BLOCK_SIZE` frames.
+//!
+//! The plugins are loaded from their built libraries, so build them first:
+//! ```text
+//! cargo build --release --examples
+//! cargo bench
+//! ```
+
+#[macro_use]
+extern crate criterion;
+extern crate vst;
+
+use criterion::{black_box, Criterion};
+use vst::buffer::SendEventBuffer;
+use vst::event::MidiEvent;
+use vst::host::{Host, HostBuffer, PluginInstance, PluginLoader};
+use vst::plugin::Plugin;
+
+use std::env;
+use std::env::consts::{DLL_PREFIX, DLL_SUFFIX};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+const BLOCK_SIZE: usize = 512;
+const SAMPLE_RATE: f32 = 44100.0;
+/// Notes held down while the synths are measured.
+const HELD_NOTES: u8 = 16;
+
+struct BenchHost;
+
+impl Host for BenchHost {
+    fn automate(&self, _index: i32, _value: f32) {}
+}
+
+/// Load a release build of the `name` example.
+fn load(name: &str) -> (PluginLoader<BenchHost>, PluginInstance) {
+    let target = env::var("CARGO_TARGET_DIR").unwrap_or_else(|_| "target".to_string());
+    let path = PathBuf::from(target)
+        .join("release")
+        .join("examples")
+        .join(format!("{}{}{}", DLL_PREFIX, name, DLL_SUFFIX));
+
+    let host = Arc::new(Mutex::new(BenchHost));
+    let mut loader = PluginLoader::load(&path, host).unwrap_or_else(|e| {
+        panic!(
+            "Couldn't load {}, run `cargo build --release --examples` first: {:?}",
+            path.display(),
+            e
+        )
+    });
+    let mut plugin = loader.instance().unwrap();
+    plugin.init();
+    plugin.set_sample_rate(SAMPLE_RATE);
+    plugin.set_block_size(BLOCK_SIZE as i64);
+    (loader, plugin)
+}
+
+fn note_on(note: u8) -> MidiEvent {
+    MidiEvent {
+        data: [0x90, note, 100],
+        delta_frames: 0,
+        live: true,
+        note_length: None,
+        note_offset: None,
+        detune: 0,
+        note_off_velocity: 0,
+    }
+}
+
+/// Benchmark one block of `name` after `setup` has set its parameters.
+fn bench_plugin<F>(c: &mut Criterion, id: &str, name: &str, notes: u8, setup: F)
+where
+    F: FnOnce(&mut PluginInstance),
+{
+    let (_loader, mut plugin) = load(name);
+    setup(&mut plugin);
+    plugin.resume();
+
+    if notes > 0 {
+        let mut events = SendEventBuffer::new(notes as usize);
+        events.store_events((0..notes).map(|i| note_on(48 + i * 2)));
+        plugin.process_events(events.events());
+    }
+
+    let info = plugin.get_info();
+    let mut host_buffer: HostBuffer<f32> =
+        HostBuffer::new(info.inputs as usize, info.outputs as usize);
+    // A sine at a moderate level so the effects have something to work on
+    let input: Vec<f32> = (0..BLOCK_SIZE)
+        .map(|i| (i as f32 * 0.05).sin() * 0.5)
+        .collect();
+    let inputs = vec![input; info.inputs as usize];
+    let mut outputs = vec![vec![0.0; BLOCK_SIZE]; info.outputs as usize];
+
+    c.bench_function(id, |b| {
+        b.iter(|| {
+            let mut buffer = host_buffer.bind(&inputs, &mut outputs);
+            plugin.process(black_box(&mut buffer));
+        })
+    });
+}
+
+fn synths(c: &mut Criterion) {
+    bench_plugin(c, "sine_synth", "sine_synth", HELD_NOTES, |_| {});
+    bench_plugin(c, "multi_synth", "multi_synth", HELD_NOTES, |_| {});
+}
+
+fn sampler(c: &mut Criterion) {
+    // At 48khz every sample goes through the converters, with no kit loaded
+    // they're most of the work
+    for &(id, quality) in [("wav_sampler linear", 0.0), ("wav_sampler sinc64", 1.0)].iter() {
+        bench_plugin(c, id, "wav_sampler", HELD_NOTES, |plugin| {
+            plugin.set_sample_rate(48000.0);
+            plugin.get_parameter_object().set_parameter(12, quality);
+        });
+    }
+}
+
+fn effects(c: &mut Criterion) {
+    bench_plugin(c, "saturate", "saturate", 0, |_| {});
+    bench_plugin(c, "slew", "slew", 0, |_| {});
+    bench_plugin(c, "reverb", "reverb", 0, |_| {});
+    bench_plugin(c, "tremolo", "tremolo", 0, |_| {});
+    bench_plugin(c, "delay", "delay", 0, |_| {});
+    bench_plugin(c, "gate", "gate", 0, |_| {});
+    bench_plugin(c, "limiter", "limiter", 0, |_| {});
+    bench_plugin(c, "autowah", "autowah", 0, |_| {});
+    bench_plugin(c, "width", "width", 0, |_| {});
+    bench_plugin(c, "multiband saturate", "multiband_saturate", 0, |_| {});
+    bench_plugin(c, "tilt", "tilt", 0, |_| {});
+    bench_plugin(c, "compressor", "compressor", 0, |_| {});
+    bench_plugin(c, "compressor true peak", "compressor", 0, |plugin| {
+        plugin.get_parameter_object().set_parameter(6, 1.0);
+    });
+    bench_plugin(c, "compressor 3 bands", "compressor", 0, |plugin| {
+        plugin.get_parameter_object().set_parameter(10, 1.0);
+    });
+}
+
+criterion_group!(benches, synths, sampler, effects);
+criterion_main!(benches);
@@ -0,0 +1,335 @@
+#[macro_use]
+extern crate vst;
+extern crate vsts;
+
+use vst::api::Supported;
+use vst::buffer::AudioBuffer;
+use vst::plugin::{CanDo, Category, Info, Plugin, PluginParameters};
+use vst::util::AtomicFloat;
+use vsts::buffer::{bypass_pairs, process_stereo, MAX_PAIRS};
+use vsts::dsp::{envelope, flush_denormal_f64, time_coeff, Biquad, FilterKind};
+use vsts::smoothing::BypassFade;
+use vsts::util::{
+    clamp_parameter, effect_can_do, from_range, from_range_log, gain_from_db, to_range,
+    to_range_log,
+};
+
+use std::sync::Arc;
+
+/// Auto-wah. An envelope follower on the input sweeps the cutoff of a
+/// resonant filter, so the harder the signal is played the further the
+/// filter opens (or closes, with the direction set to down).
+struct AutoWah {
+    params: Arc<AutoWahParameters>,
+    sample_rate: f32,
+    pairs: [PairState; MAX_PAIRS],
+}
+
+/// Envelope and filters of one channel pair.
+#[derive(Copy, Clone)]
+struct PairState {
+    /// Follows the louder of the two channels so both sweep together.
+    envelope: f64,
+    filters: [Biquad; 2],
+    /// Crossfade in and out of bypass.
+    bypass: BypassFade,
+}
+
+/// The plugin's parameter object contains the values of parameters that can be
+/// adjusted from the host.
+///
+/// The parameters object is shared between the processing and GUI threads.
+/// For this reason, all mutable state in the object has to be represented
+/// through thread-safe interior mutability.
+struct AutoWahParameters {
+    /// Gain in front of the envelope follower, more makes quieter playing
+    /// sweep the full range.
+    sensitivity: AtomicFloat,
+    attack: AtomicFloat,
+    release: AtomicFloat,
+    /// Cutoff with no signal.
+    cutoff: AtomicFloat,
+    /// How far a full envelope moves the cutoff, in octaves.
+    range: AtomicFloat,
+    resonance: AtomicFloat,
+    /// Sweep the cutoff down instead of up.
+    down: AtomicFloat,
+    /// Lowpass instead of bandpass.
+    lowpass: AtomicFloat,
+    bypass: AtomicFloat,
+}
+
+impl Default for AutoWah {
+    fn default() -> AutoWah {
+        AutoWah {
+            params: Arc::new(AutoWahParameters::default()),
+            sample_rate: 44100.0,
+            pairs: [PairState {
+                envelope: 0.0,
+                filters: [Biquad::default(); 2],
+                bypass: BypassFade::new(44100.0),
+            }; MAX_PAIRS],
+        }
+    }
+}
+
+impl Default for AutoWahParameters {
+    fn default() -> AutoWahParameters {
+        AutoWahParameters {
+            sensitivity: AtomicFloat::new(0.5),
+            attack: AtomicFloat::new(from_range_log(5.0, ATTACK_RANGE.0, ATTACK_RANGE.1)),
+            release: AtomicFloat::new(from_range_log(100.0, RELEASE_RANGE.0, RELEASE_RANGE.1)),
+            cutoff: AtomicFloat::new(0.25),
+            range: AtomicFloat::new(0.5),
+            resonance: AtomicFloat::new(from_range(4.0, RESONANCE_RANGE.0, RESONANCE_RANGE.1)),
+            down: AtomicFloat::new(0.0),
+            lowpass: AtomicFloat::new(0.0),
+            bypass: AtomicFloat::new(0.0),
+        }
+    }
+}
+
+const MAX_SENSITIVITY_DB: f32 = 40.0;
+/// Ranges of the envelope times in ms, mapped exponentially.
+const ATTACK_RANGE: (f32, f32) = (0.1, 100.0);
+const RELEASE_RANGE: (f32, f32) = (1.0, 1000.0);
+/// Range of the base cutoff in Hz, swept exponentially.
+const CUTOFF_RANGE: (f32, f32) = (100.0, 4000.0);
+const MAX_RANGE_OCTAVES: f32 = 6.0;
+/// Range of the filter Q.
+const RESONANCE_RANGE: (f32, f32) = (0.5, 10.0);
+
+/// Cutoff for an envelope of `env`, 0 being silence and 1 a full sweep.
+fn swept_cutoff(base: f32, octaves: f32, env: f32, down: bool) -> f32 {
+    let octaves = octaves * env.min(1.0);
+    base * (if down { -octaves } else { octaves }).exp2()
+}
+
+impl AutoWahParameters {
+    fn sensitivity_db(&self) -> f32 {
+        self.sensitivity.get() * MAX_SENSITIVITY_DB
+    }
+
+    fn attack_ms(&self) -> f32 {
+        to_range_log(self.attack.get(), ATTACK_RANGE.0, ATTACK_RANGE.1)
+    }
+
+    fn release_ms(&self) -> f32 {
+        to_range_log(self.release.get(), RELEASE_RANGE.0, RELEASE_RANGE.1)
+    }
+
+    fn cutoff_hz(&self) -> f32 {
+        to_range_log(self.cutoff.get(), CUTOFF_RANGE.0, CUTOFF_RANGE.1)
+    }
+
+    fn range_octaves(&self) -> f32 {
+        self.range.get() * MAX_RANGE_OCTAVES
+    }
+
+    fn resonance_q(&self) -> f32 {
+        to_range(self.resonance.get(), RESONANCE_RANGE.0, RESONANCE_RANGE.1)
+    }
+
+    fn kind(&self) -> FilterKind {
+        if self.lowpass.get() > 0.5 {
+            FilterKind::Lowpass
+        } else {
+            FilterKind::Bandpass
+        }
+    }
+}
+
+impl Plugin for AutoWah {
+    fn get_info(&self) -> Info {
+        Info {
+            name: "AutoWah".to_string(),
+            vendor: "DGriffin".to_string(),
+            unique_id: 543923375,
+            version: 1,
+            inputs: 2,
+            outputs: 2,
+            parameters: 9,
+            category: Category::Effect,
+            ..Default::default()
+        }
+    }
+
+    fn set_sample_rate(&mut self, rate: f32) {
+        self.sample_rate = rate;
+        for pair in self.pairs.iter_mut() {
+            pair.bypass.set_sample_rate(rate);
+            for filter in pair.filters.iter_mut() {
+                filter.reset();
+            }
+        }
+    }
+
+    fn process(&mut self, buffer: &mut AudioBuffer<f32>) {
+        let bypass = self.params.bypass.get() > 0.5;
+        if bypass_pairs(buffer, &mut self.pairs, bypass, |pair| &mut pair.bypass) {
+            return;
+        }
+
+        let sample_rate = self.sample_rate;
+        let sensitivity = f64::from(gain_from_db(self.params.sensitivity_db()));
+        let cte_attack = time_coeff(f64::from(self.params.attack_ms()), f64::from(sample_rate));
+        let cte_release = time_coeff(f64::from(self.params.release_ms()), f64::from(sample_rate));
+        let base = self.params.cutoff_hz();
+        let octaves = self.params.range_octaves();
+        let q = self.params.resonance_q();
+        let down = self.params.down.get() > 0.5;
+        let kind = self.params.kind();
+
+        process_stereo(buffer, &mut self.pairs, |pair, input_l, input_r| {
+            let input = f64::from(input_l.abs().max(input_r.abs())) * sensitivity;
+            let env = envelope(pair.envelope, input, cte_attack, cte_release);
+            pair.envelope = flush_denormal_f64(env);
+
+            let cutoff = swept_cutoff(base, octaves, pair.envelope as f32, down);
+            let [filter_l, filter_r] = &mut pair.filters;
+            filter_l.set_coeffs(cutoff, q, sample_rate, kind);
+            filter_r.set_coeffs(cutoff, q, sample_rate, kind);
+            let output = (filter_l.process(input_l), filter_r.process(input_r));
+            pair.bypass.mix((input_l, input_r), output)
+        });
+    }
+
+    fn get_parameter_object(&mut self) -> Arc<dyn PluginParameters> {
+        Arc::clone(&self.params) as Arc<dyn PluginParameters>
+    }
+
+    fn can_do(&self, can_do: CanDo) -> Supported {
+        effect_can_do(can_do)
+    }
+}
+
+impl PluginParameters for AutoWahParameters {
+    fn get_parameter(&self, index: i32) -> f32 {
+        match index {
+            0 => self.sensitivity.get(),
+            1 => self.attack.get(),
+            2 => self.release.get(),
+            3 => self.cutoff.get(),
+            4 => self.range.get(),
+            5 => self.resonance.get(),
+            6 => self.down.get(),
+            7 => self.lowpass.get(),
+            8 => self.bypass.get(),
+            _ => 0.0,
+        }
+    }
+
+    fn set_parameter(&self, index: i32, val: f32) {
+        let val = match clamp_parameter(val) {
+            Some(val) => val,
+            None => return,
+        };
+        match index {
+            0 => self.sensitivity.set(val),
+            1 => self.attack.set(val),
+            2 => self.release.set(val),
+            3 => self.cutoff.set(val),
+            4 => self.range.set(val),
+            5 => self.resonance.set(val),
+            6 => self.down.set(val),
+            7 => self.lowpass.set(val),
+            8 => self.bypass.set(val),
+            _ => (),
+        }
+    }
+
+    fn get_parameter_text(&self, index: i32) -> String {
+        match index {
+            0 => format!("{:.1} dB", self.sensitivity_db()),
+            1 => format!("{:.1} ms", self.attack_ms()),
+            2 => format!("{:.0} ms", self.release_ms()),
+            3 => format!("{:.0} Hz", self.cutoff_hz()),
+            4 => format!("{:.1} oct", self.range_octaves()),
+            5 => format!("{:.2}", self.resonance_q()),
+            6 => (if self.down.get() > 0.5 { "Down" } else { "Up" }).to_string(),
+            7 => (if self.lowpass.get() > 0.5 {
+                "Lowpass"
+            } else {
+                "Bandpass"
+            })
+            .to_string(),
+            8 => (if self.bypass.get() > 0.5 { "On" } else { "Off" }).to_string(),
+            _ => "".to_string(),
+        }
+    }
+
+    fn get_parameter_name(&self, index: i32) -> String {
+        match index {
+            0 => "Sensitivity",
+            1 => "Attack",
+            2 => "Release",
+            3 => "Cutoff",
+            4 => "Range",
+            5 => "Resonance",
+            6 => "Direction",
+            7 => "Mode",
+            8 => "Bypass",
+            _ => "",
+        }
+        .to_string()
+    }
+}
+
+plugin_main!(AutoWah);
+
+#[cfg(test)]
+mod tests {
+    use vst::plugin::{Plugin, PluginParameters};
+    use vsts::buffer::process_buffer;
+    use vsts::util::from_range_log;
+    use {swept_cutoff, AutoWah, AutoWahParameters};
+
+    /// Run a 2kHz sine at `level` through `wah` and return the output peak
+    /// over the second half of the block.
+    fn process_sine(wah: &mut AutoWah, level: f32) -> f32 {
+        let sine: Vec<f32> = (0..4410)
+            .map(|n| (n as f32 * 2.0 * ::std::f32::consts::PI * 2000.0 / 44100.0).sin() * level)
+            .collect();
+        let outputs = process_buffer(&[sine.clone(), sine], 2, 4410, |buffer| wah.process(buffer));
+        outputs[0][2205..]
+            .iter()
+            .fold(0.0f32, |peak, x| peak.max(x.abs()))
+    }
+
+    #[test]
+    fn test_swept_cutoff() {
+        assert_eq!(swept_cutoff(500.0, 2.0, 0.0, false), 500.0);
+        assert_eq!(swept_cutoff(500.0, 2.0, 1.0, false), 2000.0);
+        assert_eq!(swept_cutoff(500.0, 2.0, 0.5, true), 250.0);
+        // Loud input doesn't sweep past the range
+        assert_eq!(swept_cutoff(500.0, 2.0, 4.0, false), 2000.0);
+    }
+
+    #[test]
+    fn test_envelope_opens_filter() {
+        // 500Hz bandpass that a loud signal sweeps 2 octaves up to 2kHz
+        let mut wah = AutoWah::default();
+        let params = wah.get_parameter_object();
+        params.set_parameter(3, from_range_log(500.0, 100.0, 4000.0));
+        params.set_parameter(4, 2.0 / 6.0);
+        let swept = process_sine(&mut wah, 0.5);
+        assert!(swept > 0.4, "{}", swept);
+
+        let mut wah = AutoWah::default();
+        let params = wah.get_parameter_object();
+        params.set_parameter(3, from_range_log(500.0, 100.0, 4000.0));
+        params.set_parameter(4, 0.0);
+        let fixed = process_sine(&mut wah, 0.5);
+        assert!(fixed < swept * 0.5, "{} {}", fixed, swept);
+    }
+
+    #[test]
+    fn test_parameter_text() {
+        let params = AutoWahParameters::default();
+        let text: Vec<String> = (0..9).map(|i| params.get_parameter_text(i)).collect();
+        assert_eq!(
+            text,
+            ["20.0 dB", "5.0 ms", "100 ms", "251 Hz", "3.0 oct", "4.00", "Up", "Bandpass", "Off"]
+        );
+    }
+}
@@ -1,22 +1,26 @@
 #[macro_use]
 extern crate vst;
 extern crate time;
+extern crate vsts;
 
-use std::f32::consts::PI;
+use std::f32::consts::FRAC_1_SQRT_2;
+use vst::api::Supported;
 use vst::buffer::AudioBuffer;
-use vst::plugin::{Category, Info, Plugin, PluginParameters};
+use vst::plugin::{CanDo, Category, HostCallback, Info, Plugin, PluginParameters};
 use vst::util::AtomicFloat;
+use vsts::buffer::{process_stereo, MAX_PAIRS};
+use vsts::dsp::{
+    flush_denormal_f64, held_envelope, ms_decode, ms_encode, soft_limit, time_coeff, Biquad,
+    FilterKind, ThreeBandSplit, TruePeak, LIMITER_CEILING_RANGE,
+};
+use vsts::smoothing::{set_bypass, BypassFade, SmoothedValue, DEFAULT_SMOOTHING_MS};
+use vsts::util::{
+    clamp_parameter, effect_can_do, from_range, from_range_log, gain_from_db, notify_latency,
+    to_range, to_range_log,
+};
 
 use std::sync::Arc;
 
-fn gain_from_db(decibels: f32) -> f32 {
-    (10.0f32).powf(decibels * 0.05)
-}
-
-fn db_from_gain(gain: f32) -> f32 {
-    gain.max(0.0).log(10.0) * 20.0
-}
-
 /// Simple Gain Effect.
 /// Note that this does not use a proper scale for sound and shouldn't be used in
 /// a production amplification effect!  This is purely for demonstration purposes,
@@ -25,8 +29,179 @@ fn db_from_gain(gain: f32) -> f32 {
 struct GainEffect {
     // Store a handle to the plugin's parameter object.
     params: Arc<GainEffectParameters>,
+    host: HostCallback,
     sample_rate: f32,
-    prev_env: f32,
+    /// Lookahead in samples the host was last told about.
+    latency: usize,
+    pairs: Vec<PairState>,
+}
+
+/// Detectors, crossovers and lookahead of one channel pair.
+struct PairState {
+    gain: SmoothedValue,
+    /// One detector per band, single band mode only uses the first.
+    detectors: [BandDetector; MAX_BANDS],
+    /// In mid/side mode `detectors` follow the mid and these the side.
+    side_detectors: [BandDetector; MAX_BANDS],
+    /// Crossovers for each channel, two band mode only uses the low one.
+    splits: [ThreeBandSplit; 2],
+    lookahead: Lookahead,
+    /// Crossfade in and out of bypass.
+    bypass: BypassFade,
+}
+
+impl PairState {
+    fn new(params: &GainEffectParameters, sample_rate: f32) -> PairState {
+        let mut gain = SmoothedValue::new(gain_from_db(params.gain_db()));
+        gain.set_time(DEFAULT_SMOOTHING_MS, sample_rate);
+        PairState {
+            gain,
+            detectors: Default::default(),
+            side_detectors: Default::default(),
+            splits: [ThreeBandSplit::new(
+                params.crossover_low_hz(),
+                params.crossover_high_hz(),
+                sample_rate,
+            ); 2],
+            lookahead: Lookahead::new(sample_rate),
+            bypass: BypassFade::new(sample_rate),
+        }
+    }
+
+    /// Split one channel into `bands` bands, the unused ones are left at zero.
+    /// A single band is the input untouched.
+    fn split(&mut self, channel: usize, x: f32, bands: usize) -> [f64; MAX_BANDS] {
+        let mut out = [0.0; MAX_BANDS];
+        match bands {
+            1 => out[0] = f64::from(x),
+            2 => {
+                let (low, high) = self.splits[channel].split_low(x);
+                out[0] = f64::from(low);
+                out[1] = f64::from(high);
+            }
+            _ => {
+                let [low, mid, high] = self.splits[channel].split(x);
+                out[0] = f64::from(low);
+                out[1] = f64::from(mid);
+                out[2] = f64::from(high);
+            }
+        }
+        out
+    }
+}
+
+/// One sample of audio waiting in the lookahead, kept split into bands so the
+/// gain can be applied per band once the detector has seen it.
+#[derive(Copy, Clone, Default)]
+struct Frame {
+    /// The input as it came in, for the bypass crossfade.
+    input: [f32; 2],
+    dry: [f64; 2],
+    bands: [[f64; MAX_BANDS]; 2],
+}
+
+/// Delay line for the audio path. The detectors run on the undelayed signal,
+/// so the gain is already moving by the time a peak reaches the output.
+struct Lookahead {
+    buffer: Vec<Frame>,
+    pos: usize,
+}
+
+impl Lookahead {
+    fn new(sample_rate: f32) -> Lookahead {
+        Lookahead {
+            buffer: vec![Frame::default(); lookahead_samples(1.0, sample_rate) + 1],
+            pos: 0,
+        }
+    }
+
+    /// Push `frame` in and return the one from `delay` samples ago.
+    fn process(&mut self, frame: Frame, delay: usize) -> Frame {
+        let len = self.buffer.len();
+        self.buffer[self.pos] = frame;
+        let out = self.buffer[(self.pos + len - delay.min(len - 1)) % len];
+        self.pos = (self.pos + 1) % len;
+        out
+    }
+}
+
+/// Envelope follower state for one band.
+#[derive(Default)]
+struct BandDetector {
+    // The detector runs in f64 so long release tails don't accumulate error
+    prev_env: f64,
+    true_peak: TruePeak,
+    /// Slow envelope of how much of the time the detector has been above the
+    /// threshold, used by the auto release.
+    sustain_env: f64,
+    /// Samples left before the release starts.
+    hold_counter: usize,
+    /// High pass on the detector input only, so bass doesn't drive the gain.
+    sidechain_filter: Biquad,
+}
+
+/// Detector settings shared by all bands, worked out once per block.
+struct Ballistics {
+    /// Expand below the threshold instead of compressing above it.
+    gate: bool,
+    true_peak: bool,
+    /// Run the detector input through `sidechain_filter`.
+    sidechain_hpf: bool,
+    auto_release: bool,
+    hold_samples: usize,
+    cte_attack: f64,
+    cte_release: f64,
+    cte_fast_release: f64,
+    cte_sustain: f64,
+}
+
+impl BandDetector {
+    /// Run the detector on the band's mid signal and return the gain to apply.
+    fn process(&mut self, mid: f64, thrlin: f64, ratio: f64, ballistics: &Ballistics) -> f64 {
+        let mid = if ballistics.sidechain_hpf {
+            f64::from(self.sidechain_filter.process(mid as f32))
+        } else {
+            mid
+        };
+        let detector_input = if ballistics.true_peak {
+            self.true_peak.process(mid)
+        } else {
+            mid.abs()
+        };
+
+        let cte_release = if ballistics.auto_release {
+            let above = if self.prev_env > thrlin { 1.0 } else { 0.0 };
+            self.sustain_env = above + ballistics.cte_sustain * (self.sustain_env - above);
+            auto_release_coeff(
+                self.sustain_env,
+                ballistics.cte_fast_release,
+                ballistics.cte_release,
+            )
+        } else {
+            ballistics.cte_release
+        };
+
+        // Restart the hold every time the detector goes over the threshold
+        if detector_input > thrlin {
+            self.hold_counter = ballistics.hold_samples;
+        }
+
+        // Ballistics filter and envelope generation
+        let env = held_envelope(
+            self.prev_env,
+            detector_input,
+            ballistics.cte_attack,
+            cte_release,
+            &mut self.hold_counter,
+        );
+        self.prev_env = flush_denormal_f64(env);
+
+        if ballistics.gate {
+            expansion_gain(env, thrlin, ratio)
+        } else {
+            compression_gain(env, thrlin, ratio)
+        }
+    }
 }
 
 /// The plugin's parameter object contains the values of parameters that can be
@@ -44,6 +219,36 @@ struct GainEffectParameters {
     attack: AtomicFloat,
     release: AtomicFloat,
     gain: AtomicFloat,
+    bypass: AtomicFloat,
+    /// Detect inter-sample peaks by running the detector through a 4x
+    /// oversampling FIR. Only the detector is oversampled so there's no added
+    /// latency or coloration, but it costs 32 multiply-adds per sample.
+    true_peak: AtomicFloat,
+    /// Program dependent release, the release knob sets the longest release.
+    auto_release: AtomicFloat,
+    /// Dry/wet blend for parallel compression.
+    mix: AtomicFloat,
+    hold: AtomicFloat,
+    /// Number of bands, 1 to 3. Threshold and ratio set the lowest band.
+    bands: AtomicFloat,
+    crossover_low: AtomicFloat,
+    crossover_high: AtomicFloat,
+    band2_threshold: AtomicFloat,
+    band2_ratio: AtomicFloat,
+    band3_threshold: AtomicFloat,
+    band3_ratio: AtomicFloat,
+    /// Compressor or gate/expander, the gate turns down signal below the
+    /// threshold instead of signal above it.
+    mode: AtomicFloat,
+    /// Soft limiter after the makeup gain and mix.
+    limiter_enable: AtomicFloat,
+    limiter_ceiling: AtomicFloat,
+    /// Delays the audio behind the detector, reported to the host as latency.
+    lookahead: AtomicFloat,
+    /// Compress mid and side on their own instead of linking left and right.
+    ms_mode: AtomicFloat,
+    /// Cutoff of the detector's high pass, off at the bottom of the range.
+    sc_hpf: AtomicFloat,
 }
 
 // All plugins using the `vst` crate will either need to implement the `Default`
@@ -52,10 +257,15 @@ struct GainEffectParameters {
 // 0.5 means it's halfway up.
 impl Default for GainEffect {
     fn default() -> GainEffect {
+        let params = GainEffectParameters::default();
         GainEffect {
-            params: Arc::new(GainEffectParameters::default()),
+            host: HostCallback::default(),
             sample_rate: 44100.0,
-            prev_env: 0.0,
+            latency: lookahead_samples(params.lookahead.get(), 44100.0),
+            pairs: (0..MAX_PAIRS)
+                .map(|_| PairState::new(&params, 44100.0))
+                .collect(),
+            params: Arc::new(params),
         }
     }
 }
@@ -64,12 +274,174 @@ impl Default for GainEffectParameters {
     fn default() -> GainEffectParameters {
         GainEffectParameters {
             threshold: AtomicFloat::new(-20.0 / -100.0),
-            ratio: AtomicFloat::new(4.0 / 10.0),
-            attack: AtomicFloat::new(1.0 / 100.0),
-            release: AtomicFloat::new(100.0 / 100.0),
+            ratio: AtomicFloat::new(from_range(4.0, RATIO_RANGE.0, RATIO_RANGE.1)),
+            attack: AtomicFloat::new(from_range_log(1.0, ATTACK_RANGE.0, ATTACK_RANGE.1)),
+            release: AtomicFloat::new(from_range_log(100.0, RELEASE_RANGE.0, RELEASE_RANGE.1)),
             gain: AtomicFloat::new(1.0 / 100.0),
+            bypass: AtomicFloat::new(0.0),
+            true_peak: AtomicFloat::new(0.0),
+            auto_release: AtomicFloat::new(0.0),
+            mix: AtomicFloat::new(1.0),
+            hold: AtomicFloat::new(0.0),
+            bands: AtomicFloat::new(0.0),
+            crossover_low: AtomicFloat::new(0.4),
+            crossover_high: AtomicFloat::new(0.4),
+            band2_threshold: AtomicFloat::new(-20.0 / -100.0),
+            band2_ratio: AtomicFloat::new(from_range(4.0, RATIO_RANGE.0, RATIO_RANGE.1)),
+            band3_threshold: AtomicFloat::new(-20.0 / -100.0),
+            band3_ratio: AtomicFloat::new(from_range(4.0, RATIO_RANGE.0, RATIO_RANGE.1)),
+            mode: AtomicFloat::new(0.0),
+            limiter_enable: AtomicFloat::new(0.0),
+            limiter_ceiling: AtomicFloat::new(1.0),
+            lookahead: AtomicFloat::new(0.0),
+            ms_mode: AtomicFloat::new(0.0),
+            sc_hpf: AtomicFloat::new(0.0),
+        }
+    }
+}
+
+/// Most bands the compressor can split into.
+const MAX_BANDS: usize = 3;
+/// Ranges of the crossover points, the two don't overlap so the bands stay in
+/// order.
+const CROSSOVER_LOW_RANGE: (f32, f32) = (40.0, 1000.0);
+const CROSSOVER_HIGH_RANGE: (f32, f32) = (1000.0, 12000.0);
+/// Range of the detector's high pass above its off position.
+const SC_HPF_RANGE: (f32, f32) = (20.0, 500.0);
+/// Ranges of the attack and release in ms. These and the frequencies above are
+/// mapped exponentially, so the knobs move evenly in octaves.
+const ATTACK_RANGE: (f32, f32) = (0.1, 100.0);
+const RELEASE_RANGE: (f32, f32) = (1.0, 100.0);
+/// Range of the ratio, from no compression at 1:1 up to 10:1.
+const RATIO_RANGE: (f32, f32) = (1.0, 10.0);
+
+// The DSP and the parameter text both go through these, so what the host
+// shows is what gets applied.
+impl GainEffectParameters {
+    /// Threshold of `band` in dB.
+    fn threshold_db(&self, band: usize) -> f32 {
+        let threshold = [
+            &self.threshold,
+            &self.band2_threshold,
+            &self.band3_threshold,
+        ][band];
+        threshold.get() * -100.0
+    }
+
+    /// Ratio of `band`, as in 4:1.
+    fn ratio(&self, band: usize) -> f32 {
+        let ratio = [&self.ratio, &self.band2_ratio, &self.band3_ratio][band];
+        to_range(ratio.get(), RATIO_RANGE.0, RATIO_RANGE.1)
+    }
+
+    fn attack_ms(&self) -> f32 {
+        to_range_log(self.attack.get(), ATTACK_RANGE.0, ATTACK_RANGE.1)
+    }
+
+    fn release_ms(&self) -> f32 {
+        to_range_log(self.release.get(), RELEASE_RANGE.0, RELEASE_RANGE.1)
+    }
+
+    /// Makeup gain in dB.
+    fn gain_db(&self) -> f32 {
+        self.gain.get() * 100.0
+    }
+
+    fn hold_ms(&self) -> f64 {
+        f64::from(self.hold.get()) * MAX_HOLD_MS
+    }
+
+    fn lookahead_ms(&self) -> f64 {
+        f64::from(self.lookahead.get()) * MAX_LOOKAHEAD_MS
+    }
+
+    fn bands(&self) -> usize {
+        1 + (self.bands.get() * (MAX_BANDS - 1) as f32).round() as usize
+    }
+
+    fn crossover_low_hz(&self) -> f32 {
+        to_range_log(
+            self.crossover_low.get(),
+            CROSSOVER_LOW_RANGE.0,
+            CROSSOVER_LOW_RANGE.1,
+        )
+    }
+
+    fn crossover_high_hz(&self) -> f32 {
+        to_range_log(
+            self.crossover_high.get(),
+            CROSSOVER_HIGH_RANGE.0,
+            CROSSOVER_HIGH_RANGE.1,
+        )
+    }
+
+    /// Cutoff of the detector's high pass, `None` when it's off.
+    fn sc_hpf_hz(&self) -> Option<f32> {
+        let sc_hpf = self.sc_hpf.get();
+        if sc_hpf > 0.0 {
+            Some(to_range_log(sc_hpf, SC_HPF_RANGE.0, SC_HPF_RANGE.1))
+        } else {
+            None
         }
     }
+
+    fn limiter_ceiling_db(&self) -> f32 {
+        to_range(
+            self.limiter_ceiling.get(),
+            LIMITER_CEILING_RANGE.0,
+            LIMITER_CEILING_RANGE.1,
+        )
+    }
+
+    /// Linear ceiling of the output limiter, `None` when it's off.
+    fn limiter_ceiling(&self) -> Option<f32> {
+        if self.limiter_enable.get() > 0.5 {
+            Some(gain_from_db(self.limiter_ceiling_db()))
+        } else {
+            None
+        }
+    }
+}
+
+/// Time of the auto release's sustain envelope in the same units as attack
+/// and release.
+const SUSTAIN_TIME: f64 = 300.0;
+/// The auto release recovers this much faster after an isolated transient.
+const FAST_RELEASE_DIVISOR: f64 = 5.0;
+
+/// Blend between the fast and slow release, sustained compression pushes the
+/// release toward the slow one so it doesn't pump.
+fn auto_release_coeff(sustain_env: f64, cte_fast: f64, cte_slow: f64) -> f64 {
+    cte_fast + (cte_slow - cte_fast) * sustain_env
+}
+
+/// Longest hold time in ms.
+const MAX_HOLD_MS: f64 = 250.0;
+/// Longest lookahead time in ms.
+const MAX_LOOKAHEAD_MS: f64 = 10.0;
+
+/// Lookahead parameter value to a delay in samples.
+fn lookahead_samples(lookahead: f32, sample_rate: f32) -> usize {
+    (f64::from(lookahead) * MAX_LOOKAHEAD_MS * 0.001 * f64::from(sample_rate)).round() as usize
+}
+
+/// Compressor transfer function, returns the gain to apply for `env`.
+fn compression_gain(env: f64, thrlin: f64, ratio: f64) -> f64 {
+    if env <= thrlin {
+        1.0
+    } else {
+        (env / thrlin).powf(1.0 / ratio - 1.0)
+    }
+}
+
+/// Downward expander transfer function, returns the gain to apply for `env`.
+/// Ratios below 1 would boost quiet signal so they're treated as 1.
+fn expansion_gain(env: f64, thrlin: f64, ratio: f64) -> f64 {
+    if env >= thrlin {
+        1.0
+    } else {
+        (env / thrlin).powf(ratio.max(1.0) - 1.0)
+    }
 }
 
 // All plugins using `vst` also need to implement the `Plugin` trait.  Here, we
@@ -85,61 +457,157 @@ impl Plugin for GainEffect {
             outputs: 2,
             // This `parameters` bit is important; without it, none of our
             // parameters will be shown!
-            parameters: 5,
+            parameters: 23,
             category: Category::Effect,
+            initial_delay: lookahead_samples(self.params.lookahead.get(), self.sample_rate) as i32,
             ..Default::default()
         }
     }
 
+    fn new(host: HostCallback) -> GainEffect {
+        GainEffect {
+            host,
+            ..GainEffect::default()
+        }
+    }
+
     fn set_sample_rate(&mut self, rate: f32) {
         self.sample_rate = f32::from(rate);
+        for pair in self.pairs.iter_mut() {
+            pair.bypass.set_sample_rate(rate);
+            pair.gain.set_time(DEFAULT_SMOOTHING_MS, rate);
+            pair.lookahead = Lookahead::new(rate);
+        }
     }
 
     // Here is where the bulk of our audio processing code goes.
     fn process(&mut self, buffer: &mut AudioBuffer<f32>) {
-        // Read the amplitude from the parameter object
-        let threshold = self.params.threshold.get() * -100.0;
-        let ratio = self.params.ratio.get() * 10.0;
-        let attack = self.params.attack.get() * 100.0;
-        let release = self.params.release.get() * 100.0;
-        let gain = gain_from_db(self.params.gain.get() * 100.0);
-
-        let thrlin = gain_from_db(threshold);
-        let cte_attack = (-2.0 * PI * 1000.0 / attack / self.sample_rate).exp();
-        let cte_release = (-2.0 * PI * 1000.0 / release / self.sample_rate).exp();
+        // Bypassed audio still goes through the lookahead, so the latency the
+        // host compensates for doesn't change, and the detectors keep
+        // following the input ready for when the compressor comes back in
+        let bypass = self.params.bypass.get() > 0.5;
 
-        let (inputs, mut outputs) = buffer.split();
-        let (inputs_left, inputs_right) = inputs.split_at(1);
-        let (mut outputs_left, mut outputs_right) = outputs.split_at_mut(1);
+        // Read the amplitude from the parameter object
+        let attack = f64::from(self.params.attack_ms());
+        let release = f64::from(self.params.release_ms());
+        let sample_rate = f64::from(self.sample_rate);
+        let mix = f64::from(self.params.mix.get());
+        let bands = self.params.bands();
+        let limiter_ceiling = self.params.limiter_ceiling();
+        let lookahead = lookahead_samples(self.params.lookahead.get(), self.sample_rate);
+        if lookahead != self.latency {
+            self.latency = lookahead;
+            notify_latency(&self.host, lookahead);
+        }
+        let mid_side = self.params.ms_mode.get() > 0.5;
+        let gain = gain_from_db(self.params.gain_db());
 
-        let inputs_stereo = inputs_left[0].iter().zip(inputs_right[0].iter());
-        let outputs_stereo = outputs_left[0].iter_mut().zip(outputs_right[0].iter_mut());
+        // Linear threshold and ratio for each band
+        let mut band_settings = [(0.0, 0.0); MAX_BANDS];
+        for (band, settings) in band_settings.iter_mut().enumerate() {
+            let threshold = f64::from(self.params.threshold_db(band));
+            *settings = (
+                (10.0f64).powf(threshold * 0.05),
+                f64::from(self.params.ratio(band)),
+            );
+        }
 
-        for (input_pair, output_pair) in inputs_stereo.zip(outputs_stereo) {
-            let (input_l, input_r) = input_pair;
-            let (output_l, output_r) = output_pair;
+        let sc_hpf = self.params.sc_hpf_hz();
+        let crossover_low = self.params.crossover_low_hz();
+        let crossover_high = self.params.crossover_high_hz();
+        set_bypass(&mut self.pairs, bypass, |pair| &mut pair.bypass);
+        for pair in self.pairs.iter_mut() {
+            pair.gain.set_target(gain);
+            if let Some(cutoff) = sc_hpf {
+                let detectors = pair
+                    .detectors
+                    .iter_mut()
+                    .chain(pair.side_detectors.iter_mut());
+                for detector in detectors {
+                    detector.sidechain_filter.set_coeffs(
+                        cutoff,
+                        FRAC_1_SQRT_2,
+                        self.sample_rate,
+                        FilterKind::Highpass,
+                    );
+                }
+            }
+            for split in pair.splits.iter_mut() {
+                split.set_freqs(crossover_low, crossover_high, self.sample_rate);
+            }
+        }
 
-            let detector_input = (input_l + input_r).abs() * 0.5;
+        let ballistics = Ballistics {
+            gate: self.params.mode.get() > 0.5,
+            true_peak: self.params.true_peak.get() > 0.5,
+            sidechain_hpf: sc_hpf.is_some(),
+            auto_release: self.params.auto_release.get() > 0.5,
+            hold_samples: (self.params.hold_ms() * 0.001 * sample_rate) as usize,
+            cte_attack: time_coeff(attack, sample_rate),
+            cte_release: time_coeff(release, sample_rate),
+            cte_fast_release: time_coeff(release / FAST_RELEASE_DIVISOR, sample_rate),
+            cte_sustain: time_coeff(SUSTAIN_TIME, sample_rate),
+        };
 
-            // Ballistics filter and envelope generation
-            let cte = if detector_input >= self.prev_env {
-                cte_attack
+        process_stereo(buffer, &mut self.pairs, |pair, left, right| {
+            // In mid/side mode `_l` is the mid and `_r` the side until the end
+            let (input_l, input_r) = if mid_side {
+                ms_encode(left, right)
             } else {
-                cte_release
+                (left, right)
+            };
+            let makeup = f64::from(pair.gain.next());
+            let split = [pair.split(0, input_l, bands), pair.split(1, input_r, bands)];
+            let input = Frame {
+                input: [left, right],
+                // The bands add back up to the input through the same
+                // allpass response as the compressed bands, so the dry signal
+                // lines up with the wet and doesn't comb filter against it
+                // around the crossovers
+                dry: [split[0].iter().sum(), split[1].iter().sum()],
+                bands: split,
             };
-            let env = detector_input + cte * (self.prev_env - detector_input);
-            self.prev_env = env;
+            let delayed = pair.lookahead.process(input, lookahead);
+            let [bands_l, bands_r] = delayed.bands;
 
-            // Compressor transfer function
-            let cv = if env <= thrlin {
-                1.0
+            // Compress each band on its own and sum them back up
+            let (mut wet_l, mut wet_r) = (0.0, 0.0);
+            let detectors = pair
+                .detectors
+                .iter_mut()
+                .zip(pair.side_detectors.iter_mut());
+            for (band, (detector, side_detector)) in detectors.take(bands).enumerate() {
+                let (thrlin, ratio) = band_settings[band];
+                let (cv_l, cv_r) = if mid_side {
+                    (
+                        detector.process(input.bands[0][band], thrlin, ratio, &ballistics),
+                        side_detector.process(input.bands[1][band], thrlin, ratio, &ballistics),
+                    )
+                } else {
+                    let mid = (input.bands[0][band] + input.bands[1][band]) * 0.5;
+                    let cv = detector.process(mid, thrlin, ratio, &ballistics);
+                    (cv, cv)
+                };
+                wet_l += bands_l[band] * cv_l * makeup;
+                wet_r += bands_r[band] * cv_r * makeup;
+            }
+
+            let [dry_l, dry_r] = delayed.dry;
+            let output_l = (dry_l * (1.0 - mix) + wet_l * mix) as f32;
+            let output_r = (dry_r * (1.0 - mix) + wet_r * mix) as f32;
+            let (output_l, output_r) = if mid_side {
+                ms_decode(output_l, output_r)
             } else {
-                (env / thrlin).powf(1.0 / ratio - 1.0)
+                (output_l, output_r)
             };
 
-            *output_l = *input_l * cv * gain;
-            *output_r = *input_r * cv * gain;
-        }
+            let output = match limiter_ceiling {
+                Some(ceiling) => (soft_limit(output_l, ceiling), soft_limit(output_r, ceiling)),
+                None => (output_l, output_r),
+            };
+            pair.bypass
+                .mix((delayed.input[0], delayed.input[1]), output)
+        });
     }
 
     // Return the parameter object. This method can be omitted if the
@@ -147,6 +615,10 @@ impl Plugin for GainEffect {
     fn get_parameter_object(&mut self) -> Arc<dyn PluginParameters> {
         Arc::clone(&self.params) as Arc<dyn PluginParameters>
     }
+
+    fn can_do(&self, can_do: CanDo) -> Supported {
+        effect_can_do(can_do)
+    }
 }
 
 impl PluginParameters for GainEffectParameters {
@@ -158,12 +630,34 @@ impl PluginParameters for GainEffectParameters {
             2 => self.attack.get(),
             3 => self.release.get(),
             4 => self.gain.get(),
+            5 => self.bypass.get(),
+            6 => self.true_peak.get(),
+            7 => self.auto_release.get(),
+            8 => self.mix.get(),
+            9 => self.hold.get(),
+            10 => self.bands.get(),
+            11 => self.crossover_low.get(),
+            12 => self.crossover_high.get(),
+            13 => self.band2_threshold.get(),
+            14 => self.band2_ratio.get(),
+            15 => self.band3_threshold.get(),
+            16 => self.band3_ratio.get(),
+            17 => self.mode.get(),
+            18 => self.limiter_enable.get(),
+            19 => self.limiter_ceiling.get(),
+            20 => self.lookahead.get(),
+            21 => self.ms_mode.get(),
+            22 => self.sc_hpf.get(),
             _ => 0.0,
         }
     }
 
     // the `set_parameter` function sets the value of a parameter.
     fn set_parameter(&self, index: i32, val: f32) {
+        let val = match clamp_parameter(val) {
+            Some(val) => val,
+            None => return,
+        };
         #[allow(clippy::single_match)]
         match index {
             0 => self.threshold.set(val),
@@ -171,6 +665,24 @@ impl PluginParameters for GainEffectParameters {
             2 => self.attack.set(val),
             3 => self.release.set(val),
             4 => self.gain.set(val),
+            5 => self.bypass.set(val),
+            6 => self.true_peak.set(val),
+            7 => self.auto_release.set(val),
+            8 => self.mix.set(val),
+            9 => self.hold.set(val),
+            10 => self.bands.set(val),
+            11 => self.crossover_low.set(val),
+            12 => self.crossover_high.set(val),
+            13 => self.band2_threshold.set(val),
+            14 => self.band2_ratio.set(val),
+            15 => self.band3_threshold.set(val),
+            16 => self.band3_ratio.set(val),
+            17 => self.mode.set(val),
+            18 => self.limiter_enable.set(val),
+            19 => self.limiter_ceiling.set(val),
+            20 => self.lookahead.set(val),
+            21 => self.ms_mode.set(val),
+            22 => self.sc_hpf.set(val),
             _ => (),
         }
     }
@@ -180,11 +692,57 @@ impl PluginParameters for GainEffectParameters {
 
     fn get_parameter_text(&self, index: i32) -> String {
         match index {
-            0 => format!("{:.2}", self.threshold.get() * -100.0),
-            1 => format!("{:.2}", self.ratio.get() * 10.0),
-            2 => format!("{:.2}", self.attack.get() * 100.0),
-            3 => format!("{:.2}", self.release.get() * 100.0),
-            4 => format!("{:.2}", self.gain.get() * 100.0),
+            0 => format!("{:.1} dB", self.threshold_db(0)),
+            1 => format!("{:.1}:1", self.ratio(0)),
+            2 => format!("{:.1} ms", self.attack_ms()),
+            3 => format!("{:.1} ms", self.release_ms()),
+            4 => format!("{:.1} dB", self.gain_db()),
+            5 => (if self.bypass.get() > 0.5 { "On" } else { "Off" }).to_string(),
+            6 => (if self.true_peak.get() > 0.5 {
+                "On"
+            } else {
+                "Off"
+            })
+            .to_string(),
+            7 => (if self.auto_release.get() > 0.5 {
+                "On"
+            } else {
+                "Off"
+            })
+            .to_string(),
+            8 => format!("{:.0}%", self.mix.get() * 100.0),
+            9 => format!("{:.0} ms", self.hold_ms()),
+            10 => format!("{}", self.bands()),
+            11 => format!("{:.0} Hz", self.crossover_low_hz()),
+            12 => format!("{:.0} Hz", self.crossover_high_hz()),
+            13 => format!("{:.1} dB", self.threshold_db(1)),
+            14 => format!("{:.1}:1", self.ratio(1)),
+            15 => format!("{:.1} dB", self.threshold_db(2)),
+            16 => format!("{:.1}:1", self.ratio(2)),
+            17 => (if self.mode.get() > 0.5 {
+                "Gate"
+            } else {
+                "Compressor"
+            })
+            .to_string(),
+            18 => (if self.limiter_enable.get() > 0.5 {
+                "On"
+            } else {
+                "Off"
+            })
+            .to_string(),
+            19 => format!("{:.1} dB", self.limiter_ceiling_db()),
+            20 => format!("{:.1} ms", self.lookahead_ms()),
+            21 => (if self.ms_mode.get() > 0.5 {
+                "On"
+            } else {
+                "Off"
+            })
+            .to_string(),
+            22 => match self.sc_hpf_hz() {
+                Some(cutoff) => format!("{:.0} Hz", cutoff),
+                None => "Off".to_string(),
+            },
             _ => "".to_string(),
         }
     }
@@ -197,6 +755,24 @@ impl PluginParameters for GainEffectParameters {
             2 => "Attack",
             3 => "Release",
             4 => "Gain",
+            5 => "Bypass",
+            6 => "True Peak",
+            7 => "Auto Release",
+            8 => "Mix",
+            9 => "Hold",
+            10 => "Bands",
+            11 => "Crossover 1",
+            12 => "Crossover 2",
+            13 => "Band 2 Threshold",
+            14 => "Band 2 Ratio",
+            15 => "Band 3 Threshold",
+            16 => "Band 3 Ratio",
+            17 => "Mode",
+            18 => "Limiter",
+            19 => "Limiter Ceiling",
+            20 => "Lookahead",
+            21 => "Mid/Side",
+            22 => "SC HPF",
             _ => "",
         }
         .to_string()
@@ -205,3 +781,341 @@ impl PluginParameters for GainEffectParameters {
 
 // This part is important!  Without it, our plugin won't work.
 plugin_main!(GainEffect);
+
+#[cfg(test)]
+mod tests {
+    use compression_gain;
+    use expansion_gain;
+    use std::f64::consts::PI;
+    use vst::plugin::{Plugin, PluginParameters};
+    use vsts::buffer::process_buffer;
+    use vsts::dsp::{envelope, time_coeff};
+    use {auto_release_coeff, SUSTAIN_TIME};
+    use {lookahead_samples, Frame, Lookahead};
+    use {GainEffect, GainEffectParameters, MAX_BANDS};
+
+    #[test]
+    fn test_expansion_gain() {
+        let thrlin = 0.1;
+        assert_eq!(expansion_gain(0.5, thrlin, 4.0), 1.0);
+        assert_eq!(expansion_gain(thrlin, thrlin, 4.0), 1.0);
+        // 20dB under the threshold at 1:4 comes out 60dB down
+        assert!((expansion_gain(0.01, thrlin, 4.0) - 0.001).abs() < 1e-12);
+        assert_eq!(expansion_gain(0.0, thrlin, 4.0), 0.0);
+        // Ratios under 1 don't boost
+        assert_eq!(expansion_gain(0.01, thrlin, 0.5), 1.0);
+    }
+
+    #[test]
+    fn test_lookahead_reports_latency() {
+        let mut effect = GainEffect::default();
+        effect.set_sample_rate(48000.0);
+        assert_eq!(effect.get_info().initial_delay, 0);
+
+        // 5ms at 48khz
+        effect.get_parameter_object().set_parameter(20, 0.5);
+        assert_eq!(effect.get_info().initial_delay, 240);
+        assert_eq!(lookahead_samples(0.5, 48000.0), 240);
+
+        // The host hears about the change from the next block
+        assert_eq!(effect.latency, 0);
+        process_buffer(&[vec![0.0; 64], vec![0.0; 64]], 2, 64, |buffer| {
+            effect.process(buffer)
+        });
+        assert_eq!(effect.latency, 240);
+    }
+
+    #[test]
+    fn test_lookahead_delays_audio() {
+        let mut lookahead = Lookahead::new(44100.0);
+        let impulse = Frame {
+            dry: [1.0, 1.0],
+            ..Frame::default()
+        };
+        let out = (0..20)
+            .position(|n| {
+                let frame = if n == 0 { impulse } else { Frame::default() };
+                lookahead.process(frame, 12).dry[0] != 0.0
+            })
+            .unwrap();
+        assert_eq!(out, 12);
+
+        // No lookahead passes straight through
+        assert_eq!(lookahead.process(impulse, 0).dry, [1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_bypass_keeps_latency() {
+        let mut effect = GainEffect::default();
+        let params = effect.get_parameter_object();
+        params.set_parameter(20, 1.0);
+        params.set_parameter(5, 1.0);
+        // Mid/side over three bands, none of it reaches the bypassed output
+        params.set_parameter(10, 1.0);
+        params.set_parameter(21, 1.0);
+        let delay = effect.get_info().initial_delay as usize;
+        let mut impulse = vec![0.0; 1024];
+        impulse[0] = 0.7;
+        let mut process = |input: &[f32]| {
+            process_buffer(&[input.to_vec(), vec![0.0; 1024]], 2, 1024, |buffer| {
+                effect.process(buffer)
+            })
+        };
+        process(&impulse);
+
+        // Once the fade is over the impulse comes out as late as reported
+        let outputs = process(&impulse);
+        for (n, x) in outputs[0].iter().enumerate() {
+            assert_eq!(*x, if n == delay { 0.7 } else { 0.0 });
+        }
+        assert!(outputs[1].iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn test_bypass_keeps_lookahead_current() {
+        let mut effect = GainEffect::default();
+        let params = effect.get_parameter_object();
+        params.set_parameter(20, 1.0);
+        let mut process = |input: f32| {
+            process_buffer(&[vec![input; 1024], vec![input; 1024]], 2, 1024, |buffer| {
+                effect.process(buffer)
+            })
+        };
+        process(0.5);
+        params.set_parameter(5, 1.0);
+        process(0.5);
+        // Silence once fully bypassed, nothing from before is left to play back
+        process(0.0);
+        params.set_parameter(5, 0.0);
+        let outputs = process(0.0);
+        assert!(outputs[0].iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn test_bad_parameter_values_are_rejected() {
+        let params = GainEffectParameters::default();
+        for index in 0..23 {
+            params.set_parameter(index, 0.3);
+            for &val in [std::f32::NAN, std::f32::INFINITY, std::f32::NEG_INFINITY].iter() {
+                params.set_parameter(index, val);
+                assert_eq!(params.get_parameter(index), 0.3);
+            }
+            params.set_parameter(index, 2.0);
+            assert_eq!(params.get_parameter(index), 1.0);
+            params.set_parameter(index, -1.0);
+            assert_eq!(params.get_parameter(index), 0.0);
+        }
+    }
+
+    #[test]
+    fn test_parameter_text() {
+        let params = GainEffectParameters::default();
+        let text = |index| params.get_parameter_text(index);
+        assert_eq!(text(0), "-20.0 dB");
+        assert_eq!(text(1), "4.0:1");
+        assert_eq!(text(2), "1.0 ms");
+        assert_eq!(text(4), "1.0 dB");
+        assert_eq!(text(11), "145 Hz");
+        assert_eq!(text(19), "0.0 dB");
+
+        params.set_parameter(9, 0.5);
+        assert_eq!(text(9), "125 ms");
+        params.set_parameter(15, 0.3);
+        assert_eq!(text(15), "-30.0 dB");
+        params.set_parameter(20, 0.25);
+        assert_eq!(text(20), "2.5 ms");
+        assert_eq!(text(22), "Off");
+        params.set_parameter(22, 1.0);
+        assert_eq!(text(22), "500 Hz");
+    }
+
+    #[test]
+    fn test_sidechain_hpf_ignores_bass() {
+        // Peak output of a loud 50hz sine, with the detector high pass at `sc_hpf`
+        let peak = |sc_hpf: f32| {
+            let mut effect = GainEffect::default();
+            effect.get_parameter_object().set_parameter(22, sc_hpf);
+            let sine: Vec<f32> = (0..8820)
+                .map(|n| (2.0 * PI * 50.0 * n as f64 / 44100.0).sin() as f32 * 0.8)
+                .collect();
+            let inputs = vec![sine; 2];
+            let outputs = process_buffer(&inputs, 2, 8820, |buffer| effect.process(buffer));
+            outputs[0][4410..].iter().cloned().fold(0.0, f32::max)
+        };
+        // Off, the bass is compressed as usual
+        assert!(peak(0.0) < 0.5);
+        // A 500hz high pass hides it from the detector, the audio stays full range
+        assert!(peak(1.0) > 0.75);
+    }
+
+    #[test]
+    fn test_zero_ratio_stays_finite() {
+        let mut effect = GainEffect::default();
+        let params = effect.get_parameter_object();
+        // Every band, compressing and expanding
+        params.set_parameter(10, 1.0);
+        for &index in [1, 14, 16].iter() {
+            params.set_parameter(index, 0.0);
+        }
+        assert_eq!(params.get_parameter_text(1), "1.0:1");
+        let inputs: Vec<Vec<f32>> = vec![(0..512).map(|n| (n as f32 * 0.05).sin()).collect(); 2];
+        for &mode in [0.0, 1.0].iter() {
+            params.set_parameter(17, mode);
+            let outputs = process_buffer(&inputs, 2, 512, |buffer| effect.process(buffer));
+            assert!(outputs.iter().flatten().all(|x| x.is_finite()));
+        }
+    }
+
+    #[test]
+    fn test_mid_side_mono_has_no_side() {
+        let mut effect = GainEffect::default();
+        effect.get_parameter_object().set_parameter(21, 1.0);
+        let inputs: Vec<Vec<f32>> = vec![(0..512).map(|n| (n as f32 * 0.05).sin()).collect(); 2];
+        let outputs = process_buffer(&inputs, 2, 512, |buffer| effect.process(buffer));
+        assert_eq!(outputs[0], outputs[1]);
+        // Loud enough that the mid was compressed
+        assert!(outputs[0].iter().cloned().fold(0.0, f32::max) < 0.9);
+    }
+
+    #[test]
+    fn test_surround_pairs() {
+        let mut effect = GainEffect::default();
+        let inputs: Vec<Vec<f32>> = vec![(0..512).map(|n| (n as f32 * 0.05).sin()).collect(); 4];
+        let outputs = process_buffer(&inputs, 4, 512, |buffer| effect.process(buffer));
+        // Both pairs are compressed, each by its own detectors
+        assert!(outputs[2].iter().cloned().fold(0.0, f32::max) < 0.9);
+        assert_eq!(outputs[0], outputs[2]);
+        assert_eq!(outputs[1], outputs[3]);
+    }
+
+    #[test]
+    fn test_bands_sum_flat() {
+        let sample_rate = 44100.0;
+        let mut effect = GainEffect::default();
+        for &freq in [100.0, 800.0, 3000.0, 10000.0].iter() {
+            let mut peak: f64 = 0.0;
+            for n in 0..8820 {
+                let x = (2.0 * PI * freq * n as f64 / sample_rate).sin() as f32;
+                let bands = effect.pairs[0].split(0, x, MAX_BANDS);
+                if n > 4410 {
+                    peak = peak.max(bands.iter().sum::<f64>().abs());
+                }
+            }
+            assert!((peak - 1.0).abs() < 0.01);
+        }
+
+        // A single band is the input untouched
+        assert_eq!(
+            effect.pairs[0].split(1, 0.3, 1),
+            [f64::from(0.3f32), 0.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn test_multiband_mix_is_flat() {
+        let sample_rate = 44100.0;
+        let mut effect = GainEffect::default();
+        let params = effect.get_parameter_object();
+        // Three bands half mixed in, with no makeup gain and the thresholds
+        // too high to compress
+        params.set_parameter(10, 1.0);
+        params.set_parameter(8, 0.5);
+        for &index in [0, 4, 13, 15].iter() {
+            params.set_parameter(index, 0.0);
+        }
+        let crossovers = [
+            effect.params.crossover_low_hz(),
+            effect.params.crossover_high_hz(),
+        ];
+        for &freq in [50.0, crossovers[0], 2000.0, crossovers[1], 15000.0].iter() {
+            let input: Vec<f32> = (0..8820)
+                .map(|n| (2.0 * PI * f64::from(freq) * n as f64 / sample_rate).sin() as f32 * 0.5)
+                .collect();
+            let outputs = process_buffer(&[input.clone(), input], 2, 8820, |buffer| {
+                effect.process(buffer)
+            });
+            let peak = outputs[0][4410..]
+                .iter()
+                .fold(0.0f32, |peak, x| peak.max(x.abs()));
+            assert!((peak - 0.5).abs() < 0.01, "{} {}", freq, peak);
+        }
+    }
+
+    #[test]
+    fn test_auto_release_follows_program() {
+        let sample_rate = 44100.0;
+        let cte_sustain = time_coeff(SUSTAIN_TIME, sample_rate);
+        let cte_slow = time_coeff(100.0, sample_rate);
+        let cte_fast = time_coeff(20.0, sample_rate);
+
+        // An isolated 5ms transient barely moves the sustain envelope
+        let mut sustain_env = 0.0;
+        for _ in 0..(sample_rate as usize / 200) {
+            sustain_env = 1.0 + cte_sustain * (sustain_env - 1.0);
+        }
+        let transient = auto_release_coeff(sustain_env, cte_fast, cte_slow);
+        assert!((transient - cte_fast).abs() < (transient - cte_slow).abs());
+
+        // A second above the threshold gets close to the slow release
+        for _ in 0..sample_rate as usize {
+            sustain_env = 1.0 + cte_sustain * (sustain_env - 1.0);
+        }
+        let sustained = auto_release_coeff(sustain_env, cte_fast, cte_slow);
+        assert!((sustained - cte_slow).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_f64_is_more_accurate_than_f32() {
+        let sample_rate = 44100.0;
+        // A quiet square wave under a low threshold, where f32 has the fewest
+        // bits to spare, held for a second then released for a second
+        let level = 0.001;
+        let samples = sample_rate as usize;
+        let thrlin = (10.0f64).powf(-70.0 * 0.05);
+        let ratio = 4.0;
+        let cte_attack = time_coeff(10.0, sample_rate);
+        let cte_release = time_coeff(100.0, sample_rate);
+
+        let mut prev_env = 0.0;
+        let mut prev_env_32 = 0.0f32;
+        let (mut error, mut error_32) = (0.0f64, 0.0f64);
+        for i in 0..samples * 2 {
+            let input = match i {
+                i if i >= samples => 0.0,
+                i if i % 100 < 50 => level,
+                _ => -level,
+            };
+            // The input's level never changes, so the envelope has a closed form
+            let exact_env = if i < samples {
+                level * (1.0 - cte_attack.powi(i as i32 + 1))
+            } else {
+                let held = level * (1.0 - cte_attack.powi(samples as i32));
+                held * cte_release.powi((i - samples) as i32 + 1)
+            };
+            let exact = input * compression_gain(exact_env, thrlin, ratio);
+
+            prev_env = envelope(prev_env, input.abs(), cte_attack, cte_release);
+            let output = input * compression_gain(prev_env, thrlin, ratio);
+
+            // The same math at 32 bits
+            let input_32 = input as f32;
+            let detector_32 = input_32.abs();
+            let cte_32 = if detector_32 >= prev_env_32 {
+                cte_attack as f32
+            } else {
+                cte_release as f32
+            };
+            prev_env_32 = detector_32 + cte_32 * (prev_env_32 - detector_32);
+            let cv_32 = if prev_env_32 <= thrlin as f32 {
+                1.0
+            } else {
+                (prev_env_32 / thrlin as f32).powf(1.0 / ratio as f32 - 1.0)
+            };
+            let output_32 = input_32 * cv_32;
+
+            error = error.max((output - exact).abs());
+            error_32 = error_32.max((f64::from(output_32) - exact).abs());
+        }
+        assert!(error * 1000.0 < error_32, "{} {}", error, error_32);
+    }
+}
@@ -0,0 +1,421 @@
+#[macro_use]
+extern crate vst;
+extern crate vsts;
+
+use vst::api::Supported;
+use vst::buffer::AudioBuffer;
+use vst::plugin::{CanDo, Category, HostCallback, Info, Plugin, PluginParameters};
+use vst::util::AtomicFloat;
+use vsts::buffer::{bypass_pairs, process_stereo, MAX_PAIRS};
+use vsts::dsp::{feedback_tail, mix_equal_power, Biquad, DelayLine, FilterKind};
+use vsts::smoothing::{BypassFade, SmoothedValue};
+use vsts::tempo::{division, host_bpm, DEFAULT_BPM, DOTTED_DIVISIONS};
+use vsts::util::{clamp_parameter, effect_can_do, to_range_log};
+
+use std::sync::Arc;
+
+/// Echo with a free or tempo synced time. The feedback path runs through a
+/// lowpass so each repeat comes back a little darker than the last. In ping
+/// pong mode the input is summed to mono and the repeats bounce between the
+/// left and right channels.
+struct Delay {
+    params: Arc<DelayParameters>,
+    host: HostCallback,
+    sample_rate: f32,
+    pairs: Vec<PairState>,
+}
+
+/// Delay lines and filters of one channel pair.
+struct PairState {
+    line_l: DelayLine,
+    line_r: DelayLine,
+    damping_l: Biquad,
+    damping_r: Biquad,
+    /// Delay time in samples, smoothed so time changes glide instead of click.
+    delay: SmoothedValue,
+    /// Crossfade in and out of bypass.
+    bypass: BypassFade,
+}
+
+impl PairState {
+    fn new(params: &DelayParameters, sample_rate: f32) -> PairState {
+        let damping = Biquad::new(
+            params.damping_hz(),
+            DAMPING_Q,
+            sample_rate,
+            FilterKind::Lowpass,
+        );
+        let mut delay = SmoothedValue::new(params.delay_ms(None) * 0.001 * sample_rate);
+        delay.set_time(DELAY_SMOOTHING_MS, sample_rate);
+        PairState {
+            line_l: delay_line(sample_rate),
+            line_r: delay_line(sample_rate),
+            damping_l: damping,
+            damping_r: damping,
+            delay,
+            bypass: BypassFade::new(sample_rate),
+        }
+    }
+}
+
+/// The plugin's parameter object contains the values of parameters that can be
+/// adjusted from the host.
+///
+/// The parameters object is shared between the processing and GUI threads.
+/// For this reason, all mutable state in the object has to be represented
+/// through thread-safe interior mutability.
+struct DelayParameters {
+    time: AtomicFloat,
+    /// Lock the time to a note division at the host tempo.
+    sync: AtomicFloat,
+    feedback: AtomicFloat,
+    /// Cutoff of the lowpass in the feedback path.
+    damping: AtomicFloat,
+    mix: AtomicFloat,
+    ping_pong: AtomicFloat,
+    bypass: AtomicFloat,
+}
+
+/// Longest delay time, long enough for a whole note at 60bpm.
+const MAX_DELAY_MS: f32 = 4000.0;
+
+/// A line long enough for `MAX_DELAY_MS` at `sample_rate`.
+fn delay_line(sample_rate: f32) -> DelayLine {
+    DelayLine::new((MAX_DELAY_MS * 0.001 * sample_rate).ceil() as usize)
+}
+
+/// How long a change of delay time takes to glide to the new time.
+const DELAY_SMOOTHING_MS: f32 = 50.0;
+
+/// Feedback at the top of the parameter, kept below 1 so the repeats always die out.
+const MAX_FEEDBACK: f32 = 0.95;
+
+/// Q of the feedback lowpass, flat with no resonant peak.
+const DAMPING_Q: f32 = 0.707;
+
+/// Delay time in ms. Free running times go from 1ms to 2s, synced times snap
+/// to a note division at `bpm`.
+fn delay_ms(time: f32, sync: bool, bpm: Option<f64>) -> f32 {
+    if sync {
+        let (_, beats) = division(&DOTTED_DIVISIONS, time);
+        ((beats * 60000.0 / bpm.unwrap_or(DEFAULT_BPM)) as f32).min(MAX_DELAY_MS)
+    } else {
+        2000.0f32.powf(time)
+    }
+}
+
+impl Default for Delay {
+    fn default() -> Delay {
+        let sample_rate = 44100.0;
+        let params = DelayParameters::default();
+        let pairs = (0..MAX_PAIRS)
+            .map(|_| PairState::new(&params, sample_rate))
+            .collect();
+        Delay {
+            params: Arc::new(params),
+            host: HostCallback::default(),
+            sample_rate,
+            pairs,
+        }
+    }
+}
+
+impl Default for DelayParameters {
+    fn default() -> DelayParameters {
+        DelayParameters {
+            // About 300ms
+            time: AtomicFloat::new(0.75),
+            sync: AtomicFloat::new(0.0),
+            feedback: AtomicFloat::new(0.4),
+            damping: AtomicFloat::new(0.7),
+            mix: AtomicFloat::new(0.3),
+            ping_pong: AtomicFloat::new(0.0),
+            bypass: AtomicFloat::new(0.0),
+        }
+    }
+}
+
+impl DelayParameters {
+    fn sync(&self) -> bool {
+        self.sync.get() > 0.5
+    }
+
+    fn delay_ms(&self, bpm: Option<f64>) -> f32 {
+        delay_ms(self.time.get(), self.sync(), bpm)
+    }
+
+    fn feedback(&self) -> f32 {
+        self.feedback.get() * MAX_FEEDBACK
+    }
+
+    /// Feedback lowpass cutoff, 200hz to 20khz.
+    fn damping_hz(&self) -> f32 {
+        to_range_log(self.damping.get(), 200.0, 20000.0)
+    }
+}
+
+impl Delay {
+    /// Delay time in ms, at the host tempo when synced.
+    fn delay_ms(&self) -> f32 {
+        let bpm = if self.params.sync() {
+            host_bpm(&self.host)
+        } else {
+            None
+        };
+        self.params.delay_ms(bpm)
+    }
+}
+
+impl Plugin for Delay {
+    fn get_info(&self) -> Info {
+        Info {
+            name: "Delay".to_string(),
+            vendor: "DGriffin".to_string(),
+            unique_id: 435670513,
+            version: 1,
+            inputs: 2,
+            outputs: 2,
+            parameters: 7,
+            category: Category::Effect,
+            ..Default::default()
+        }
+    }
+
+    fn new(host: HostCallback) -> Delay {
+        Delay {
+            host,
+            ..Delay::default()
+        }
+    }
+
+    fn set_sample_rate(&mut self, rate: f32) {
+        self.sample_rate = rate;
+        let params = &self.params;
+        self.pairs = (0..MAX_PAIRS)
+            .map(|_| PairState::new(params, rate))
+            .collect();
+    }
+
+    /// Tell the host to keep calling `process` until the repeats have died
+    /// out, the damping only makes them die out sooner.
+    fn get_tail_size(&self) -> isize {
+        let tail_ms = feedback_tail(self.delay_ms(), self.params.feedback());
+        (tail_ms * 0.001 * self.sample_rate).ceil() as isize
+    }
+
+    fn process(&mut self, buffer: &mut AudioBuffer<f32>) {
+        let bypass = self.params.bypass.get() > 0.5;
+        if bypass_pairs(buffer, &mut self.pairs, bypass, |pair| &mut pair.bypass) {
+            return;
+        }
+
+        let delay = self.delay_ms() * 0.001 * self.sample_rate;
+        let damping_hz = self.params.damping_hz();
+        for pair in self.pairs.iter_mut() {
+            pair.delay.set_target(delay);
+            for damping in [&mut pair.damping_l, &mut pair.damping_r].iter_mut() {
+                damping.set_coeffs(damping_hz, DAMPING_Q, self.sample_rate, FilterKind::Lowpass);
+            }
+        }
+        let feedback = self.params.feedback();
+        let mix = self.params.mix.get();
+        let ping_pong = self.params.ping_pong.get() > 0.5;
+
+        process_stereo(buffer, &mut self.pairs, |pair, input_l, input_r| {
+            let delay = pair.delay.next();
+            let wet_l = pair.damping_l.process(pair.line_l.read(delay));
+            let wet_r = pair.damping_r.process(pair.line_r.read(delay));
+            if ping_pong {
+                // Each side feeds the other, the input only enters on the left
+                let mono = (input_l + input_r) * 0.5;
+                pair.line_l.write(mono + wet_r * feedback);
+                pair.line_r.write(wet_l * feedback);
+            } else {
+                pair.line_l.write(input_l + wet_l * feedback);
+                pair.line_r.write(input_r + wet_r * feedback);
+            }
+            // The echoes are uncorrelated with the dry signal, so keep the
+            // power steady through the middle of the mix
+            let output = (
+                mix_equal_power(input_l, wet_l, mix),
+                mix_equal_power(input_r, wet_r, mix),
+            );
+            pair.bypass.mix((input_l, input_r), output)
+        });
+    }
+
+    fn get_parameter_object(&mut self) -> Arc<dyn PluginParameters> {
+        Arc::clone(&self.params) as Arc<dyn PluginParameters>
+    }
+
+    fn can_do(&self, can_do: CanDo) -> Supported {
+        match can_do {
+            CanDo::ReceiveTimeInfo => Supported::Yes,
+            other => effect_can_do(other),
+        }
+    }
+}
+
+impl PluginParameters for DelayParameters {
+    fn get_parameter(&self, index: i32) -> f32 {
+        match index {
+            0 => self.time.get(),
+            1 => self.sync.get(),
+            2 => self.feedback.get(),
+            3 => self.damping.get(),
+            4 => self.mix.get(),
+            5 => self.ping_pong.get(),
+            6 => self.bypass.get(),
+            _ => 0.0,
+        }
+    }
+
+    fn set_parameter(&self, index: i32, val: f32) {
+        let val = match clamp_parameter(val) {
+            Some(val) => val,
+            None => return,
+        };
+        match index {
+            0 => self.time.set(val),
+            1 => self.sync.set(val),
+            2 => self.feedback.set(val),
+            3 => self.damping.set(val),
+            4 => self.mix.set(val),
+            5 => self.ping_pong.set(val),
+            6 => self.bypass.set(val),
+            _ => (),
+        }
+    }
+
+    fn get_parameter_text(&self, index: i32) -> String {
+        match index {
+            0 => {
+                if self.sync() {
+                    division(&DOTTED_DIVISIONS, self.time.get()).0.to_string()
+                } else {
+                    format!("{:.0} ms", self.delay_ms(None))
+                }
+            }
+            1 => (if self.sync() { "On" } else { "Off" }).to_string(),
+            2 => format!("{:.0}%", self.feedback() * 100.0),
+            3 => format!("{:.0} Hz", self.damping_hz()),
+            4 => format!("{:.0}%", self.mix.get() * 100.0),
+            5 => (if self.ping_pong.get() > 0.5 {
+                "On"
+            } else {
+                "Off"
+            })
+            .to_string(),
+            6 => (if self.bypass.get() > 0.5 { "On" } else { "Off" }).to_string(),
+            _ => "".to_string(),
+        }
+    }
+
+    fn get_parameter_name(&self, index: i32) -> String {
+        match index {
+            0 => "Time",
+            1 => "Sync",
+            2 => "Feedback",
+            3 => "Damping",
+            4 => "Mix",
+            5 => "Ping Pong",
+            6 => "Bypass",
+            _ => "",
+        }
+        .to_string()
+    }
+}
+
+plugin_main!(Delay);
+
+#[cfg(test)]
+mod tests {
+    use vst::api::Supported;
+    use vst::plugin::{CanDo, Plugin};
+    use vsts::buffer::process_buffer;
+    use {delay_ms, Delay};
+
+    /// Run an impulse on the left input through a fully wet 1/16 note delay.
+    /// At 120bpm and 1khz the echoes land every 125 samples.
+    fn impulse_response(ping_pong: bool) -> Vec<Vec<f32>> {
+        let mut delay = Delay::default();
+        let params = delay.get_parameter_object();
+        params.set_parameter(1, 1.0);
+        params.set_parameter(0, 7.0 / 8.0);
+        params.set_parameter(2, 1.0);
+        params.set_parameter(3, 1.0);
+        params.set_parameter(4, 1.0);
+        params.set_parameter(5, if ping_pong { 1.0 } else { 0.0 });
+        delay.set_sample_rate(1000.0);
+
+        let mut input = vec![0.0; 1000];
+        input[0] = 1.0;
+        process_buffer(&[input, vec![0.0; 1000]], 2, 1000, |buffer| {
+            delay.process(buffer)
+        })
+    }
+
+    /// Index of the loudest sample.
+    fn peak(samples: &[f32]) -> usize {
+        (0..samples.len())
+            .max_by(|&a, &b| samples[a].abs().partial_cmp(&samples[b].abs()).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_delay_ms() {
+        // A quarter note at 120bpm
+        assert_eq!(delay_ms(3.0 / 8.0, true, Some(120.0)), 500.0);
+        assert_eq!(delay_ms(3.0 / 8.0, true, None), 500.0);
+        // A whole note at 40bpm is longer than the buffer
+        assert_eq!(delay_ms(0.0, true, Some(40.0)), 4000.0);
+        assert_eq!(delay_ms(0.0, false, None), 1.0);
+        assert!((delay_ms(1.0, false, None) - 2000.0).abs() < 1e-2);
+    }
+
+    #[test]
+    fn test_echoes() {
+        let outputs = impulse_response(false);
+        assert_eq!(peak(&outputs[0][..200]), 125);
+        assert_eq!(peak(&outputs[0][200..]), 50);
+        // Nothing crosses over to the right
+        assert!(outputs[1].iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn test_tail_size() {
+        let mut delay = Delay::default();
+        let params = delay.get_parameter_object();
+        params.set_parameter(0, 0.5);
+        delay.set_sample_rate(1000.0);
+        // Without feedback the tail is a single echo
+        params.set_parameter(2, 0.0);
+        let echo = delay.get_tail_size();
+        assert_eq!(echo, (2000.0f32.powf(0.5)).ceil() as isize);
+        params.set_parameter(2, 0.5);
+        let short = delay.get_tail_size();
+        params.set_parameter(2, 1.0);
+        let long = delay.get_tail_size();
+        assert!(echo < short && short < long);
+    }
+
+    #[test]
+    fn test_can_do() {
+        let delay = Delay::default();
+        assert_eq!(delay.can_do(CanDo::ReceiveTimeInfo), Supported::Yes);
+        assert_eq!(delay.can_do(CanDo::ReceiveMidiEvent), Supported::No);
+        assert_eq!(
+            delay.can_do(CanDo::Other("2in2out".to_string())),
+            Supported::Yes
+        );
+    }
+
+    #[test]
+    fn test_ping_pong() {
+        let outputs = impulse_response(true);
+        // The first echo is on the left, the next bounces to the right
+        assert_eq!(peak(&outputs[0][..200]), 125);
+        assert_eq!(peak(&outputs[1]), 250);
+        assert!(outputs[1][..200].iter().all(|&x| x == 0.0));
+    }
+}
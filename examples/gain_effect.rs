@@ -3,10 +3,14 @@
 #[macro_use]
 extern crate vst;
 extern crate time;
+extern crate vsts;
 
+use vst::api::Supported;
 use vst::buffer::AudioBuffer;
-use vst::plugin::{Category, Info, Plugin, PluginParameters};
+use vst::plugin::{CanDo, Category, Info, Plugin, PluginParameters};
 use vst::util::AtomicFloat;
+use vsts::smoothing::BlockRamp;
+use vsts::util::{clamp_parameter, effect_can_do};
 
 use std::sync::Arc;
 
@@ -18,6 +22,8 @@ use std::sync::Arc;
 struct GainEffect {
     // Store a handle to the plugin's parameter object.
     params: Arc<GainEffectParameters>,
+    // Ramps the amplitude across each block so automation doesn't step.
+    amplitude: BlockRamp,
 }
 
 /// The plugin's parameter object contains the values of parameters that can be
@@ -39,8 +45,10 @@ struct GainEffectParameters {
 // 0.5 means it's halfway up.
 impl Default for GainEffect {
     fn default() -> GainEffect {
+        let params = GainEffectParameters::default();
         GainEffect {
-            params: Arc::new(GainEffectParameters::default()),
+            amplitude: BlockRamp::new(params.amplitude.get()),
+            params: Arc::new(params),
         }
     }
 }
@@ -74,18 +82,24 @@ impl Plugin for GainEffect {
 
     // Here is where the bulk of our audio processing code goes.
     fn process(&mut self, buffer: &mut AudioBuffer<f32>) {
-        // Read the amplitude from the parameter object
+        // Read the amplitude from the parameter object, and ramp to it from
+        // the last block's value over this one
         let amplitude = self.params.amplitude.get();
+        self.amplitude.start(amplitude, buffer.samples());
+        let ramp = self.amplitude;
         // First, we destructure our audio buffer into an arbitrary number of
         // input and output buffers.  Usually, we'll be dealing with stereo (2 of each)
         // but that might change.
         for (input_buffer, output_buffer) in buffer.zip() {
             // Next, we'll loop through each individual sample so we can apply the amplitude
             // value to it.
+            // Every channel follows the same ramp.
+            let mut ramp = ramp;
             for (input_sample, output_sample) in input_buffer.iter().zip(output_buffer) {
-                *output_sample = *input_sample * amplitude;
+                *output_sample = *input_sample * ramp.next();
             }
         }
+        self.amplitude.reset(amplitude);
     }
 
     // Return the parameter object. This method can be omitted if the
@@ -93,6 +107,10 @@ impl Plugin for GainEffect {
     fn get_parameter_object(&mut self) -> Arc<dyn PluginParameters> {
         Arc::clone(&self.params) as Arc<dyn PluginParameters>
     }
+
+    fn can_do(&self, can_do: CanDo) -> Supported {
+        effect_can_do(can_do)
+    }
 }
 
 impl PluginParameters for GainEffectParameters {
@@ -106,6 +124,10 @@ impl PluginParameters for GainEffectParameters {
 
     // the `set_parameter` function sets the value of a parameter.
     fn set_parameter(&self, index: i32, val: f32) {
+        let val = match clamp_parameter(val) {
+            Some(val) => val,
+            None => return,
+        };
         #[allow(clippy::single_match)]
         match index {
             0 => self.amplitude.set(val),
@@ -0,0 +1,408 @@
+#[macro_use]
+extern crate vst;
+extern crate vsts;
+
+use vst::api::Supported;
+use vst::buffer::AudioBuffer;
+use vst::plugin::{CanDo, Category, Info, Plugin, PluginParameters};
+use vst::util::AtomicFloat;
+use vsts::buffer::{bypass_pairs, process_stereo, MAX_PAIRS};
+use vsts::dsp::{envelope, flush_denormal_f64, held_envelope, time_coeff};
+use vsts::smoothing::BypassFade;
+use vsts::util::{
+    clamp_parameter, effect_can_do, from_range, from_range_log, gain_from_db, to_range,
+    to_range_log,
+};
+
+use std::sync::Arc;
+
+/// Noise gate. Signal that falls below the threshold is turned down by the
+/// range, rather than muted, once the hold time has run out.
+///
+/// The gate opens at the threshold but only closes again once the level has
+/// dropped below the threshold minus the hysteresis, so a signal hovering
+/// around the threshold doesn't make it chatter.
+struct Gate {
+    params: Arc<GateParameters>,
+    sample_rate: f32,
+    pairs: [PairState; MAX_PAIRS],
+}
+
+/// Detector of one channel pair.
+#[derive(Copy, Clone)]
+struct PairState {
+    detector: GateDetector,
+    /// Crossfade in and out of bypass.
+    bypass: BypassFade,
+}
+
+/// The plugin's parameter object contains the values of parameters that can be
+/// adjusted from the host.
+///
+/// The parameters object is shared between the processing and GUI threads.
+/// For this reason, all mutable state in the object has to be represented
+/// through thread-safe interior mutability.
+struct GateParameters {
+    threshold: AtomicFloat,
+    /// Gap in dB between the open and close thresholds.
+    hysteresis: AtomicFloat,
+    /// How far the closed gate turns the signal down.
+    range: AtomicFloat,
+    attack: AtomicFloat,
+    hold: AtomicFloat,
+    release: AtomicFloat,
+    bypass: AtomicFloat,
+    /// Read-only meter, 1 while the gate is open. Not a control, the host only
+    /// reads it back.
+    open: AtomicFloat,
+}
+
+/// Detector and gain state, linked across both channels.
+#[derive(Copy, Clone, Default)]
+struct GateDetector {
+    level: f64,
+    open: bool,
+    /// Gain applied to the signal, from the floor set by the range up to 1.
+    gain: f64,
+    /// Samples left before the release starts.
+    hold_counter: usize,
+}
+
+/// Detector settings worked out once per block.
+struct Ballistics {
+    open_threshold: f64,
+    close_threshold: f64,
+    floor: f64,
+    hold_samples: usize,
+    cte_attack: f64,
+    cte_release: f64,
+    cte_detector: f64,
+}
+
+impl GateDetector {
+    /// Run the detector on the louder of the two channels and return the gain
+    /// to apply.
+    fn process(&mut self, input: f64, ballistics: &Ballistics) -> f64 {
+        // Instant attack so the gate opens on the first sample of a transient
+        self.level = flush_denormal_f64(envelope(
+            self.level,
+            input.abs(),
+            0.0,
+            ballistics.cte_detector,
+        ));
+
+        if self.level > ballistics.open_threshold {
+            self.open = true;
+        } else if self.level < ballistics.close_threshold {
+            self.open = false;
+        }
+
+        // Restart the hold for as long as the gate is open
+        if self.open {
+            self.hold_counter = ballistics.hold_samples;
+        }
+        let target = if self.open { 1.0 } else { ballistics.floor };
+        self.gain = held_envelope(
+            self.gain,
+            target,
+            ballistics.cte_attack,
+            ballistics.cte_release,
+            &mut self.hold_counter,
+        );
+        self.gain
+    }
+}
+
+impl Default for Gate {
+    fn default() -> Gate {
+        Gate {
+            params: Arc::new(GateParameters::default()),
+            sample_rate: 44100.0,
+            pairs: [PairState {
+                detector: GateDetector::default(),
+                bypass: BypassFade::new(44100.0),
+            }; MAX_PAIRS],
+        }
+    }
+}
+
+impl Default for GateParameters {
+    fn default() -> GateParameters {
+        GateParameters {
+            threshold: AtomicFloat::new(from_range(-40.0, THRESHOLD_RANGE.0, THRESHOLD_RANGE.1)),
+            hysteresis: AtomicFloat::new(3.0 / MAX_HYSTERESIS_DB),
+            range: AtomicFloat::new(0.5),
+            attack: AtomicFloat::new(from_range_log(1.0, ATTACK_RANGE.0, ATTACK_RANGE.1)),
+            hold: AtomicFloat::new(50.0 / MAX_HOLD_MS),
+            release: AtomicFloat::new(from_range_log(100.0, RELEASE_RANGE.0, RELEASE_RANGE.1)),
+            bypass: AtomicFloat::new(0.0),
+            open: AtomicFloat::new(0.0),
+        }
+    }
+}
+
+/// Threshold the gate opens at, in dB.
+const THRESHOLD_RANGE: (f32, f32) = (-80.0, 0.0);
+const MAX_HYSTERESIS_DB: f32 = 12.0;
+/// Most attenuation the closed gate can apply, in dB.
+const MAX_RANGE_DB: f32 = 80.0;
+/// Ranges of the attack and release in ms, mapped exponentially. Hold stays
+/// linear, no hold at all is a useful setting.
+const ATTACK_RANGE: (f32, f32) = (0.1, 50.0);
+const MAX_HOLD_MS: f32 = 500.0;
+const RELEASE_RANGE: (f32, f32) = (1.0, 1000.0);
+/// Release of the level detector, in the same units as attack and release.
+/// Long enough to ride over the troughs of a low note.
+const DETECTOR_RELEASE: f64 = 10.0;
+
+impl GateParameters {
+    fn threshold_db(&self) -> f32 {
+        to_range(self.threshold.get(), THRESHOLD_RANGE.0, THRESHOLD_RANGE.1)
+    }
+
+    fn hysteresis_db(&self) -> f32 {
+        self.hysteresis.get() * MAX_HYSTERESIS_DB
+    }
+
+    /// Attenuation of the closed gate in dB, as a positive number.
+    fn range_db(&self) -> f32 {
+        self.range.get() * MAX_RANGE_DB
+    }
+
+    fn attack_ms(&self) -> f32 {
+        to_range_log(self.attack.get(), ATTACK_RANGE.0, ATTACK_RANGE.1)
+    }
+
+    fn hold_ms(&self) -> f32 {
+        self.hold.get() * MAX_HOLD_MS
+    }
+
+    fn release_ms(&self) -> f32 {
+        to_range_log(self.release.get(), RELEASE_RANGE.0, RELEASE_RANGE.1)
+    }
+
+    fn ballistics(&self, sample_rate: f32) -> Ballistics {
+        let threshold = self.threshold_db();
+        let sample_rate = f64::from(sample_rate);
+        Ballistics {
+            open_threshold: f64::from(gain_from_db(threshold)),
+            close_threshold: f64::from(gain_from_db(threshold - self.hysteresis_db())),
+            floor: f64::from(gain_from_db(-self.range_db())),
+            hold_samples: (f64::from(self.hold_ms()) * 0.001 * sample_rate) as usize,
+            cte_attack: time_coeff(f64::from(self.attack_ms()), sample_rate),
+            cte_release: time_coeff(f64::from(self.release_ms()), sample_rate),
+            cte_detector: time_coeff(DETECTOR_RELEASE, sample_rate),
+        }
+    }
+}
+
+impl Plugin for Gate {
+    fn get_info(&self) -> Info {
+        Info {
+            name: "Gate".to_string(),
+            vendor: "DGriffin".to_string(),
+            unique_id: 543923173,
+            version: 1,
+            inputs: 2,
+            outputs: 2,
+            parameters: 8,
+            category: Category::Effect,
+            ..Default::default()
+        }
+    }
+
+    fn set_sample_rate(&mut self, rate: f32) {
+        self.sample_rate = rate;
+        for pair in self.pairs.iter_mut() {
+            pair.bypass.set_sample_rate(rate);
+        }
+    }
+
+    fn process(&mut self, buffer: &mut AudioBuffer<f32>) {
+        let bypass = self.params.bypass.get() > 0.5;
+        if bypass_pairs(buffer, &mut self.pairs, bypass, |pair| &mut pair.bypass) {
+            self.params.open.set(0.0);
+            return;
+        }
+
+        let ballistics = self.params.ballistics(self.sample_rate);
+        process_stereo(buffer, &mut self.pairs, |pair, input_l, input_r| {
+            let input = f64::from(input_l.abs().max(input_r.abs()));
+            let gain = pair.detector.process(input, &ballistics) as f32;
+            pair.bypass
+                .mix((input_l, input_r), (input_l * gain, input_r * gain))
+        });
+        // The meter follows the first pair, the main stereo pair
+        let open = self.pairs[0].detector.open;
+        self.params.open.set(if open { 1.0 } else { 0.0 });
+    }
+
+    fn get_parameter_object(&mut self) -> Arc<dyn PluginParameters> {
+        Arc::clone(&self.params) as Arc<dyn PluginParameters>
+    }
+
+    fn can_do(&self, can_do: CanDo) -> Supported {
+        effect_can_do(can_do)
+    }
+}
+
+impl PluginParameters for GateParameters {
+    fn get_parameter(&self, index: i32) -> f32 {
+        match index {
+            0 => self.threshold.get(),
+            1 => self.hysteresis.get(),
+            2 => self.range.get(),
+            3 => self.attack.get(),
+            4 => self.hold.get(),
+            5 => self.release.get(),
+            6 => self.bypass.get(),
+            7 => self.open.get(),
+            _ => 0.0,
+        }
+    }
+
+    fn set_parameter(&self, index: i32, val: f32) {
+        let val = match clamp_parameter(val) {
+            Some(val) => val,
+            None => return,
+        };
+        match index {
+            0 => self.threshold.set(val),
+            1 => self.hysteresis.set(val),
+            2 => self.range.set(val),
+            3 => self.attack.set(val),
+            4 => self.hold.set(val),
+            5 => self.release.set(val),
+            6 => self.bypass.set(val),
+            // The open meter is written by `process` only
+            _ => (),
+        }
+    }
+
+    fn can_be_automated(&self, index: i32) -> bool {
+        index != 7
+    }
+
+    fn get_parameter_text(&self, index: i32) -> String {
+        match index {
+            0 => format!("{:.1} dB", self.threshold_db()),
+            1 => format!("{:.1} dB", self.hysteresis_db()),
+            2 => format!("{:.1} dB", -self.range_db()),
+            3 => format!("{:.1} ms", self.attack_ms()),
+            4 => format!("{:.0} ms", self.hold_ms()),
+            5 => format!("{:.0} ms", self.release_ms()),
+            6 => (if self.bypass.get() > 0.5 { "On" } else { "Off" }).to_string(),
+            7 => (if self.open.get() > 0.5 {
+                "Open"
+            } else {
+                "Closed"
+            })
+            .to_string(),
+            _ => "".to_string(),
+        }
+    }
+
+    fn get_parameter_name(&self, index: i32) -> String {
+        match index {
+            0 => "Threshold",
+            1 => "Hysteresis",
+            2 => "Range",
+            3 => "Attack",
+            4 => "Hold",
+            5 => "Release",
+            6 => "Bypass",
+            7 => "Gate",
+            _ => "",
+        }
+        .to_string()
+    }
+}
+
+plugin_main!(Gate);
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+    use vst::plugin::{Plugin, PluginParameters};
+    use vsts::buffer::process_buffer;
+    use {Gate, GateDetector, GateParameters};
+
+    /// Run a constant `level` on both channels through `gate` and return the
+    /// last output sample.
+    fn process_level(gate: &mut Gate, level: f32) -> f32 {
+        let inputs = vec![vec![level; 4410]; 2];
+        let outputs = process_buffer(&inputs, 2, 4410, |buffer| gate.process(buffer));
+        outputs[0][4409]
+    }
+
+    #[test]
+    fn test_hysteresis() {
+        let params = GateParameters::default();
+        // Opens at -40dB, closes at -43dB
+        let ballistics = params.ballistics(44100.0);
+        let between = 0.008;
+        let mut detector = GateDetector::default();
+        for _ in 0..1000 {
+            detector.process(between, &ballistics);
+        }
+        assert!(!detector.open);
+
+        detector.process(0.1, &ballistics);
+        assert!(detector.open);
+        for _ in 0..1000 {
+            detector.process(between, &ballistics);
+        }
+        assert!(detector.open);
+    }
+
+    #[test]
+    fn test_range_limits_attenuation() {
+        let mut gate = Gate::default();
+        // -40dB range on a signal below the threshold
+        let out = process_level(&mut gate, 0.001);
+        assert!((out / 0.001 - 0.01).abs() < 1e-3);
+        assert_eq!(gate.params.open.get(), 0.0);
+
+        let out = process_level(&mut gate, 0.5);
+        assert!((out - 0.5).abs() < 1e-3);
+        assert_eq!(gate.params.open.get(), 1.0);
+    }
+
+    #[test]
+    fn test_bypass_crossfades() {
+        // A sine the gate holds closed, bypassing it jumps from -40dB to full level
+        let mut gate = Gate::default();
+        gate.params.set_parameter(0, 1.0);
+        let sine: Vec<f32> = (0..9000)
+            .map(|n| (2.0 * PI * 100.0 * n as f32 / 44100.0).sin() * 0.5)
+            .collect();
+        // The host can only switch it between blocks, the fades run on across them
+        let mut output = Vec::new();
+        for (block, chunk) in sine.chunks(1000).enumerate() {
+            gate.params
+                .set_parameter(6, if block == 3 || block == 4 { 1.0 } else { 0.0 });
+            let inputs = vec![chunk.to_vec(); 2];
+            let outputs = process_buffer(&inputs, 2, chunk.len(), |buffer| gate.process(buffer));
+            output.extend_from_slice(&outputs[0]);
+        }
+        // Bypassed all the way to the input, then back to the gate
+        let peak = |samples: &[f32]| samples.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+        assert!(peak(&output[4000..5000]) > 0.49);
+        assert!(peak(&output[2000..3000]) < 0.01 && peak(&output[8000..]) < 0.01);
+        // No faster than the sine itself moves, plus a little for the fade
+        let jump = output
+            .windows(2)
+            .fold(0.0f32, |jump, pair| jump.max((pair[1] - pair[0]).abs()));
+        assert!(jump < 0.01, "jump {}", jump);
+    }
+
+    #[test]
+    fn test_parameter_text() {
+        let params = GateParameters::default();
+        let text: Vec<String> = (0..8).map(|i| params.get_parameter_text(i)).collect();
+        assert_eq!(
+            text,
+            ["-40.0 dB", "3.0 dB", "-40.0 dB", "1.0 ms", "50 ms", "100 ms", "Off", "Closed"]
+        );
+    }
+}
@@ -0,0 +1,497 @@
+#[macro_use]
+extern crate vst;
+extern crate vsts;
+
+use vst::api::Supported;
+use vst::buffer::AudioBuffer;
+use vst::plugin::{CanDo, Category, HostCallback, Info, Plugin, PluginParameters};
+use vst::util::AtomicFloat;
+use vsts::buffer::{process_stereo, MAX_PAIRS};
+use vsts::dsp::{soft_clip, time_coeff, DelayLine};
+use vsts::smoothing::{set_bypass, BypassFade};
+use vsts::util::{
+    clamp_parameter, db_from_gain, effect_can_do, from_range, from_range_log, gain_from_db,
+    notify_latency, to_range, to_range_log, MIN_DB,
+};
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// Brickwall limiter. The gain is worked out from the undelayed input and the
+/// audio is delayed by the lookahead, so the gain has already come down by the
+/// time a peak reaches the output.
+struct Limiter {
+    params: Arc<LimiterParameters>,
+    host: HostCallback,
+    sample_rate: f32,
+    /// Lookahead in samples the host was last told about.
+    latency: usize,
+    pairs: Vec<PairState>,
+}
+
+/// Lookahead and gain of one channel pair.
+struct PairState {
+    lines: [DelayLine; 2],
+    gain: GainComputer,
+    /// Crossfade in and out of bypass.
+    bypass: BypassFade,
+}
+
+impl PairState {
+    fn new(sample_rate: f32) -> PairState {
+        let max_lookahead = lookahead_samples(1.0, sample_rate);
+        PairState {
+            lines: [DelayLine::new(max_lookahead), DelayLine::new(max_lookahead)],
+            gain: GainComputer::new(max_lookahead),
+            bypass: BypassFade::new(sample_rate),
+        }
+    }
+}
+
+/// The plugin's parameter object contains the values of parameters that can be
+/// adjusted from the host.
+///
+/// The parameters object is shared between the processing and GUI threads.
+/// For this reason, all mutable state in the object has to be represented
+/// through thread-safe interior mutability.
+struct LimiterParameters {
+    ceiling: AtomicFloat,
+    release: AtomicFloat,
+    lookahead: AtomicFloat,
+    bypass: AtomicFloat,
+    /// Read-only meter, the most gain reduction in the last block in dB. Not
+    /// a control, the host only reads it back.
+    reduction: AtomicFloat,
+}
+
+/// Turns the gain each sample needs into a smooth gain curve that is never
+/// above any of them.
+///
+/// The needed gain is held at the smallest value of the last `window` samples,
+/// released with an instant attack and exponential release, then averaged
+/// over the same `window`. Every value in the average is at most the gain the
+/// oldest sample in the window needs, so once the audio is delayed by
+/// `window - 1` samples the limiter can't overshoot, and the average fades the
+/// gain down across the lookahead instead of stepping.
+struct GainComputer {
+    window: usize,
+    /// Candidates for the smallest gain in the window as (sample, gain), the
+    /// gains rising from front to back.
+    hold: VecDeque<(u64, f32)>,
+    sample: u64,
+    envelope: f32,
+    /// The last `window` released gains and their sum.
+    fade: Vec<f32>,
+    fade_pos: usize,
+    fade_sum: f64,
+}
+
+impl GainComputer {
+    /// A gain computer for up to `max_lookahead` samples of lookahead.
+    fn new(max_lookahead: usize) -> GainComputer {
+        let mut gain = GainComputer {
+            window: 1,
+            // The window plus the sample pushed before the oldest is dropped
+            hold: VecDeque::with_capacity(max_lookahead + 2),
+            sample: 0,
+            envelope: 1.0,
+            fade: Vec::with_capacity(max_lookahead + 1),
+            fade_pos: 0,
+            fade_sum: 0.0,
+        };
+        gain.set_lookahead(0);
+        gain
+    }
+
+    /// Change the lookahead, starting over from unity gain.
+    fn set_lookahead(&mut self, samples: usize) {
+        self.window = samples + 1;
+        self.hold.clear();
+        self.envelope = 1.0;
+        self.fade.clear();
+        self.fade.resize(self.window, 1.0);
+        self.fade_pos = 0;
+        self.fade_sum = self.window as f64;
+    }
+
+    /// Take the gain the newest sample needs, and return the gain for the
+    /// sample `window - 1` samples ago.
+    fn process(&mut self, needed: f32, cte_release: f32) -> f32 {
+        while self.hold.back().map_or(false, |&(_, gain)| gain >= needed) {
+            self.hold.pop_back();
+        }
+        self.hold.push_back((self.sample, needed));
+        let window = self.window as u64;
+        while self
+            .hold
+            .front()
+            .map_or(false, |&(sample, _)| sample + window <= self.sample)
+        {
+            self.hold.pop_front();
+        }
+        self.sample += 1;
+        let held = self.hold.front().map_or(needed, |&(_, gain)| gain);
+
+        self.envelope = if held < self.envelope {
+            held
+        } else {
+            held + cte_release * (self.envelope - held)
+        };
+
+        self.fade_sum += f64::from(self.envelope) - f64::from(self.fade[self.fade_pos]);
+        self.fade[self.fade_pos] = self.envelope;
+        self.fade_pos = (self.fade_pos + 1) % self.window;
+        (self.fade_sum / self.window as f64) as f32
+    }
+}
+
+impl Default for Limiter {
+    fn default() -> Limiter {
+        let sample_rate = 44100.0;
+        let params = LimiterParameters::default();
+        Limiter {
+            host: HostCallback::default(),
+            sample_rate,
+            latency: lookahead_samples(params.lookahead.get(), sample_rate),
+            params: Arc::new(params),
+            pairs: (0..MAX_PAIRS)
+                .map(|_| PairState::new(sample_rate))
+                .collect(),
+        }
+    }
+}
+
+impl Default for LimiterParameters {
+    fn default() -> LimiterParameters {
+        LimiterParameters {
+            ceiling: AtomicFloat::new(from_range(-0.3, CEILING_RANGE.0, CEILING_RANGE.1)),
+            release: AtomicFloat::new(from_range_log(50.0, RELEASE_RANGE.0, RELEASE_RANGE.1)),
+            lookahead: AtomicFloat::new(0.5),
+            bypass: AtomicFloat::new(0.0),
+            reduction: AtomicFloat::new(0.0),
+        }
+    }
+}
+
+/// Range of the ceiling in dBFS.
+const CEILING_RANGE: (f32, f32) = (-24.0, 0.0);
+/// Range of the release in ms, mapped exponentially.
+const RELEASE_RANGE: (f32, f32) = (1.0, 1000.0);
+/// Longest lookahead time in ms.
+const MAX_LOOKAHEAD_MS: f32 = 10.0;
+/// Span of the reduction meter in dB.
+const REDUCTION_RANGE: (f32, f32) = (-30.0, 0.0);
+/// Fraction of the ceiling where the safety clipper starts to bend. It only
+/// catches what the gain misses, so it sits just under the ceiling.
+const SAFETY_KNEE: f32 = 0.98;
+
+/// Lookahead parameter value to a delay in samples.
+fn lookahead_samples(lookahead: f32, sample_rate: f32) -> usize {
+    (lookahead * MAX_LOOKAHEAD_MS * 0.001 * sample_rate).round() as usize
+}
+
+/// Gain that brings a peak of `peak` down to `ceiling`.
+fn needed_gain(peak: f32, ceiling: f32) -> f32 {
+    if peak > ceiling {
+        ceiling / peak
+    } else {
+        1.0
+    }
+}
+
+impl LimiterParameters {
+    fn ceiling_db(&self) -> f32 {
+        to_range(self.ceiling.get(), CEILING_RANGE.0, CEILING_RANGE.1)
+    }
+
+    fn release_ms(&self) -> f32 {
+        to_range_log(self.release.get(), RELEASE_RANGE.0, RELEASE_RANGE.1)
+    }
+
+    fn lookahead_ms(&self) -> f32 {
+        self.lookahead.get() * MAX_LOOKAHEAD_MS
+    }
+}
+
+impl Plugin for Limiter {
+    fn get_info(&self) -> Info {
+        Info {
+            name: "Limiter".to_string(),
+            vendor: "DGriffin".to_string(),
+            unique_id: 543923274,
+            version: 1,
+            inputs: 2,
+            outputs: 2,
+            parameters: 5,
+            category: Category::Mastering,
+            initial_delay: lookahead_samples(self.params.lookahead.get(), self.sample_rate) as i32,
+            ..Default::default()
+        }
+    }
+
+    fn new(host: HostCallback) -> Limiter {
+        Limiter {
+            host,
+            ..Limiter::default()
+        }
+    }
+
+    fn set_sample_rate(&mut self, rate: f32) {
+        self.sample_rate = rate;
+        self.pairs = (0..MAX_PAIRS).map(|_| PairState::new(rate)).collect();
+    }
+
+    fn process(&mut self, buffer: &mut AudioBuffer<f32>) {
+        // Bypassed audio still goes through the lookahead delay, so the
+        // latency the host compensates for doesn't change, and the gain keeps
+        // following the input ready for when the limiter comes back in
+        let bypass = self.params.bypass.get() > 0.5;
+        let lookahead = lookahead_samples(self.params.lookahead.get(), self.sample_rate);
+        if lookahead != self.latency {
+            self.latency = lookahead;
+            notify_latency(&self.host, lookahead);
+        }
+        let bypassed = set_bypass(&mut self.pairs, bypass, |pair| &mut pair.bypass);
+        for pair in self.pairs.iter_mut() {
+            if lookahead + 1 != pair.gain.window {
+                pair.gain.set_lookahead(lookahead);
+            }
+        }
+        let ceiling = gain_from_db(self.params.ceiling_db());
+        let cte_release = time_coeff(
+            f64::from(self.params.release_ms()),
+            f64::from(self.sample_rate),
+        ) as f32;
+
+        let mut min_gain: f32 = 1.0;
+        process_stereo(buffer, &mut self.pairs, |pair, input_l, input_r| {
+            let peak = input_l.abs().max(input_r.abs());
+            let gain = pair.gain.process(needed_gain(peak, ceiling), cte_release);
+            min_gain = min_gain.min(gain);
+
+            let [line_l, line_r] = &mut pair.lines;
+            let (delayed_l, delayed_r) = if lookahead > 0 {
+                (line_l.read(lookahead as f32), line_r.read(lookahead as f32))
+            } else {
+                (input_l, input_r)
+            };
+            line_l.write(input_l);
+            line_r.write(input_r);
+
+            let output = (
+                soft_clip(delayed_l * gain, ceiling, SAFETY_KNEE),
+                soft_clip(delayed_r * gain, ceiling, SAFETY_KNEE),
+            );
+            pair.bypass.mix((delayed_l, delayed_r), output)
+        });
+        if bypassed {
+            self.params.reduction.set(0.0);
+        } else {
+            self.params
+                .reduction
+                .set(db_from_gain(min_gain).max(MIN_DB));
+        }
+    }
+
+    fn get_parameter_object(&mut self) -> Arc<dyn PluginParameters> {
+        Arc::clone(&self.params) as Arc<dyn PluginParameters>
+    }
+
+    fn can_do(&self, can_do: CanDo) -> Supported {
+        effect_can_do(can_do)
+    }
+}
+
+impl PluginParameters for LimiterParameters {
+    fn get_parameter(&self, index: i32) -> f32 {
+        match index {
+            0 => self.ceiling.get(),
+            1 => self.release.get(),
+            2 => self.lookahead.get(),
+            3 => self.bypass.get(),
+            4 => from_range(self.reduction.get(), REDUCTION_RANGE.0, REDUCTION_RANGE.1),
+            _ => 0.0,
+        }
+    }
+
+    fn set_parameter(&self, index: i32, val: f32) {
+        let val = match clamp_parameter(val) {
+            Some(val) => val,
+            None => return,
+        };
+        match index {
+            0 => self.ceiling.set(val),
+            1 => self.release.set(val),
+            2 => self.lookahead.set(val),
+            3 => self.bypass.set(val),
+            // The reduction meter is written by `process` only
+            _ => (),
+        }
+    }
+
+    fn can_be_automated(&self, index: i32) -> bool {
+        index != 4
+    }
+
+    fn get_parameter_text(&self, index: i32) -> String {
+        match index {
+            0 => format!("{:.1} dB", self.ceiling_db()),
+            1 => format!("{:.0} ms", self.release_ms()),
+            2 => format!("{:.1} ms", self.lookahead_ms()),
+            3 => (if self.bypass.get() > 0.5 { "On" } else { "Off" }).to_string(),
+            4 => format!("{:.1} dB", self.reduction.get()),
+            _ => "".to_string(),
+        }
+    }
+
+    fn get_parameter_name(&self, index: i32) -> String {
+        match index {
+            0 => "Ceiling",
+            1 => "Release",
+            2 => "Lookahead",
+            3 => "Bypass",
+            4 => "Reduction",
+            _ => "",
+        }
+        .to_string()
+    }
+}
+
+plugin_main!(Limiter);
+
+#[cfg(test)]
+mod tests {
+    use vst::plugin::{Plugin, PluginParameters};
+    use vsts::buffer::process_buffer;
+    use vsts::util::gain_from_db;
+    use {lookahead_samples, GainComputer, Limiter, LimiterParameters};
+
+    /// Run a sine `gain` times the level of a full scale one through `limiter`.
+    fn process_sine(limiter: &mut Limiter, gain: f32) -> Vec<Vec<f32>> {
+        let sine: Vec<f32> = (0..4410).map(|n| (n as f32 * 0.06).sin() * gain).collect();
+        process_buffer(&[sine.clone(), sine], 2, 4410, |buffer| {
+            limiter.process(buffer)
+        })
+    }
+
+    #[test]
+    fn test_sine_held_under_ceiling() {
+        for &lookahead in [0.0, 0.5, 1.0].iter() {
+            let mut limiter = Limiter::default();
+            let params = limiter.get_parameter_object();
+            params.set_parameter(2, lookahead);
+            // -6dB ceiling
+            params.set_parameter(0, 0.75);
+            let ceiling = gain_from_db(-6.0);
+            let outputs = process_sine(&mut limiter, 4.0);
+            let peak = outputs[0].iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+            assert!(peak <= ceiling, "{} over {}", peak, ceiling);
+            assert!(peak > ceiling * 0.9);
+            assert!(params.get_parameter(4) < 0.5);
+        }
+    }
+
+    #[test]
+    fn test_quiet_signal_passes() {
+        let mut limiter = Limiter::default();
+        limiter.get_parameter_object().set_parameter(2, 0.0);
+        let outputs = process_sine(&mut limiter, 0.5);
+        for (n, x) in outputs[1].iter().enumerate() {
+            assert_eq!(*x, (n as f32 * 0.06).sin() * 0.5);
+        }
+        assert_eq!(limiter.params.reduction.get(), 0.0);
+    }
+
+    #[test]
+    fn test_gain_arrives_before_peak() {
+        let mut gain = GainComputer::new(8);
+        gain.set_lookahead(4);
+        // A peak needing half gain, the output 4 samples later must be there
+        let curve: Vec<f32> = [1.0, 1.0, 0.5, 1.0, 1.0, 1.0, 1.0, 1.0]
+            .iter()
+            .map(|&needed| gain.process(needed, 0.0))
+            .collect();
+        assert!(curve[6] <= 0.5);
+        // Faded down rather than stepped
+        assert!(curve[2] < 1.0 && curve[2] > curve[5]);
+    }
+
+    #[test]
+    fn test_hold_never_reallocates() {
+        let mut gain = GainComputer::new(8);
+        gain.set_lookahead(8);
+        let capacity = gain.hold.capacity();
+        // A falling level needs more gain each sample, so nothing held is
+        // ever dropped for a lower one and the window stays full
+        for n in 0..64 {
+            gain.process(0.1 + n as f32 * 0.01, 0.0);
+        }
+        assert_eq!(gain.hold.capacity(), capacity);
+    }
+
+    #[test]
+    fn test_latency() {
+        let mut limiter = Limiter::default();
+        limiter.get_parameter_object().set_parameter(2, 1.0);
+        assert_eq!(limiter.get_info().initial_delay, 441);
+        // The host hears about the change from the next block
+        assert_eq!(limiter.latency, lookahead_samples(0.5, 44100.0));
+        process_sine(&mut limiter, 0.5);
+        assert_eq!(limiter.latency, 441);
+        assert_eq!(lookahead_samples(0.5, 48000.0), 240);
+        let params = LimiterParameters::default();
+        assert_eq!(params.get_parameter_text(2), "5.0 ms");
+    }
+
+    #[test]
+    fn test_bypass_keeps_latency() {
+        let mut limiter = Limiter::default();
+        let params = limiter.get_parameter_object();
+        params.set_parameter(2, 1.0);
+        params.set_parameter(3, 1.0);
+        let delay = limiter.get_info().initial_delay as usize;
+        let mut impulse = vec![0.0; 1024];
+        impulse[0] = 1.0;
+        let mut process = |input: &[f32]| {
+            process_buffer(&[input.to_vec(), input.to_vec()], 2, 1024, |buffer| {
+                limiter.process(buffer)
+            })
+        };
+        process(&impulse);
+
+        // Once the fade is over the impulse comes out as late as reported
+        let outputs = process(&impulse);
+        for (n, x) in outputs[0].iter().enumerate() {
+            assert_eq!(*x, if n == delay { 1.0 } else { 0.0 });
+        }
+    }
+
+    #[test]
+    fn test_bypass_keeps_delay_current() {
+        let mut limiter = Limiter::default();
+        let params = limiter.get_parameter_object();
+        params.set_parameter(2, 1.0);
+        let mut process = |input: f32| {
+            process_buffer(&[vec![input; 1024], vec![input; 1024]], 2, 1024, |buffer| {
+                limiter.process(buffer)
+            })
+        };
+        process(0.5);
+        params.set_parameter(3, 1.0);
+        process(0.5);
+        // Silence once fully bypassed, nothing from before is left to play back
+        process(0.0);
+        params.set_parameter(3, 0.0);
+        let outputs = process(0.0);
+        assert!(outputs[0].iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn test_release_text() {
+        let params = LimiterParameters::default();
+        assert_eq!(params.get_parameter_text(1), "50 ms");
+        // Exponential, half way up is the geometric middle of 1 - 1000ms
+        params.set_parameter(1, 0.5);
+        assert_eq!(params.get_parameter_text(1), "32 ms");
+    }
+}
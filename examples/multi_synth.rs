@@ -1,25 +1,132 @@
 #[macro_use]
 extern crate vst;
+extern crate log;
+extern crate vsts;
 
 use std::f64::consts::PI;
-use std::sync::Arc;
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use vst::api::{Events, Supported};
 use vst::buffer::AudioBuffer;
 use vst::event::Event;
-use vst::plugin::{CanDo, Category, Info, Plugin, PluginParameters};
+use vst::plugin::{CanDo, Category, HostCallback, Info, Plugin, PluginParameters};
 use vst::util::AtomicFloat;
+use vsts::dsp::{poly_blep, soft_limit, Biquad, FilterKind, Tilt, MAX_TILT_DB};
+use vsts::midi::{release_velocity, MidiQueue};
+use vsts::noise::{PinkFilter, XorShift};
+use vsts::smoothing::BlockRamp;
+use vsts::tempo::{division, host_bpm, DEFAULT_BPM, DIVISIONS};
+use vsts::util::{clamp_parameter, to_range_log};
 
-/// Convert the midi note's pitch into the equivalent frequency.
-///
-/// This function assumes A4 is 440hz.
-fn midi_pitch_to_freq(pitch: u8) -> f64 {
+/// Convert the midi note's pitch into the equivalent frequency, with A4 tuned
+/// to `a4_freq`.
+fn midi_pitch_to_freq(pitch: u8, a4_freq: f64) -> f64 {
     const A4_PITCH: i8 = 69;
-    const A4_FREQ: f64 = 440.0;
 
     // Midi notes can be 0-127
-    ((f64::from(pitch as i8 - A4_PITCH)) / 12.).exp2() * A4_FREQ
+    ((f64::from(pitch as i8 - A4_PITCH)) / 12.).exp2() * a4_freq
+}
+
+/// Range of the tuning parameter, the frequency of A4 in hz.
+const MIN_TUNING: f64 = 400.0;
+const MAX_TUNING: f64 = 460.0;
+
+/// Scale degree 0 of a loaded scale sits on middle C, A4 stays at the tuning
+/// parameter's frequency.
+const SCALE_ROOT_NOTE: i32 = 60;
+const SCALE_REFERENCE_NOTE: i32 = 69;
+
+/// A Scala (.scl) tuning, as ratios from the root.
+#[derive(Clone, Debug, PartialEq)]
+struct Scale {
+    /// Ratios for degrees 1 to n, the last one is the period (usually 2/1).
+    degrees: Vec<f64>,
+}
+
+impl Scale {
+    /// Ratio from the root for a note `steps` scale degrees away.
+    fn ratio(&self, steps: i32) -> f64 {
+        let len = self.degrees.len() as i32;
+        let period = self.degrees[self.degrees.len() - 1];
+        let degree = steps.rem_euclid(len);
+        let ratio = if degree == 0 {
+            1.0
+        } else {
+            self.degrees[degree as usize - 1]
+        };
+        ratio * period.powi(steps.div_euclid(len))
+    }
+}
+
+/// Parse a single pitch line, cents if it has a period, otherwise a ratio.
+fn parse_scl_pitch(line: &str) -> Option<f64> {
+    let value = line.split_whitespace().next()?;
+    let ratio = if value.contains('.') {
+        (value.parse::<f64>().ok()? / 1200.0).exp2()
+    } else if let Some(slash) = value.find('/') {
+        let numerator = value[..slash].parse::<f64>().ok()?;
+        let denominator = value[slash + 1..].parse::<f64>().ok()?;
+        numerator / denominator
+    } else {
+        value.parse::<f64>().ok()?
+    };
+    if ratio.is_finite() && ratio > 0.0 {
+        Some(ratio)
+    } else {
+        None
+    }
+}
+
+/// Parse the contents of a Scala .scl file.
+fn parse_scl(text: &str) -> Result<Scale, String> {
+    // Lines starting with ! are comments, the first real line is the description
+    let mut lines = text.lines().filter(|line| !line.starts_with('!')).skip(1);
+
+    let count = lines
+        .next()
+        .and_then(|line| line.trim().parse::<usize>().ok())
+        .ok_or_else(|| "missing note count".to_string())?;
+    if count == 0 {
+        return Err("scale has no notes".to_string());
+    }
+
+    let mut degrees = Vec::with_capacity(count);
+    for line in lines.take(count) {
+        match parse_scl_pitch(line) {
+            Some(ratio) => degrees.push(ratio),
+            None => return Err(format!("invalid pitch: {}", line.trim())),
+        }
+    }
+    if degrees.len() != count {
+        return Err(format!("expected {} notes, found {}", count, degrees.len()));
+    }
+    Ok(Scale { degrees })
+}
+
+/// Frequency of every midi note. Without a scale this is equal temperament.
+fn tuning_table(scale: Option<&Scale>, a4_freq: f64) -> [f64; 128] {
+    let mut table = [0.0; 128];
+    for (note, freq) in table.iter_mut().enumerate() {
+        *freq = match scale {
+            Some(scale) => {
+                let reference = scale.ratio(SCALE_REFERENCE_NOTE - SCALE_ROOT_NOTE);
+                a4_freq * scale.ratio(note as i32 - SCALE_ROOT_NOTE) / reference
+            }
+            None => midi_pitch_to_freq(note as u8, a4_freq),
+        };
+    }
+    table
 }
 
+const PARAMETER_COUNT: i32 = 37;
+/// Index of the read-only voice count.
+const VOICES_PARAMETER: i32 = 28;
+/// Voices `process` scans, seven stacked on every key plus the mono voice.
+const MAX_VOICES: usize = 7 * 255 + 1;
+/// Parameter snapshots the host or a midi program change can switch between.
+const PROGRAM_COUNT: usize = 8;
+
 struct SineSynthParameters {
     // The plugin's state consists of a single parameter: amplitude.
     amplitude: AtomicFloat,
@@ -31,11 +138,486 @@ struct SineSynthParameters {
     triangle: AtomicFloat,
     saw: AtomicFloat,
     square: AtomicFloat,
+    cutoff: AtomicFloat,
+    resonance: AtomicFloat,
+    lfo_rate: AtomicFloat,
+    lfo_sync: AtomicFloat,
+    lfo_depth: AtomicFloat,
+    mono: AtomicFloat,
+    glide_time: AtomicFloat,
+    tuning: AtomicFloat,
+    white: AtomicFloat,
+    pink: AtomicFloat,
+    sub_level: AtomicFloat,
+    sync_enable: AtomicFloat,
+    sync_ratio: AtomicFloat,
+    vel_to_cutoff: AtomicFloat,
+    /// How far aftertouch opens the filter.
+    pressure_amount: AtomicFloat,
+    /// How far each voice's random pan reaches from the centre.
+    pan_spread: AtomicFloat,
+    /// How far the four basic oscillators fan out across the stereo field,
+    /// see `OSC_PANS`.
+    osc_spread: AtomicFloat,
+    /// Scale the oscillator levels so their mix stays at the same loudness.
+    osc_normalize: AtomicFloat,
+    wavetable_level: AtomicFloat,
+    /// Which built-in table the wavetable oscillator plays.
+    wavetable_shape: AtomicFloat,
+    /// How far each voice's random tuning and level drift reach.
+    analog_amount: AtomicFloat,
+    /// How much harder played notes shorten the attack.
+    vel_to_attack: AtomicFloat,
+    /// How much a harder note off shortens the release, and a softer one
+    /// lengthens it.
+    release_vel_amount: AtomicFloat,
+    /// Start each voice's oscillators at a random phase.
+    free_phase: AtomicFloat,
+    /// Replaying a note that is still releasing restarts that voice instead
+    /// of starting another one alongside it.
+    retrigger_steal: AtomicFloat,
+    /// Tilt of the master output, darker below 0.5 and brighter above.
+    tone: AtomicFloat,
+    /// Soft clip the output so dense chords can't go past full scale.
+    output_clip: AtomicFloat,
+    /// Read-only, how many voices are sounding. Written by the audio thread
+    /// each block for debugging voice stealing, the host can't set it.
+    voices: AtomicFloat,
+    /// Whether the last block went past full scale before the output clip,
+    /// shown with the voice count as a headroom warning.
+    clipping: AtomicBool,
+    /// Path of the loaded .scl file, empty for equal temperament.
+    scale_path: Mutex<String>,
+    /// Newly loaded scale waiting to be picked up by the audio thread.
+    scale: Mutex<Option<Scale>>,
+    scale_changed: AtomicBool,
+    /// Parameter values of every program, indexed by parameter. The live
+    /// parameters are the current program's, its snapshot here is only
+    /// brought up to date when switching away or saving the bank.
+    programs: Mutex<Vec<Vec<f32>>>,
+    program: AtomicUsize,
+}
+
+/// How far a full velocity note opens the filter at full vel_to_cutoff.
+const MAX_VEL_CUTOFF_OCTAVES: f32 = 4.0;
+
+/// Raise `cutoff` for harder played notes, `velocity` goes from 0 to 1.
+fn velocity_cutoff(cutoff: f32, amount: f32, velocity: f32) -> f32 {
+    cutoff * 2.0f32.powf(amount * velocity * MAX_VEL_CUTOFF_OCTAVES)
+}
+
+/// How many times shorter a full velocity note's attack is at full
+/// vel_to_attack, in octaves.
+const MAX_VEL_ATTACK_OCTAVES: f64 = 4.0;
+
+/// Shorten `attack` for harder played notes, `velocity` goes from 0 to 1.
+fn velocity_attack(attack: f64, amount: f32, velocity: f32) -> f64 {
+    attack * (-f64::from(amount * velocity) * MAX_VEL_ATTACK_OCTAVES).exp2()
+}
+
+/// How many times shorter a full release velocity note off's release is at
+/// full release_vel_amount, in octaves. The softest note off makes it as many
+/// times longer.
+const MAX_RELEASE_VEL_OCTAVES: f64 = 2.0;
+
+/// Shorten `release` for harder note offs, `velocity` goes from -1 to 1.
+fn velocity_release(release: f64, amount: f32, velocity: f32) -> f64 {
+    release * (-f64::from(amount * velocity) * MAX_RELEASE_VEL_OCTAVES).exp2()
+}
+
+/// How far full pressure opens the filter at full pressure_amount.
+const MAX_PRESSURE_CUTOFF_OCTAVES: f32 = 4.0;
+
+/// Raise `cutoff` with aftertouch, `pressure` goes from 0 to 1.
+fn pressure_cutoff(cutoff: f32, amount: f32, pressure: f32) -> f32 {
+    cutoff * 2.0f32.powf(amount * pressure * MAX_PRESSURE_CUTOFF_OCTAVES)
+}
+
+/// Frequency the tone knob tilts the output around.
+const TONE_PIVOT: f32 = 1000.0;
+
+/// Furthest a voice drifts from its tuning at full analog amount, in cents.
+const MAX_DRIFT_CENTS: f64 = 15.0;
+/// Furthest a voice drifts from its level at full analog amount, in dB.
+const MAX_DRIFT_DB: f64 = 1.0;
+
+/// Frequency ratio and gain for a voice's drift offsets, `pitch` and `level`
+/// go from -1 to 1.
+fn analog_drift(pitch: f32, level: f32, amount: f32) -> (f64, f64) {
+    let amount = f64::from(amount);
+    let ratio = (f64::from(pitch) * amount * MAX_DRIFT_CENTS / 1200.0).exp2();
+    let gain = 10.0f64.powf(f64::from(level) * amount * MAX_DRIFT_DB / 20.0);
+    (ratio, gain)
+}
+
+/// Sine, triangle, saw, square and wavetable levels divided by their sum, so
+/// the mix peaks at the same level however many oscillators are turned up.
+/// Levels that are all off stay off.
+fn normalize_levels(mut levels: [f64; 5]) -> [f64; 5] {
+    let total: f64 = levels.iter().sum();
+    if total > 0.0 {
+        for level in levels.iter_mut() {
+            *level /= total;
+        }
+    }
+    levels
+}
+
+/// Samples in one cycle of a wavetable.
+const TABLE_SIZE: usize = 1024;
+/// Harmonics in the full bandwidth copy of a table, half the table size so
+/// the highest is still sampled twice a cycle.
+const TABLE_HARMONICS: usize = TABLE_SIZE / 2;
+/// Band limited copies of each table, each with half the harmonics of the one
+/// before, down to the fundamental alone.
+const TABLE_MIPS: usize = 10;
+
+/// The built-in wavetables.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum TableShape {
+    Sine,
+    Saw,
+    /// Two resonant peaks over a quiet saw, loosely an "ah" vowel.
+    Formant,
+}
+
+/// Every table, in the order the shape parameter steps through them.
+const TABLE_SHAPES: [TableShape; 3] = [TableShape::Sine, TableShape::Saw, TableShape::Formant];
+
+impl TableShape {
+    /// Shape for a 0 - 1 parameter value.
+    fn from_parameter(x: f32) -> TableShape {
+        let index = (x * (TABLE_SHAPES.len() - 1) as f32).round() as usize;
+        TABLE_SHAPES[index.min(TABLE_SHAPES.len() - 1)]
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            TableShape::Sine => "Sine",
+            TableShape::Saw => "Saw",
+            TableShape::Formant => "Formant",
+        }
+    }
+
+    /// Amplitude of harmonic `n`, the fundamental being 1.
+    fn harmonic(self, n: usize) -> f64 {
+        let n = n as f64;
+        match self {
+            TableShape::Sine => {
+                if n == 1.0 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            TableShape::Saw => (if n % 2.0 == 0.0 { -1.0 } else { 1.0 }) / n,
+            TableShape::Formant => {
+                0.2 / n
+                    + (-(n - 5.0).powi(2) / 8.0).exp()
+                    + 0.5 * (-(n - 12.0).powi(2) / 18.0).exp()
+            }
+        }
+    }
+}
+
+/// A single cycle waveform, stored as a stack of band limited copies so high
+/// notes can read one without harmonics above nyquist.
+struct Wavetable {
+    mips: Vec<[f32; TABLE_SIZE]>,
+}
+
+impl Wavetable {
+    /// Build the table additively from the shape's harmonics.
+    fn new(shape: TableShape) -> Wavetable {
+        // Every harmonic lands on a sample of the fundamental, so one cycle of
+        // sine covers them all
+        let sine: Vec<f64> = (0..TABLE_SIZE)
+            .map(|i| (i as f64 / TABLE_SIZE as f64 * TAU).sin())
+            .collect();
+        let mut mips = vec![[0.0f32; TABLE_SIZE]; TABLE_MIPS];
+        for (mip, table) in mips.iter_mut().enumerate() {
+            let harmonics = TABLE_HARMONICS >> mip;
+            for (i, sample) in table.iter_mut().enumerate() {
+                *sample = (1..=harmonics)
+                    .map(|n| shape.harmonic(n) * sine[(n * i) % TABLE_SIZE])
+                    .sum::<f64>() as f32;
+            }
+        }
+
+        // The same scale for every copy, so moving between them doesn't change the level
+        let peak = mips[0].iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+        for table in mips.iter_mut() {
+            for sample in table.iter_mut() {
+                *sample /= peak;
+            }
+        }
+        Wavetable { mips }
+    }
+
+    /// Read the table at `phase`, from 0 to 1, with linear interpolation. The
+    /// copy read is the fullest one whose harmonics all fit below nyquist at
+    /// `freq`.
+    fn read(&self, phase: f64, freq: f64, sample_rate: f64) -> f64 {
+        let table = &self.mips[table_mip(freq, sample_rate)];
+        let pos = phase.rem_euclid(1.0) * TABLE_SIZE as f64;
+        let index = pos as usize;
+        let frac = pos - index as f64;
+        let a = f64::from(table[index % TABLE_SIZE]);
+        let b = f64::from(table[(index + 1) % TABLE_SIZE]);
+        a + (b - a) * frac
+    }
+}
+
+/// Which band limited copy of a table to play at `freq`.
+fn table_mip(freq: f64, sample_rate: f64) -> usize {
+    let fits = (sample_rate * 0.5 / freq.max(1.0)) as usize;
+    (0..TABLE_MIPS)
+        .find(|&mip| TABLE_HARMONICS >> mip <= fits)
+        .unwrap_or(TABLE_MIPS - 1)
+}
+
+/// Left and right gain for a pan from -1 (left) to 1 (right). A balance law,
+/// so the centre stays at unity gain like an unpanned voice.
+fn pan_gains(pan: f32) -> (f32, f32) {
+    (1.0 - pan.max(0.0), 1.0 + pan.min(0.0))
+}
+
+/// Where the sine, triangle, saw and square sit at full osc spread. The sine
+/// holds the centre and the brighter waveforms go out furthest.
+const OSC_PANS: [f32; 4] = [0.0, 0.5, -1.0, 1.0];
+
+/// Left and right gains of the sine, triangle, saw and square for `spread`
+/// from 0 (all centred) to 1.
+fn osc_spread_gains(spread: f32) -> [(f64, f64); 4] {
+    let mut gains = [(1.0, 1.0); 4];
+    for (gain, &pan) in gains.iter_mut().zip(OSC_PANS.iter()) {
+        let (gain_l, gain_r) = pan_gains(pan * spread);
+        *gain = (f64::from(gain_l), f64::from(gain_r));
+    }
+    gains
+}
+
+/// Add the sine, triangle, saw and square in `oscs` to the centred `signal`,
+/// each placed by its `osc_spread_gains`.
+fn spread_oscs(signal: f64, oscs: [f64; 4], gains: &[(f64, f64); 4]) -> (f64, f64) {
+    let mut signal_l = signal;
+    let mut signal_r = signal;
+    for (osc, &(gain_l, gain_r)) in oscs.iter().zip(gains.iter()) {
+        signal_l += osc * gain_l;
+        signal_r += osc * gain_r;
+    }
+    (signal_l, signal_r)
+}
+
+/// Longest portamento in mono mode, in seconds.
+const MAX_GLIDE: f64 = 1.0;
+
+/// Lfo frequency in hz. Free running rates go from 0.05hz to 20hz, synced
+/// rates snap to a note division at `bpm`.
+fn lfo_hz(rate: f32, sync: bool, bpm: Option<f64>) -> f64 {
+    if sync {
+        let (_, beats) = division(&DIVISIONS, rate);
+        bpm.unwrap_or(DEFAULT_BPM) / 60.0 / beats
+    } else {
+        0.05 * 400.0f64.powf(f64::from(rate))
+    }
+}
+
+impl SineSynthParameters {
+    /// Filter cutoff in hz, mapped exponentially from 20hz to 20khz.
+    fn cutoff_hz(&self) -> f32 {
+        to_range_log(self.cutoff.get(), 20.0, 20000.0)
+    }
+
+    /// Filter q, starting from a flat 0.707.
+    fn resonance_q(&self) -> f32 {
+        0.707 * (2.0f32).powf(self.resonance.get() * 4.0)
+    }
+
+    /// Tilt of the output from the lows to the highs, 0 is flat.
+    fn tone_db(&self) -> f32 {
+        (self.tone.get() * 2.0 - 1.0) * MAX_TILT_DB
+    }
+
+    /// Frequency of the synced saw relative to the note, from 1 to 8.
+    fn sync_ratio(&self) -> f64 {
+        1.0 + f64::from(self.sync_ratio.get()) * 7.0
+    }
+
+    /// Table the wavetable oscillator plays, stepped through in order.
+    fn wavetable_shape(&self) -> TableShape {
+        TableShape::from_parameter(self.wavetable_shape.get())
+    }
+
+    /// Frequency of A4, rounded to a hundredth of a hz so the default is
+    /// exactly 440.
+    fn tuning_hz(&self) -> f64 {
+        let hz = MIN_TUNING + f64::from(self.tuning.get()) * (MAX_TUNING - MIN_TUNING);
+        (hz * 100.0).round() / 100.0
+    }
+
+    /// Load a .scl file for the synth to use, an empty path goes back to
+    /// equal temperament.
+    fn load_scale(&self, path: &str) {
+        let scale = if path.is_empty() {
+            None
+        } else {
+            match fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|text| parse_scl(&text))
+            {
+                Ok(scale) => Some(scale),
+                Err(e) => {
+                    ::log::error!("failed to load scale {}: {}", path, e);
+                    None
+                }
+            }
+        };
+        if let (Ok(mut scale_path), Ok(mut current)) = (self.scale_path.lock(), self.scale.lock()) {
+            *scale_path = path.to_string();
+            *current = scale;
+            self.scale_changed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Current parameter values, indexed by parameter.
+    fn snapshot(&self) -> Vec<f32> {
+        (0..PARAMETER_COUNT)
+            .map(|index| self.get_parameter(index))
+            .collect()
+    }
+
+    /// Make `preset` the current program. The values are written into the
+    /// programs' own storage, so switching never allocates.
+    fn switch_program(&self, programs: &mut [Vec<f32>], preset: usize) {
+        let current = self.program.load(Ordering::Relaxed);
+        for (index, value) in programs[current].iter_mut().enumerate() {
+            *value = self.get_parameter(index as i32);
+        }
+        self.apply_snapshot(&programs[preset]);
+        self.program.store(preset, Ordering::Relaxed);
+    }
+
+    /// Program change from midi. It comes in on the audio thread, so a bank
+    /// that's locked for saving or loading drops the change instead of waiting.
+    fn midi_program_change(&self, program: u8) {
+        let preset = usize::from(program);
+        if preset >= PROGRAM_COUNT || preset == self.program.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Ok(mut programs) = self.programs.try_lock() {
+            self.switch_program(&mut programs, preset);
+        }
+    }
+
+    fn apply_snapshot(&self, values: &[f32]) {
+        for (index, &value) in values.iter().enumerate() {
+            self.set_parameter(index as i32, value);
+        }
+    }
+
+    /// Parameter values as `key=value` lines.
+    fn serialize_values(data: &mut String, values: &[f32]) {
+        // The voice count is a meter, not part of the patch
+        for (index, value) in values.iter().enumerate() {
+            if index as i32 != VOICES_PARAMETER {
+                data.push_str(&format!("{}={}\n", index, value));
+            }
+        }
+    }
+
+    /// Parameter values and the scale path as `key=value` lines.
+    fn serialize_state(&self) -> Vec<u8> {
+        let mut data = String::new();
+        Self::serialize_values(&mut data, &self.snapshot());
+        if let Ok(scale_path) = self.scale_path.lock() {
+            data.push_str(&format!("scale={}\n", scale_path));
+        }
+        data.into_bytes()
+    }
+
+    /// Read `key=value` lines into `values`, loading the scale right away.
+    fn parse_state(&self, data: &str, values: &mut [f32]) {
+        for line in data.lines() {
+            let mut parts = line.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("scale"), Some(path)) => self.load_scale(path),
+                (Some(index), Some(value)) => {
+                    match (index.parse::<usize>(), value.parse::<f32>()) {
+                        (Ok(index), Ok(value)) if index < values.len() => values[index] = value,
+                        _ => ::log::error!("invalid state entry: {}", line),
+                    }
+                }
+                _ => ::log::error!("invalid state entry: {}", line),
+            }
+        }
+    }
+
+    fn deserialize_state(&self, data: &[u8]) {
+        let mut values = self.snapshot();
+        self.parse_state(&String::from_utf8_lossy(data), &mut values);
+        self.apply_snapshot(&values);
+    }
+
+    /// Every program as a `program=N` line followed by its values, then the
+    /// scale path, which the programs share.
+    fn serialize_bank(&self) -> Vec<u8> {
+        let mut data = String::new();
+        if let Ok(programs) = self.programs.lock() {
+            let current = self.program.load(Ordering::Relaxed);
+            for (program, values) in programs.iter().enumerate() {
+                data.push_str(&format!("program={}\n", program));
+                if program == current {
+                    Self::serialize_values(&mut data, &self.snapshot());
+                } else {
+                    Self::serialize_values(&mut data, values);
+                }
+            }
+        }
+        if let Ok(scale_path) = self.scale_path.lock() {
+            data.push_str(&format!("scale={}\n", scale_path));
+        }
+        data.into_bytes()
+    }
+
+    /// Read a bank from `serialize_bank`. Lines before the first `program=`
+    /// go to program 0, so banks saved before there were programs still load.
+    fn deserialize_bank(&self, data: &[u8]) {
+        let data = String::from_utf8_lossy(data);
+        let mut sections = vec![String::new(); PROGRAM_COUNT];
+        let mut program = 0;
+        for line in data.lines() {
+            let mut parts = line.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("program"), Some(index)) => match index.parse::<usize>() {
+                    Ok(index) if index < PROGRAM_COUNT => program = index,
+                    _ => ::log::error!("invalid program: {}", line),
+                },
+                _ => {
+                    sections[program].push_str(line);
+                    sections[program].push('\n');
+                }
+            }
+        }
+        let defaults = SineSynthParameters::default().snapshot();
+        let programs: Vec<Vec<f32>> = sections
+            .iter()
+            .map(|section| {
+                let mut values = defaults.clone();
+                self.parse_state(section, &mut values);
+                values
+            })
+            .collect();
+        self.apply_snapshot(&programs[self.program.load(Ordering::Relaxed)]);
+        if let Ok(mut current) = self.programs.lock() {
+            *current = programs;
+        }
+    }
 }
 
 impl Default for SineSynthParameters {
     fn default() -> SineSynthParameters {
-        SineSynthParameters {
+        let mut params = SineSynthParameters {
             amplitude: AtomicFloat::new(0.5),
             attack: AtomicFloat::new(0.5),
             decay: AtomicFloat::new(0.5),
@@ -45,7 +627,44 @@ impl Default for SineSynthParameters {
             triangle: AtomicFloat::new(0.0),
             saw: AtomicFloat::new(0.0),
             square: AtomicFloat::new(0.0),
-        }
+            cutoff: AtomicFloat::new(1.0),
+            resonance: AtomicFloat::new(0.0),
+            lfo_rate: AtomicFloat::new(0.5),
+            lfo_sync: AtomicFloat::new(0.0),
+            lfo_depth: AtomicFloat::new(0.0),
+            mono: AtomicFloat::new(0.0),
+            glide_time: AtomicFloat::new(0.1),
+            tuning: AtomicFloat::new(((440.0 - MIN_TUNING) / (MAX_TUNING - MIN_TUNING)) as f32),
+            white: AtomicFloat::new(0.0),
+            pink: AtomicFloat::new(0.0),
+            sub_level: AtomicFloat::new(0.0),
+            sync_enable: AtomicFloat::new(0.0),
+            sync_ratio: AtomicFloat::new(0.0),
+            vel_to_cutoff: AtomicFloat::new(0.0),
+            pressure_amount: AtomicFloat::new(0.0),
+            pan_spread: AtomicFloat::new(0.0),
+            osc_spread: AtomicFloat::new(0.0),
+            osc_normalize: AtomicFloat::new(0.0),
+            wavetable_level: AtomicFloat::new(0.0),
+            wavetable_shape: AtomicFloat::new(0.0),
+            voices: AtomicFloat::new(0.0),
+            analog_amount: AtomicFloat::new(0.0),
+            vel_to_attack: AtomicFloat::new(0.0),
+            release_vel_amount: AtomicFloat::new(0.0),
+            free_phase: AtomicFloat::new(0.0),
+            retrigger_steal: AtomicFloat::new(0.0),
+            tone: AtomicFloat::new(0.5),
+            output_clip: AtomicFloat::new(1.0),
+            clipping: AtomicBool::new(false),
+            scale_path: Mutex::new(String::new()),
+            scale: Mutex::new(None),
+            scale_changed: AtomicBool::new(false),
+            programs: Mutex::new(Vec::new()),
+            program: AtomicUsize::new(0),
+        };
+        // Every program starts out as the default patch
+        params.programs = Mutex::new(vec![params.snapshot(); PROGRAM_COUNT]);
+        params
     }
 }
 
@@ -55,19 +674,51 @@ impl PluginParameters for SineSynthParameters {
         match index {
             0 => self.amplitude.get(),
             1 => self.attack.get(),
-            3 => self.decay.get(),
-            2 => self.sustain.get(),
+            2 => self.decay.get(),
+            3 => self.sustain.get(),
             4 => self.release.get(),
             5 => self.sine.get(),
             6 => self.triangle.get(),
             7 => self.saw.get(),
             8 => self.square.get(),
+            9 => self.cutoff.get(),
+            10 => self.resonance.get(),
+            11 => self.lfo_rate.get(),
+            12 => self.lfo_sync.get(),
+            13 => self.lfo_depth.get(),
+            14 => self.mono.get(),
+            15 => self.glide_time.get(),
+            16 => self.tuning.get(),
+            17 => self.white.get(),
+            18 => self.pink.get(),
+            19 => self.sub_level.get(),
+            20 => self.sync_enable.get(),
+            21 => self.sync_ratio.get(),
+            22 => self.vel_to_cutoff.get(),
+            23 => self.pressure_amount.get(),
+            24 => self.pan_spread.get(),
+            25 => self.osc_normalize.get(),
+            26 => self.wavetable_level.get(),
+            27 => self.wavetable_shape.get(),
+            VOICES_PARAMETER => self.voices.get() / MAX_VOICES as f32,
+            29 => self.analog_amount.get(),
+            30 => self.vel_to_attack.get(),
+            31 => self.free_phase.get(),
+            32 => self.tone.get(),
+            33 => self.output_clip.get(),
+            34 => self.release_vel_amount.get(),
+            35 => self.osc_spread.get(),
+            36 => self.retrigger_steal.get(),
             _ => 0.0,
         }
     }
 
     // the `set_parameter` function sets the value of a parameter.
     fn set_parameter(&self, index: i32, val: f32) {
+        let val = match clamp_parameter(val) {
+            Some(val) => val,
+            None => return,
+        };
         #[allow(clippy::single_match)]
         match index {
             0 => self.amplitude.set(val),
@@ -79,10 +730,42 @@ impl PluginParameters for SineSynthParameters {
             6 => self.triangle.set(val),
             7 => self.saw.set(val),
             8 => self.square.set(val),
+            9 => self.cutoff.set(val),
+            10 => self.resonance.set(val),
+            11 => self.lfo_rate.set(val),
+            12 => self.lfo_sync.set(val),
+            13 => self.lfo_depth.set(val),
+            14 => self.mono.set(val),
+            15 => self.glide_time.set(val),
+            16 => self.tuning.set(val),
+            17 => self.white.set(val),
+            18 => self.pink.set(val),
+            19 => self.sub_level.set(val),
+            20 => self.sync_enable.set(val),
+            21 => self.sync_ratio.set(val),
+            22 => self.vel_to_cutoff.set(val),
+            23 => self.pressure_amount.set(val),
+            24 => self.pan_spread.set(val),
+            25 => self.osc_normalize.set(val),
+            26 => self.wavetable_level.set(val),
+            27 => self.wavetable_shape.set(val),
+            29 => self.analog_amount.set(val),
+            30 => self.vel_to_attack.set(val),
+            31 => self.free_phase.set(val),
+            32 => self.tone.set(val),
+            33 => self.output_clip.set(val),
+            34 => self.release_vel_amount.set(val),
+            35 => self.osc_spread.set(val),
+            36 => self.retrigger_steal.set(val),
+            // The voice count is written by `process` only
             _ => (),
         }
     }
 
+    fn can_be_automated(&self, index: i32) -> bool {
+        index != VOICES_PARAMETER
+    }
+
     // This is what will display underneath our control.  We can
     // format it into a string that makes the most since.
     fn get_parameter_text(&self, index: i32) -> String {
@@ -96,6 +779,80 @@ impl PluginParameters for SineSynthParameters {
             6 => format!("{:.2}", (self.triangle.get())),
             7 => format!("{:.2}", (self.saw.get())),
             8 => format!("{:.2}", (self.square.get())),
+            9 => format!("{:.0}", self.cutoff_hz()),
+            10 => format!("{:.2}", self.resonance_q()),
+            11 => {
+                if self.lfo_sync.get() > 0.5 {
+                    division(&DIVISIONS, self.lfo_rate.get()).0.to_string()
+                } else {
+                    format!("{:.2}", lfo_hz(self.lfo_rate.get(), false, None))
+                }
+            }
+            12 => (if self.lfo_sync.get() > 0.5 {
+                "On"
+            } else {
+                "Off"
+            })
+            .to_string(),
+            13 => format!("{:.2}", self.lfo_depth.get()),
+            14 => (if self.mono.get() > 0.5 { "On" } else { "Off" }).to_string(),
+            15 => format!(
+                "{:.0}",
+                f64::from(self.glide_time.get()) * MAX_GLIDE * 1000.0
+            ),
+            16 => format!("{:.2}", self.tuning_hz()),
+            17 => format!("{:.2}", self.white.get()),
+            18 => format!("{:.2}", self.pink.get()),
+            19 => format!("{:.2}", self.sub_level.get()),
+            20 => (if self.sync_enable.get() > 0.5 {
+                "On"
+            } else {
+                "Off"
+            })
+            .to_string(),
+            21 => format!("{:.2}", self.sync_ratio()),
+            22 => format!("{:.2}", self.vel_to_cutoff.get()),
+            23 => format!("{:.2}", self.pressure_amount.get()),
+            24 => format!("{:.2}", self.pan_spread.get()),
+            25 => (if self.osc_normalize.get() > 0.5 {
+                "On"
+            } else {
+                "Off"
+            })
+            .to_string(),
+            26 => format!("{:.2}", self.wavetable_level.get()),
+            27 => self.wavetable_shape().name().to_string(),
+            VOICES_PARAMETER => {
+                let voices = self.voices.get() as usize;
+                if self.clipping.load(Ordering::Relaxed) {
+                    format!("{} (clipping)", voices)
+                } else {
+                    format!("{}", voices)
+                }
+            }
+            29 => format!("{:.2}", self.analog_amount.get()),
+            30 => format!("{:.2}", self.vel_to_attack.get()),
+            31 => (if self.free_phase.get() > 0.5 {
+                "On"
+            } else {
+                "Off"
+            })
+            .to_string(),
+            32 => format!("{:+.1} dB", self.tone_db()),
+            33 => (if self.output_clip.get() > 0.5 {
+                "On"
+            } else {
+                "Off"
+            })
+            .to_string(),
+            34 => format!("{:.2}", self.release_vel_amount.get()),
+            35 => format!("{:.2}", self.osc_spread.get()),
+            36 => (if self.retrigger_steal.get() > 0.5 {
+                "Steal Releasing"
+            } else {
+                "New Voice"
+            })
+            .to_string(),
             _ => "".to_string(),
         }
     }
@@ -112,10 +869,74 @@ impl PluginParameters for SineSynthParameters {
             6 => "Triangle",
             7 => "Saw",
             8 => "Square",
+            9 => "Cutoff",
+            10 => "Resonance",
+            11 => "LFO Rate",
+            12 => "LFO Sync",
+            13 => "LFO Depth",
+            14 => "Mono",
+            15 => "Glide ms",
+            16 => "Tuning A4",
+            17 => "White Noise",
+            18 => "Pink Noise",
+            19 => "Sub",
+            20 => "Sync",
+            21 => "Sync Ratio",
+            22 => "Vel > Cutoff",
+            23 => "Pressure > Cutoff",
+            24 => "Pan Spread",
+            25 => "Osc Normalize",
+            26 => "Wavetable",
+            27 => "Table",
+            VOICES_PARAMETER => "Voices",
+            29 => "Analog",
+            30 => "Vel > Attack",
+            31 => "Free Phase",
+            32 => "Tone",
+            33 => "Output Clip",
+            34 => "Rel Vel > Release",
+            35 => "Osc Spread",
+            36 => "Retrigger",
             _ => "",
         }
         .to_string()
     }
+
+    /// Switch programs, keeping the edits made to the one being left.
+    /// Amplitude ramps to the new program's level over the next block.
+    fn change_preset(&self, preset: i32) {
+        let preset = preset as usize;
+        if preset >= PROGRAM_COUNT || preset == self.program.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Ok(mut programs) = self.programs.lock() {
+            self.switch_program(&mut programs, preset);
+        }
+    }
+
+    fn get_preset_num(&self) -> i32 {
+        self.program.load(Ordering::Relaxed) as i32
+    }
+
+    fn get_preset_name(&self, preset: i32) -> String {
+        format!("Program {}", preset + 1)
+    }
+
+    fn get_preset_data(&self) -> Vec<u8> {
+        self.serialize_state()
+    }
+
+    fn get_bank_data(&self) -> Vec<u8> {
+        self.serialize_bank()
+    }
+
+    fn load_preset_data(&self, data: &[u8]) {
+        self.deserialize_state(data);
+    }
+
+    fn load_bank_data(&self, data: &[u8]) {
+        self.deserialize_bank(data);
+    }
 }
 #[derive(Copy, Clone, PartialEq)]
 enum NoteState {
@@ -129,6 +950,33 @@ struct Note {
     off_time: f64,
     level: f64,
     state: NoteState,
+    /// Phase of the slave oscillator when hard sync is on.
+    sync_phase: f64,
+    /// Each voice has its own filter so the cutoff can follow velocity, one
+    /// per side for when the osc spread pulls them apart.
+    filter: [Biquad; 2],
+    /// Polyphonic key pressure from 0 to 1.
+    pressure: f32,
+    /// Envelope level of the last sample, where the fade out starts from.
+    last_alpha: f64,
+    /// Seconds of fade out left once the envelope has finished.
+    fade_out: f64,
+    /// Which note on started the voice, counting up, so a note off can find
+    /// the most recent of several voices on the same key.
+    order: u64,
+    /// Random position from -1 (left) to 1 (right), scaled by the pan spread.
+    pan: f32,
+    /// Random tuning and level offsets from -1 to 1, scaled by the analog
+    /// amount, see `analog_drift`.
+    drift_pitch: f32,
+    drift_level: f32,
+    /// Added to the oscillators' phase, random from 0 to 1 with free phase on.
+    phase_offset: f64,
+    /// Release velocity of the note off from -1 to 1, 0 until there is one.
+    release_velocity: f32,
+    /// Level the attack rises from, above 0 when a releasing voice was
+    /// retriggered so its envelope carries on without a jump.
+    attack_from: f64,
 }
 
 impl Default for Note {
@@ -138,24 +986,329 @@ impl Default for Note {
             off_time: 0.0,
             level: 0.0,
             state: NoteState::NONE,
+            sync_phase: 0.0,
+            filter: [Biquad::default(); 2],
+            pressure: 0.0,
+            last_alpha: 0.0,
+            fade_out: FADE_OUT_TIME,
+            order: 0,
+            pan: 0.0,
+            drift_pitch: 0.0,
+            drift_level: 0.0,
+            phase_offset: 0.0,
+            release_velocity: 0.0,
+            attack_from: 0.0,
         }
     }
 }
 
+/// Length of the fade a voice gets after its envelope ends, so a short release
+/// doesn't cut the oscillator off mid cycle.
+const FADE_OUT_TIME: f64 = 0.003;
+
+impl Note {
+    /// Give the voice its own drift offsets, like an analog oscillator that
+    /// never quite matches the last one.
+    fn roll_drift(&mut self, rng: &mut XorShift) {
+        self.drift_pitch = rng.next_f32();
+        self.drift_level = rng.next_f32();
+    }
+
+    /// A random starting phase, so voices stacked on the same pitch don't
+    /// start out adding up in phase.
+    fn roll_phase(&mut self, rng: &mut XorShift) {
+        self.phase_offset = f64::from(rng.next_f32()) * 0.5 + 0.5;
+    }
+
+    /// Play the voice again from the start of its envelope, rising from
+    /// wherever its release had got to. It keeps its pan, drift, phase and
+    /// filter, so it carries on as the same voice.
+    fn retrigger(&mut self, level: f64, order: u64) {
+        *self = Note {
+            level,
+            state: NoteState::ON,
+            order,
+            sync_phase: self.sync_phase,
+            filter: self.filter,
+            pan: self.pan,
+            drift_pitch: self.drift_pitch,
+            drift_level: self.drift_level,
+            phase_offset: self.phase_offset,
+            // Part way through the fade the level has already dropped below the envelope
+            attack_from: self.last_alpha * (self.fade_out / FADE_OUT_TIME).clamp(0.0, 1.0),
+            ..Note::default()
+        };
+    }
+
+    /// Note on velocity from 0 to 1, `level` holds velocity / 255.
+    fn velocity(&self) -> f32 {
+        (self.level * 255.0 / 127.0).min(1.0) as f32
+    }
+
+    /// Run the voice's left and right output through its filters. Unless
+    /// `stereo` the sides are the same, so only the left one is run and the
+    /// right one copies it, ready for when the osc spread is turned up.
+    fn filter(
+        &mut self,
+        (input_l, input_r): (f32, f32),
+        cutoff: f32,
+        resonance: f32,
+        sample_rate: f32,
+        stereo: bool,
+    ) -> (f32, f32) {
+        self.filter[0].set_coeffs(cutoff, resonance, sample_rate, FilterKind::Lowpass);
+        if !stereo {
+            let output = self.filter[0].process(input_l);
+            self.filter[1] = self.filter[0];
+            return (output, output);
+        }
+        self.filter[1].set_coeffs(cutoff, resonance, sample_rate, FilterKind::Lowpass);
+        (
+            self.filter[0].process(input_l),
+            self.filter[1].process(input_r),
+        )
+    }
+
+    /// Current envelope level, `None` once the release has finished.
+    fn envelope(&self, attack: f64, decay: f64, sustain: f64, release: f64) -> Option<f64> {
+        let on_alpha = if self.time < attack {
+            mix(self.attack_from, 1.0, self.time / attack)
+        } else if self.time < attack + decay {
+            mix(1.0, sustain, (self.time - attack) / decay)
+        } else {
+            sustain
+        };
+        match self.state {
+            NoteState::ON => Some(on_alpha),
+            NoteState::OFF => {
+                if self.off_time < release {
+                    Some(
+                        mix(on_alpha, 0.0, self.off_time / release)
+                            .max(0.0)
+                            .min(1.0),
+                    )
+                } else {
+                    None
+                }
+            }
+            NoteState::NONE => None,
+        }
+    }
+
+    /// `envelope` followed by a short linear fade to zero, `None` once the
+    /// voice can be freed.
+    fn output_level(
+        &mut self,
+        attack: f64,
+        decay: f64,
+        sustain: f64,
+        release: f64,
+        per_sample: f64,
+    ) -> Option<f64> {
+        match self.envelope(attack, decay, sustain, release) {
+            Some(alpha) => {
+                self.last_alpha = alpha;
+                Some(alpha)
+            }
+            None if self.fade_out > 0.0 => {
+                let alpha = self.last_alpha * self.fade_out / FADE_OUT_TIME;
+                self.fade_out -= per_sample;
+                Some(alpha)
+            }
+            None => None,
+        }
+    }
+
+    /// Saw wave hard synced to the note's own oscillator, which is at
+    /// `master_phase` and moves `master_step` each sample. The slave runs
+    /// `ratio` times faster and restarts whenever the master wraps.
+    fn synced_saw(&mut self, master_phase: f64, master_step: f64, ratio: f64) -> f64 {
+        let slave_step = (master_step * ratio).min(0.5);
+        if master_phase < master_step {
+            // The master wrapped during the last sample, restart the slave from the
+            // exact point it happened
+            let since_reset = master_phase / master_step;
+            let before = (self.sync_phase + slave_step * (1.0 - since_reset)) % 1.0;
+            self.sync_phase = (since_reset * slave_step) % 1.0;
+            // Smooth the side of the jump after the reset
+            let t = self.sync_phase;
+            2.0 * t - 1.0 - before * poly_blep(t, slave_step)
+        } else {
+            self.sync_phase = (self.sync_phase + slave_step) % 1.0;
+            let t = self.sync_phase;
+            2.0 * t - 1.0 - poly_blep(t, slave_step)
+        }
+    }
+
+    fn advance(&mut self, per_sample: f64) {
+        self.time += per_sample;
+        if self.state == NoteState::OFF {
+            self.off_time += per_sample;
+        }
+    }
+}
+
+/// The single voice used in mono mode.
+///
+/// Notes played while another is held glide to the new pitch without
+/// retriggering the envelope, releasing a note falls back to the last one
+/// still held.
+struct MonoVoice {
+    note: Note,
+    held: Vec<u8>,
+    phase: f64,
+    /// Phase of the sub oscillator an octave below.
+    sub_phase: f64,
+    glide_from: f64,
+    glide_target: f64,
+    glide_elapsed: f64,
+    /// Portamento time in seconds.
+    glide_time: f64,
+    /// Frequency ratio from the note's analog drift, set each block.
+    detune: f64,
+}
+
+impl Default for MonoVoice {
+    fn default() -> MonoVoice {
+        MonoVoice {
+            note: Note::default(),
+            // Sized for every midi note so note on never allocates
+            held: Vec::with_capacity(128),
+            phase: 0.0,
+            sub_phase: 0.0,
+            glide_from: 0.0,
+            glide_target: 0.0,
+            glide_elapsed: 0.0,
+            glide_time: 0.0,
+            detune: 1.0,
+        }
+    }
+}
+
+impl MonoVoice {
+    /// Returns true when this starts a new note rather than gliding the one
+    /// that's sounding.
+    fn note_on(&mut self, note: u8, level: f64, tuning_table: &[f64; 128]) -> bool {
+        self.held.retain(|&held| held != note);
+        self.held.push(note);
+
+        let freq = tuning_table[note as usize];
+        if self.note.state == NoteState::ON {
+            self.glide_to(freq);
+            false
+        } else {
+            self.note = Note {
+                level,
+                state: NoteState::ON,
+                ..Note::default()
+            };
+            self.glide_from = freq;
+            self.glide_target = freq;
+            self.glide_elapsed = 0.0;
+            true
+        }
+    }
+
+    fn note_off(&mut self, note: u8, release_velocity: f32, tuning_table: &[f64; 128]) {
+        let was_playing = self.held.last() == Some(&note);
+        self.held.retain(|&held| held != note);
+        match self.held.last() {
+            Some(&held) => {
+                if was_playing {
+                    self.glide_to(tuning_table[held as usize]);
+                }
+            }
+            None => {
+                if self.note.state == NoteState::ON {
+                    self.note.state = NoteState::OFF;
+                    self.note.release_velocity = release_velocity;
+                }
+            }
+        }
+    }
+
+    /// Start a glide from the current pitch to `freq`.
+    fn glide_to(&mut self, freq: f64) {
+        self.glide_from = self.frequency();
+        self.glide_target = freq;
+        self.glide_elapsed = 0.0;
+    }
+
+    /// Current pitch, the glide is linear in pitch rather than frequency.
+    fn frequency(&self) -> f64 {
+        if self.glide_elapsed >= self.glide_time {
+            self.glide_target
+        } else {
+            let progress = self.glide_elapsed / self.glide_time;
+            self.glide_from * (self.glide_target / self.glide_from).powf(progress)
+        }
+    }
+
+    /// `frequency` with the analog drift, what the oscillators play.
+    fn detuned_frequency(&self) -> f64 {
+        self.frequency() * self.detune
+    }
+
+    fn advance(&mut self, per_sample: f64) {
+        let freq = self.detuned_frequency();
+        self.phase = (self.phase + freq * per_sample) % 1.0;
+        self.sub_phase = (self.sub_phase + freq * 0.5 * per_sample) % 1.0;
+        self.glide_elapsed += per_sample;
+        self.note.advance(per_sample);
+    }
+}
+
 struct SineSynth {
     sample_rate: f64,
     time: f64,
     notes: [[Note; 256]; 8],
+    amplitude: BlockRamp,
     params: Arc<SineSynthParameters>,
+    host: HostCallback,
+    lfo_phase: f64,
+    mono: MonoVoice,
+    scale: Option<Scale>,
+    tuning_table: [f64; 128],
+    /// The A4 frequency `tuning_table` was built for.
+    table_tuning: f64,
+    rng: XorShift,
+    pink_filter: PinkFilter,
+    /// Channel pressure from 0 to 1, applies to every voice.
+    pressure: f32,
+    /// Number of poly note ons so far, see `Note::order`.
+    note_count: u64,
+    /// One table for each `TableShape`, in `TABLE_SHAPES` order.
+    wavetables: Vec<Wavetable>,
+    /// Master tone for each channel.
+    tone: [Tilt; 2],
+    midi_queue: MidiQueue,
 }
 
 impl Default for SineSynth {
     fn default() -> SineSynth {
+        let params = SineSynthParameters::default();
         SineSynth {
             sample_rate: 44100.0,
             time: 0.0,
             notes: [[Note::default(); 256]; 8],
-            params: Arc::new(SineSynthParameters::default()),
+            amplitude: BlockRamp::new(params.amplitude.get()),
+            params: Arc::new(params),
+            host: HostCallback::default(),
+            lfo_phase: 0.0,
+            mono: MonoVoice::default(),
+            scale: None,
+            tuning_table: tuning_table(None, 440.0),
+            table_tuning: 440.0,
+            rng: XorShift::default(),
+            pink_filter: PinkFilter::default(),
+            pressure: 0.0,
+            note_count: 0,
+            wavetables: TABLE_SHAPES
+                .iter()
+                .map(|&shape| Wavetable::new(shape))
+                .collect(),
+            tone: [Tilt::default(); 2],
+            midi_queue: MidiQueue::default(),
         }
     }
 }
@@ -165,6 +1318,26 @@ impl SineSynth {
         1.0 / self.sample_rate
     }
 
+    /// Pick up a newly loaded scale and rebuild the tuning table when it or
+    /// the A4 frequency changes.
+    fn update_tuning_table(&mut self, a4_freq: f64) {
+        let mut rebuild = a4_freq != self.table_tuning;
+        if self.params.scale_changed.swap(false, Ordering::Relaxed) {
+            match self.params.scale.try_lock() {
+                Ok(mut scale) => {
+                    self.scale = scale.take();
+                    rebuild = true;
+                }
+                // Try again next block
+                Err(_) => self.params.scale_changed.store(true, Ordering::Relaxed),
+            }
+        }
+        if rebuild {
+            self.tuning_table = tuning_table(self.scale.as_ref(), a4_freq);
+            self.table_tuning = a4_freq;
+        }
+    }
+
     /// Process an incoming midi event.
     ///
     /// The midi data is split up like so:
@@ -177,34 +1350,127 @@ impl SineSynth {
     /// [source]: http://www.midimountain.com/midi/midi_status.htm
     fn process_midi_event(&mut self, data: [u8; 3]) {
         match data[0] {
-            128 => self.note_off(data[1]),
+            128 => self.note_off(data[1], release_velocity(data[2])),
+            // A note on with zero velocity is a note off, without a release velocity
+            144 if data[2] == 0 => self.note_off(data[1], 0.0),
             144 => self.note_on(data[1], data[2]),
+            // Polyphonic key pressure
+            160 => self.key_pressure(data[1], f32::from(data[2]) / 127.0),
+            // Program change, programs past the bank are ignored
+            192 => self.params.midi_program_change(data[1]),
+            // Channel pressure
+            208 => self.pressure = f32::from(data[1]) / 127.0,
             _ => (),
         }
     }
 
+    /// Hold a midi event until `process` reaches the sample `delta_frames`
+    /// into the block.
+    fn queue_midi_event(&mut self, delta_frames: i32, data: [u8; 3]) {
+        if !self.midi_queue.push(delta_frames, data) {
+            self.process_midi_event(data);
+        }
+    }
+
+    /// Poly voices plus the mono voice that are still sounding.
+    fn active_voices(&self) -> usize {
+        let poly = self
+            .notes
+            .iter()
+            .flat_map(|notes| notes.iter())
+            .filter(|note| note.state != NoteState::NONE)
+            .count();
+        let mono = (self.mono.note.state != NoteState::NONE) as usize;
+        poly + mono
+    }
+
+    fn key_pressure(&mut self, note: u8, pressure: f32) {
+        if usize::from(note) >= self.tuning_table.len() {
+            return;
+        }
+        if self.mono.held.last() == Some(&note) {
+            self.mono.note.pressure = pressure;
+        }
+        for plevel in 0..7 {
+            let voice = &mut self.notes[plevel][note as usize];
+            if voice.state != NoteState::NONE {
+                voice.pressure = pressure;
+            }
+        }
+    }
+
     fn note_on(&mut self, note: u8, level: u8) {
+        // Midi data bytes are 7 bit, anything higher is a malformed event and
+        // would index past `tuning_table`
+        if usize::from(note) >= self.tuning_table.len() {
+            return;
+        }
+        let free_phase = self.params.free_phase.get() > 0.5;
+        if self.params.mono.get() > 0.5 {
+            // Legato notes keep the drift of the note they glide from
+            if self
+                .mono
+                .note_on(note, (level as f64) / 255.0, &self.tuning_table)
+            {
+                self.mono.note.roll_drift(&mut self.rng);
+                // Otherwise the mono oscillator carries on from the last note
+                if free_phase {
+                    self.mono.note.roll_phase(&mut self.rng);
+                    self.mono.phase = self.mono.note.phase_offset;
+                    self.mono.sub_phase = self.mono.phase * 0.5;
+                }
+            }
+            return;
+        }
         let note = note as usize;
+        if self.params.retrigger_steal.get() > 0.5 {
+            // The most recent releasing voice, if switching modes left several
+            let releasing = (0..7)
+                .filter(|&plevel| self.notes[plevel][note].state == NoteState::OFF)
+                .max_by_key(|&plevel| self.notes[plevel][note].order);
+            if let Some(plevel) = releasing {
+                self.note_count += 1;
+                self.notes[plevel][note].retrigger((level as f64) / 255.0, self.note_count);
+                return;
+            }
+        }
         for plevel in 0..7 {
             if self.notes[plevel][note].state == NoteState::NONE {
-                self.notes[plevel][note] = Note {
-                    time: 0.0,
-                    off_time: 0.0,
+                self.note_count += 1;
+                let voice = &mut self.notes[plevel][note];
+                *voice = Note {
                     level: (level as f64) / 255.0,
                     state: NoteState::ON,
+                    order: self.note_count,
+                    pan: self.rng.next_f32(),
+                    ..Note::default()
                 };
+                voice.roll_drift(&mut self.rng);
+                if free_phase {
+                    voice.roll_phase(&mut self.rng);
+                }
                 return;
             }
         }
     }
 
-    fn note_off(&mut self, note: u8) {
+    fn note_off(&mut self, note: u8, release_velocity: f32) {
+        if usize::from(note) >= self.tuning_table.len() {
+            return;
+        }
+        // Always release both so switching modes can't leave a note hanging
+        self.mono
+            .note_off(note, release_velocity, &self.tuning_table);
         let note = note as usize;
-        //Just picking which is on and setting it to off may not work
-        for plevel in 0..7 {
-            if self.notes[plevel][note].state == NoteState::ON {
-                self.notes[plevel][note].state = NoteState::OFF;
-            }
+        // A retriggered key has a voice per note on, each note off releases
+        // the most recent one
+        let latest = (0..7)
+            .filter(|&plevel| self.notes[plevel][note].state == NoteState::ON)
+            .max_by_key(|&plevel| self.notes[plevel][note].order);
+        if let Some(plevel) = latest {
+            let voice = &mut self.notes[plevel][note];
+            voice.state = NoteState::OFF;
+            voice.release_velocity = release_velocity;
         }
     }
 }
@@ -227,22 +1493,6 @@ fn square(n: f64) -> f64 {
     (n.sin() * 100.0).max(0.0).min(2.0) - 1.0
 }
 
-fn sine_note(t: f64, note_value: u8) -> f64 {
-    (t * midi_pitch_to_freq(note_value) * TAU).sin()
-}
-
-fn triangle_note(t: f64, note_value: u8) -> f64 {
-    triangle(t * midi_pitch_to_freq(note_value) * TAU)
-}
-
-fn saw_note(t: f64, note_value: u8) -> f64 {
-    saw(t * midi_pitch_to_freq(note_value) * TAU)
-}
-
-fn square_note(t: f64, note_value: u8) -> f64 {
-    square(t * midi_pitch_to_freq(note_value) * TAU)
-}
-
 impl Plugin for SineSynth {
     fn get_info(&self) -> Info {
         Info {
@@ -252,108 +1502,266 @@ impl Plugin for SineSynth {
             category: Category::Synth,
             inputs: 2,
             outputs: 2,
-            parameters: 9,
+            parameters: PARAMETER_COUNT,
+            presets: PROGRAM_COUNT as i32,
+            preset_chunks: true,
             initial_delay: 0,
             ..Info::default()
         }
     }
 
+    fn new(host: HostCallback) -> SineSynth {
+        SineSynth {
+            host,
+            ..SineSynth::default()
+        }
+    }
+
     #[allow(unused_variables)]
     #[allow(clippy::single_match)]
     fn process_events(&mut self, events: &Events) {
         for event in events.events() {
             match event {
-                Event::Midi(ev) => self.process_midi_event(ev.data),
-                // More events can be handled here.
+                // Applied in `process` at their own sample, not the block start
+                Event::Midi(ev) => self.queue_midi_event(ev.delta_frames, ev.data),
+                // More events can be handled here. VST 2.4 has no parameter
+                // events, automation comes through `set_parameter` and is
+                // ramped across the block in `process`.
                 _ => (),
             }
         }
     }
 
     fn set_sample_rate(&mut self, rate: f32) {
+        // Voices and oscillators keep time in seconds and every coefficient is
+        // worked out from `sample_rate` per block or per sample, so sounding
+        // notes carry on at the new rate without resetting their phase
         self.sample_rate = f64::from(rate);
     }
 
     fn process(&mut self, buffer: &mut AudioBuffer<f32>) {
-        let amplitude = self.params.amplitude.get();
         let attack = self.params.attack.get() as f64;
         let decay = self.params.decay.get() as f64;
         let sustain = self.params.sustain.get() as f64;
         let release = self.params.release.get() as f64;
 
-        let sine_level = self.params.sine.get() as f64;
-        let triangle_level = self.params.triangle.get() as f64;
-        let saw_level = self.params.saw.get() as f64;
-        let square_level = self.params.square.get() as f64;
+        let mut levels = [
+            self.params.sine.get() as f64,
+            self.params.triangle.get() as f64,
+            self.params.saw.get() as f64,
+            self.params.square.get() as f64,
+            self.params.wavetable_level.get() as f64,
+        ];
+        if self.params.osc_normalize.get() > 0.5 {
+            levels = normalize_levels(levels);
+        }
+        let [sine_level, triangle_level, saw_level, square_level, wavetable_level] = levels;
+        let white_level = self.params.white.get() as f64;
+        let pink_level = self.params.pink.get() as f64;
+        let sub_level = self.params.sub_level.get() as f64;
+        let sync_enable = self.params.sync_enable.get() > 0.5;
+        let sync_ratio = self.params.sync_ratio();
+        let tuning = self.params.tuning_hz();
+        self.update_tuning_table(tuning);
+
+        let cutoff = self.params.cutoff_hz();
+        let resonance = self.params.resonance_q();
+        let vel_to_cutoff = self.params.vel_to_cutoff.get();
+        let vel_to_attack = self.params.vel_to_attack.get();
+        let release_vel_amount = self.params.release_vel_amount.get();
+        let pressure_amount = self.params.pressure_amount.get();
+        let channel_pressure = self.pressure;
+        let pan_spread = self.params.pan_spread.get();
+        let osc_spread = self.params.osc_spread.get();
+        let osc_gains = osc_spread_gains(osc_spread);
+        let stereo = osc_spread > 0.0;
+        let analog_amount = self.params.analog_amount.get();
+        let sample_rate = self.sample_rate as f32;
+        let tone_db = self.params.tone_db();
+        let output_clip = self.params.output_clip.get() > 0.5;
+        let mut peak: f32 = 0.0;
+        for tone in self.tone.iter_mut() {
+            tone.set(tone_db, TONE_PIVOT, sample_rate);
+        }
+
+        let lfo_sync = self.params.lfo_sync.get() > 0.5;
+        let bpm = if lfo_sync { host_bpm(&self.host) } else { None };
+        let lfo_step = lfo_hz(self.params.lfo_rate.get(), lfo_sync, bpm) / self.sample_rate;
+        // Depth sweeps the cutoff up to 4 octaves either way
+        let lfo_octaves = self.params.lfo_depth.get() * 4.0;
+
+        self.mono.glide_time = f64::from(self.params.glide_time.get()) * MAX_GLIDE;
+        let wavetable_shape = self.params.wavetable_shape() as usize;
 
         let samples = buffer.samples();
+        self.amplitude.start(self.params.amplitude.get(), samples);
         let (_, mut outputs) = buffer.split();
         let output_count = outputs.len();
         let per_sample = self.time_per_sample();
-        let mut output_sample;
         for sample_idx in 0..samples {
-            output_sample = 0.0;
+            while let Some(data) = self.midi_queue.pop(sample_idx) {
+                self.process_midi_event(data);
+            }
+            let wavetable = &self.wavetables[wavetable_shape];
+
+            let lfo = (self.lfo_phase * TAU).sin() as f32;
+            self.lfo_phase = (self.lfo_phase + lfo_step) % 1.0;
+            let lfo_cutoff = cutoff * 2.0f32.powf(lfo * lfo_octaves);
+
+            // One noise sample is shared by every voice, each one gates it with its envelope
+            let white = self.rng.next_f32();
+            let pink = self.pink_filter.process(white);
+            let noise = f64::from(white) * white_level + f64::from(pink) * pink_level;
+
+            let mut output_l = 0.0;
+            let mut output_r = 0.0;
             for plevel in 0..7 {
                 for note_value in 0..255 {
                     let note = &mut self.notes[plevel][note_value as usize];
-                    let on_alpha = if note.state != NoteState::NONE {
-                        if note.time < attack {
-                            note.time / attack
-                        } else if note.time < attack + decay {
-                            mix(1.0, sustain, (note.time - attack) / decay)
-                        } else {
-                            sustain
-                        }
-                    } else {
-                        0.0
-                    };
-                    match note.state {
-                        NoteState::ON => {
-                            let mut signal = 0.0;
-                            signal += sine_note(self.time, note_value) * note.level * sine_level;
-                            signal +=
-                                triangle_note(self.time, note_value) * note.level * triangle_level;
-                            signal += saw_note(self.time, note_value) * note.level * saw_level;
-                            signal +=
-                                square_note(self.time, note_value) * note.level * square_level;
-
-                            output_sample += (signal * on_alpha) as f32;
-
-                            note.time += per_sample;
-                        }
-                        NoteState::OFF => {
-                            let mut signal = 0.0;
-                            signal += sine_note(self.time, note_value) * note.level * sine_level;
-                            signal +=
-                                triangle_note(self.time, note_value) * note.level * triangle_level;
-                            signal += saw_note(self.time, note_value) * note.level * saw_level;
-                            signal +=
-                                square_note(self.time, note_value) * note.level * square_level;
-
-                            if note.off_time < release {
-                                let alpha = mix(on_alpha, 0.0, note.off_time / release)
-                                    .max(0.0)
-                                    .min(1.0);
-                                output_sample += (signal * alpha) as f32;
-
-                                note.time += per_sample;
-                                note.off_time += per_sample;
+                    if note.state == NoteState::NONE {
+                        continue;
+                    }
+                    let attack = velocity_attack(attack, vel_to_attack, note.velocity());
+                    let release =
+                        velocity_release(release, release_vel_amount, note.release_velocity);
+                    match note.output_level(attack, decay, sustain, release, per_sample) {
+                        Some(alpha) => {
+                            let (detune, drift_gain) =
+                                analog_drift(note.drift_pitch, note.drift_level, analog_amount);
+                            let freq = self.tuning_table[note_value as usize] * detune;
+                            // Poly voices run off the synth's clock, offset by their own phase
+                            let phase = self.time * freq + note.phase_offset;
+                            let n = phase * TAU;
+                            let saw = if sync_enable {
+                                let master_phase = phase % 1.0;
+                                note.synced_saw(master_phase, freq * per_sample, sync_ratio)
                             } else {
-                                *note = Note::default();
-                            }
+                                saw(n)
+                            };
+                            let oscs = [
+                                n.sin() * sine_level,
+                                triangle(n) * triangle_level,
+                                saw * saw_level,
+                                square(n) * square_level,
+                            ];
+                            // The wavetable, sub and noise stay in the centre
+                            let mut signal =
+                                wavetable.read(phase, freq, self.sample_rate) * wavetable_level;
+                            signal += (n * 0.5).sin() * sub_level;
+                            signal += noise;
+                            let (signal_l, signal_r) = spread_oscs(signal, oscs, &osc_gains);
+                            let gain = note.level * alpha * drift_gain;
+
+                            let voice_cutoff = pressure_cutoff(
+                                velocity_cutoff(lfo_cutoff, vel_to_cutoff, note.velocity()),
+                                pressure_amount,
+                                note.pressure.max(channel_pressure),
+                            );
+                            let (filtered_l, filtered_r) = note.filter(
+                                ((signal_l * gain) as f32, (signal_r * gain) as f32),
+                                voice_cutoff,
+                                resonance,
+                                sample_rate,
+                                stereo,
+                            );
+                            let (gain_l, gain_r) = pan_gains(note.pan * pan_spread);
+                            output_l += filtered_l * gain_l;
+                            output_r += filtered_r * gain_r;
+
+                            note.advance(per_sample);
                         }
-                        NoteState::NONE => {}
+                        None => *note = Note::default(),
+                    }
+                }
+            }
+
+            if self.mono.note.state != NoteState::NONE {
+                let attack = velocity_attack(attack, vel_to_attack, self.mono.note.velocity());
+                let release =
+                    velocity_release(release, release_vel_amount, self.mono.note.release_velocity);
+                match self
+                    .mono
+                    .note
+                    .output_level(attack, decay, sustain, release, per_sample)
+                {
+                    Some(alpha) => {
+                        let (detune, drift_gain) = analog_drift(
+                            self.mono.note.drift_pitch,
+                            self.mono.note.drift_level,
+                            analog_amount,
+                        );
+                        self.mono.detune = detune;
+                        let n = self.mono.phase * TAU;
+                        let saw = if sync_enable {
+                            let master_step = self.mono.detuned_frequency() * per_sample;
+                            let master_phase = self.mono.phase;
+                            self.mono
+                                .note
+                                .synced_saw(master_phase, master_step, sync_ratio)
+                        } else {
+                            saw(n)
+                        };
+                        let oscs = [
+                            n.sin() * sine_level,
+                            triangle(n) * triangle_level,
+                            saw * saw_level,
+                            square(n) * square_level,
+                        ];
+                        let freq = self.mono.detuned_frequency();
+                        let mut signal = wavetable.read(self.mono.phase, freq, self.sample_rate)
+                            * wavetable_level;
+                        signal += (self.mono.sub_phase * TAU).sin() * sub_level;
+                        signal += noise;
+                        let (signal_l, signal_r) = spread_oscs(signal, oscs, &osc_gains);
+
+                        let note = &mut self.mono.note;
+                        let voice_cutoff = pressure_cutoff(
+                            velocity_cutoff(lfo_cutoff, vel_to_cutoff, note.velocity()),
+                            pressure_amount,
+                            note.pressure.max(channel_pressure),
+                        );
+                        // A single voice has no pan of its own, only the osc spread
+                        let gain = note.level * alpha * drift_gain;
+                        let (filtered_l, filtered_r) = note.filter(
+                            ((signal_l * gain) as f32, (signal_r * gain) as f32),
+                            voice_cutoff,
+                            resonance,
+                            sample_rate,
+                            stereo,
+                        );
+                        output_l += filtered_l;
+                        output_r += filtered_r;
+
+                        self.mono.advance(per_sample);
                     }
+                    None => self.mono.note = Note::default(),
                 }
             }
 
+            let output_l = self.tone[0].process(output_l);
+            let output_r = self.tone[1].process(output_r);
+            let amplitude = self.amplitude.next();
+            let output_l = output_l * amplitude;
+            let output_r = output_r * amplitude;
+            peak = peak.max(output_l.abs()).max(output_r.abs());
+            let (output_l, output_r) = if output_clip {
+                (soft_limit(output_l, 1.0), soft_limit(output_r, 1.0))
+            } else {
+                (output_l, output_r)
+            };
             for buf_idx in 0..output_count {
                 let buff = outputs.get_mut(buf_idx);
-                buff[sample_idx] = output_sample * amplitude;
+                buff[sample_idx] = [output_l, output_r][buf_idx.min(1)];
             }
 
             self.time += per_sample;
         }
+        // Anything timed past the end of the block starts now, before the next one
+        while let Some(data) = self.midi_queue.pop(usize::MAX) {
+            self.process_midi_event(data);
+        }
+        self.params.voices.set(self.active_voices() as f32);
+        self.params.clipping.store(peak > 1.0, Ordering::Relaxed);
     }
 
     fn get_parameter_object(&mut self) -> Arc<dyn PluginParameters> {
@@ -363,6 +1771,7 @@ impl Plugin for SineSynth {
     fn can_do(&self, can_do: CanDo) -> Supported {
         match can_do {
             CanDo::ReceiveMidiEvent => Supported::Yes,
+            CanDo::ReceiveTimeInfo => Supported::Yes,
             _ => Supported::Maybe,
         }
     }
@@ -372,13 +1781,688 @@ plugin_main!(SineSynth);
 
 #[cfg(test)]
 mod tests {
+    use lfo_hz;
     use midi_pitch_to_freq;
+    use vst::plugin::{Plugin, PluginParameters};
+    use vsts::buffer::process_buffer;
+    use MonoVoice;
+    use Note;
+    use NoteState;
+    use {
+        analog_drift, normalize_levels, osc_spread_gains, pan_gains, parse_scl, pressure_cutoff,
+        tuning_table, velocity_attack, velocity_cutoff, velocity_release,
+    };
+    use {table_mip, TableShape, Wavetable, TABLE_HARMONICS, TABLE_MIPS, TABLE_SIZE};
+    use {SineSynth, SineSynthParameters, VOICES_PARAMETER};
+    use {FADE_OUT_TIME, MAX_DRIFT_CENTS, MAX_DRIFT_DB, TAU};
 
     #[test]
     fn test_midi_pitch_to_freq() {
-        for i in 0..127 {
-            // expect no panics
-            midi_pitch_to_freq(i);
+        for &a4_freq in [432.0, 440.0, 442.0].iter() {
+            for i in 0..127 {
+                // expect no panics
+                midi_pitch_to_freq(i, a4_freq);
+            }
+            assert_eq!(midi_pitch_to_freq(69, a4_freq), a4_freq);
+            assert!((midi_pitch_to_freq(81, a4_freq) - a4_freq * 2.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_default_tuning_is_440() {
+        assert_eq!(SineSynthParameters::default().tuning_hz(), 440.0);
+    }
+
+    #[test]
+    fn test_lfo_sync_divisions() {
+        // 1/4 at 120 bpm is two cycles a second
+        assert!((lfo_hz(2.0 / 6.0, true, Some(120.0)) - 2.0).abs() < 1e-9);
+        assert!((lfo_hz(3.0 / 6.0, true, Some(120.0)) - 4.0).abs() < 1e-9);
+        assert!((lfo_hz(0.0, true, Some(60.0)) - 0.25).abs() < 1e-9);
+        // No tempo from the host falls back to the default
+        assert!((lfo_hz(2.0 / 6.0, true, None) - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_lfo_free_rate() {
+        assert!((lfo_hz(0.0, false, Some(174.0)) - 0.05).abs() < 1e-9);
+        assert!((lfo_hz(1.0, false, None) - 20.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_velocity_cutoff() {
+        assert_eq!(velocity_cutoff(1000.0, 0.0, 1.0), 1000.0);
+        assert_eq!(velocity_cutoff(1000.0, 1.0, 0.0), 1000.0);
+        assert!(velocity_cutoff(1000.0, 0.5, 1.0) > velocity_cutoff(1000.0, 0.5, 0.5));
+        assert!((velocity_cutoff(1000.0, 1.0, 1.0) - 16000.0).abs() < 0.1);
+
+        let note = Note {
+            level: 127.0 / 255.0,
+            ..Note::default()
+        };
+        assert_eq!(note.velocity(), 1.0);
+    }
+
+    #[test]
+    fn test_velocity_attack() {
+        assert_eq!(velocity_attack(0.5, 0.0, 1.0), 0.5);
+        assert_eq!(velocity_attack(0.5, 1.0, 0.0), 0.5);
+        assert!((velocity_attack(0.5, 1.0, 1.0) - 0.5 / 16.0).abs() < 1e-9);
+
+        // Halfway through the soft note's attack the hard one is already at its peak
+        let envelope = |velocity: u8| {
+            let note = Note {
+                time: 0.25,
+                level: f64::from(velocity) / 255.0,
+                state: NoteState::ON,
+                ..Note::default()
+            };
+            let attack = velocity_attack(0.5, 0.5, note.velocity());
+            note.envelope(attack, 1.0, 1.0, 0.0).unwrap()
+        };
+        assert!(envelope(10) < 0.6);
+        assert!(envelope(127) > 0.99);
+    }
+
+    #[test]
+    fn test_release_velocity() {
+        assert_eq!(velocity_release(0.5, 0.0, 1.0), 0.5);
+        assert_eq!(velocity_release(0.5, 1.0, 0.0), 0.5);
+        assert!((velocity_release(0.5, 1.0, 1.0) - 0.5 / 4.0).abs() < 1e-9);
+        assert!((velocity_release(0.5, 1.0, -1.0) - 0.5 * 4.0).abs() < 1e-9);
+
+        // The note off's velocity reaches the voice it releases
+        let mut synth = SineSynth::default();
+        synth.note_on(60, 255);
+        synth.process_midi_event([128, 60, 127]);
+        assert_eq!(synth.notes[0][60].release_velocity, 1.0);
+        synth.params.mono.set(1.0);
+        synth.note_on(64, 255);
+        synth.process_midi_event([128, 64, 0]);
+        assert_eq!(synth.mono.note.release_velocity, -1.0);
+    }
+
+    #[test]
+    fn test_voice_fades_out_when_freed() {
+        // No release, so the envelope ends the moment the note is let go
+        let sample_rate = 44100.0;
+        let per_sample = 1.0 / sample_rate;
+        let mut note = Note {
+            state: NoteState::ON,
+            ..Note::default()
+        };
+        let mut prev = 0.0;
+        let mut max_delta: f64 = 0.0;
+        let mut n = 0;
+        while let Some(alpha) = note.output_level(0.0, 0.0, 1.0, 0.0, per_sample) {
+            if n == 1000 {
+                note.state = NoteState::OFF;
+            }
+            let out = (TAU * 440.0 * n as f64 / sample_rate).sin() * alpha;
+            max_delta = max_delta.max((out - prev).abs());
+            prev = out;
+            note.advance(per_sample);
+            n += 1;
+        }
+        // Steeper than the sine itself by no more than the fade's slope
+        assert!(max_delta < TAU * 440.0 / sample_rate + 1.0 / (FADE_OUT_TIME * sample_rate));
+        assert!(n > 1000 + (FADE_OUT_TIME * sample_rate) as usize - 1);
+    }
+
+    #[test]
+    fn test_hard_sync_restarts_slave() {
+        let mut note = Note::default();
+        let master_step = 0.01;
+        let mut master_phase = 0.0;
+        let mut resets = 0;
+        for _ in 0..1000 {
+            master_phase = (master_phase + master_step) % 1.0;
+            let out = note.synced_saw(master_phase, master_step, 2.5);
+            assert!(out.is_finite() && out.abs() <= 1.5);
+            if master_phase < master_step {
+                // Restarted near the bottom of the ramp
+                assert!(note.sync_phase < master_step * 2.5);
+                resets += 1;
+            }
+        }
+        assert_eq!(resets, 10);
+    }
+
+    #[test]
+    fn test_mono_legato() {
+        let table = tuning_table(None, 440.0);
+        let mut voice = MonoVoice {
+            glide_time: 0.1,
+            ..MonoVoice::default()
+        };
+        voice.note_on(60, 0.5, &table);
+        for _ in 0..100 {
+            voice.advance(0.001);
+        }
+
+        // Overlapping note glides without retriggering the envelope
+        voice.note_on(67, 0.5, &table);
+        assert!(voice.note.time > 0.09);
+        assert!((voice.frequency() - midi_pitch_to_freq(60, 440.0)).abs() < 1e-9);
+        for _ in 0..50 {
+            voice.advance(0.001);
+        }
+        let mid = voice.frequency();
+        assert!(mid > midi_pitch_to_freq(60, 440.0) && mid < midi_pitch_to_freq(67, 440.0));
+        for _ in 0..60 {
+            voice.advance(0.001);
+        }
+        assert!((voice.frequency() - midi_pitch_to_freq(67, 440.0)).abs() < 1e-9);
+
+        // Releasing the top note falls back to the held one
+        voice.note_off(67, 0.0, &table);
+        assert!(voice.note.state == NoteState::ON);
+        for _ in 0..110 {
+            voice.advance(0.001);
+        }
+        assert!((voice.frequency() - midi_pitch_to_freq(60, 440.0)).abs() < 1e-9);
+
+        voice.note_off(60, 0.0, &table);
+        assert!(voice.note.state == NoteState::OFF);
+    }
+
+    #[test]
+    fn test_mono_release_of_held_note_keeps_pitch() {
+        let table = tuning_table(None, 440.0);
+        let mut voice = MonoVoice::default();
+        voice.note_on(60, 0.5, &table);
+        voice.note_on(64, 0.5, &table);
+        voice.advance(0.001);
+        // 60 isn't sounding so letting go of it changes nothing
+        voice.note_off(60, 0.0, &table);
+        assert!((voice.frequency() - midi_pitch_to_freq(64, 440.0)).abs() < 1e-9);
+        assert!(voice.note.state == NoteState::ON);
+    }
+
+    const JUST_SCALE: &str = "! just.scl
+!
+Five limit just intonation
+ 12
+!
+ 16/15
+ 9/8
+ 6/5
+ 5/4
+ 4/3
+ 45/32
+ 3/2
+ 8/5
+ 5/3
+ 9/5
+ 15/8
+ 2/1
+";
+
+    #[test]
+    fn test_pressure() {
+        assert_eq!(pressure_cutoff(1000.0, 1.0, 0.0), 1000.0);
+        assert_eq!(pressure_cutoff(1000.0, 0.0, 1.0), 1000.0);
+        assert_eq!(pressure_cutoff(1000.0, 0.25, 1.0), 2000.0);
+
+        let mut synth = SineSynth::default();
+        synth.process_midi_event([144, 60, 100]);
+        synth.process_midi_event([144, 64, 100]);
+        synth.process_midi_event([160, 60, 127]);
+        assert_eq!(synth.notes[0][60].pressure, 1.0);
+        assert_eq!(synth.notes[0][64].pressure, 0.0);
+        synth.process_midi_event([208, 127, 0]);
+        assert_eq!(synth.pressure, 1.0);
+    }
+
+    #[test]
+    fn test_malformed_note_is_ignored() {
+        let mut synth = SineSynth::default();
+        for &mono in [0.0, 1.0].iter() {
+            synth.params.mono.set(mono);
+            synth.process_midi_event([144, 200, 100]);
+            synth.process_midi_event([160, 200, 127]);
+            synth.process_midi_event([128, 200, 64]);
+            process_buffer(&[], 2, 64, |buffer| synth.process(buffer));
+            assert_eq!(synth.active_voices(), 0);
+        }
+    }
+
+    #[test]
+    fn test_zero_velocity_is_note_off() {
+        let mut synth = SineSynth::default();
+        synth.process_midi_event([144, 60, 100]);
+        assert!(synth.notes[0][60].state == NoteState::ON);
+        synth.process_midi_event([144, 60, 0]);
+        assert!(synth.notes[0][60].state == NoteState::OFF);
+        // Only the held note is released, nothing new starts
+        assert!(synth.notes[1][60].state == NoteState::NONE);
+    }
+
+    #[test]
+    fn test_note_off_releases_latest_voice() {
+        let mut synth = SineSynth::default();
+        synth.note_on(60, 100);
+        synth.note_on(60, 100);
+        synth.note_off(60, 0.0);
+        let on: Vec<usize> = (0..7)
+            .filter(|&plevel| synth.notes[plevel][60].state == NoteState::ON)
+            .collect();
+        assert_eq!(on, vec![0]);
+
+        // A newer note in a lower slot is still the one released
+        synth.notes[1][60] = Note::default();
+        synth.note_on(60, 100);
+        synth.notes[0][60] = Note::default();
+        synth.note_on(60, 100);
+        synth.note_off(60, 0.0);
+        assert!(synth.notes[0][60].state == NoteState::OFF);
+        assert!(synth.notes[1][60].state == NoteState::ON);
+    }
+
+    #[test]
+    fn test_normalize_levels() {
+        assert_eq!(
+            normalize_levels([1.0, 1.0, 1.0, 1.0, 0.0]),
+            [0.25, 0.25, 0.25, 0.25, 0.0]
+        );
+        assert_eq!(
+            normalize_levels([0.0, 0.5, 0.0, 0.0, 0.0]),
+            [0.0, 1.0, 0.0, 0.0, 0.0]
+        );
+        assert_eq!(normalize_levels([0.0; 5]), [0.0; 5]);
+    }
+
+    #[test]
+    fn test_wavetable() {
+        let sine = Wavetable::new(TableShape::Sine);
+        assert!(sine.read(0.0, 440.0, 44100.0).abs() < 1e-6);
+        assert!((sine.read(0.25, 440.0, 44100.0) - 1.0).abs() < 1e-6);
+        assert!((sine.read(1.75, 440.0, 44100.0) + 1.0).abs() < 1e-6);
+
+        // Halfway between two samples
+        let pos = 0.5 / TABLE_SIZE as f64;
+        let between = (sine.read(0.0, 440.0, 44100.0) + sine.read(2.0 * pos, 440.0, 44100.0)) / 2.0;
+        assert!((sine.read(pos, 440.0, 44100.0) - between).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_wavetable_is_band_limited() {
+        assert_eq!(table_mip(20.0, 44100.0), 0);
+        assert_eq!(table_mip(440.0, 44100.0), 4);
+        assert_eq!(table_mip(15000.0, 44100.0), TABLE_MIPS - 1);
+        // Every harmonic in the copy used fits below nyquist
+        for &freq in [20.0, 100.0, 440.0, 3000.0, 10000.0].iter() {
+            let harmonics = TABLE_HARMONICS >> table_mip(freq, 44100.0);
+            assert!(harmonics as f64 * freq <= 22050.0);
+        }
+
+        // Near the top only the fundamental is left, so a saw plays as a sine
+        let saw = Wavetable::new(TableShape::Saw);
+        let sine = Wavetable::new(TableShape::Sine);
+        let scale = saw.read(0.25, 15000.0, 44100.0) / sine.read(0.25, 15000.0, 44100.0);
+        for i in 0..16 {
+            let phase = i as f64 / 16.0;
+            let expected = sine.read(phase, 15000.0, 44100.0) * scale;
+            assert!((saw.read(phase, 15000.0, 44100.0) - expected).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_pan_spread() {
+        assert_eq!(pan_gains(0.0), (1.0, 1.0));
+        assert_eq!(pan_gains(-1.0), (1.0, 0.0));
+        assert_eq!(pan_gains(0.5), (0.5, 1.0));
+
+        let mut synth = SineSynth::default();
+        for note in 60..64 {
+            synth.note_on(note, 100);
+        }
+        let pans: Vec<f32> = (60..64).map(|note| synth.notes[0][note].pan).collect();
+        assert!(pans.iter().all(|pan| pan.abs() <= 1.0));
+        assert!(pans.iter().any(|&pan| pan != pans[0]));
+    }
+
+    #[test]
+    fn test_osc_spread() {
+        assert_eq!(osc_spread_gains(0.0), [(1.0, 1.0); 4]);
+        assert_eq!(osc_spread_gains(1.0)[2], (1.0, 0.0));
+
+        // Left and right outputs of a note with the sine at `sine` and the
+        // saw at `saw`, in poly and mono mode
+        let render = |sine: f32, saw: f32, spread: f32, mono: f32| {
+            let mut synth = SineSynth::default();
+            let params = &synth.params;
+            for &(index, value) in [(5, sine), (6, 0.0), (7, saw), (8, 0.0)].iter() {
+                params.set_parameter(index, value);
+            }
+            params.set_parameter(14, mono);
+            params.set_parameter(35, spread);
+            synth.note_on(60, 100);
+            let outputs = process_buffer(&[], 2, 441, |buffer| synth.process(buffer));
+            (outputs[0].clone(), outputs[1].clone())
+        };
+        for &mono in [0.0, 1.0].iter() {
+            let (left, right) = render(1.0, 1.0, 0.0, mono);
+            assert!(left.iter().any(|&x| x != 0.0));
+            assert_eq!(left, right);
+            // The sine holds the centre
+            let (left, right) = render(1.0, 0.0, 1.0, mono);
+            assert_eq!(left, right);
+            // The saw goes all the way left
+            let (left, right) = render(0.0, 1.0, 1.0, mono);
+            assert!(left.iter().any(|&x| x != 0.0));
+            assert!(right.iter().all(|&x| x == 0.0));
+        }
+    }
+
+    #[test]
+    fn test_analog_drift() {
+        assert_eq!(analog_drift(0.7, -0.4, 0.0), (1.0, 1.0));
+        let (ratio, gain) = analog_drift(1.0, -1.0, 1.0);
+        assert!((ratio.log2() * 1200.0 - MAX_DRIFT_CENTS).abs() < 1e-9);
+        assert!((20.0 * gain.log10() + MAX_DRIFT_DB).abs() < 1e-9);
+
+        // Every note on gets its own offsets, mono ones too
+        let mut synth = SineSynth::default();
+        synth.note_on(60, 100);
+        synth.note_on(64, 100);
+        let (a, b) = (synth.notes[0][60], synth.notes[0][64]);
+        assert!(a.drift_pitch != b.drift_pitch && a.drift_level != b.drift_level);
+        assert!(a.drift_pitch.abs() <= 1.0 && a.drift_level.abs() <= 1.0);
+        synth.params.mono.set(1.0);
+        synth.note_on(67, 100);
+        assert!(synth.mono.note.drift_pitch != 0.0);
+    }
+
+    #[test]
+    fn test_free_phase() {
+        // Each voice stacked on a key gets its own phase
+        let offsets = |free_phase: f32| {
+            let mut synth = SineSynth::default();
+            synth.params.set_parameter(31, free_phase);
+            for _ in 0..3 {
+                synth.note_on(60, 255);
+            }
+            synth.params.mono.set(1.0);
+            synth.note_on(64, 255);
+            assert_eq!(synth.mono.phase, synth.mono.note.phase_offset);
+            let mut offsets: Vec<f64> = (0..3).map(|i| synth.notes[i][60].phase_offset).collect();
+            offsets.push(synth.mono.note.phase_offset);
+            offsets
+        };
+        assert_eq!(offsets(0.0), [0.0; 4]);
+        let offsets = offsets(1.0);
+        assert!(offsets[0] != offsets[1] && offsets[1] != offsets[2]);
+        assert!(offsets.iter().all(|&offset| (0.0..1.0).contains(&offset)));
+
+        // Two voices half a cycle apart cancel out from the first sample
+        let mut synth = SineSynth::default();
+        synth.params.set_parameter(1, 0.0);
+        synth.note_on(60, 255);
+        synth.note_on(60, 255);
+        synth.notes[1][60].phase_offset = 0.5;
+        let outputs = process_buffer(&[], 2, 4410, |buffer| synth.process(buffer));
+        assert!(outputs[0].iter().all(|x| x.abs() < 1e-6));
+    }
+
+    #[test]
+    fn test_events_start_at_their_sample() {
+        // Output of a 256 sample block with note ons at `delta_frames`
+        let render = |delta_frames: &[i32]| {
+            let mut synth = SineSynth::default();
+            synth.params.set_parameter(1, 0.0);
+            for (&delta_frames, note) in delta_frames.iter().zip(60..) {
+                synth.queue_midi_event(delta_frames, [144, note, 255]);
+            }
+            process_buffer(&[], 2, 256, |buffer| synth.process(buffer)).remove(0)
+        };
+        let first = render(&[16]);
+        assert!(first[..16].iter().all(|&x| x == 0.0));
+        assert!(first[16..32].iter().any(|&x| x != 0.0));
+
+        // The second note leaves the block alone until its own sample
+        let both = render(&[16, 200]);
+        assert_eq!(both[..200], first[..200]);
+        assert_ne!(both[200..], first[200..]);
+    }
+
+    #[test]
+    fn test_output_clip() {
+        // A full chord with every oscillator at full level
+        let render = |output_clip: f32| {
+            let mut synth = SineSynth::default();
+            let params = &synth.params;
+            for &index in [0, 5, 6, 7, 8].iter() {
+                params.set_parameter(index, 1.0);
+            }
+            params.set_parameter(1, 0.0);
+            params.set_parameter(33, output_clip);
+            for &note in [48, 52, 55, 60, 64, 67].iter() {
+                synth.note_on(note, 255);
+            }
+            let outputs = process_buffer(&[], 2, 4410, |buffer| synth.process(buffer));
+            let peak = outputs[0].iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+            (peak, synth.params.get_parameter_text(VOICES_PARAMETER))
+        };
+        let (peak, voices) = render(0.0);
+        assert!(peak > 1.0, "peak {}", peak);
+        assert_eq!(voices, "6 (clipping)");
+        // Still flagged, the warning is about the headroom not the output
+        let (peak, voices) = render(1.0);
+        assert!(peak <= 1.0, "peak {}", peak);
+        assert_eq!(voices, "6 (clipping)");
+    }
+
+    #[test]
+    fn test_tone() {
+        // Peak of a low note with the tone knob at `tone`
+        let peak = |tone: f32| {
+            let mut synth = SineSynth::default();
+            synth.params.set_parameter(1, 0.0);
+            synth.params.set_parameter(32, tone);
+            synth.note_on(45, 255);
+            let outputs = process_buffer(&[], 2, 4410, |buffer| synth.process(buffer));
+            outputs[0].iter().fold(0.0f32, |peak, x| peak.max(x.abs()))
+        };
+        assert!(peak(0.0) > peak(0.5) * 1.5);
+        assert!(peak(1.0) < peak(0.5) / 1.5);
+        assert_eq!(
+            SineSynthParameters::default().get_parameter_text(32),
+            "+0.0 dB"
+        );
+    }
+
+    #[test]
+    fn test_parse_scl() {
+        let scale = parse_scl(JUST_SCALE).unwrap();
+        assert_eq!(scale.degrees.len(), 12);
+        assert_eq!(scale.degrees[6], 1.5);
+        assert_eq!(scale.ratio(0), 1.0);
+        assert_eq!(scale.ratio(12), 2.0);
+        assert_eq!(scale.ratio(-5), 0.75);
+
+        let cents = parse_scl("cents\n2\n700.0 fifth\n1200.0\n").unwrap();
+        assert!((cents.degrees[0] - 1.4983).abs() < 1e-4);
+
+        assert!(parse_scl("missing notes\n3\n3/2\n2/1\n").is_err());
+        assert!(parse_scl("bad pitch\n1\nabc\n").is_err());
+    }
+
+    #[test]
+    fn test_tuning_table() {
+        let equal = tuning_table(None, 440.0);
+        for (note, &freq) in equal.iter().enumerate() {
+            assert_eq!(freq, midi_pitch_to_freq(note as u8, 440.0));
+        }
+
+        let just = tuning_table(Some(&parse_scl(JUST_SCALE).unwrap()), 440.0);
+        assert!((just[69] - 440.0).abs() < 1e-9);
+        assert!((just[60] - 440.0 * 3.0 / 5.0).abs() < 1e-9);
+        assert!((just[67] / just[60] - 1.5).abs() < 1e-9);
+        assert!((just[72] / just[60] - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_set_sample_rate_while_playing() {
+        let mut synth = SineSynth::default();
+        synth.params.set_parameter(1, 0.0);
+        synth.note_on(69, 255);
+        let mut output = Vec::new();
+        for &(rate, samples) in [(44100.0, 441), (48000.0, 480), (96000.0, 960)].iter() {
+            synth.set_sample_rate(rate);
+            let outputs = process_buffer(&[], 2, samples, |buffer| synth.process(buffer));
+            output.extend_from_slice(&outputs[0]);
+        }
+
+        // A 440Hz sine moves about 2 * PI * 440 / 44100 of its peak per sample,
+        // with some room for the filter, a reset phase would jump further
+        let peak = output.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+        assert!(peak > 0.1);
+        for pair in output.windows(2) {
+            assert!((pair[1] - pair[0]).abs() < peak * 0.1);
         }
     }
+
+    #[test]
+    fn test_voice_count() {
+        let mut synth = SineSynth::default();
+        synth.note_on(60, 255);
+        synth.note_on(60, 255);
+        synth.note_on(64, 255);
+        process_buffer(&[], 2, 64, |buffer| synth.process(buffer));
+
+        let params = &synth.params;
+        assert_eq!(params.get_parameter_text(VOICES_PARAMETER), "3");
+        // Read-only, and left out of presets
+        params.set_parameter(VOICES_PARAMETER, 0.0);
+        assert_eq!(params.voices.get(), 3.0);
+        assert!(!params.can_be_automated(VOICES_PARAMETER));
+        let data = String::from_utf8(params.get_preset_data()).unwrap();
+        assert!(!data.contains(&format!("{}=", VOICES_PARAMETER)));
+    }
+
+    #[test]
+    fn test_retrigger() {
+        // Quick repeats of one note, each released before the next
+        let repeats = |steal: f32| {
+            let mut synth = SineSynth::default();
+            synth.params.set_parameter(36, steal);
+            for _ in 0..5 {
+                synth.note_on(60, 100);
+                process_buffer(&[], 2, 441, |buffer| synth.process(buffer));
+                synth.note_off(60, 0.0);
+                process_buffer(&[], 2, 441, |buffer| synth.process(buffer));
+            }
+            synth
+        };
+        assert_eq!(repeats(0.0).active_voices(), 5);
+        let mut synth = repeats(1.0);
+        assert_eq!(synth.active_voices(), 1);
+        assert_eq!(synth.params.get_parameter_text(36), "Steal Releasing");
+
+        // The attack rises from where the release had got to
+        let (released, pan) = (synth.notes[0][60].last_alpha, synth.notes[0][60].pan);
+        assert!(released > 0.0);
+        synth.note_on(60, 100);
+        let voice = synth.notes[0][60];
+        assert!(voice.state == NoteState::ON);
+        assert_eq!(voice.pan, pan);
+        assert_eq!(voice.envelope(0.5, 0.5, 0.5, 0.5), Some(released));
+    }
+
+    #[test]
+    fn test_envelope_round_trip() {
+        let params = SineSynthParameters::default();
+        params.set_parameter(2, 0.2);
+        params.set_parameter(3, 0.7);
+        assert_eq!(
+            (params.get_parameter(2), params.get_parameter(3)),
+            (0.2, 0.7)
+        );
+
+        // Decay and sustain come back where they were, not swapped
+        let loaded = SineSynthParameters::default();
+        loaded.load_preset_data(&params.get_preset_data());
+        assert_eq!(loaded.decay.get(), 0.2);
+        assert_eq!(loaded.sustain.get(), 0.7);
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let path = ::std::env::temp_dir().join("multi_synth_test_just.scl");
+        ::std::fs::write(&path, JUST_SCALE).unwrap();
+
+        let params = SineSynthParameters::default();
+        params.set_parameter(9, 0.25);
+        params.load_scale(path.to_str().unwrap());
+        let data = params.get_preset_data();
+
+        let loaded = SineSynthParameters::default();
+        loaded.load_preset_data(&data);
+        assert_eq!(loaded.get_parameter(9), 0.25);
+        assert_eq!(*loaded.scale_path.lock().unwrap(), path.to_str().unwrap());
+        assert_eq!(
+            *loaded.scale.lock().unwrap(),
+            Some(parse_scl(JUST_SCALE).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_program_change() {
+        let mut synth = SineSynth::default();
+        synth.params.set_parameter(9, 0.25);
+        synth.process_midi_event([192, 3, 0]);
+        assert_eq!(synth.params.get_preset_num(), 3);
+        // A fresh program has the default patch
+        assert_eq!(synth.params.get_parameter(9), 1.0);
+        synth.params.set_parameter(9, 0.75);
+        // Edits to the program being left are kept
+        synth.process_midi_event([192, 0, 0]);
+        assert_eq!(synth.params.get_parameter(9), 0.25);
+        synth.process_midi_event([192, 3, 0]);
+        assert_eq!(synth.params.get_parameter(9), 0.75);
+        // Past the bank
+        synth.process_midi_event([192, 100, 0]);
+        assert_eq!(synth.params.get_preset_num(), 3);
+    }
+
+    #[test]
+    fn test_midi_program_change_never_waits() {
+        let mut synth = SineSynth::default();
+        synth.params.set_parameter(9, 0.25);
+        // The GUI thread is saving the bank, the change is dropped
+        let params = synth.params.clone();
+        let programs = params.programs.lock().unwrap();
+        synth.process_midi_event([192, 3, 0]);
+        assert_eq!(synth.params.get_preset_num(), 0);
+        assert_eq!(synth.params.get_parameter(9), 0.25);
+        drop(programs);
+
+        synth.process_midi_event([192, 3, 0]);
+        assert_eq!(synth.params.get_preset_num(), 3);
+    }
+
+    #[test]
+    fn test_bank_round_trip() {
+        let params = SineSynthParameters::default();
+        params.set_parameter(9, 0.25);
+        params.change_preset(2);
+        params.set_parameter(9, 0.5);
+        let data = params.get_bank_data();
+
+        let loaded = SineSynthParameters::default();
+        loaded.load_bank_data(&data);
+        assert_eq!(loaded.get_parameter(9), 0.25);
+        loaded.change_preset(2);
+        assert_eq!(loaded.get_parameter(9), 0.5);
+        loaded.change_preset(1);
+        assert_eq!(loaded.get_parameter(9), 1.0);
+
+        // Banks from before programs load into the first program
+        let loaded = SineSynthParameters::default();
+        loaded.load_bank_data(b"9=0.125\n");
+        assert_eq!(loaded.get_parameter(9), 0.125);
+        loaded.change_preset(1);
+        assert_eq!(loaded.get_parameter(9), 1.0);
+    }
 }
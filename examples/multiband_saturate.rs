@@ -0,0 +1,378 @@
+#[macro_use]
+extern crate vst;
+extern crate vsts;
+
+use vst::api::Supported;
+use vst::buffer::AudioBuffer;
+use vst::plugin::{CanDo, Category, Info, Plugin, PluginParameters};
+use vst::util::AtomicFloat;
+use vsts::buffer::{bypass_pairs, process_stereo, MAX_PAIRS};
+use vsts::dsp::{mix, saturate, ThreeBandSplit};
+use vsts::smoothing::BypassFade;
+use vsts::util::{clamp_parameter, effect_can_do, to_range_log};
+
+use std::sync::Arc;
+
+/// Multiband saturation. The signal is split into low, mid and high bands with
+/// the compressor's crossover, each band goes through the Saturate plugin's
+/// curve with its own drive, and the bands are summed back up. Useful for
+/// adding grit to the highs without muddying the bass.
+struct MultibandSaturate {
+    params: Arc<MultibandSaturateParameters>,
+    sample_rate: f32,
+    pairs: [PairState; MAX_PAIRS],
+}
+
+/// Crossovers and saturators of one channel pair.
+#[derive(Copy, Clone)]
+struct PairState {
+    splits: [ThreeBandSplit; 2],
+    /// Low, mid and high band saturators for each channel.
+    saturators: [[Saturator; BANDS]; 2],
+    /// Crossfade in and out of bypass.
+    bypass: BypassFade,
+}
+
+/// The plugin's parameter object contains the values of parameters that can be
+/// adjusted from the host.
+///
+/// The parameters object is shared between the processing and GUI threads.
+/// For this reason, all mutable state in the object has to be represented
+/// through thread-safe interior mutability.
+struct MultibandSaturateParameters {
+    crossover_low: AtomicFloat,
+    crossover_high: AtomicFloat,
+    /// Curve A gain of each band's saturation, 0 leaves the band clean.
+    low_drive: AtomicFloat,
+    mid_drive: AtomicFloat,
+    high_drive: AtomicFloat,
+    /// Dry/wet of each band's saturation.
+    low_mix: AtomicFloat,
+    mid_mix: AtomicFloat,
+    high_mix: AtomicFloat,
+    bypass: AtomicFloat,
+}
+
+/// The state `saturate` carries from one sample to the next, for one band.
+#[derive(Copy, Clone, Default)]
+struct Saturator {
+    input_prev: f32,
+    output_prev: f32,
+}
+
+impl Saturator {
+    /// Curve A only, `drive` being its gain.
+    fn process(&mut self, x: f32, drive: f32) -> f32 {
+        let movement = (x - self.input_prev).abs();
+        let y = saturate(
+            self.output_prev,
+            self.input_prev,
+            x,
+            movement,
+            drive,
+            0.0,
+            0.0,
+        );
+        self.input_prev = x;
+        self.output_prev = y;
+        y
+    }
+}
+
+impl Default for MultibandSaturate {
+    fn default() -> MultibandSaturate {
+        let params = MultibandSaturateParameters::default();
+        MultibandSaturate {
+            sample_rate: 44100.0,
+            pairs: [PairState {
+                splits: [ThreeBandSplit::new(
+                    params.crossover_low_hz(),
+                    params.crossover_high_hz(),
+                    44100.0,
+                ); 2],
+                saturators: [[Saturator::default(); BANDS]; 2],
+                bypass: BypassFade::new(44100.0),
+            }; MAX_PAIRS],
+            params: Arc::new(params),
+        }
+    }
+}
+
+impl Default for MultibandSaturateParameters {
+    fn default() -> MultibandSaturateParameters {
+        MultibandSaturateParameters {
+            crossover_low: AtomicFloat::new(0.4),
+            crossover_high: AtomicFloat::new(0.4),
+            low_drive: AtomicFloat::new(0.0),
+            mid_drive: AtomicFloat::new(0.25),
+            high_drive: AtomicFloat::new(0.5),
+            low_mix: AtomicFloat::new(1.0),
+            mid_mix: AtomicFloat::new(1.0),
+            high_mix: AtomicFloat::new(1.0),
+            bypass: AtomicFloat::new(0.0),
+        }
+    }
+}
+
+const BANDS: usize = 3;
+/// Ranges of the crossover points, the same as the compressor's. Mapped
+/// exponentially so the knobs move evenly in octaves.
+const CROSSOVER_LOW_RANGE: (f32, f32) = (40.0, 1000.0);
+const CROSSOVER_HIGH_RANGE: (f32, f32) = (1000.0, 12000.0);
+/// Curve A gain at full drive, the Saturate plugin's A gain scale.
+const MAX_DRIVE: f32 = 12.0;
+
+impl MultibandSaturateParameters {
+    fn crossover_low_hz(&self) -> f32 {
+        to_range_log(
+            self.crossover_low.get(),
+            CROSSOVER_LOW_RANGE.0,
+            CROSSOVER_LOW_RANGE.1,
+        )
+    }
+
+    fn crossover_high_hz(&self) -> f32 {
+        to_range_log(
+            self.crossover_high.get(),
+            CROSSOVER_HIGH_RANGE.0,
+            CROSSOVER_HIGH_RANGE.1,
+        )
+    }
+
+    /// Low, mid and high drive, `None` for a band left clean.
+    fn drives(&self) -> [Option<f32>; BANDS] {
+        let drive = |drive: &AtomicFloat| match drive.get() {
+            x if x > 0.0 => Some(x * MAX_DRIVE),
+            _ => None,
+        };
+        [
+            drive(&self.low_drive),
+            drive(&self.mid_drive),
+            drive(&self.high_drive),
+        ]
+    }
+
+    fn mixes(&self) -> [f32; BANDS] {
+        [self.low_mix.get(), self.mid_mix.get(), self.high_mix.get()]
+    }
+}
+
+impl Plugin for MultibandSaturate {
+    fn get_info(&self) -> Info {
+        Info {
+            name: "Multiband Saturate".to_string(),
+            vendor: "DGriffin".to_string(),
+            unique_id: 543923577,
+            version: 1,
+            inputs: 2,
+            outputs: 2,
+            parameters: 9,
+            category: Category::Effect,
+            ..Default::default()
+        }
+    }
+
+    fn set_sample_rate(&mut self, rate: f32) {
+        self.sample_rate = rate;
+        for pair in self.pairs.iter_mut() {
+            pair.bypass.set_sample_rate(rate);
+            for split in pair.splits.iter_mut() {
+                split.reset();
+            }
+        }
+    }
+
+    fn process(&mut self, buffer: &mut AudioBuffer<f32>) {
+        let bypass = self.params.bypass.get() > 0.5;
+        let crossover_low = self.params.crossover_low_hz();
+        let crossover_high = self.params.crossover_high_hz();
+        for pair in self.pairs.iter_mut() {
+            for split in pair.splits.iter_mut() {
+                split.set_freqs(crossover_low, crossover_high, self.sample_rate);
+            }
+        }
+        if bypass_pairs(buffer, &mut self.pairs, bypass, |pair| &mut pair.bypass) {
+            return;
+        }
+        let drives = self.params.drives();
+        let mixes = self.params.mixes();
+
+        let process_channel = |pair: &mut PairState, channel: usize, x: f32| {
+            let bands = pair.splits[channel].split(x);
+            let mut output = 0.0;
+            for (band, saturator) in pair.saturators[channel].iter_mut().enumerate() {
+                output += match drives[band] {
+                    Some(drive) => mix(
+                        bands[band],
+                        saturator.process(bands[band], drive),
+                        mixes[band],
+                    ),
+                    None => bands[band],
+                };
+            }
+            output
+        };
+        process_stereo(buffer, &mut self.pairs, |pair, input_l, input_r| {
+            let output = (
+                process_channel(pair, 0, input_l),
+                process_channel(pair, 1, input_r),
+            );
+            pair.bypass.mix((input_l, input_r), output)
+        });
+    }
+
+    fn get_parameter_object(&mut self) -> Arc<dyn PluginParameters> {
+        Arc::clone(&self.params) as Arc<dyn PluginParameters>
+    }
+
+    fn can_do(&self, can_do: CanDo) -> Supported {
+        effect_can_do(can_do)
+    }
+}
+
+impl PluginParameters for MultibandSaturateParameters {
+    fn get_parameter(&self, index: i32) -> f32 {
+        match index {
+            0 => self.crossover_low.get(),
+            1 => self.crossover_high.get(),
+            2 => self.low_drive.get(),
+            3 => self.mid_drive.get(),
+            4 => self.high_drive.get(),
+            5 => self.low_mix.get(),
+            6 => self.mid_mix.get(),
+            7 => self.high_mix.get(),
+            8 => self.bypass.get(),
+            _ => 0.0,
+        }
+    }
+
+    fn set_parameter(&self, index: i32, val: f32) {
+        let val = match clamp_parameter(val) {
+            Some(val) => val,
+            None => return,
+        };
+        match index {
+            0 => self.crossover_low.set(val),
+            1 => self.crossover_high.set(val),
+            2 => self.low_drive.set(val),
+            3 => self.mid_drive.set(val),
+            4 => self.high_drive.set(val),
+            5 => self.low_mix.set(val),
+            6 => self.mid_mix.set(val),
+            7 => self.high_mix.set(val),
+            8 => self.bypass.set(val),
+            _ => (),
+        }
+    }
+
+    fn get_parameter_text(&self, index: i32) -> String {
+        match index {
+            0 => format!("{:.0} Hz", self.crossover_low_hz()),
+            1 => format!("{:.0} Hz", self.crossover_high_hz()),
+            2..=4 => match self.drives()[index as usize - 2] {
+                Some(drive) => format!("{:.2}", drive),
+                None => "Off".to_string(),
+            },
+            5..=7 => format!("{:.0}%", self.mixes()[index as usize - 5] * 100.0),
+            8 => (if self.bypass.get() > 0.5 { "On" } else { "Off" }).to_string(),
+            _ => "".to_string(),
+        }
+    }
+
+    fn get_parameter_name(&self, index: i32) -> String {
+        match index {
+            0 => "Low Crossover",
+            1 => "High Crossover",
+            2 => "Low Drive",
+            3 => "Mid Drive",
+            4 => "High Drive",
+            5 => "Low Mix",
+            6 => "Mid Mix",
+            7 => "High Mix",
+            8 => "Bypass",
+            _ => "",
+        }
+        .to_string()
+    }
+}
+
+plugin_main!(MultibandSaturate);
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+    use vst::plugin::{Plugin, PluginParameters};
+    use vsts::buffer::process_buffer;
+    use vsts::dsp::ThreeBandSplit;
+    use {MultibandSaturate, MultibandSaturateParameters};
+
+    /// A `freq` Hz sine on both channels.
+    fn sine(freq: f32) -> Vec<Vec<f32>> {
+        let sine: Vec<f32> = (0..8820)
+            .map(|n| (n as f32 * 2.0 * PI * freq / 44100.0).sin() * 0.5)
+            .collect();
+        vec![sine; 2]
+    }
+
+    /// Largest difference between `saturate`'s output for a `freq` Hz sine and
+    /// the sine split into bands and summed back up without saturation.
+    fn error(saturate: &mut MultibandSaturate, freq: f32) -> f32 {
+        let inputs = sine(freq);
+        let outputs = process_buffer(&inputs, 2, 8820, |buffer| saturate.process(buffer));
+        let params = &saturate.params;
+        let mut split = ThreeBandSplit::new(
+            params.crossover_low_hz(),
+            params.crossover_high_hz(),
+            44100.0,
+        );
+        inputs[0]
+            .iter()
+            .zip(&outputs[0])
+            .map(|(&x, y)| (split.split(x).iter().sum::<f32>() - y).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn test_clean_bands_sum_back_up() {
+        for &freq in [100.0, 1000.0, 5000.0].iter() {
+            // Every band off
+            let mut saturate = MultibandSaturate::default();
+            for index in 2..5 {
+                saturate.params.set_parameter(index, 0.0);
+            }
+            assert!(error(&mut saturate, freq) < 1e-5);
+
+            // Driven but fully dry
+            let mut saturate = MultibandSaturate::default();
+            for index in 2..8 {
+                let val = if index < 5 { 1.0 } else { 0.0 };
+                saturate.params.set_parameter(index, val);
+            }
+            assert!(error(&mut saturate, freq) < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_bands_are_driven_separately() {
+        // Bass through the high band's drive comes out nearly clean
+        let mut saturate = MultibandSaturate::default();
+        saturate.params.set_parameter(3, 0.0);
+        saturate.params.set_parameter(4, 1.0);
+        assert!(error(&mut saturate, 60.0) < 0.01);
+
+        // The low band's drive does change it
+        let mut saturate = MultibandSaturate::default();
+        saturate.params.set_parameter(2, 1.0);
+        assert!(error(&mut saturate, 60.0) > 0.1);
+    }
+
+    #[test]
+    fn test_parameter_text() {
+        let params = MultibandSaturateParameters::default();
+        let text: Vec<String> = (0..9).map(|i| params.get_parameter_text(i)).collect();
+        assert_eq!(
+            text,
+            ["145 Hz", "2702 Hz", "Off", "3.00", "6.00", "100%", "100%", "100%", "Off"]
+        );
+    }
+}
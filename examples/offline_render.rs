@@ -0,0 +1,381 @@
+//! Runs a plugin over a wav file without a DAW.
+//!
+//! Build the plugin first, then pass the library it produced:
+//! ```text
+//! cargo build --example saturate
+//! cargo run --example offline_render -- target/debug/examples/libsaturate.so in.wav out.wav 0=0.5
+//! ```
+//! Anything after the output path is an `index=value` parameter to set
+//! before rendering, or an option:
+//! - `--bits=16` or `--bits=24` writes fixed point instead of 32 bit float.
+//! - `--dither` adds TPDF dither before rounding to fixed point, so reverb
+//!   tails and fades end in a steady noise floor rather than distortion.
+
+extern crate hound;
+extern crate vst;
+extern crate vsts;
+
+use vst::host::{Host, HostBuffer, PluginLoader};
+use vst::plugin::Plugin;
+use vsts::noise::{tpdf_dither, XorShift};
+
+use std::env;
+use std::path::Path;
+use std::process;
+use std::sync::{Arc, Mutex};
+
+/// Samples handed to the plugin per `process` call.
+const BLOCK_SIZE: usize = 512;
+
+struct OfflineHost;
+
+impl Host for OfflineHost {
+    fn automate(&self, _index: i32, _value: f32) {}
+}
+
+/// Read a wav file into one buffer per channel, normalized to -1 to 1.
+fn read_wav(path: &Path) -> Result<(Vec<Vec<f32>>, u32), hound::Error> {
+    let reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+
+    let interleaved = match spec.sample_format {
+        hound::SampleFormat::Float => reader
+            .into_samples::<f32>()
+            .collect::<Result<Vec<_>, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = 1.0 / (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .into_samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as f32 * scale))
+                .collect::<Result<Vec<_>, _>>()?
+        }
+    };
+
+    let channels = spec.channels as usize;
+    let mut audio = vec![Vec::with_capacity(interleaved.len() / channels); channels];
+    for frame in interleaved.chunks(channels) {
+        for (channel, sample) in audio.iter_mut().zip(frame) {
+            channel.push(*sample);
+        }
+    }
+    Ok((audio, spec.sample_rate))
+}
+
+/// Sample format of the rendered file.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct OutputFormat {
+    /// 32 writes float, 16 and 24 fixed point.
+    bits: u16,
+    /// TPDF dither before rounding, only used for fixed point.
+    dither: bool,
+}
+
+impl Default for OutputFormat {
+    fn default() -> OutputFormat {
+        OutputFormat {
+            bits: 32,
+            dither: false,
+        }
+    }
+}
+
+/// Round `x` to a `bits` bit integer, clipping at full scale.
+fn quantize(x: f32, bits: u16, dither: Option<&mut XorShift>) -> i32 {
+    let x = match dither {
+        Some(rng) => tpdf_dither(x, u32::from(bits), rng),
+        None => x,
+    };
+    let full_scale = (1i64 << (bits - 1)) as f32;
+    (x * full_scale)
+        .round()
+        .max(-full_scale)
+        .min(full_scale - 1.0) as i32
+}
+
+fn write_wav(
+    path: &Path,
+    audio: &[Vec<f32>],
+    sample_rate: u32,
+    format: OutputFormat,
+) -> Result<(), hound::Error> {
+    let float = format.bits == 32;
+    let spec = hound::WavSpec {
+        channels: audio.len() as u16,
+        sample_rate,
+        bits_per_sample: format.bits,
+        sample_format: if float {
+            hound::SampleFormat::Float
+        } else {
+            hound::SampleFormat::Int
+        },
+    };
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    let mut rng = XorShift::default();
+    let frames = audio.iter().map(Vec::len).min().unwrap_or(0);
+    for i in 0..frames {
+        for channel in audio {
+            if float {
+                writer.write_sample(channel[i])?;
+            } else {
+                let dither = if format.dither { Some(&mut rng) } else { None };
+                writer.write_sample(quantize(channel[i], format.bits, dither))?;
+            }
+        }
+    }
+    writer.finalize()
+}
+
+/// Parse an `index=value` parameter argument.
+fn parse_parameter(arg: &str) -> Option<(i32, f32)> {
+    let mut parts = arg.splitn(2, '=');
+    let index = parts.next()?.trim().parse().ok()?;
+    let value = parts.next()?.trim().parse().ok()?;
+    Some((index, value))
+}
+
+/// Apply a `--` option argument to `format`, `false` if it isn't one.
+fn parse_option(arg: &str, format: &mut OutputFormat) -> bool {
+    match arg {
+        "--bits=16" => format.bits = 16,
+        "--bits=24" => format.bits = 24,
+        "--bits=32" => format.bits = 32,
+        "--dither" => format.dither = true,
+        _ => return false,
+    }
+    true
+}
+
+/// Samples to render after the input for a `get_tail_size` of `tail_size`.
+/// 0 means the plugin didn't say and 1 that it has no tail.
+fn tail_samples(tail_size: isize) -> usize {
+    if tail_size > 1 {
+        tail_size as usize
+    } else {
+        0
+    }
+}
+
+/// Feed `input` through the plugin in blocks. The plugin's inputs take the
+/// wav channels in order, a mono file is copied to every input.
+///
+/// The plugin's latency is trimmed off the start so the output lines up with
+/// the input, and silence keeps going in after the input ends until the tail
+/// the plugin reports has rung out.
+fn render<P: Plugin>(plugin: &mut P, input: &[Vec<f32>]) -> Vec<Vec<f32>> {
+    let info = plugin.get_info();
+    let input_count = info.inputs as usize;
+    let output_count = info.outputs as usize;
+    let frames = input.first().map(Vec::len).unwrap_or(0);
+    let latency = info.initial_delay.max(0) as usize;
+    let length = frames + tail_samples(plugin.get_tail_size());
+
+    let mut host_buffer: HostBuffer<f32> = HostBuffer::new(input_count, output_count);
+    let mut inputs = vec![vec![0.0; BLOCK_SIZE]; input_count];
+    let mut outputs = vec![vec![0.0; BLOCK_SIZE]; output_count];
+    let mut rendered = vec![Vec::with_capacity(length); output_count];
+
+    let mut start = 0;
+    while start < latency + length {
+        let len = BLOCK_SIZE.min(latency + length - start);
+        let (from, to) = (start.min(frames), (start + len).min(frames));
+        for (i, block) in inputs.iter_mut().enumerate() {
+            let channel = &input[i.min(input.len() - 1)];
+            block[..to - from].copy_from_slice(&channel[from..to]);
+            // Past the end of the input the plugin gets silence
+            for sample in block[to - from..].iter_mut() {
+                *sample = 0.0;
+            }
+        }
+
+        {
+            let mut buffer = host_buffer.bind(&inputs, &mut outputs);
+            plugin.process(&mut buffer);
+        }
+
+        // Until the latency has passed the plugin is only putting out its delay
+        let skip = latency.saturating_sub(start).min(len);
+        for (channel, block) in rendered.iter_mut().zip(outputs.iter()) {
+            channel.extend_from_slice(&block[skip..len]);
+        }
+        start += len;
+    }
+    rendered
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    if args.len() < 4 {
+        return Err(format!(
+            "usage: {} <plugin library> <input.wav> <output.wav> [--bits=16|24|32] [--dither] [index=value ...]",
+            args[0]
+        ));
+    }
+
+    let (input, sample_rate) =
+        read_wav(Path::new(&args[2])).map_err(|e| format!("Couldn't read {}: {}", args[2], e))?;
+    if input.is_empty() {
+        return Err(format!("{} has no channels", args[2]));
+    }
+
+    let host = Arc::new(Mutex::new(OfflineHost));
+    let mut loader = PluginLoader::load(Path::new(&args[1]), host)
+        .map_err(|e| format!("Couldn't load {}: {:?}", args[1], e))?;
+    let mut plugin = loader
+        .instance()
+        .map_err(|e| format!("Couldn't create the plugin: {:?}", e))?;
+
+    plugin.init();
+    plugin.set_sample_rate(sample_rate as f32);
+    plugin.set_block_size(BLOCK_SIZE as i64);
+
+    let params = plugin.get_parameter_object();
+    let mut format = OutputFormat::default();
+    for arg in &args[4..] {
+        if parse_option(arg, &mut format) {
+            continue;
+        }
+        let (index, value) = parse_parameter(arg)
+            .ok_or_else(|| format!("Bad parameter {}, use index=value", arg))?;
+        params.set_parameter(index, value);
+    }
+
+    plugin.resume();
+    plugin.start_process();
+    let output = render(&mut plugin, &input);
+    plugin.stop_process();
+    plugin.suspend();
+
+    write_wav(Path::new(&args[3]), &output, sample_rate, format)
+        .map_err(|e| format!("Couldn't write {}: {}", args[3], e))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+    use vst::buffer::AudioBuffer;
+    use vst::plugin::{Info, Plugin};
+    use vsts::noise::XorShift;
+    use {parse_option, parse_parameter, quantize, render, OutputFormat};
+
+    /// Mono plugin that delays its input by `latency` samples and reports a
+    /// tail of `tail` samples.
+    #[derive(Default)]
+    struct Latency {
+        latency: usize,
+        tail: usize,
+        line: Vec<f32>,
+    }
+
+    impl Plugin for Latency {
+        fn get_info(&self) -> Info {
+            Info {
+                inputs: 1,
+                outputs: 1,
+                initial_delay: self.latency as i32,
+                ..Default::default()
+            }
+        }
+
+        fn get_tail_size(&self) -> isize {
+            self.tail as isize
+        }
+
+        fn process(&mut self, buffer: &mut AudioBuffer<f32>) {
+            let (inputs, mut outputs) = buffer.split();
+            for (input, output) in inputs.get(0).iter().zip(outputs.get_mut(0)) {
+                self.line.push(*input);
+                *output = self.line.remove(0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_render_trims_latency_and_keeps_tail() {
+        let mut plugin = Latency {
+            latency: 700,
+            tail: 300,
+            line: vec![0.0; 700],
+        };
+        let input = vec![(0..1000).map(|n| n as f32).collect::<Vec<f32>>()];
+        let output = render(&mut plugin, &input);
+        // Lined up with the input, followed by the tail
+        assert_eq!(output[0].len(), 1300);
+        assert_eq!(output[0][..1000], input[0][..]);
+        assert!(output[0][1000..].iter().all(|&x| x == 0.0));
+
+        // A tail size of 1 is no tail at all
+        let mut plugin = Latency {
+            tail: 1,
+            ..Latency::default()
+        };
+        assert_eq!(render(&mut plugin, &input), input);
+    }
+
+    #[test]
+    fn test_parse_parameter() {
+        assert_eq!(parse_parameter("3=0.25"), Some((3, 0.25)));
+        assert_eq!(parse_parameter(" 10 = 1 "), Some((10, 1.0)));
+        assert_eq!(parse_parameter("3"), None);
+        assert_eq!(parse_parameter("gain=1"), None);
+    }
+
+    #[test]
+    fn test_parse_option() {
+        let mut format = OutputFormat::default();
+        assert!(parse_option("--bits=16", &mut format));
+        assert!(parse_option("--dither", &mut format));
+        assert_eq!(
+            format,
+            OutputFormat {
+                bits: 16,
+                dither: true
+            }
+        );
+        assert!(!parse_option("--bits=12", &mut format));
+        assert!(!parse_option("3=0.25", &mut format));
+    }
+
+    /// Round a 1kHz sine fading out from 1 LSB of 16 bit to silence. Returns
+    /// the RMS of the rounding error over the first and last quarter of the
+    /// fade, in LSB.
+    fn fade_error(dither: bool) -> (f32, f32) {
+        let mut rng = XorShift::default();
+        let frames = 44100;
+        let mut quarter_power = [0.0; 4];
+        for n in 0..frames {
+            let level = 1.0 - n as f32 / frames as f32;
+            let x = (2.0 * PI * 1000.0 * n as f32 / 44100.0).sin() * level / 32768.0;
+            let rng = if dither { Some(&mut rng) } else { None };
+            let error = quantize(x, 16, rng) as f32 - x * 32768.0;
+            quarter_power[n * 4 / frames] += error * error;
+        }
+        let rms = |power: f32| (power / (frames / 4) as f32).sqrt();
+        (rms(quarter_power[0]), rms(quarter_power[3]))
+    }
+
+    #[test]
+    fn test_dither_noise_floor() {
+        // Without dither the error follows the signal down, it's distortion
+        let (first, last) = fade_error(false);
+        assert!(last < first * 0.5);
+        // With it the error is a steady floor of about half an LSB, whatever
+        // the signal is doing
+        let (first, last) = fade_error(true);
+        assert!((first - 0.5).abs() < 0.02);
+        assert!((last - 0.5).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_quantize_clips() {
+        assert_eq!(quantize(1.0, 16, None), 32767);
+        assert_eq!(quantize(-2.0, 16, None), -32768);
+        assert_eq!(quantize(0.5, 24, None), 1 << 22);
+    }
+}
@@ -1,11 +1,23 @@
 #[macro_use]
 extern crate vst;
 extern crate time;
+extern crate vsts;
 
+use vst::api::Supported;
 use vst::buffer::AudioBuffer;
-use vst::plugin::{Category, Info, Plugin, PluginParameters};
+use vst::plugin::{CanDo, Category, Info, Plugin, PluginParameters};
 use vst::util::AtomicFloat;
+use vsts::buffer::{bypass_pairs, process_stereo, MAX_PAIRS};
+use vsts::dsp::{
+    flush_denormal, mix, ms_decode, ms_encode, saturate, soft_limit, Biquad, DcBlocker, FilterKind,
+    LIMITER_CEILING_RANGE,
+};
+use vsts::smoothing::{BypassFade, SmoothedValue, DEFAULT_SMOOTHING_MS};
+use vsts::util::{
+    clamp_parameter, db_from_gain, effect_can_do, gain_from_db, to_range, to_range_log,
+};
 
+use std::f32::consts::{FRAC_1_SQRT_2, PI};
 use std::sync::Arc;
 
 /// Simple Gain Effect.
@@ -17,10 +29,48 @@ struct GainEffect {
     // Store a handle to the plugin's parameter object.
     params: Arc<GainEffectParameters>,
 
-    output_prev_l: f32,
-    input_prev_l: f32,
-    output_prev_r: f32,
-    input_prev_r: f32,
+    pairs: [PairState; MAX_PAIRS],
+    emphasis_coeff: f32,
+    rms_coeff: f32,
+
+    sample_rate: f32,
+}
+
+/// Smoothing, channel state and auto gain of one channel pair.
+#[derive(Copy, Clone)]
+struct PairState {
+    gain: SmoothedValue,
+    master: SmoothedValue,
+
+    channels: [ChannelState; 2],
+
+    // Running mean squares of the input and the saturated output
+    input_ms: f32,
+    output_ms: f32,
+    compensation: SmoothedValue,
+
+    /// Crossfade in and out of bypass.
+    bypass: BypassFade,
+}
+
+impl PairState {
+    fn new(params: &GainEffectParameters, sample_rate: f32) -> PairState {
+        let mut gain = SmoothedValue::new(params.gain_linear());
+        let mut master = SmoothedValue::new(params.master_linear());
+        gain.set_time(DEFAULT_SMOOTHING_MS, sample_rate);
+        master.set_time(DEFAULT_SMOOTHING_MS, sample_rate);
+        let mut compensation = SmoothedValue::new(1.0);
+        compensation.set_time(COMPENSATION_SMOOTHING_MS, sample_rate);
+        PairState {
+            gain,
+            master,
+            channels: [ChannelState::new(sample_rate); 2],
+            input_ms: 0.0,
+            output_ms: 0.0,
+            compensation,
+            bypass: BypassFade::new(sample_rate),
+        }
+    }
 }
 
 /// The plugin's parameter object contains the values of parameters that can be
@@ -38,6 +88,21 @@ struct GainEffectParameters {
     a_gain: AtomicFloat,
     b_gain: AtomicFloat,
     ab_mix: AtomicFloat,
+    bypass: AtomicFloat,
+    tone: AtomicFloat,
+    mix: AtomicFloat,
+    bias: AtomicFloat,
+    // Replaces `master` with a gain that keeps the output as loud as the input
+    auto_gain: AtomicFloat,
+    // Soft limiter on the output so the saturation can't run away
+    limiter_enable: AtomicFloat,
+    limiter_ceiling: AtomicFloat,
+    // Saturate mid and side instead of left and right
+    ms_mode: AtomicFloat,
+    // Drive both channels from one detector so the stereo image holds together
+    link: AtomicFloat,
+    // High pass ahead of the saturation so the bass stays clean, 0 is off
+    low_cut: AtomicFloat,
 }
 
 // All plugins using the `vst` crate will either need to implement the `Default`
@@ -46,12 +111,13 @@ struct GainEffectParameters {
 // 0.5 means it's halfway up.
 impl Default for GainEffect {
     fn default() -> GainEffect {
+        let params = GainEffectParameters::default();
         GainEffect {
-            params: Arc::new(GainEffectParameters::default()),
-            output_prev_l: 0.0,
-            input_prev_l: 0.0,
-            output_prev_r: 0.0,
-            input_prev_r: 0.0,
+            pairs: [PairState::new(&params, 44100.0); MAX_PAIRS],
+            params: Arc::new(params),
+            emphasis_coeff: emphasis_coeff(44100.0),
+            rms_coeff: rms_coeff(44100.0),
+            sample_rate: 44100.0,
         }
     }
 }
@@ -64,26 +130,206 @@ impl Default for GainEffectParameters {
             a_gain: AtomicFloat::new(1.0),
             b_gain: AtomicFloat::new(1.0),
             ab_mix: AtomicFloat::new(0.5),
+            bypass: AtomicFloat::new(0.0),
+            tone: AtomicFloat::new(0.5),
+            mix: AtomicFloat::new(1.0),
+            bias: AtomicFloat::new(0.5),
+            auto_gain: AtomicFloat::new(0.0),
+            limiter_enable: AtomicFloat::new(0.0),
+            limiter_ceiling: AtomicFloat::new(1.0),
+            ms_mode: AtomicFloat::new(0.0),
+            link: AtomicFloat::new(0.0),
+            low_cut: AtomicFloat::new(0.0),
+        }
+    }
+}
+
+impl GainEffectParameters {
+    fn gain_linear(&self) -> f32 {
+        (self.gain.get() * 100.0) + 1.0
+    }
+
+    fn master_linear(&self) -> f32 {
+        1.0 / ((self.master.get() * 100.0) + 1.0)
+    }
+
+    /// Tone from -1 (darker) to 1 (brighter).
+    fn tone(&self) -> f32 {
+        self.tone.get() * 2.0 - 1.0
+    }
+
+    /// Bias from -1 to 1, 0 keeps the curve symmetric.
+    fn bias(&self) -> f32 {
+        self.bias.get() * 2.0 - 1.0
+    }
+
+    fn limiter_ceiling_db(&self) -> f32 {
+        to_range(
+            self.limiter_ceiling.get(),
+            LIMITER_CEILING_RANGE.0,
+            LIMITER_CEILING_RANGE.1,
+        )
+    }
+
+    /// Linear ceiling of the output limiter, `None` when it's off.
+    fn limiter_ceiling(&self) -> Option<f32> {
+        if self.limiter_enable.get() > 0.5 {
+            Some(gain_from_db(self.limiter_ceiling_db()))
+        } else {
+            None
         }
     }
+
+    /// Corner of the high pass ahead of the saturation, `None` when it's off.
+    fn low_cut_hz(&self) -> Option<f32> {
+        let low_cut = self.low_cut.get();
+        if low_cut > 0.0 {
+            Some(to_range_log(low_cut, LOW_CUT_RANGE.0, LOW_CUT_RANGE.1))
+        } else {
+            None
+        }
+    }
+
+    /// Gain the highs get before the saturation.
+    fn emphasis_gain(&self) -> f32 {
+        MAX_EMPHASIS.powf(self.tone())
+    }
+}
+
+/// Input offset at full bias.
+const MAX_BIAS: f32 = 0.5;
+/// Corner of the output DC blocker.
+const DC_BLOCKER_HZ: f32 = 10.0;
+/// Range of the low cut above its off position.
+const LOW_CUT_RANGE: (f32, f32) = (20.0, 500.0);
+
+/// Averaging time of the auto gain level detectors.
+const RMS_WINDOW_MS: f32 = 300.0;
+/// Smoothing of the auto gain changes.
+const COMPENSATION_SMOOTHING_MS: f32 = 100.0;
+/// Limits of the auto gain, -40 to +12 dB.
+const MIN_COMPENSATION: f32 = 0.01;
+const MAX_COMPENSATION: f32 = 4.0;
+/// Below this mean square the input counts as silence and the auto gain holds.
+const SILENCE_MS: f32 = 1e-8;
+
+fn rms_coeff(sample_rate: f32) -> f32 {
+    1.0 - (-1000.0 / (RMS_WINDOW_MS * sample_rate)).exp()
+}
+
+/// Gain that brings the output level back to the input level.
+fn compensation_gain(input_ms: f32, output_ms: f32) -> Option<f32> {
+    if input_ms < SILENCE_MS || output_ms < SILENCE_MS {
+        None
+    } else {
+        Some(
+            (input_ms / output_ms)
+                .sqrt()
+                .max(MIN_COMPENSATION)
+                .min(MAX_COMPENSATION),
+        )
+    }
+}
+
+/// Corner of the pre-emphasis shelf.
+const EMPHASIS_HZ: f32 = 1000.0;
+/// Largest shelf gain of the tone control, about 12 dB.
+const MAX_EMPHASIS: f32 = 4.0;
+
+fn emphasis_coeff(sample_rate: f32) -> f32 {
+    1.0 - (-2.0 * PI * EMPHASIS_HZ / sample_rate).exp()
+}
+
+/// One pole high shelf around the saturation. `pre` scales the highs by
+/// `gain` and `post` undoes it exactly, so only the distortion changes.
+#[derive(Copy, Clone, Default)]
+struct Emphasis {
+    pre_lp: f32,
+    post_lp: f32,
+}
+
+impl Emphasis {
+    fn pre(&mut self, x: f32, gain: f32, coeff: f32) -> f32 {
+        self.pre_lp = flush_denormal(self.pre_lp + coeff * (x - self.pre_lp));
+        self.pre_lp + gain * (x - self.pre_lp)
+    }
+
+    fn post(&mut self, y: f32, gain: f32, coeff: f32) -> f32 {
+        // Solve y = gain * x + (1 - gain) * lowpass(x) for x
+        let x = (y - (1.0 - gain) * (1.0 - coeff) * self.post_lp) / (gain + (1.0 - gain) * coeff);
+        self.post_lp = flush_denormal(self.post_lp + coeff * (x - self.post_lp));
+        x
+    }
+}
+
+/// Saturation settings shared by both channels, worked out once per block.
+struct Drive {
+    a: f32,
+    b: f32,
+    ab_mix: f32,
+    emphasis: f32,
+    coeff: f32,
+    bias: f32,
+    low_cut: bool,
+}
+
+/// Saturation and filter state for one channel.
+#[derive(Copy, Clone)]
+struct ChannelState {
+    output_prev: f32,
+    input_prev: f32,
+    emphasis: Emphasis,
+    dc: DcBlocker,
+    low_cut: Biquad,
 }
 
-fn mix(x: f32, y: f32, a: f32) -> f32 {
-    x * (1.0 - a) + y * a
+impl ChannelState {
+    fn new(sample_rate: f32) -> ChannelState {
+        ChannelState {
+            output_prev: 0.0,
+            input_prev: 0.0,
+            emphasis: Emphasis::default(),
+            dc: DcBlocker::new(DC_BLOCKER_HZ, sample_rate),
+            low_cut: Biquad::default(),
+        }
+    }
+
+    /// Low cut, emphasis and bias ahead of the saturation.
+    fn pre(&mut self, x: f32, drive: &Drive) -> f32 {
+        let x = if drive.low_cut {
+            self.low_cut.process(x)
+        } else {
+            x
+        };
+        // The offset makes the curve asymmetric, adding even harmonics
+        self.emphasis.pre(x, drive.emphasis, drive.coeff) + drive.bias
+    }
+
+    /// How far `x`, from `pre`, is from the last input.
+    fn movement(&self, x: f32) -> f32 {
+        (x - self.input_prev).abs()
+    }
 }
 
-//let delta_input = input - input_prev;
-//(output_prev + a * ((input * 2.0).tanh() - output_prev) * delta_input.abs() + b * delta_input / (input * 2.0).cosh().powi(2)).tanh()
+/// Saturate one channel's `pre` output and undo the emphasis. `movement` is
+/// the channel's own unless the channels are linked.
+fn saturate_channel(state: &mut ChannelState, x: f32, movement: f32, drive: &Drive) -> f32 {
+    let sat = saturate(
+        state.output_prev,
+        state.input_prev,
+        x,
+        movement,
+        drive.a,
+        drive.b,
+        drive.ab_mix,
+    );
+    state.input_prev = x;
+    state.output_prev = sat;
 
-fn saturate(output_prev: f32, input_prev: f32, input: f32, a: f32, b: f32, ab_mix: f32) -> f32 {
-    let delta_input = input - input_prev;
-    let dist_a = ((a * input).tanh() - output_prev) * a * delta_input.abs();
-    let dist_b = b * delta_input / (b * input).cosh().powi(2);
-    mix(
-        (output_prev + dist_a).tanh(),
-        (output_prev + dist_b).tanh() * 12.0,
-        ab_mix.max(0.0).min(1.0),
-    )
+    // Remove the DC the bias leaves behind
+    state
+        .dc
+        .process(state.emphasis.post(sat, drive.emphasis, drive.coeff))
 }
 
 // All plugins using `vst` also need to implement the `Plugin` trait.  Here, we
@@ -99,51 +345,125 @@ impl Plugin for GainEffect {
             outputs: 2,
             // This `parameters` bit is important; without it, none of our
             // parameters will be shown!
-            parameters: 5,
+            parameters: 15,
             category: Category::Effect,
             ..Default::default()
         }
     }
 
+    fn set_sample_rate(&mut self, rate: f32) {
+        self.sample_rate = rate;
+        self.emphasis_coeff = emphasis_coeff(rate);
+        self.rms_coeff = rms_coeff(rate);
+        for pair in self.pairs.iter_mut() {
+            pair.bypass.set_sample_rate(rate);
+            pair.gain.set_time(DEFAULT_SMOOTHING_MS, rate);
+            pair.master.set_time(DEFAULT_SMOOTHING_MS, rate);
+            for channel in pair.channels.iter_mut() {
+                channel.dc = DcBlocker::new(DC_BLOCKER_HZ, rate);
+            }
+            pair.compensation.set_time(COMPENSATION_SMOOTHING_MS, rate);
+        }
+    }
+
     // Here is where the bulk of our audio processing code goes.
     fn process(&mut self, buffer: &mut AudioBuffer<f32>) {
-        // Read the amplitude from the parameter object
-        let a = self.params.a_gain.get() * 12.0;
-        let b = self.params.b_gain.get() * 1.0;
-        let ab_mix = self.params.ab_mix.get();
-        let gain = (self.params.gain.get() * 100.0) + 1.0;
-        let master = 1.0 / ((self.params.master.get() * 100.0) + 1.0);
-        // First, we destructure our audio buffer into an arbitrary number of
-        // input and output buffers.  Usually, we'll be dealing with stereo (2 of each)
-        // but that might change.
-
-        let (inputs, mut outputs) = buffer.split();
-        let (inputs_left, inputs_right) = inputs.split_at(1);
-        let (mut outputs_left, mut outputs_right) = outputs.split_at_mut(1);
-
-        let inputs_stereo = inputs_left[0].iter().zip(inputs_right[0].iter());
-        let outputs_stereo = outputs_left[0].iter_mut().zip(outputs_right[0].iter_mut());
+        let bypass = self.params.bypass.get() > 0.5;
+        let low_cut = self.params.low_cut_hz();
+        let gain = self.params.gain_linear();
+        let master = self.params.master_linear();
+        for pair in self.pairs.iter_mut() {
+            pair.gain.set_target(gain);
+            pair.master.set_target(master);
+            if let Some(cutoff) = low_cut {
+                for channel in pair.channels.iter_mut() {
+                    channel.low_cut.set_coeffs(
+                        cutoff,
+                        FRAC_1_SQRT_2,
+                        self.sample_rate,
+                        FilterKind::Highpass,
+                    );
+                }
+            }
+        }
+        if bypass_pairs(buffer, &mut self.pairs, bypass, |pair| &mut pair.bypass) {
+            return;
+        }
 
-        for (input_pair, output_pair) in inputs_stereo.zip(outputs_stereo) {
-            let (input_l, input_r) = input_pair;
-            let (output_l, output_r) = output_pair;
+        // Read the amplitude from the parameter object
+        let drive = Drive {
+            a: self.params.a_gain.get() * 12.0,
+            b: self.params.b_gain.get() * 1.0,
+            ab_mix: self.params.ab_mix.get(),
+            emphasis: self.params.emphasis_gain(),
+            coeff: self.emphasis_coeff,
+            bias: self.params.bias() * MAX_BIAS,
+            low_cut: low_cut.is_some(),
+        };
+        let wet = self.params.mix.get();
+        let link = self.params.link.get() > 0.5;
+        let auto_gain = self.params.auto_gain.get() > 0.5;
+        let limiter_ceiling = self.params.limiter_ceiling();
+        let mid_side = self.params.ms_mode.get() > 0.5;
+        let rms_coeff = self.rms_coeff;
+        process_stereo(buffer, &mut self.pairs, |pair, input_l, input_r| {
+            // In mid/side mode `_l` is the mid and `_r` the side until the end
+            let (dry_l, dry_r) = if mid_side {
+                ms_encode(input_l, input_r)
+            } else {
+                (input_l, input_r)
+            };
 
-            let l = *input_l * gain;
-            let r = *input_r * gain;
+            let gain = pair.gain.next();
+            let master = pair.master.next();
 
-            *output_l = saturate(self.output_prev_l, self.input_prev_l, l, a, b, ab_mix);
+            let [state_l, state_r] = &mut pair.channels;
+            let l = state_l.pre(dry_l * gain, &drive);
+            let r = state_r.pre(dry_r * gain, &drive);
 
-            self.input_prev_l = l;
-            self.output_prev_l = *output_l;
+            // Linked, both channels follow whichever is moving the most, so a
+            // loud side doesn't saturate differently from a quiet one
+            let (movement_l, movement_r) = (state_l.movement(l), state_r.movement(r));
+            let (movement_l, movement_r) = if link {
+                let movement = movement_l.max(movement_r);
+                (movement, movement)
+            } else {
+                (movement_l, movement_r)
+            };
+            let wet_l = saturate_channel(state_l, l, movement_l, &drive);
+            let wet_r = saturate_channel(state_r, r, movement_r, &drive);
 
-            *output_r = saturate(self.output_prev_r, self.input_prev_r, r, a, b, ab_mix);
+            let output_gain = if auto_gain {
+                let input_ms = (dry_l * dry_l + dry_r * dry_r) * 0.5;
+                let output_ms = (wet_l * wet_l + wet_r * wet_r) * 0.5;
+                pair.input_ms =
+                    flush_denormal(pair.input_ms + rms_coeff * (input_ms - pair.input_ms));
+                pair.output_ms =
+                    flush_denormal(pair.output_ms + rms_coeff * (output_ms - pair.output_ms));
+                if let Some(target) = compensation_gain(pair.input_ms, pair.output_ms) {
+                    pair.compensation.set_target(target);
+                }
+                pair.compensation.next()
+            } else {
+                master
+            };
+            let wet_l = wet_l * output_gain;
+            let wet_r = wet_r * output_gain;
 
-            self.input_prev_r = r;
-            self.output_prev_r = *output_r;
+            let output_l = mix(dry_l, wet_l, wet);
+            let output_r = mix(dry_r, wet_r, wet);
+            let (output_l, output_r) = if mid_side {
+                ms_decode(output_l, output_r)
+            } else {
+                (output_l, output_r)
+            };
 
-            *output_l = *output_l * master;
-            *output_r = *output_r * master;
-        }
+            let output = match limiter_ceiling {
+                Some(ceiling) => (soft_limit(output_l, ceiling), soft_limit(output_r, ceiling)),
+                None => (output_l, output_r),
+            };
+            pair.bypass.mix((input_l, input_r), output)
+        });
     }
 
     // Return the parameter object. This method can be omitted if the
@@ -151,6 +471,10 @@ impl Plugin for GainEffect {
     fn get_parameter_object(&mut self) -> Arc<dyn PluginParameters> {
         Arc::clone(&self.params) as Arc<dyn PluginParameters>
     }
+
+    fn can_do(&self, can_do: CanDo) -> Supported {
+        effect_can_do(can_do)
+    }
 }
 
 impl PluginParameters for GainEffectParameters {
@@ -162,12 +486,26 @@ impl PluginParameters for GainEffectParameters {
             2 => self.a_gain.get(),
             3 => self.b_gain.get(),
             4 => self.ab_mix.get(),
+            5 => self.bypass.get(),
+            6 => self.tone.get(),
+            7 => self.mix.get(),
+            8 => self.bias.get(),
+            9 => self.auto_gain.get(),
+            10 => self.limiter_enable.get(),
+            11 => self.limiter_ceiling.get(),
+            12 => self.ms_mode.get(),
+            13 => self.link.get(),
+            14 => self.low_cut.get(),
             _ => 0.0,
         }
     }
 
     // the `set_parameter` function sets the value of a parameter.
     fn set_parameter(&self, index: i32, val: f32) {
+        let val = match clamp_parameter(val) {
+            Some(val) => val,
+            None => return,
+        };
         #[allow(clippy::single_match)]
         match index {
             0 => self.gain.set(val),
@@ -175,6 +513,16 @@ impl PluginParameters for GainEffectParameters {
             2 => self.a_gain.set(val),
             3 => self.b_gain.set(val),
             4 => self.ab_mix.set(val),
+            5 => self.bypass.set(val),
+            6 => self.tone.set(val),
+            7 => self.mix.set(val),
+            8 => self.bias.set(val),
+            9 => self.auto_gain.set(val),
+            10 => self.limiter_enable.set(val),
+            11 => self.limiter_ceiling.set(val),
+            12 => self.ms_mode.set(val),
+            13 => self.link.set(val),
+            14 => self.low_cut.set(val),
             _ => (),
         }
     }
@@ -183,11 +531,39 @@ impl PluginParameters for GainEffectParameters {
     // format it into a string that makes the most since.
     fn get_parameter_text(&self, index: i32) -> String {
         match index {
-            0 => format!("{:.2}", self.gain.get() * 48.0),
-            1 => format!("{:.2}", -self.master.get() * 48.0),
+            0 => format!("{:.1} dB", db_from_gain(self.gain_linear())),
+            1 => format!("{:.1} dB", db_from_gain(self.master_linear())),
             2 => format!("{:.2}", self.a_gain.get()),
             3 => format!("{:.2}", self.b_gain.get()),
             4 => format!("{:.2}", self.ab_mix.get()),
+            5 => (if self.bypass.get() > 0.5 { "On" } else { "Off" }).to_string(),
+            6 => format!("{:.2}", self.tone()),
+            7 => format!("{:.0}%", self.mix.get() * 100.0),
+            8 => format!("{:.2}", self.bias()),
+            9 => (if self.auto_gain.get() > 0.5 {
+                "On"
+            } else {
+                "Off"
+            })
+            .to_string(),
+            10 => (if self.limiter_enable.get() > 0.5 {
+                "On"
+            } else {
+                "Off"
+            })
+            .to_string(),
+            11 => format!("{:.1} dB", self.limiter_ceiling_db()),
+            12 => (if self.ms_mode.get() > 0.5 {
+                "On"
+            } else {
+                "Off"
+            })
+            .to_string(),
+            13 => (if self.link.get() > 0.5 { "On" } else { "Off" }).to_string(),
+            14 => match self.low_cut_hz() {
+                Some(cutoff) => format!("{:.0} Hz", cutoff),
+                None => "Off".to_string(),
+            },
             _ => "".to_string(),
         }
     }
@@ -200,6 +576,16 @@ impl PluginParameters for GainEffectParameters {
             2 => "A",
             3 => "B",
             4 => "A/B Mix",
+            5 => "Bypass",
+            6 => "Tone",
+            7 => "Mix",
+            8 => "Bias",
+            9 => "Auto Gain",
+            10 => "Limiter",
+            11 => "Limiter Ceiling",
+            12 => "Mid/Side",
+            13 => "Link",
+            14 => "Low Cut",
             _ => "",
         }
         .to_string()
@@ -208,3 +594,149 @@ impl PluginParameters for GainEffectParameters {
 
 // This part is important!  Without it, our plugin won't work.
 plugin_main!(GainEffect);
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+    use vst::plugin::{Plugin, PluginParameters};
+    use vsts::buffer::process_buffer;
+    use Emphasis;
+    use {compensation_gain, emphasis_coeff, GainEffect, GainEffectParameters, MAX_COMPENSATION};
+
+    #[test]
+    fn test_parameter_text() {
+        let params = GainEffectParameters::default();
+        // The text is the gain that's applied, not a separate scale
+        assert_eq!(params.get_parameter_text(0), "0.0 dB");
+        assert_eq!(params.get_parameter_text(1), "-40.1 dB");
+        params.set_parameter(0, 0.09);
+        assert_eq!(params.get_parameter_text(0), "20.0 dB");
+        assert_eq!(params.get_parameter_text(7), "100%");
+        assert_eq!(params.get_parameter_text(11), "0.0 dB");
+        assert_eq!(params.get_parameter_text(14), "Off");
+        params.set_parameter(14, 1.0);
+        assert_eq!(params.get_parameter_text(14), "500 Hz");
+    }
+
+    #[test]
+    fn test_emphasis_round_trip() {
+        let coeff = emphasis_coeff(44100.0);
+        for &gain in [0.25, 1.0, 4.0].iter() {
+            let mut emphasis = Emphasis::default();
+            for n in 0..1000 {
+                let x = (n as f32 * 0.3).sin() + if n % 7 == 0 { 0.5 } else { -0.2 };
+                let y = emphasis.pre(x, gain, coeff);
+                assert!((emphasis.post(y, gain, coeff) - x).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn test_compensation_gain() {
+        // Output 4x louder in power needs half the gain
+        assert_eq!(compensation_gain(0.25, 1.0), Some(0.5));
+        assert_eq!(compensation_gain(1.0, 1e-6), Some(MAX_COMPENSATION));
+        // Holds through silence
+        assert_eq!(compensation_gain(0.0, 0.5), None);
+    }
+
+    #[test]
+    fn test_mono_buffer() {
+        let mut effect = GainEffect::default();
+        let inputs = vec![vec![0.5; 64]];
+        let outputs = process_buffer(&inputs, 1, 64, |buffer| effect.process(buffer));
+        assert_eq!(outputs, inputs);
+    }
+
+    #[test]
+    fn test_mid_side_mono_has_no_side() {
+        let mut effect = GainEffect::default();
+        effect.get_parameter_object().set_parameter(12, 1.0);
+        let inputs: Vec<Vec<f32>> =
+            vec![(0..512).map(|n| (n as f32 * 0.05).sin() * 0.5).collect(); 2];
+        let outputs = process_buffer(&inputs, 2, 512, |buffer| effect.process(buffer));
+        // The side stays silent, so both channels come out the same
+        assert_eq!(outputs[0], outputs[1]);
+        assert!(outputs[0].iter().any(|x| *x != 0.0));
+    }
+
+    #[test]
+    fn test_surround_pairs() {
+        let mut effect = GainEffect::default();
+        effect.get_parameter_object().set_parameter(0, 0.1);
+        let sine: Vec<f32> = (0..512).map(|n| (n as f32 * 0.05).sin() * 0.5).collect();
+        let outputs = process_buffer(&vec![sine.clone(); 4], 4, 512, |buffer| {
+            effect.process(buffer)
+        });
+        // Both pairs are saturated, each from its own state
+        assert_ne!(outputs[2], sine);
+        assert_eq!(outputs[0], outputs[2]);
+        assert_eq!(outputs[1], outputs[3]);
+    }
+
+    /// Run `inputs` through the saturation with the link at `link`.
+    fn process_linked(inputs: &[Vec<f32>], link: f32) -> Vec<Vec<f32>> {
+        let mut effect = GainEffect::default();
+        let params = effect.get_parameter_object();
+        params.set_parameter(0, 0.1);
+        params.set_parameter(13, link);
+        process_buffer(inputs, 2, inputs[0].len(), |buffer| effect.process(buffer))
+    }
+
+    #[test]
+    fn test_link() {
+        let sine: Vec<f32> = (0..512).map(|n| (n as f32 * 0.05).sin() * 0.5).collect();
+
+        // Matching channels move together anyway, so the link changes nothing
+        let inputs = vec![sine.clone(); 2];
+        assert_eq!(process_linked(&inputs, 1.0), process_linked(&inputs, 0.0));
+
+        // The quiet side follows the loud side's detector, but none of its signal
+        let quiet: Vec<f32> = sine.iter().map(|x| x * 0.1).collect();
+        let inputs = vec![sine.clone(), quiet];
+        let linked = process_linked(&inputs, 1.0);
+        let unlinked = process_linked(&inputs, 0.0);
+        assert_eq!(linked[0], unlinked[0]);
+        assert_ne!(linked[1], unlinked[1]);
+        let silent = process_linked(&[sine, vec![0.0; 512]], 1.0);
+        assert!(silent[1].iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn test_low_cut_keeps_bass_clean() {
+        // Peak output of a loud 50hz sine, with the low cut at `low_cut`
+        let peak = |low_cut: f32| {
+            let mut effect = GainEffect::default();
+            let params = effect.get_parameter_object();
+            params.set_parameter(0, 0.1);
+            // Unity master and only curve A, which follows a slow input closely
+            params.set_parameter(1, 0.0);
+            params.set_parameter(4, 0.0);
+            params.set_parameter(14, low_cut);
+            let sine: Vec<f32> = (0..8820)
+                .map(|n| (2.0 * PI * 50.0 * n as f32 / 44100.0).sin() * 0.5)
+                .collect();
+            let inputs = vec![sine; 2];
+            let outputs = process_buffer(&inputs, 2, 8820, |buffer| effect.process(buffer));
+            outputs[0][4410..].iter().cloned().fold(0.0, f32::max)
+        };
+        // Off, the bass is driven into the saturation
+        let full = peak(0.0);
+        assert!(full > 0.5, "peak {}", full);
+        // A 500hz low cut keeps it out
+        let cut = peak(1.0);
+        assert!(cut < full * 0.25, "peak {} of {}", cut, full);
+    }
+
+    #[test]
+    fn test_emphasis_boosts_highs() {
+        let coeff = emphasis_coeff(44100.0);
+        let mut emphasis = Emphasis::default();
+        let mut peak: f32 = 0.0;
+        for n in 0..1000 {
+            let x = if n % 2 == 0 { 1.0 } else { -1.0 };
+            peak = peak.max(emphasis.pre(x, 4.0, coeff).abs());
+        }
+        assert!(peak > 3.0);
+    }
+}
@@ -2,6 +2,7 @@
 
 #[macro_use]
 extern crate vst;
+extern crate vsts;
 
 use std::sync::Arc;
 use vst::api::{Events, Supported};
@@ -9,6 +10,8 @@ use vst::buffer::AudioBuffer;
 use vst::event::Event;
 use vst::plugin::{CanDo, Category, Info, Plugin, PluginParameters};
 use vst::util::AtomicFloat;
+use vsts::smoothing::BlockRamp;
+use vsts::util::clamp_parameter;
 
 use std::f64::consts::PI;
 
@@ -31,6 +34,7 @@ struct SineSynth {
     last_note: Option<u8>,
     last_note_level: f64,
     last_note_time: f64,
+    amplitude: BlockRamp,
     params: Arc<SineSynthParameters>,
 }
 
@@ -58,6 +62,10 @@ impl PluginParameters for SineSynthParameters {
 
     // the `set_parameter` function sets the value of a parameter.
     fn set_parameter(&self, index: i32, val: f32) {
+        let val = match clamp_parameter(val) {
+            Some(val) => val,
+            None => return,
+        };
         #[allow(clippy::single_match)]
         match index {
             0 => self.amplitude.set(val),
@@ -102,6 +110,8 @@ impl SineSynth {
     fn process_midi_event(&mut self, data: [u8; 3]) {
         match data[0] {
             128 => self.note_off(data[1]),
+            // A note on with zero velocity is a note off
+            144 if data[2] == 0 => self.note_off(data[1]),
             144 => self.note_on(data[1]),
             _ => (),
         }
@@ -126,6 +136,7 @@ pub const TAU: f64 = PI * 2.0;
 
 impl Default for SineSynth {
     fn default() -> SineSynth {
+        let params = SineSynthParameters::default();
         SineSynth {
             sample_rate: 44100.0,
             note_duration: 0.0,
@@ -134,7 +145,8 @@ impl Default for SineSynth {
             last_note: None,
             last_note_level: 0.0,
             last_note_time: 0.0,
-            params: Arc::new(SineSynthParameters::default()),
+            amplitude: BlockRamp::new(params.amplitude.get()),
+            params: Arc::new(params),
         }
     }
 }
@@ -160,7 +172,9 @@ impl Plugin for SineSynth {
         for event in events.events() {
             match event {
                 Event::Midi(ev) => self.process_midi_event(ev.data),
-                // More events can be handled here.
+                // More events can be handled here. VST 2.4 has no parameter
+                // events, automation comes through `set_parameter` and is
+                // ramped across the block in `process`.
                 _ => (),
             }
         }
@@ -172,6 +186,7 @@ impl Plugin for SineSynth {
 
     fn process(&mut self, buffer: &mut AudioBuffer<f32>) {
         let samples = buffer.samples();
+        self.amplitude.start(self.params.amplitude.get(), samples);
         let (_, mut outputs) = buffer.split();
         let output_count = outputs.len();
         let per_sample = self.time_per_sample();
@@ -209,7 +224,7 @@ impl Plugin for SineSynth {
                 }
             }
 
-            let amplitude = self.params.amplitude.get();
+            let amplitude = self.amplitude.next();
             for buf_idx in 0..output_count {
                 let buff = outputs.get_mut(buf_idx);
                 buff[sample_idx] = output_sample * amplitude;
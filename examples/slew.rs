@@ -1,10 +1,15 @@
 #[macro_use]
 extern crate vst;
 extern crate time;
+extern crate vsts;
 
+use vst::api::Supported;
 use vst::buffer::AudioBuffer;
-use vst::plugin::{Category, Info, Plugin, PluginParameters};
+use vst::plugin::{CanDo, Category, Info, Plugin, PluginParameters};
 use vst::util::AtomicFloat;
+use vsts::buffer::{bypass_pairs, process_stereo, MAX_PAIRS};
+use vsts::smoothing::BypassFade;
+use vsts::util::{clamp_parameter, db_from_gain, effect_can_do, from_range, to_range, MIN_DB};
 
 use std::sync::Arc;
 
@@ -17,8 +22,16 @@ struct GainEffect {
     // Store a handle to the plugin's parameter object.
     params: Arc<GainEffectParameters>,
     sample_rate: f32,
+    pairs: [PairState; MAX_PAIRS],
+}
+
+/// Last output of one channel pair.
+#[derive(Copy, Clone)]
+struct PairState {
     prev_l: f32,
     prev_r: f32,
+    /// Crossfade in and out of bypass.
+    bypass: BypassFade,
 }
 
 /// The plugin's parameter object contains the values of parameters that can be
@@ -35,6 +48,11 @@ struct GainEffectParameters {
     slew_max: AtomicFloat,
     rise: AtomicFloat,
     fall: AtomicFloat,
+    shape: AtomicFloat,
+    bypass: AtomicFloat,
+    // Read only, the average difference between input and output over the
+    // last block in dB
+    reduction: AtomicFloat,
 }
 
 // All plugins using the `vst` crate will either need to implement the `Default`
@@ -45,9 +63,12 @@ impl Default for GainEffect {
     fn default() -> GainEffect {
         GainEffect {
             params: Arc::new(GainEffectParameters::default()),
-            prev_l: 0.0,
-            prev_r: 0.0,
             sample_rate: 44100.0,
+            pairs: [PairState {
+                prev_l: 0.0,
+                prev_r: 0.0,
+                bypass: BypassFade::new(44100.0),
+            }; MAX_PAIRS],
         }
     }
 }
@@ -56,17 +77,52 @@ impl Default for GainEffectParameters {
     fn default() -> GainEffectParameters {
         GainEffectParameters {
             slew_min: AtomicFloat::new(0.1),
-            slew_max: AtomicFloat::new(10000.0 / 100000.0),
+            slew_max: AtomicFloat::new(from_range(10000.0, SLEW_MAX_RANGE.0, SLEW_MAX_RANGE.1)),
             rise: AtomicFloat::new(0.5),
             fall: AtomicFloat::new(0.5),
+            shape: AtomicFloat::new(0.0),
+            bypass: AtomicFloat::new(0.0),
+            reduction: AtomicFloat::new(MIN_DB),
         }
     }
 }
 
+/// Range of the slew max parameter in volts per second. It stays above zero,
+/// slew min is taken as a fraction of it.
+const SLEW_MAX_RANGE: (f32, f32) = (1.0, 100000.0);
+
+impl GainEffectParameters {
+    /// Slew max in volts per second, as used by the DSP and the parameter text.
+    fn slew_max(&self) -> f32 {
+        to_range(self.slew_max.get(), SLEW_MAX_RANGE.0, SLEW_MAX_RANGE.1)
+    }
+}
+
+/// Span of the reduction meter in dB.
+const REDUCTION_RANGE: (f32, f32) = (-60.0, 0.0);
+
 fn mix(x: f32, y: f32, a: f32) -> f32 {
     x * (1.0 - a) + y * a
 }
 
+// Blend between a constant rate linear slew (shape = 0) and a one-pole
+// exponential approach toward the input (shape = 1).
+fn slew(prev: f32, input: f32, rise: f32, fall: f32, k_rise: f32, k_fall: f32, shape: f32) -> f32 {
+    if input > prev {
+        mix(
+            input.min(prev + rise),
+            prev + (input - prev) * k_rise,
+            shape,
+        )
+    } else {
+        mix(
+            input.max(prev - fall),
+            prev + (input - prev) * k_fall,
+            shape,
+        )
+    }
+}
+
 // All plugins using `vst` also need to implement the `Plugin` trait.  Here, we
 // define functions that give necessary info to our host.
 impl Plugin for GainEffect {
@@ -80,54 +136,79 @@ impl Plugin for GainEffect {
             outputs: 2,
             // This `parameters` bit is important; without it, none of our
             // parameters will be shown!
-            parameters: 4,
+            parameters: 7,
             category: Category::Effect,
             ..Default::default()
         }
     }
 
+    fn set_sample_rate(&mut self, rate: f32) {
+        self.sample_rate = rate;
+        for pair in self.pairs.iter_mut() {
+            pair.bypass.set_sample_rate(rate);
+        }
+    }
+
     // Here is where the bulk of our audio processing code goes.
     fn process(&mut self, buffer: &mut AudioBuffer<f32>) {
+        let bypass = self.params.bypass.get() > 0.5;
+        if bypass_pairs(buffer, &mut self.pairs, bypass, |pair| &mut pair.bypass) {
+            self.params.reduction.set(MIN_DB);
+            return;
+        }
+
         let time_step = 1.0 / self.sample_rate;
 
         let slew_min = self.params.slew_min.get();
-        let slew_max = self.params.slew_max.get() * 100000.0;
+        let slew_max = self.params.slew_max();
         let rise = self.params.rise.get();
         let fall = self.params.fall.get();
+        let shape = self.params.shape.get();
 
         let slew_rise = slew_max * time_step * (slew_min / slew_max).powf(rise);
         let slew_fall = slew_max * time_step * (slew_min / slew_max).powf(fall);
 
-        // First, we destructure our audio buffer into an arbitrary number of
-        // input and output buffers.  Usually, we'll be dealing with stereo (2 of each)
-        // but that might change.
-
-        let (inputs, mut outputs) = buffer.split();
-        let (inputs_left, inputs_right) = inputs.split_at(1);
-        let (mut outputs_left, mut outputs_right) = outputs.split_at_mut(1);
-
-        let inputs_stereo = inputs_left[0].iter().zip(inputs_right[0].iter());
-        let outputs_stereo = outputs_left[0].iter_mut().zip(outputs_right[0].iter_mut());
+        // One-pole coefficients, the slew rates are already scaled by the time step
+        let k_rise = 1.0 - (-slew_rise).exp();
+        let k_fall = 1.0 - (-slew_fall).exp();
 
-        for (input_pair, output_pair) in inputs_stereo.zip(outputs_stereo) {
-            let (input_l, input_r) = input_pair;
-            let (output_l, output_r) = output_pair;
+        let mut difference = 0.0;
+        let mut count = 0;
+        process_stereo(buffer, &mut self.pairs, |pair, input_l, input_r| {
+            let output_l = slew(
+                pair.prev_l,
+                input_l,
+                slew_rise,
+                slew_fall,
+                k_rise,
+                k_fall,
+                shape,
+            );
 
-            *output_l = if *input_l > self.prev_l {
-                input_l.min(self.prev_l + slew_rise)
-            } else {
-                input_l.max(self.prev_l - slew_fall)
-            };
+            let output_r = slew(
+                pair.prev_r,
+                input_r,
+                slew_rise,
+                slew_fall,
+                k_rise,
+                k_fall,
+                shape,
+            );
 
-            *output_r = if *input_r > self.prev_r {
-                input_r.min(self.prev_r + slew_rise)
-            } else {
-                input_r.max(self.prev_r - slew_fall)
-            };
+            pair.prev_l = output_l;
+            pair.prev_r = output_r;
+            difference += (output_l - input_l).abs() + (output_r - input_r).abs();
+            count += 2;
+            pair.bypass.mix((input_l, input_r), (output_l, output_r))
+        });
 
-            self.prev_l = *output_l;
-            self.prev_r = *output_r;
-        }
+        // A buffer passed through untouched has nothing to report
+        let reduction = if count > 0 {
+            db_from_gain(difference / count as f32)
+        } else {
+            MIN_DB
+        };
+        self.params.reduction.set(reduction);
     }
 
     // Return the parameter object. This method can be omitted if the
@@ -135,6 +216,10 @@ impl Plugin for GainEffect {
     fn get_parameter_object(&mut self) -> Arc<dyn PluginParameters> {
         Arc::clone(&self.params) as Arc<dyn PluginParameters>
     }
+
+    fn can_do(&self, can_do: CanDo) -> Supported {
+        effect_can_do(can_do)
+    }
 }
 
 impl PluginParameters for GainEffectParameters {
@@ -145,18 +230,27 @@ impl PluginParameters for GainEffectParameters {
             1 => self.slew_max.get(),
             2 => self.rise.get(),
             3 => self.fall.get(),
+            4 => self.shape.get(),
+            5 => self.bypass.get(),
+            6 => from_range(self.reduction.get(), REDUCTION_RANGE.0, REDUCTION_RANGE.1),
             _ => 0.0,
         }
     }
 
     // the `set_parameter` function sets the value of a parameter.
     fn set_parameter(&self, index: i32, val: f32) {
+        let val = match clamp_parameter(val) {
+            Some(val) => val,
+            None => return,
+        };
         #[allow(clippy::single_match)]
         match index {
             0 => self.slew_min.set(val),
             1 => self.slew_max.set(val),
             2 => self.rise.set(val),
             3 => self.fall.set(val),
+            4 => self.shape.set(val),
+            5 => self.bypass.set(val),
             _ => (),
         }
     }
@@ -165,21 +259,32 @@ impl PluginParameters for GainEffectParameters {
     // format it into a string that makes the most since.
     fn get_parameter_text(&self, index: i32) -> String {
         match index {
-            0 => format!("{:.2}", self.slew_min.get()),
-            1 => format!("{:.2}", self.slew_max.get() * 100000.0),
+            0 => format!("{:.2} v/s", self.slew_min.get()),
+            1 => format!("{:.0} v/s", self.slew_max()),
             2 => format!("{:.2}", self.rise.get()),
             3 => format!("{:.2}", self.fall.get()),
+            4 => format!("{:.2}", self.shape.get()),
+            5 => (if self.bypass.get() > 0.5 { "On" } else { "Off" }).to_string(),
+            6 => format!("{:.1} dB", self.reduction.get()),
             _ => "".to_string(),
         }
     }
 
+    // The reduction meter is written by the plugin, the host can't set it
+    fn can_be_automated(&self, index: i32) -> bool {
+        index != 6
+    }
+
     // This shows the control's name.
     fn get_parameter_name(&self, index: i32) -> String {
         match index {
-            0 => "Slew Min v/s",
-            1 => "Slew Max v/s",
+            0 => "Slew Min",
+            1 => "Slew Max",
             2 => "Rise",
             3 => "Fall",
+            4 => "Shape",
+            5 => "Bypass",
+            6 => "Reduction",
             _ => "",
         }
         .to_string()
@@ -188,3 +293,73 @@ impl PluginParameters for GainEffectParameters {
 
 // This part is important!  Without it, our plugin won't work.
 plugin_main!(GainEffect);
+
+#[cfg(test)]
+mod tests {
+    use vst::plugin::Plugin;
+    use vsts::buffer::process_buffer;
+    use GainEffect;
+
+    /// Reduction reported after processing a square wave with `slew_min`.
+    fn square_reduction(slew_min: f32) -> f32 {
+        let mut effect = GainEffect::default();
+        let params = effect.get_parameter_object();
+        params.set_parameter(0, slew_min);
+        params.set_parameter(1, slew_min);
+        let square: Vec<f32> = (0..512)
+            .map(|n| if n % 100 < 50 { 0.5 } else { -0.5 })
+            .collect();
+        process_buffer(&[square.clone(), square], 2, 512, |buffer| {
+            effect.process(buffer)
+        });
+        params.get_parameter(6)
+    }
+
+    #[test]
+    fn test_reduction_follows_slew() {
+        // Slow slew smooths the edges far more than fast slew
+        let slow = square_reduction(0.001);
+        let fast = square_reduction(1.0);
+        assert!(slow > fast);
+        assert!(slow > 0.0 && slow <= 1.0);
+    }
+
+    #[test]
+    fn test_zero_slew_stays_finite() {
+        let mut effect = GainEffect::default();
+        let params = effect.get_parameter_object();
+        params.set_parameter(0, 0.0);
+        params.set_parameter(1, 0.0);
+        assert_eq!(params.get_parameter_text(1), "1 v/s");
+        let input: Vec<f32> = (0..512).map(|n| (n as f32 * 0.05).sin()).collect();
+        let outputs = process_buffer(&[input.clone(), input], 2, 512, |buffer| {
+            effect.process(buffer)
+        });
+        assert!(outputs.iter().flatten().all(|x| x.is_finite()));
+        assert!(params.get_parameter(6).is_finite());
+    }
+
+    #[test]
+    fn test_surround_pairs() {
+        let mut effect = GainEffect::default();
+        effect.get_parameter_object().set_parameter(1, 0.001);
+        let square: Vec<f32> = (0..512)
+            .map(|n| if n % 100 < 50 { 0.5 } else { -0.5 })
+            .collect();
+        let outputs = process_buffer(&vec![square; 4], 4, 512, |buffer| effect.process(buffer));
+        // Both pairs are slewed, each from its own state
+        assert_ne!(outputs[2][1], 0.5);
+        assert_eq!(outputs[0], outputs[2]);
+        assert_eq!(outputs[1], outputs[3]);
+    }
+
+    #[test]
+    fn test_parameter_text() {
+        let mut effect = GainEffect::default();
+        let params = effect.get_parameter_object();
+        assert_eq!(params.get_parameter_text(0), "0.10 v/s");
+        assert_eq!(params.get_parameter_text(1), "10000 v/s");
+        params.set_parameter(1, 1.0);
+        assert_eq!(params.get_parameter_text(1), "100000 v/s");
+    }
+}
@@ -3,29 +3,19 @@
 #[macro_use]
 extern crate vst;
 extern crate time;
+extern crate vsts;
 
+use vst::api::Supported;
 use vst::buffer::AudioBuffer;
-use vst::plugin::{Category, Info, Plugin, PluginParameters};
+use vst::plugin::{CanDo, Category, Info, Plugin, PluginParameters};
 use vst::util::AtomicFloat;
+use vsts::util::{
+    clamp_parameter, db_from_gain, effect_can_do, from_range, from_range_log, gain_from_db,
+    to_range, to_range_log,
+};
 
 use std::sync::Arc;
 
-fn gain_from_db(decibels: f32) -> f32 {
-    (10.0f32).powf(decibels * 0.05)
-}
-
-fn db_from_gain(gain: f32) -> f32 {
-    gain.max(0.0).log(10.0) * 20.0
-}
-
-fn to_range(x: f32, bottom: f32, top: f32) -> f32 {
-    x * (top - bottom) + bottom
-}
-
-fn from_range(x: f32, bottom: f32, top: f32) -> f32 {
-    (x - bottom) / (top - bottom)
-}
-
 /// Simple Gain Effect.
 /// Note that this does not use a proper scale for sound and shouldn't be used in
 /// a production amplification effect!  This is purely for demonstration purposes,
@@ -85,6 +75,10 @@ impl Plugin for ReverbEffect {
     fn get_parameter_object(&mut self) -> Arc<dyn PluginParameters> {
         Arc::clone(&self.params) as Arc<dyn PluginParameters>
     }
+
+    fn can_do(&self, can_do: CanDo) -> Supported {
+        effect_can_do(can_do)
+    }
 }
 
 /// The plugin's parameter object contains the values of parameters that can be
@@ -151,7 +145,7 @@ impl PluginParameters for ReverbEffectParameters {
             3 => from_range(self.decay_init.get(), 0.0, 1.5),
             4 => from_range(self.decay_delta.get(), 0.5, 1.5),
             5 => from_range(self.iterations.get(), 1.0, 64.0).floor(),
-            6 => from_range(self.lpf_cutoff.get(), 1.0, 20000.0),
+            6 => from_range_log(self.lpf_cutoff.get(), 20.0, 20000.0),
             7 => from_range(self.lpf_slope.get(), 0.04, 1.0),
             8 => self.saturation_mix.get(),
             9 => from_range(self.saturation.get(), 0.0, 100.0),
@@ -162,6 +156,10 @@ impl PluginParameters for ReverbEffectParameters {
 
     // the `set_parameter` function sets the value of a parameter.
     fn set_parameter(&self, index: i32, val: f32) {
+        let val = match clamp_parameter(val) {
+            Some(val) => val,
+            None => return,
+        };
         #[allow(clippy::single_match)]
         match index {
             0 => self.mix.set(val),
@@ -170,7 +168,7 @@ impl PluginParameters for ReverbEffectParameters {
             3 => self.decay_init.set(to_range(val, 0.0, 1.5)),
             4 => self.decay_delta.set(to_range(val, 0.5, 1.5)),
             5 => self.iterations.set(to_range(val, 1.0, 64.0)),
-            6 => self.lpf_cutoff.set(to_range(val, 1.0, 20000.0)),
+            6 => self.lpf_cutoff.set(to_range_log(val, 20.0, 20000.0)),
             7 => self.lpf_slope.set(to_range(val, 0.04, 1.0)),
             8 => self.saturation_mix.set(val),
             9 => self.saturation.set(to_range(val, 0.0, 100.0)),
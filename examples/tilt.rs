@@ -0,0 +1,235 @@
+#[macro_use]
+extern crate vst;
+extern crate vsts;
+
+use vst::api::Supported;
+use vst::buffer::AudioBuffer;
+use vst::plugin::{CanDo, Category, Info, Plugin, PluginParameters};
+use vst::util::AtomicFloat;
+use vsts::buffer::{bypass_pairs, process_stereo, MAX_PAIRS};
+use vsts::dsp::{Tilt, MAX_TILT_DB};
+use vsts::smoothing::BypassFade;
+use vsts::util::{clamp_parameter, effect_can_do, from_range_log, to_range_log};
+
+use std::sync::Arc;
+
+/// Tilt EQ. One knob brightens or darkens the whole signal by leaning the
+/// response around a pivot, the same tone control as multi_synth's output.
+struct TiltEq {
+    params: Arc<TiltParameters>,
+    sample_rate: f32,
+    pairs: [PairState; MAX_PAIRS],
+}
+
+/// Filters of one channel pair.
+#[derive(Copy, Clone)]
+struct PairState {
+    filters: [Tilt; 2],
+    /// Crossfade in and out of bypass.
+    bypass: BypassFade,
+}
+
+/// The plugin's parameter object contains the values of parameters that can be
+/// adjusted from the host.
+///
+/// The parameters object is shared between the processing and GUI threads.
+/// For this reason, all mutable state in the object has to be represented
+/// through thread-safe interior mutability.
+struct TiltParameters {
+    /// Darker below 0.5 and brighter above.
+    tilt: AtomicFloat,
+    pivot: AtomicFloat,
+    bypass: AtomicFloat,
+}
+
+impl Default for TiltEq {
+    fn default() -> TiltEq {
+        TiltEq {
+            params: Arc::new(TiltParameters::default()),
+            sample_rate: 44100.0,
+            pairs: [PairState {
+                filters: [Tilt::default(); 2],
+                bypass: BypassFade::new(44100.0),
+            }; MAX_PAIRS],
+        }
+    }
+}
+
+impl Default for TiltParameters {
+    fn default() -> TiltParameters {
+        TiltParameters {
+            tilt: AtomicFloat::new(0.5),
+            pivot: AtomicFloat::new(from_range_log(1000.0, PIVOT_RANGE.0, PIVOT_RANGE.1)),
+            bypass: AtomicFloat::new(0.0),
+        }
+    }
+}
+
+/// Range of the pivot in Hz.
+const PIVOT_RANGE: (f32, f32) = (100.0, 5000.0);
+
+impl TiltParameters {
+    fn tilt_db(&self) -> f32 {
+        (self.tilt.get() * 2.0 - 1.0) * MAX_TILT_DB
+    }
+
+    /// Exponential so the knob moves evenly in octaves.
+    fn pivot_hz(&self) -> f32 {
+        to_range_log(self.pivot.get(), PIVOT_RANGE.0, PIVOT_RANGE.1)
+    }
+}
+
+impl Plugin for TiltEq {
+    fn get_info(&self) -> Info {
+        Info {
+            name: "Tilt".to_string(),
+            vendor: "DGriffin".to_string(),
+            unique_id: 543923678,
+            version: 1,
+            inputs: 2,
+            outputs: 2,
+            parameters: 3,
+            category: Category::Effect,
+            ..Default::default()
+        }
+    }
+
+    fn set_sample_rate(&mut self, rate: f32) {
+        self.sample_rate = rate;
+        for pair in self.pairs.iter_mut() {
+            pair.bypass.set_sample_rate(rate);
+            for filter in pair.filters.iter_mut() {
+                filter.reset();
+            }
+        }
+    }
+
+    fn process(&mut self, buffer: &mut AudioBuffer<f32>) {
+        let bypass = self.params.bypass.get() > 0.5;
+        let tilt_db = self.params.tilt_db();
+        let pivot = self.params.pivot_hz();
+        for pair in self.pairs.iter_mut() {
+            for filter in pair.filters.iter_mut() {
+                filter.set(tilt_db, pivot, self.sample_rate);
+            }
+        }
+        if bypass_pairs(buffer, &mut self.pairs, bypass, |pair| &mut pair.bypass) {
+            return;
+        }
+
+        process_stereo(buffer, &mut self.pairs, |pair, input_l, input_r| {
+            let [filter_l, filter_r] = &mut pair.filters;
+            let output = (filter_l.process(input_l), filter_r.process(input_r));
+            pair.bypass.mix((input_l, input_r), output)
+        });
+    }
+
+    fn get_parameter_object(&mut self) -> Arc<dyn PluginParameters> {
+        Arc::clone(&self.params) as Arc<dyn PluginParameters>
+    }
+
+    fn can_do(&self, can_do: CanDo) -> Supported {
+        effect_can_do(can_do)
+    }
+}
+
+impl PluginParameters for TiltParameters {
+    fn get_parameter(&self, index: i32) -> f32 {
+        match index {
+            0 => self.tilt.get(),
+            1 => self.pivot.get(),
+            2 => self.bypass.get(),
+            _ => 0.0,
+        }
+    }
+
+    fn set_parameter(&self, index: i32, val: f32) {
+        let val = match clamp_parameter(val) {
+            Some(val) => val,
+            None => return,
+        };
+        match index {
+            0 => self.tilt.set(val),
+            1 => self.pivot.set(val),
+            2 => self.bypass.set(val),
+            _ => (),
+        }
+    }
+
+    fn get_parameter_text(&self, index: i32) -> String {
+        match index {
+            0 => format!("{:+.1} dB", self.tilt_db()),
+            1 => format!("{:.0} Hz", self.pivot_hz()),
+            2 => (if self.bypass.get() > 0.5 { "On" } else { "Off" }).to_string(),
+            _ => "".to_string(),
+        }
+    }
+
+    fn get_parameter_name(&self, index: i32) -> String {
+        match index {
+            0 => "Tilt",
+            1 => "Pivot",
+            2 => "Bypass",
+            _ => "",
+        }
+        .to_string()
+    }
+}
+
+plugin_main!(TiltEq);
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+    use vst::plugin::{Plugin, PluginParameters};
+    use vsts::buffer::process_buffer;
+    use {TiltEq, TiltParameters};
+
+    /// A `freq` Hz sine on both channels.
+    fn sine(freq: f32) -> Vec<Vec<f32>> {
+        let sine: Vec<f32> = (0..4410)
+            .map(|n| (n as f32 * 2.0 * PI * freq / 44100.0).sin() * 0.5)
+            .collect();
+        vec![sine; 2]
+    }
+
+    /// Output peak over the second half of a `freq` Hz sine through `eq`.
+    fn peak(eq: &mut TiltEq, freq: f32) -> f32 {
+        let outputs = process_buffer(&sine(freq), 2, 4410, |buffer| eq.process(buffer));
+        outputs[0][2205..]
+            .iter()
+            .fold(0.0f32, |peak, x| peak.max(x.abs()))
+    }
+
+    #[test]
+    fn test_flat_passes_through() {
+        let mut eq = TiltEq::default();
+        let inputs = sine(440.0);
+        let outputs = process_buffer(&inputs, 2, 4410, |buffer| eq.process(buffer));
+        assert_eq!(outputs, inputs);
+    }
+
+    #[test]
+    fn test_tilt() {
+        // Fully bright, the lows drop and the highs come up by half the tilt
+        let mut eq = TiltEq::default();
+        eq.params.set_parameter(0, 1.0);
+        assert!((peak(&mut eq, 30.0) - 0.25).abs() < 0.01);
+        let mut eq = TiltEq::default();
+        eq.params.set_parameter(0, 1.0);
+        assert!((peak(&mut eq, 15000.0) - 1.0).abs() < 0.02);
+
+        let mut eq = TiltEq::default();
+        eq.params.set_parameter(0, 0.0);
+        assert!((peak(&mut eq, 30.0) - 1.0).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_parameter_text() {
+        let params = TiltParameters::default();
+        let text: Vec<String> = (0..3).map(|i| params.get_parameter_text(i)).collect();
+        assert_eq!(text, ["+0.0 dB", "1000 Hz", "Off"]);
+        params.set_parameter(0, 0.25);
+        assert_eq!(params.get_parameter_text(0), "-6.0 dB");
+    }
+}
@@ -0,0 +1,304 @@
+#[macro_use]
+extern crate vst;
+extern crate vsts;
+
+use vst::api::Supported;
+use vst::buffer::AudioBuffer;
+use vst::plugin::{CanDo, Category, HostCallback, Info, Plugin, PluginParameters};
+use vst::util::AtomicFloat;
+use vsts::buffer::{bypass_pairs, process_stereo, MAX_PAIRS};
+use vsts::lfo::{Lfo, LfoShape};
+use vsts::smoothing::{BypassFade, SmoothedValue, DEFAULT_SMOOTHING_MS};
+use vsts::tempo::{division, host_bpm, DEFAULT_BPM, DIVISIONS};
+use vsts::util::{clamp_parameter, effect_can_do};
+
+use std::sync::Arc;
+
+/// Tremolo and auto-pan. The signal is turned down by the lfo, offsetting the
+/// right channel's lfo by 180 degrees pans it from side to side instead.
+struct Tremolo {
+    params: Arc<TremoloParameters>,
+    host: HostCallback,
+    sample_rate: f32,
+    pairs: [PairState; MAX_PAIRS],
+}
+
+/// Lfos and smoothing of one channel pair.
+#[derive(Copy, Clone)]
+struct PairState {
+    lfo_l: Lfo,
+    lfo_r: Lfo,
+    depth: SmoothedValue,
+    /// Crossfade in and out of bypass.
+    bypass: BypassFade,
+}
+
+/// The plugin's parameter object contains the values of parameters that can be
+/// adjusted from the host.
+///
+/// The parameters object is shared between the processing and GUI threads.
+/// For this reason, all mutable state in the object has to be represented
+/// through thread-safe interior mutability.
+struct TremoloParameters {
+    depth: AtomicFloat,
+    rate: AtomicFloat,
+    /// Lock the rate to a note division at the host tempo.
+    sync: AtomicFloat,
+    shape: AtomicFloat,
+    /// Offset of the right lfo from the left, 0 to 180 degrees.
+    stereo_phase: AtomicFloat,
+    bypass: AtomicFloat,
+}
+
+impl Default for Tremolo {
+    fn default() -> Tremolo {
+        let params = TremoloParameters::default();
+        let shape = params.shape();
+        let mut depth = SmoothedValue::new(params.depth.get());
+        depth.set_time(DEFAULT_SMOOTHING_MS, 44100.0);
+        Tremolo {
+            params: Arc::new(params),
+            host: HostCallback::default(),
+            sample_rate: 44100.0,
+            pairs: [PairState {
+                lfo_l: Lfo::new(shape),
+                lfo_r: Lfo::new(shape),
+                depth,
+                bypass: BypassFade::new(44100.0),
+            }; MAX_PAIRS],
+        }
+    }
+}
+
+impl Default for TremoloParameters {
+    fn default() -> TremoloParameters {
+        TremoloParameters {
+            depth: AtomicFloat::new(0.5),
+            rate: AtomicFloat::new(0.5),
+            sync: AtomicFloat::new(0.0),
+            shape: AtomicFloat::new(0.0),
+            stereo_phase: AtomicFloat::new(0.0),
+            bypass: AtomicFloat::new(0.0),
+        }
+    }
+}
+
+/// Largest offset between the left and right lfos, as a fraction of a cycle.
+const MAX_STEREO_PHASE: f32 = 0.5;
+
+/// Lfo rate in hz. Free running rates go from 0.05hz to 20hz, synced rates
+/// snap to a note division at `bpm`.
+fn rate_hz(rate: f32, sync: bool, bpm: Option<f64>) -> f32 {
+    if sync {
+        let (_, beats) = division(&DIVISIONS, rate);
+        (bpm.unwrap_or(DEFAULT_BPM) / 60.0 / beats) as f32
+    } else {
+        0.05 * 400.0f32.powf(rate)
+    }
+}
+
+/// Gain for a bipolar lfo value, 1 at the top of the cycle down to
+/// `1 - depth` at the bottom.
+fn tremolo_gain(lfo: f32, depth: f32) -> f32 {
+    1.0 - depth * (1.0 - lfo) * 0.5
+}
+
+impl TremoloParameters {
+    fn shape(&self) -> LfoShape {
+        LfoShape::from_parameter(self.shape.get())
+    }
+
+    fn stereo_phase(&self) -> f32 {
+        self.stereo_phase.get() * MAX_STEREO_PHASE
+    }
+}
+
+impl Plugin for Tremolo {
+    fn get_info(&self) -> Info {
+        Info {
+            name: "Tremolo".to_string(),
+            vendor: "DGriffin".to_string(),
+            unique_id: 435670412,
+            version: 1,
+            inputs: 2,
+            outputs: 2,
+            parameters: 6,
+            category: Category::Effect,
+            ..Default::default()
+        }
+    }
+
+    fn new(host: HostCallback) -> Tremolo {
+        Tremolo {
+            host,
+            ..Tremolo::default()
+        }
+    }
+
+    fn set_sample_rate(&mut self, rate: f32) {
+        self.sample_rate = rate;
+        for pair in self.pairs.iter_mut() {
+            pair.bypass.set_sample_rate(rate);
+            pair.depth.set_time(DEFAULT_SMOOTHING_MS, rate);
+        }
+    }
+
+    fn process(&mut self, buffer: &mut AudioBuffer<f32>) {
+        let bypass = self.params.bypass.get() > 0.5;
+        if bypass_pairs(buffer, &mut self.pairs, bypass, |pair| &mut pair.bypass) {
+            return;
+        }
+
+        let sync = self.params.sync.get() > 0.5;
+        let bpm = if sync { host_bpm(&self.host) } else { None };
+        let rate = rate_hz(self.params.rate.get(), sync, bpm);
+        let shape = self.params.shape();
+        let stereo_phase = self.params.stereo_phase();
+        let depth = self.params.depth.get();
+        for pair in self.pairs.iter_mut() {
+            for lfo in [&mut pair.lfo_l, &mut pair.lfo_r].iter_mut() {
+                lfo.set_rate_hz(rate, self.sample_rate);
+                lfo.set_shape(shape);
+            }
+            // Both lfos run at the same rate, so lining them up once a block is enough
+            let phase_r = pair.lfo_l.phase() + stereo_phase;
+            pair.lfo_r.set_phase(phase_r);
+            pair.depth.set_target(depth);
+        }
+
+        process_stereo(buffer, &mut self.pairs, |pair, input_l, input_r| {
+            let depth = pair.depth.next();
+            let output = (
+                input_l * tremolo_gain(pair.lfo_l.next(), depth),
+                input_r * tremolo_gain(pair.lfo_r.next(), depth),
+            );
+            pair.bypass.mix((input_l, input_r), output)
+        });
+    }
+
+    fn get_parameter_object(&mut self) -> Arc<dyn PluginParameters> {
+        Arc::clone(&self.params) as Arc<dyn PluginParameters>
+    }
+
+    fn can_do(&self, can_do: CanDo) -> Supported {
+        match can_do {
+            CanDo::ReceiveTimeInfo => Supported::Yes,
+            other => effect_can_do(other),
+        }
+    }
+}
+
+impl PluginParameters for TremoloParameters {
+    fn get_parameter(&self, index: i32) -> f32 {
+        match index {
+            0 => self.depth.get(),
+            1 => self.rate.get(),
+            2 => self.sync.get(),
+            3 => self.shape.get(),
+            4 => self.stereo_phase.get(),
+            5 => self.bypass.get(),
+            _ => 0.0,
+        }
+    }
+
+    fn set_parameter(&self, index: i32, val: f32) {
+        let val = match clamp_parameter(val) {
+            Some(val) => val,
+            None => return,
+        };
+        #[allow(clippy::single_match)]
+        match index {
+            0 => self.depth.set(val),
+            1 => self.rate.set(val),
+            2 => self.sync.set(val),
+            3 => self.shape.set(val),
+            4 => self.stereo_phase.set(val),
+            5 => self.bypass.set(val),
+            _ => (),
+        }
+    }
+
+    fn get_parameter_text(&self, index: i32) -> String {
+        match index {
+            0 => format!("{:.0}%", self.depth.get() * 100.0),
+            1 => {
+                if self.sync.get() > 0.5 {
+                    division(&DIVISIONS, self.rate.get()).0.to_string()
+                } else {
+                    format!("{:.2} Hz", rate_hz(self.rate.get(), false, None))
+                }
+            }
+            2 => (if self.sync.get() > 0.5 { "On" } else { "Off" }).to_string(),
+            3 => self.shape().name().to_string(),
+            4 => format!("{:.0}°", self.stereo_phase() * 360.0),
+            5 => (if self.bypass.get() > 0.5 { "On" } else { "Off" }).to_string(),
+            _ => "".to_string(),
+        }
+    }
+
+    fn get_parameter_name(&self, index: i32) -> String {
+        match index {
+            0 => "Depth",
+            1 => "Rate",
+            2 => "Sync",
+            3 => "Shape",
+            4 => "Stereo Phase",
+            5 => "Bypass",
+            _ => "",
+        }
+        .to_string()
+    }
+}
+
+plugin_main!(Tremolo);
+
+#[cfg(test)]
+mod tests {
+    use vst::plugin::Plugin;
+    use vsts::buffer::process_buffer;
+    use {rate_hz, tremolo_gain, Tremolo};
+
+    #[test]
+    fn test_tremolo_gain() {
+        assert_eq!(tremolo_gain(1.0, 1.0), 1.0);
+        assert_eq!(tremolo_gain(-1.0, 1.0), 0.0);
+        assert_eq!(tremolo_gain(-1.0, 0.5), 0.5);
+        // No depth leaves the signal alone
+        assert_eq!(tremolo_gain(-1.0, 0.0), 1.0);
+    }
+
+    #[test]
+    fn test_synced_rate() {
+        // A quarter note at 120bpm
+        assert_eq!(rate_hz(0.33, true, Some(120.0)), 2.0);
+        assert_eq!(rate_hz(0.33, true, None), 2.0);
+        assert!((rate_hz(0.5, false, None) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_parameter_text() {
+        let mut tremolo = Tremolo::default();
+        let params = tremolo.get_parameter_object();
+        params.set_parameter(1, 0.5);
+        assert_eq!(params.get_parameter_text(1), "1.00 Hz");
+        params.set_parameter(4, 1.0);
+        assert_eq!(params.get_parameter_text(4), "180°");
+    }
+
+    #[test]
+    fn test_auto_pan() {
+        let mut tremolo = Tremolo::default();
+        tremolo.set_sample_rate(1000.0);
+        let params = tremolo.get_parameter_object();
+        params.set_parameter(0, 1.0);
+        params.set_parameter(4, 1.0);
+
+        let inputs = vec![vec![1.0; 1000]; 2];
+        let outputs = process_buffer(&inputs, 2, 1000, |buffer| tremolo.process(buffer));
+
+        // 180 degrees apart, so the two sides always add up to the same level
+        for (l, r) in outputs[0].iter().zip(outputs[1].iter()).skip(100) {
+            assert!((l + r - 1.0).abs() < 1e-3);
+        }
+    }
+}
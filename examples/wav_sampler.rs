@@ -12,28 +12,60 @@ extern crate log_panics;
 extern crate ringbuf;
 extern crate simplelog;
 extern crate time;
+extern crate vsts;
 
 use vst::api::{Events, Supported};
 use vst::buffer::AudioBuffer;
 use vst::event::Event;
 use vst::plugin::{CanDo, Category, Info, Plugin, PluginParameters};
 use vst::util::AtomicFloat;
-
-use std::sync::Arc;
+use vsts::midi::{release_velocity, MidiQueue};
+use vsts::smoothing::BlockRamp;
+use vsts::util::clamp_parameter;
+
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
 
 use ringbuf::{Consumer, Producer, RingBuffer};
 
+use dasp::interpolate::{linear::Linear, sinc::Sinc};
 use dasp::signal::interpolate::Converter;
-use dasp::{interpolate::sinc::Sinc, ring_buffer, signal, Signal};
+use dasp::{ring_buffer, signal, Signal};
 
 use std::thread;
 
-fn setup_logging(path: &str) {
-    let log_folder = ::dirs::home_dir().unwrap().join("tmp");
+/// Create `path` in the `tmp` folder of the user's home.
+fn open_log_file(path: &str) -> io::Result<File> {
+    let log_folder = ::dirs::home_dir()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no home folder"))?
+        .join("tmp");
+
+    let _ = ::std::fs::create_dir(&log_folder);
+
+    File::create(log_folder.join(path))
+}
 
-    let _ = ::std::fs::create_dir(log_folder.clone());
+fn setup_logging(path: &str) {
+    // A panic on any of the plugin's threads can take the host down with it,
+    // so they're logged from the start. This goes first so a failure below
+    // still gets reported once there's somewhere to write to.
+    ::log_panics::init();
 
-    let log_file = ::std::fs::File::create(log_folder.join(path)).unwrap();
+    // Without a log the plugin still works, it just can't say what went wrong
+    let log_file = match open_log_file(path) {
+        Ok(log_file) => log_file,
+        Err(err) => {
+            eprintln!("failed to open log file {}: {}", path, err);
+            return;
+        }
+    };
 
     let log_config = ::simplelog::ConfigBuilder::new()
         .set_time_to_local(true)
@@ -41,43 +73,321 @@ fn setup_logging(path: &str) {
 
     let _ = ::simplelog::WriteLogger::init(simplelog::LevelFilter::Info, log_config, log_file);
 
-    ::log_panics::init();
-
     ::log::info!("init");
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 struct WavData {
+    // Recordings for different velocity ranges
+    layers: Vec<VelocityLayer>,
+    // Starting a note in a choke group cuts off everything else in it
+    choke_group: Option<u8>,
+}
+
+/// A contiguous range of keys played by one set of samples. A drum map uses
+/// zones one key wide, a multisampled instrument stretches each sample across
+/// a wider zone around its root.
+#[derive(Debug, Clone)]
+struct Zone {
+    // Lowest and highest key in the zone
+    lo: u8,
+    hi: u8,
+    // The samples play back unshifted here
+    root: u8,
+    // Fine tuning in cents
+    tune: i32,
+    data: WavData,
+}
+
+impl Zone {
+    fn contains(&self, note: u8) -> bool {
+        self.lo <= note && note <= self.hi
+    }
+
+    /// Playback speed for `note`, relative to the sample's own rate.
+    fn speed(&self, note: u8) -> f64 {
+        midi_pitch_to_freq(note) / midi_pitch_to_freq(self.root)
+            * (f64::from(self.tune) / 1200.0).exp2()
+    }
+}
+
+/// The samples played over one range of velocities.
+#[derive(Debug, Clone)]
+struct VelocityLayer {
+    // Round robin alternates, consecutive hits cycle through these
+    buffers: Vec<SampleBuffer>,
+    // Lowest and highest velocity the layer plays at
+    velocity_range: (u8, u8),
+}
+
+/// Pick the layers to play at `velocity` as `(lower, upper, fade)`, where
+/// `fade` is how much of the upper layer is mixed in. Where two layers overlap
+/// the velocity crossfades from one to the other, elsewhere both are the same
+/// layer and `fade` is 0.
+fn select_layers(layers: &[VelocityLayer], velocity: u8) -> Option<(usize, usize, f32)> {
+    let mut matching = layers.iter().enumerate().filter(|(_, layer)| {
+        layer.velocity_range.0 <= velocity && velocity <= layer.velocity_range.1
+    });
+    let first = matching.next()?;
+    let (lower, upper) = match matching.next() {
+        Some(second) if second.1.velocity_range.0 < first.1.velocity_range.0 => (second, first),
+        Some(second) => (first, second),
+        None => return Some((first.0, first.0, 0.0)),
+    };
+
+    let overlap_start = upper.1.velocity_range.0;
+    let overlap_end = lower.1.velocity_range.1.min(upper.1.velocity_range.1);
+    let fade = f32::from(velocity - overlap_start + 1) / f32::from(overlap_end - overlap_start + 2);
+    Some((lower.0, upper.0, fade))
+}
+
+/// The audio of a single wav file.
+#[derive(Debug, Clone, Default)]
+struct SampleBuffer {
+    // Interleaved audio, `channels` samples per frame
     audio: Vec<f32>,
+    channels: usize,
+    // Loop region in frames, the end is exclusive
+    loop_start: usize,
+    loop_end: usize,
+    loop_mode: LoopMode,
+    // Rate the file was recorded at in Hz
+    sample_rate: u32,
+}
+
+/// How a held note moves through the loop region, from the `smpl` chunk's
+/// loop type.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+enum LoopMode {
+    /// Jump from the end back to the start.
+    #[default]
+    Forward,
+    /// Bounce between the two ends, alternating direction.
+    PingPong,
+}
+
+impl SampleBuffer {
+    /// Frames of the file that go by for each frame at the base rate.
+    fn rate_ratio(&self) -> f64 {
+        f64::from(self.sample_rate) / f64::from(BASE_SAMPLE_RATE)
+    }
+
+    fn frames(&self) -> usize {
+        if self.channels == 0 {
+            0
+        } else {
+            self.audio.len() / self.channels
+        }
+    }
+
+    /// Left and right sample of a frame, mono samples are sent to both sides.
+    fn frame(&self, index: usize) -> (f32, f32) {
+        let start = index * self.channels;
+        if self.channels == 1 {
+            (self.audio[start], self.audio[start])
+        } else {
+            (self.audio[start], self.audio[start + 1])
+        }
+    }
+
+    /// Like `frame`, but the last `crossfade` frames of the loop are faded into
+    /// the audio leading up to the loop start so the jump back doesn't click.
+    fn loop_frame(&self, index: usize, crossfade: usize) -> (f32, f32) {
+        let fade_len = crossfade
+            .min(self.loop_start)
+            .min(self.loop_end - self.loop_start);
+        let fade_start = self.loop_end - fade_len;
+        if fade_len == 0 || index < fade_start || index >= self.loop_end {
+            return self.frame(index);
+        }
+
+        let fade = (index - fade_start) as f32 / fade_len as f32;
+        let (l, r) = self.frame(index);
+        let (pre_l, pre_r) = self.frame(self.loop_start - fade_len + (index - fade_start));
+        (mix(l, pre_l, fade), mix(r, pre_r, fade))
+    }
+
+    /// Linearly interpolated frame at a fractional position. With `looping`
+    /// the read wraps from the loop end to the start, crossfaded over
+    /// `crossfade` frames.
+    fn interpolated_frame(&self, position: f64, looping: bool, crossfade: usize) -> (f32, f32) {
+        let index = position as usize;
+        let fract = (position - index as f64) as f32;
+        let next = if looping && index + 1 == self.loop_end {
+            self.loop_start
+        } else {
+            (index + 1).min(self.frames() - 1)
+        };
+        let ((l, r), (next_l, next_r)) = if looping {
+            (
+                self.loop_frame(index, crossfade),
+                self.loop_frame(next, crossfade),
+            )
+        } else {
+            (self.frame(index), self.frame(next))
+        };
+        (mix(l, next_l, fract), mix(r, next_r, fract))
+    }
+}
+
+/// One row of the note to file table.
+#[derive(Debug, Clone, PartialEq)]
+struct SampleMapping {
     note: usize,
+    key_range: (usize, usize),
+    // Fine tuning in cents
+    tune: i32,
+    choke_group: Option<u8>,
+    // Rows for the same zone with different ranges become velocity layers
+    velocity_range: (u8, u8),
+    // More than one path makes a set of round robin alternates
+    paths: Vec<String>,
+}
+
+impl SampleMapping {
+    fn new(note: usize, path: &str) -> SampleMapping {
+        SampleMapping {
+            note,
+            key_range: (note, note),
+            tune: 0,
+            choke_group: None,
+            velocity_range: (0, 127),
+            paths: vec![path.to_string()],
+        }
+    }
+}
+
+/// Convert the midi note's pitch into the equivalent frequency.
+///
+/// This function assumes A4 is 440hz.
+fn midi_pitch_to_freq(pitch: u8) -> f64 {
+    const A4_PITCH: i8 = 69;
+    const A4_FREQ: f64 = 440.0;
+
+    // Midi notes can be 0-127
+    ((f64::from(pitch as i8 - A4_PITCH)) / 12.).exp2() * A4_FREQ
 }
 
-fn load_wav(path: &str) -> Vec<f32> {
-    // Find and load the wav.
-    //let assets = find_folder::Search::ParentsThenKids(5, 5)
-    //    .for_folder("assets")
-    //    .unwrap();
-    //let reader = hound::WavReader::open(assets.join(path)).unwrap();
-    let reader = hound::WavReader::open(path).unwrap();
-    //let spec = reader.spec();
+fn mix(x: f32, y: f32, a: f32) -> f32 {
+    x * (1.0 - a) + y * a
+}
 
-    // Read the interleaved samples and convert them to a signal.
-    let samples = reader.into_samples::<i16>();
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let b = bytes.get(offset..offset + 4)?;
+    Some(u32::from(b[0]) | u32::from(b[1]) << 8 | u32::from(b[2]) << 16 | u32::from(b[3]) << 24)
+}
 
-    let filter_map = samples.filter_map(Result::ok);
+/// Find the first loop in the `smpl` chunk of a wav file, hound doesn't expose it.
+fn read_smpl_loop(path: &Path) -> Option<(usize, usize, LoopMode)> {
+    let bytes = ::std::fs::read(path).ok()?;
+    if bytes.get(0..4)? != b"RIFF" || bytes.get(8..12)? != b"WAVE" {
+        return None;
+    }
 
-    let frames = signal::from_iter(filter_map);
+    let mut offset = 12;
+    while offset + 8 <= bytes.len() {
+        let id = &bytes[offset..offset + 4];
+        let size = read_u32(&bytes, offset + 4)? as usize;
+        let data = offset + 8;
+        if id == b"smpl" {
+            let loop_count = read_u32(&bytes, data + 28)?;
+            if loop_count == 0 {
+                return None;
+            }
+            // The first loop follows the 36 byte header, start and end are in frames
+            let mode = match read_u32(&bytes, data + 36 + 4)? {
+                1 => LoopMode::PingPong,
+                // Backward loops (type 2) are rare enough to play forward
+                _ => LoopMode::Forward,
+            };
+            let start = read_u32(&bytes, data + 36 + 8)? as usize;
+            let end = read_u32(&bytes, data + 36 + 12)? as usize;
+            return Some((start, end + 1, mode));
+        }
+        // Chunks are padded to an even size
+        offset = data + size + (size & 1);
+    }
+    None
+}
+
+fn load_wav(path: &Path) -> Result<SampleBuffer, hound::Error> {
+    let reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
 
     let mut output = Vec::new();
-    for frame in frames.until_exhausted() {
-        output.push(dasp::sample::conv::i16::to_f32(frame));
+    match (spec.sample_format, spec.bits_per_sample) {
+        (hound::SampleFormat::Float, _) => {
+            for sample in reader.into_samples::<f32>() {
+                output.push(sample?);
+            }
+        }
+        (hound::SampleFormat::Int, 16) => {
+            // Read the interleaved samples and convert them to a signal.
+            let samples = reader.into_samples::<i16>();
+
+            let filter_map = samples.filter_map(Result::ok);
+
+            let frames = signal::from_iter(filter_map);
+
+            for frame in frames.until_exhausted() {
+                output.push(dasp::sample::conv::i16::to_f32(frame));
+            }
+        }
+        (hound::SampleFormat::Int, bits) => {
+            // 24 bit samples are normalized by 2^23, 8 and 32 bit the same way
+            let scale = 1.0 / (1i64 << (bits - 1)) as f32;
+            for sample in reader.into_samples::<i32>() {
+                output.push(sample? as f32 * scale);
+            }
+        }
+    }
+    let mut buffer = SampleBuffer {
+        audio: output,
+        channels: spec.channels as usize,
+        loop_start: 0,
+        loop_end: 0,
+        loop_mode: LoopMode::Forward,
+        sample_rate: spec.sample_rate,
+    };
+
+    // Loop the whole buffer unless the file has valid loop points
+    buffer.loop_end = buffer.frames();
+    if let Some((start, end, mode)) = read_smpl_loop(path) {
+        if start < end && end <= buffer.frames() {
+            buffer.loop_start = start;
+            buffer.loop_end = end;
+            buffer.loop_mode = mode;
+        }
+    }
+
+    Ok(buffer)
+}
+
+/// Relative sample paths are looked up in the nearest `assets` folder.
+fn resolve_sample_path(path: &str) -> PathBuf {
+    let path = Path::new(path);
+    if path.is_relative() {
+        if let Ok(assets) = find_folder::Search::ParentsThenKids(5, 5).for_folder("assets") {
+            return assets.join(path);
+        }
     }
-    output
+    path.to_path_buf()
 }
 
-const POLY: usize = 3;
+// Size of the voice pool. Twice the most the polyphony parameter allows, so
+// stolen voices can fade out while the notes that took their place start
+const POLY: usize = 32;
+const MAX_POLYPHONY: usize = 16;
+// Room for zones is reserved up front, so loading a kit never allocates on the audio thread
+const MAX_ZONES: usize = 128;
+// How often the kit loader thread looks for a new kit
+const KIT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+// Kits the host or a midi program change can switch between
+const PROGRAM_COUNT: usize = 8;
 const BASE_SAMPLE_RATE: i32 = 44100;
-const SINC_INTERPOLATOR_SIZE: usize = 24;
+// Loop crossfade in frames, at the default and the top of the parameter
+const DEFAULT_LOOP_CROSSFADE: usize = 64;
+const MAX_LOOP_CROSSFADE: usize = 4410;
 
 struct RingBufferSignal {
     consumer: Consumer<f32>,
@@ -99,45 +409,187 @@ impl Signal for RingBufferSignal {
     }
 }
 
+/// Interpolation the sample rate converters use, from cheapest to cleanest.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Quality {
+    Linear,
+    Sinc16,
+    Sinc24,
+    Sinc64,
+}
+
+/// What the sampler resamples with unless the interpolation parameter is changed.
+const DEFAULT_QUALITY: Quality = Quality::Sinc24;
+
+/// Every quality, in the order the interpolation parameter steps through them.
+const QUALITIES: [Quality; 4] = [
+    Quality::Linear,
+    Quality::Sinc16,
+    Quality::Sinc24,
+    Quality::Sinc64,
+];
+
+impl Quality {
+    /// Quality for a 0 - 1 parameter value.
+    fn from_parameter(x: f32) -> Quality {
+        let index = (x * (QUALITIES.len() - 1) as f32).round() as usize;
+        QUALITIES[index.min(QUALITIES.len() - 1)]
+    }
+
+    fn to_parameter(self) -> f32 {
+        let index = QUALITIES
+            .iter()
+            .position(|&quality| quality == self)
+            .unwrap_or(0);
+        index as f32 / (QUALITIES.len() - 1) as f32
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Quality::Linear => "Linear",
+            Quality::Sinc16 => "Sinc 16",
+            Quality::Sinc24 => "Sinc 24",
+            Quality::Sinc64 => "Sinc 64",
+        }
+    }
+}
+
+/// A converter for each `Quality`. The interpolator is part of the
+/// converter's type, so each size of sinc needs its own variant.
+enum Interpolated {
+    Linear(Converter<RingBufferSignal, Linear<f32>>),
+    Sinc16(Converter<RingBufferSignal, Sinc<[f32; 16]>>),
+    Sinc24(Converter<RingBufferSignal, Sinc<[f32; 24]>>),
+    Sinc64(Converter<RingBufferSignal, Sinc<[f32; 64]>>),
+}
+
+impl Interpolated {
+    fn new(signal: RingBufferSignal, quality: Quality, source_hz: f64, target_hz: f64) -> Self {
+        match quality {
+            Quality::Linear => Interpolated::Linear(signal.from_hz_to_hz(
+                Linear::new(0.0, 0.0),
+                source_hz,
+                target_hz,
+            )),
+            Quality::Sinc16 => Interpolated::Sinc16(signal.from_hz_to_hz(
+                Sinc::new(ring_buffer::Fixed::from([0.0f32; 16])),
+                source_hz,
+                target_hz,
+            )),
+            Quality::Sinc24 => Interpolated::Sinc24(signal.from_hz_to_hz(
+                Sinc::new(ring_buffer::Fixed::from([0.0f32; 24])),
+                source_hz,
+                target_hz,
+            )),
+            Quality::Sinc64 => Interpolated::Sinc64(signal.from_hz_to_hz(
+                Sinc::new(ring_buffer::Fixed::from([0.0f32; 64])),
+                source_hz,
+                target_hz,
+            )),
+        }
+    }
+
+    fn source_mut(&mut self) -> &mut RingBufferSignal {
+        match self {
+            Interpolated::Linear(converter) => converter.source_mut(),
+            Interpolated::Sinc16(converter) => converter.source_mut(),
+            Interpolated::Sinc24(converter) => converter.source_mut(),
+            Interpolated::Sinc64(converter) => converter.source_mut(),
+        }
+    }
+
+    fn set_hz_to_hz(&mut self, source_hz: f64, target_hz: f64) {
+        match self {
+            Interpolated::Linear(converter) => converter.set_hz_to_hz(source_hz, target_hz),
+            Interpolated::Sinc16(converter) => converter.set_hz_to_hz(source_hz, target_hz),
+            Interpolated::Sinc24(converter) => converter.set_hz_to_hz(source_hz, target_hz),
+            Interpolated::Sinc64(converter) => converter.set_hz_to_hz(source_hz, target_hz),
+        }
+    }
+
+    fn next(&mut self) -> f32 {
+        match self {
+            Interpolated::Linear(converter) => converter.next(),
+            Interpolated::Sinc16(converter) => converter.next(),
+            Interpolated::Sinc24(converter) => converter.next(),
+            Interpolated::Sinc64(converter) => converter.next(),
+        }
+    }
+}
+
+/// Source samples to buffer for a block of `target_buffer_size` samples.
+fn source_capacity(source_hz: f64, target_hz: f64, target_buffer_size: usize) -> usize {
+    (target_buffer_size as f64 * (source_hz / target_hz)) as usize + 1
+}
+
 struct SampleRateConverter {
-    source_signal: Converter<RingBufferSignal, Sinc<[f32; SINC_INTERPOLATOR_SIZE]>>,
+    source_signal: Interpolated,
     source_producer: Producer<f32>,
     source_hz: f64,
     target_hz: f64,
-    source_buffer_size: usize,
     target_buffer_size: usize,
 }
 
 impl SampleRateConverter {
-    fn new(source_hz: f64, target_hz: f64, target_buffer_size: usize) -> SampleRateConverter {
-        let source_buffer_size = (target_buffer_size as f64 * (source_hz / target_hz)) as usize;
-
-        let (signal, source_producer) = RingBufferSignal::new((source_buffer_size + 1) as usize);
-
-        let source_signal = signal.from_hz_to_hz(
-            Sinc::new(ring_buffer::Fixed::from([0.0f32; SINC_INTERPOLATOR_SIZE])),
-            source_hz,
-            target_hz,
-        );
+    fn new(
+        source_hz: f64,
+        target_hz: f64,
+        target_buffer_size: usize,
+        quality: Quality,
+    ) -> SampleRateConverter {
+        let (signal, source_producer) =
+            RingBufferSignal::new(source_capacity(source_hz, target_hz, target_buffer_size));
 
-        //let source_signal = source_signal.from_hz_to_hz(
-        //    Sinc::new(ring_buffer::Fixed::from([0.0f32; SINC_INTERPOLATOR_SIZE])),
-        //    target_hz * 2.0,
-        //    target_hz,
-        //);
+        let source_signal = Interpolated::new(signal, quality, source_hz, target_hz);
 
         SampleRateConverter {
             source_signal,
             source_producer,
             source_hz,
             target_hz,
-            source_buffer_size,
             target_buffer_size,
         }
     }
 
+    /// Resize the source buffer for a new host block size. Samples already
+    /// buffered and the interpolator's history are kept, so the output carries
+    /// on without a glitch.
+    fn set_target_buffer_size(&mut self, target_buffer_size: usize) {
+        if target_buffer_size == self.target_buffer_size {
+            return;
+        }
+        self.target_buffer_size = target_buffer_size;
+        self.resize_source();
+    }
+
+    /// Convert to a new host rate, keeping what's buffered like
+    /// `set_target_buffer_size` does.
+    fn set_target_hz(&mut self, target_hz: f64) {
+        if target_hz == self.target_hz {
+            return;
+        }
+        self.target_hz = target_hz;
+        self.source_signal.set_hz_to_hz(self.source_hz, target_hz);
+        self.resize_source();
+    }
+
+    /// Move the buffered source samples into a ring sized for the current
+    /// rates and block size.
+    fn resize_source(&mut self) {
+        let consumer = &mut self.source_signal.source_mut().consumer;
+        // Never smaller than what's in flight, shrinking mustn't drop samples
+        let capacity = source_capacity(self.source_hz, self.target_hz, self.target_buffer_size)
+            .max(consumer.len());
+        let (mut signal, mut source_producer) = RingBufferSignal::new(capacity);
+        consumer.move_to(&mut source_producer, None);
+        mem::swap(consumer, &mut signal.consumer);
+        self.source_producer = source_producer;
+    }
+
     fn push(&mut self, sample: f32) {
-        self.source_producer.push(sample);
+        // `render` only pushes while there's room, a full ring drops the sample
+        // rather than stalling the audio thread
+        let _ = self.source_producer.push(sample);
     }
 
     fn pop(&mut self) -> f32 {
@@ -153,14 +605,24 @@ impl SampleRateConverter {
 struct SamplerSynth {
     // Store a handle to the plugin's parameter object.
     params: Arc<SamplerSynthParameters>,
-    wav_data: Vec<Vec<f32>>,
-    wav_data_consumer: Option<Consumer<WavData>>,
+    // Loaded key zones, in the order they finished loading
+    zones: Vec<Zone>,
+    // Next round robin alternate for each midi note
+    round_robin: [usize; 128],
+    zone_consumer: Option<Consumer<Zone>>,
 
     sample_rate: f64,
-    notes: [[Note; 64]; POLY],
-    samples_out: Vec<f32>,
-    sample_rate_converter: SampleRateConverter,
+    block_size: usize,
+    notes: [Note; POLY],
+    // Number of note ons so far, see `Note::order`
+    note_count: u64,
+    samples_out: [Vec<f32>; 2],
+    sample_rate_converters: [SampleRateConverter; 2],
+    // The interpolation `sample_rate_converters` were built with
+    quality: Quality,
     time_per_sample: f64,
+    amplitude: BlockRamp,
+    midi_queue: MidiQueue,
 }
 
 /// The plugin's parameter object contains the values of parameters that can be
@@ -174,6 +636,39 @@ struct SamplerSynth {
 struct SamplerSynthParameters {
     // The plugin's state consists of a single parameter: amplitude.
     amplitude: AtomicFloat,
+    attack: AtomicFloat,
+    decay: AtomicFloat,
+    sustain: AtomicFloat,
+    release: AtomicFloat,
+    looping: AtomicFloat,
+    // Play samples backwards, from the end to the start
+    reverse: AtomicFloat,
+    // Where playback starts, as a fraction of the sample length
+    start_offset: AtomicFloat,
+    // Hitting a note that's still sounding restarts it instead of taking a new voice
+    retrigger: AtomicFloat,
+    polyphony: AtomicFloat,
+    // Length of the crossfade over a forward loop's seam
+    loop_crossfade: AtomicFloat,
+    // Interpolation used when the host isn't at the base sample rate, taken up
+    // the next time the host resumes the plugin
+    interpolation: AtomicFloat,
+    // How much a harder note off shortens the release, and a softer one lengthens it
+    release_vel_amount: AtomicFloat,
+    // Read-only, how many voices are sounding. Written by the audio thread
+    // each block for debugging voice stealing, the host can't set it
+    voices: AtomicFloat,
+    // Which wav file each midi note plays, saved with the preset data.
+    sample_paths: Mutex<Vec<SampleMapping>>,
+    // Set when the kit changes, the kit loader thread clears it
+    sample_paths_changed: AtomicBool,
+    // Zones of the newest kit, waiting for the audio thread to swap them in
+    loaded_kit: Mutex<Option<Consumer<Zone>>>,
+    // The sample paths of every program. `sample_paths` is the current
+    // program's kit, its entry here is only brought up to date when switching
+    // away or saving the bank.
+    kits: Mutex<Vec<Vec<SampleMapping>>>,
+    program: AtomicUsize,
 }
 
 // All plugins using the `vst` crate will either need to implement the `Default`
@@ -182,15 +677,25 @@ struct SamplerSynthParameters {
 // 0.5 means it's halfway up.
 impl Default for SamplerSynth {
     fn default() -> SamplerSynth {
+        let params = SamplerSynthParameters::default();
         SamplerSynth {
-            params: Arc::new(SamplerSynthParameters::default()),
-            wav_data: vec![Vec::new(); 64],
-            wav_data_consumer: None,
+            amplitude: BlockRamp::new(params.amplitude.get()),
+            params: Arc::new(params),
+            zones: Vec::with_capacity(MAX_ZONES),
+            round_robin: [0; 128],
+            zone_consumer: None,
             sample_rate: 44100.0,
-            notes: [[Note::default(); 64]; POLY],
-            samples_out: Vec::new(),
-            sample_rate_converter: SampleRateConverter::new(44100.0, 44100.0, 64),
+            block_size: 64,
+            notes: [Note::default(); POLY],
+            note_count: 0,
+            samples_out: [Vec::new(), Vec::new()],
+            sample_rate_converters: [
+                SampleRateConverter::new(44100.0, 44100.0, 64, DEFAULT_QUALITY),
+                SampleRateConverter::new(44100.0, 44100.0, 64, DEFAULT_QUALITY),
+            ],
+            quality: DEFAULT_QUALITY,
             time_per_sample: 44100.0 / 1.0,
+            midi_queue: MidiQueue::default(),
         }
     }
 }
@@ -199,6 +704,282 @@ impl Default for SamplerSynthParameters {
     fn default() -> SamplerSynthParameters {
         SamplerSynthParameters {
             amplitude: AtomicFloat::new(0.5),
+            attack: AtomicFloat::new(0.0),
+            decay: AtomicFloat::new(0.0),
+            sustain: AtomicFloat::new(1.0),
+            release: AtomicFloat::new(0.25),
+            looping: AtomicFloat::new(0.0),
+            reverse: AtomicFloat::new(0.0),
+            start_offset: AtomicFloat::new(0.0),
+            retrigger: AtomicFloat::new(0.0),
+            // Three voices
+            polyphony: AtomicFloat::new(2.0 / (MAX_POLYPHONY - 1) as f32),
+            loop_crossfade: AtomicFloat::new(
+                DEFAULT_LOOP_CROSSFADE as f32 / MAX_LOOP_CROSSFADE as f32,
+            ),
+            interpolation: AtomicFloat::new(DEFAULT_QUALITY.to_parameter()),
+            release_vel_amount: AtomicFloat::new(0.0),
+            voices: AtomicFloat::new(0.0),
+            sample_paths: Mutex::new(default_kit()),
+            // The default kit still has to be loaded
+            sample_paths_changed: AtomicBool::new(true),
+            loaded_kit: Mutex::new(None),
+            // Every program starts out with the default kit
+            kits: Mutex::new(vec![default_kit(); PROGRAM_COUNT]),
+            program: AtomicUsize::new(0),
+        }
+    }
+}
+
+fn default_kit() -> Vec<SampleMapping> {
+    vec![
+        SampleMapping::new(36, "kick.wav"),
+        SampleMapping::new(38, "snare.wav"),
+        SampleMapping::new(41, "floor.wav"),
+        SampleMapping::new(43, "rack.wav"),
+    ]
+}
+
+impl SamplerSynthParameters {
+    /// Maximum number of voices sounding at once, at least one.
+    fn polyphony(&self) -> usize {
+        1 + (self.polyphony.get() * (MAX_POLYPHONY - 1) as f32).round() as usize
+    }
+
+    /// Loop crossfade in frames of the sample.
+    fn loop_crossfade(&self) -> usize {
+        (self.loop_crossfade.get() * MAX_LOOP_CROSSFADE as f32).round() as usize
+    }
+
+    fn sample_paths(&self) -> Vec<SampleMapping> {
+        match self.sample_paths.lock() {
+            Ok(sample_paths) => sample_paths.clone(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Serialize the sample paths one per line, as `note=path`. The note is the
+    /// root of the zone, it can be followed by `:low-high` when the zone covers
+    /// a range of keys, by `@group` for a choke group, by `tcents` to tune the
+    /// zone and by `vlow-high` for a velocity layer. Round robin alternates are
+    /// separated by `|`.
+    fn serialize_sample_paths(&self) -> Vec<u8> {
+        let mut data = String::new();
+        serialize_kit(&mut data, &self.sample_paths());
+        data.into_bytes()
+    }
+
+    fn deserialize_sample_paths(&self, data: &[u8]) {
+        self.set_sample_paths(parse_kit(&String::from_utf8_lossy(data)));
+    }
+
+    fn set_sample_paths(&self, sample_paths: Vec<SampleMapping>) {
+        if let Ok(mut current) = self.sample_paths.lock() {
+            *current = sample_paths;
+            self.sample_paths_changed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Make `preset` the current program. The live kit moves back into the
+    /// program being left and the new one moves in, so nothing is copied.
+    fn switch_kit(
+        &self,
+        kits: &mut [Vec<SampleMapping>],
+        sample_paths: &mut Vec<SampleMapping>,
+        preset: usize,
+    ) {
+        let current = self.program.load(Ordering::Relaxed);
+        mem::swap(sample_paths, &mut kits[current]);
+        mem::swap(sample_paths, &mut kits[preset]);
+        self.sample_paths_changed.store(true, Ordering::Relaxed);
+        self.program.store(preset, Ordering::Relaxed);
+    }
+
+    /// Program change from midi. It comes in on the audio thread, so a kit
+    /// that's locked for saving or loading drops the change instead of waiting.
+    fn midi_program_change(&self, program: u8) {
+        let preset = usize::from(program);
+        if preset >= PROGRAM_COUNT || preset == self.program.load(Ordering::Relaxed) {
+            return;
+        }
+        if let (Ok(mut kits), Ok(mut sample_paths)) =
+            (self.kits.try_lock(), self.sample_paths.try_lock())
+        {
+            self.switch_kit(&mut kits, &mut sample_paths, preset);
+        }
+    }
+
+    /// Every program's kit as a `program=N` line followed by its sample paths.
+    fn serialize_bank(&self) -> Vec<u8> {
+        let mut data = String::new();
+        if let Ok(kits) = self.kits.lock() {
+            let current = self.program.load(Ordering::Relaxed);
+            for (program, kit) in kits.iter().enumerate() {
+                data.push_str(&format!("program={}\n", program));
+                if program == current {
+                    serialize_kit(&mut data, &self.sample_paths());
+                } else {
+                    serialize_kit(&mut data, kit);
+                }
+            }
+        }
+        data.into_bytes()
+    }
+
+    /// Read a bank from `serialize_bank`. Lines before the first `program=`
+    /// go to program 0, so banks saved before there were programs still load.
+    fn deserialize_bank(&self, data: &[u8]) {
+        let data = String::from_utf8_lossy(data);
+        let mut sections = vec![String::new(); PROGRAM_COUNT];
+        let mut program = 0;
+        for line in data.lines() {
+            let mut parts = line.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("program"), Some(index)) => match index.parse::<usize>() {
+                    Ok(index) if index < PROGRAM_COUNT => program = index,
+                    _ => ::log::error!("invalid program: {}", line),
+                },
+                _ => {
+                    sections[program].push_str(line);
+                    sections[program].push('\n');
+                }
+            }
+        }
+        let kits: Vec<Vec<SampleMapping>> =
+            sections.iter().map(|section| parse_kit(section)).collect();
+        self.set_sample_paths(kits[self.program.load(Ordering::Relaxed)].clone());
+        if let Ok(mut current) = self.kits.lock() {
+            *current = kits;
+        }
+    }
+}
+
+/// Append `kit` to `data` one mapping per line, see `serialize_sample_paths`.
+fn serialize_kit(data: &mut String, kit: &[SampleMapping]) {
+    for mapping in kit {
+        let (low, high) = mapping.key_range;
+        data.push_str(&mapping.note.to_string());
+        if low != mapping.note || high != mapping.note {
+            data.push_str(&format!(":{}-{}", low, high));
+        }
+        if let Some(choke_group) = mapping.choke_group {
+            data.push_str(&format!("@{}", choke_group));
+        }
+        if mapping.tune != 0 {
+            data.push_str(&format!("t{}", mapping.tune));
+        }
+        if mapping.velocity_range != (0, 127) {
+            let (low, high) = mapping.velocity_range;
+            data.push_str(&format!("v{}-{}", low, high));
+        }
+        data.push_str(&format!("={}\n", mapping.paths.join("|")));
+    }
+}
+
+fn parse_kit(data: &str) -> Vec<SampleMapping> {
+    let mut kit = Vec::new();
+    for line in data.lines() {
+        match parse_sample_mapping(line) {
+            Some(mapping) => kit.push(mapping),
+            None => ::log::error!("invalid sample path entry: {}", line),
+        }
+    }
+    kit
+}
+
+fn parse_sample_mapping(line: &str) -> Option<SampleMapping> {
+    let mut pair = line.splitn(2, '=');
+    let (key, path) = (pair.next()?, pair.next()?);
+    let mut key = key.trim().splitn(2, 'v');
+    let (key, velocity_range) = match (key.next()?, key.next()) {
+        (key, Some(range)) => {
+            let mut range = range.splitn(2, '-');
+            let low = range.next()?.parse::<u8>().ok()?;
+            let high = range.next()?.parse::<u8>().ok()?;
+            if low > high || high > 127 {
+                return None;
+            }
+            (key, (low, high))
+        }
+        (key, None) => (key, (0, 127)),
+    };
+    let mut key = key.splitn(2, 't');
+    let (key, tune) = match (key.next()?, key.next()) {
+        (key, Some(tune)) => (key, tune.parse::<i32>().ok()?),
+        (key, None) => (key, 0),
+    };
+    let mut key = key.splitn(2, '@');
+    let (key, choke_group) = match (key.next()?, key.next()) {
+        (key, Some(choke_group)) => (key, choke_group.parse::<u8>().ok()?),
+        (key, None) => (key, 0),
+    };
+    // Group 0 is the same as no group
+    let choke_group = if choke_group == 0 {
+        None
+    } else {
+        Some(choke_group)
+    };
+    let mut key = key.splitn(2, ':');
+    let note = key.next()?.parse::<usize>().ok()?;
+    let key_range = match key.next() {
+        Some(range) => {
+            let mut range = range.splitn(2, '-');
+            let low = range.next()?.parse::<usize>().ok()?;
+            let high = range.next()?.parse::<usize>().ok()?;
+            (low, high)
+        }
+        None => (note, note),
+    };
+    if key_range.0 > note || key_range.1 < note || key_range.1 > 127 {
+        return None;
+    }
+    let paths: Vec<String> = path
+        .split('|')
+        .filter(|path| !path.is_empty())
+        .map(|path| path.to_string())
+        .collect();
+    if paths.is_empty() {
+        return None;
+    }
+    Some(SampleMapping {
+        note,
+        key_range,
+        tune,
+        choke_group,
+        velocity_range,
+        paths,
+    })
+}
+
+const MAX_ATTACK: f32 = 2.0;
+const MAX_DECAY: f32 = 2.0;
+const MAX_RELEASE: f32 = 4.0;
+// How many times shorter a full release velocity note off's release is at full
+// release velocity amount, in octaves. The softest makes it as many times longer
+const MAX_RELEASE_VEL_OCTAVES: f32 = 2.0;
+// Fade out time of a voice that gets stolen for a new note
+const STEAL_FADE: f32 = 0.005;
+
+/// Per sample envelope increments, worked out once per block from the parameters.
+#[derive(Copy, Clone)]
+struct EnvelopeRates {
+    attack: f32,
+    decay: f32,
+    sustain: f32,
+    release: f32,
+    steal: f32,
+}
+
+impl EnvelopeRates {
+    fn new(attack: f32, decay: f32, sustain: f32, release: f32, sample_rate: f32) -> EnvelopeRates {
+        // A zero time moves through the whole stage in a single sample
+        let rate = |seconds: f32| 1.0 / (seconds * sample_rate).max(1.0);
+        EnvelopeRates {
+            attack: rate(attack),
+            decay: rate(decay),
+            sustain,
+            release: rate(release),
+            steal: rate(STEAL_FADE),
         }
     }
 }
@@ -209,25 +990,104 @@ enum NoteState {
     OFF,
     NONE,
 }
+#[derive(Copy, Clone, PartialEq)]
+enum EnvelopePhase {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
 #[derive(Copy, Clone)]
 struct Note {
-    sample: usize,
+    // Midi note that triggered the voice
+    key: usize,
+    // Fractional read position in frames and how far it moves per sample
+    position: f64,
+    speed: f64,
+    // Reads from the end of the sample toward the start
+    reverse: bool,
+    // On the way back through a ping-pong loop
+    loop_backward: bool,
+    // Which zone, velocity layer and round robin alternate the note is playing
+    zone: usize,
+    layer: usize,
+    buffer_index: usize,
+    // Second layer crossfaded in between two velocity layers, `blend` is its share
+    blend_layer: usize,
+    blend_buffer_index: usize,
+    blend: f32,
     time: f64,
     level: f32,
     state: NoteState,
+    envelope: f32,
+    envelope_phase: EnvelopePhase,
+    // Set when the voice is fading out quickly to make room for a new note
+    stolen: bool,
+    // Which note on started the voice, counting up, so a note off can find the
+    // most recent of several voices on the same key
+    order: u64,
+    // Multiplies the release rate, set from the note off's release velocity
+    release_speed: f32,
 }
 
 impl Default for Note {
     fn default() -> Note {
         Note {
-            sample: 0,
+            key: 0,
+            position: 0.0,
+            speed: 1.0,
+            reverse: false,
+            loop_backward: false,
+            zone: 0,
+            layer: 0,
+            buffer_index: 0,
+            blend_layer: 0,
+            blend_buffer_index: 0,
+            blend: 0.0,
             time: 0.0,
             level: 0.0,
             state: NoteState::NONE,
+            envelope: 0.0,
+            envelope_phase: EnvelopePhase::Attack,
+            stolen: false,
+            order: 0,
+            release_speed: 1.0,
         }
     }
 }
 
+impl Note {
+    /// Step the envelope forward one sample and return its new value.
+    fn advance_envelope(&mut self, rates: &EnvelopeRates) -> f32 {
+        match self.envelope_phase {
+            EnvelopePhase::Attack => {
+                self.envelope += rates.attack;
+                if self.envelope >= 1.0 {
+                    self.envelope = 1.0;
+                    self.envelope_phase = EnvelopePhase::Decay;
+                }
+            }
+            EnvelopePhase::Decay => {
+                self.envelope -= rates.decay;
+                if self.envelope <= rates.sustain {
+                    self.envelope = rates.sustain;
+                    self.envelope_phase = EnvelopePhase::Sustain;
+                }
+            }
+            EnvelopePhase::Sustain => self.envelope = rates.sustain,
+            EnvelopePhase::Release => {
+                let rate = if self.stolen {
+                    rates.steal
+                } else {
+                    rates.release * self.release_speed
+                };
+                self.envelope = (self.envelope - rate).max(0.0)
+            }
+        }
+        self.envelope
+    }
+}
+
 impl SamplerSynth {
     /// Process an incoming midi event.
     ///
@@ -241,125 +1101,481 @@ impl SamplerSynth {
     /// [source]: http://www.midimountain.com/midi/midi_status.htm
     fn process_midi_event(&mut self, data: [u8; 3]) {
         match data[0] {
-            128 => self.note_off(data[1]),
+            128 => self.note_off(data[1], release_velocity(data[2])),
+            // A note on with zero velocity is a note off, without a release velocity
+            144 if data[2] == 0 => self.note_off(data[1], 0.0),
             144 => self.note_on(data[1], data[2]),
+            // Program change, programs past the bank are ignored
+            192 => self.params.midi_program_change(data[1]),
             _ => (),
         }
     }
 
+    /// Hold a midi event until `render` reaches the sample `delta_frames`
+    /// into the block.
+    fn queue_midi_event(&mut self, delta_frames: i32, data: [u8; 3]) {
+        if !self.midi_queue.push(delta_frames, data) {
+            self.process_midi_event(data);
+        }
+    }
+
     fn note_on(&mut self, note: u8, level: u8) {
+        // Midi data bytes are 7 bit, anything higher is a malformed event and
+        // would index past `round_robin`
+        if usize::from(note) >= self.round_robin.len() {
+            return;
+        }
+        let zone = match self.find_zone(note) {
+            Some(zone) => zone,
+            None => return,
+        };
+        let (layer, blend_layer, blend) =
+            match select_layers(&self.zones[zone].data.layers, level.min(127)) {
+                Some(layers) => layers,
+                None => return,
+            };
+        let speed = self.zones[zone].speed(note);
         let note = note as usize;
-        for plevel in 0..POLY {
-            if self.notes[plevel][note].state == NoteState::NONE {
-                self.notes[plevel][note] = Note {
-                    sample: 0,
-                    time: 0.0,
-                    level: (level as f32) / 255.0,
-                    state: NoteState::ON,
-                };
-                return;
+
+        // Cycle through the alternates each time this note is hit
+        let layers = &self.zones[zone].data.layers;
+        let round_robin = self.round_robin[note];
+        let buffer_index = round_robin % layers[layer].buffers.len();
+        let blend_buffer_index = round_robin % layers[blend_layer].buffers.len();
+        self.round_robin[note] = round_robin + 1;
+        // Voices render at the base rate, the converter takes that to the host
+        // rate. Both layers share the position, so the blend is assumed to
+        // have been recorded at the same rate.
+        let speed = speed * layers[layer].buffers[buffer_index].rate_ratio();
+
+        // With retrigger on, a note that's still sounding restarts in its own voice
+        let retrigger_slot = if self.params.retrigger.get() > 0.5 {
+            self.notes.iter().position(|voice| {
+                voice.key == note && voice.state != NoteState::NONE && !voice.stolen
+            })
+        } else {
+            None
+        };
+
+        if let Some(choke_group) = self.zones[zone].data.choke_group {
+            for (slot, voice) in self.notes.iter_mut().enumerate() {
+                if voice.state != NoteState::NONE
+                    && Some(slot) != retrigger_slot
+                    && self.zones[voice.zone].data.choke_group == Some(choke_group)
+                {
+                    voice.state = NoteState::OFF;
+                    voice.envelope_phase = EnvelopePhase::Release;
+                    voice.stolen = true;
+                }
             }
         }
+
+        let slot = match retrigger_slot {
+            Some(slot) => slot,
+            None => match self.allocate_voice() {
+                Some(slot) => slot,
+                None => return,
+            },
+        };
+
+        // The offset is measured from the end for reversed notes, which start
+        // on the last frame of the longest buffer they play
+        let reverse = self.params.reverse.get() > 0.5;
+        let layers = &self.zones[zone].data.layers;
+        let frames = layers[layer].buffers[buffer_index]
+            .frames()
+            .max(layers[blend_layer].buffers[blend_buffer_index].frames());
+        let last_frame = frames.saturating_sub(1) as f64;
+        let offset = f64::from(self.params.start_offset.get()) * last_frame;
+        let position = if reverse { last_frame - offset } else { offset };
+
+        self.note_count += 1;
+        self.notes[slot] = Note {
+            key: note,
+            position,
+            speed,
+            reverse,
+            loop_backward: false,
+            zone,
+            layer,
+            buffer_index,
+            blend_layer,
+            blend_buffer_index,
+            blend,
+            time: 0.0,
+            level: (level as f32) / 255.0,
+            state: NoteState::ON,
+            envelope: 0.0,
+            envelope_phase: EnvelopePhase::Attack,
+            stolen: false,
+            order: self.note_count,
+            release_speed: 1.0,
+        };
     }
 
-    fn note_off(&mut self, note: u8) {
-        let note = note as usize;
-        //Just picking which is on and setting it to off may not work
-        for plevel in 0..POLY {
-            if self.notes[plevel][note].state == NoteState::ON {
-                self.notes[plevel][note].state = NoteState::OFF;
+    /// Find a free voice for a new note. Past the polyphony limit the oldest
+    /// voice fades out to make room.
+    fn allocate_voice(&mut self) -> Option<usize> {
+        let polyphony = self.params.polyphony();
+        let active = self
+            .notes
+            .iter()
+            .filter(|voice| voice.state != NoteState::NONE && !voice.stolen)
+            .count();
+        if active >= polyphony {
+            if let Some(oldest) = self.oldest_voice(false) {
+                let voice = &mut self.notes[oldest];
+                voice.state = NoteState::OFF;
+                voice.envelope_phase = EnvelopePhase::Release;
+                voice.stolen = true;
             }
         }
+
+        // Reuse the oldest fading voice outright if the whole pool is busy
+        match self
+            .notes
+            .iter()
+            .position(|voice| voice.state == NoteState::NONE)
+        {
+            Some(slot) => Some(slot),
+            None => self.oldest_voice(true),
+        }
     }
 
-    fn process_sample(&mut self) -> f32 {
-        let mut output_sample = 0.0;
-        for plevel in 0..POLY {
-            for note_value in 0..64usize {
-                let note = &mut self.notes[plevel][note_value];
-                match note.state {
-                    NoteState::ON | NoteState::OFF => {
-                        if note_value == 1 {
-                            output_sample = 1.0;
-                            note.state = NoteState::OFF;
-                        }
+    /// The longest playing voice, optionally including ones already being stolen.
+    fn oldest_voice(&self, include_stolen: bool) -> Option<usize> {
+        self.notes
+            .iter()
+            .enumerate()
+            .filter(|(_, voice)| {
+                voice.state != NoteState::NONE && (include_stolen || !voice.stolen)
+            })
+            .max_by(|(_, a), (_, b)| a.time.partial_cmp(&b.time).unwrap_or(cmp::Ordering::Equal))
+            .map(|(slot, _)| slot)
+    }
 
-                        //We need to play the sound all the way through, even if it's off
-                        if note.sample >= self.wav_data[note_value].len() {
-                            *note = Note::default();
-                            continue;
-                        }
+    /// Release the most recent voice on `note`. `release_velocity` goes from
+    /// -1 to 1, harder note offs release faster.
+    fn note_off(&mut self, note: u8, release_velocity: f32) {
+        let octaves = self.params.release_vel_amount.get() * release_velocity;
+        let note = note as usize;
+        // A retriggered key has a voice per note on, each note off releases
+        // the most recent one
+        let latest = self
+            .notes
+            .iter_mut()
+            .filter(|voice| voice.key == note && voice.state == NoteState::ON)
+            .max_by_key(|voice| voice.order);
+        if let Some(voice) = latest {
+            voice.state = NoteState::OFF;
+            voice.envelope_phase = EnvelopePhase::Release;
+            voice.release_speed = (octaves * MAX_RELEASE_VEL_OCTAVES).exp2();
+        }
+    }
 
-                        output_sample += self.wav_data[note_value][note.sample] * note.level;
+    fn process_sample(&mut self, envelope_rates: &EnvelopeRates, looping: bool) -> (f32, f32) {
+        let mut output_l = 0.0;
+        let mut output_r = 0.0;
+        let crossfade = self.params.loop_crossfade();
+        for note in self.notes.iter_mut() {
+            match note.state {
+                NoteState::ON | NoteState::OFF => {
+                    //We need to play the sound all the way through, even if it's off
+                    let layers = &self.zones[note.zone].data.layers;
+                    let buffer = &layers[note.layer].buffers[note.buffer_index];
+                    let blend_buffer = &layers[note.blend_layer].buffers[note.blend_buffer_index];
+                    let frames = if note.blend > 0.0 {
+                        buffer.frames().max(blend_buffer.frames())
+                    } else {
+                        buffer.frames()
+                    };
+                    let finished = if note.reverse {
+                        note.position < 0.0
+                    } else {
+                        note.position as usize >= frames
+                    };
+                    if finished {
+                        *note = Note::default();
+                        continue;
+                    }
+
+                    let envelope = note.advance_envelope(envelope_rates);
+                    if note.envelope_phase == EnvelopePhase::Release && envelope <= 0.0 {
+                        *note = Note::default();
+                        continue;
+                    }
 
-                        note.time += self.time_per_sample;
-                        note.sample += 1;
+                    // Held notes wrap around the loop, released and reversed ones play
+                    // out to the end
+                    let looping = looping && note.state == NoteState::ON && !note.reverse;
+                    // A ping-pong loop turns around instead of jumping, so it has no
+                    // seam to crossfade
+                    let ping_pong = looping && buffer.loop_mode == LoopMode::PingPong;
+                    let wrapping = looping && !ping_pong;
+                    let position = note.position;
+                    let play = |buffer: &SampleBuffer| {
+                        if (position as usize) < buffer.frames() {
+                            buffer.interpolated_frame(position, wrapping, crossfade)
+                        } else {
+                            (0.0, 0.0)
+                        }
+                    };
+                    let (l, r) = if note.blend > 0.0 {
+                        let (l, r) = play(buffer);
+                        let (blend_l, blend_r) = play(blend_buffer);
+                        (mix(l, blend_l, note.blend), mix(r, blend_r, note.blend))
+                    } else {
+                        play(buffer)
+                    };
+                    output_l += l * note.level * envelope;
+                    output_r += r * note.level * envelope;
+
+                    note.time += self.time_per_sample;
+                    // Released in the middle of a ping-pong pass, the note plays
+                    // forward out to the end like any other
+                    if note.reverse || (ping_pong && note.loop_backward) {
+                        note.position -= note.speed;
+                    } else {
+                        note.position += note.speed;
+                    }
+                    if ping_pong {
+                        // Reflect off the ends so the read turns around without a jump
+                        let first = buffer.loop_start as f64;
+                        let last = (buffer.loop_end - 1) as f64;
+                        if note.position > last {
+                            note.position = (2.0 * last - note.position).max(first);
+                            note.loop_backward = true;
+                        } else if note.loop_backward && note.position < first {
+                            note.position = (2.0 * first - note.position).min(last);
+                            note.loop_backward = false;
+                        }
+                    } else if wrapping && note.position >= buffer.loop_end as f64 {
+                        note.position -= (buffer.loop_end - buffer.loop_start) as f64;
                     }
-                    NoteState::NONE => {}
                 }
+                NoteState::NONE => {}
             }
         }
 
-        output_sample
+        (output_l, output_r)
     }
 
     fn handle_wav_loading(&mut self) {
-        if let Some(ref mut consumer) = self.wav_data_consumer {
+        // A new kit is picked up next block if the loader thread has the lock
+        let new_kit = match self.params.loaded_kit.try_lock() {
+            Ok(mut loaded_kit) => loaded_kit.take(),
+            Err(_) => None,
+        };
+        if let Some(consumer) = new_kit {
+            // Drop the old kit. Voices point into the zones, so they go with it
+            self.zone_consumer = Some(consumer);
+            self.zones.clear();
+            for voice in self.notes.iter_mut() {
+                *voice = Note::default();
+            }
+        }
+
+        if let Some(ref mut consumer) = self.zone_consumer {
             for _ in 0..consumer.len() {
-                if let Some(wav_data) = consumer.pop() {
-                    self.wav_data[wav_data.note] = wav_data.audio;
-                } else {
-                    break;
+                match consumer.pop() {
+                    Some(zone) if self.zones.len() < MAX_ZONES => self.zones.push(zone),
+                    Some(_) => ::log::error!("too many zones, skipping one"),
+                    None => break,
                 }
             }
+        }
+    }
+}
+
+impl SamplerSynth {
+    /// Render `samples` samples at the host rate into `samples_out`.
+    fn render(&mut self, samples: usize) {
+        // Some hosts send bigger blocks than they announced in `set_block_size`,
+        // or call `process` before it at all
+        if samples > self.samples_out[0].len() {
+            for samples_out in self.samples_out.iter_mut() {
+                samples_out.resize(samples, 0.0);
+            }
+        }
+        // Applied after conversion, so the ramp runs at the host rate
+        self.amplitude.start(self.params.amplitude.get(), samples);
+        // Voices are rendered at the base rate and converted afterwards
+        let envelope_rates = EnvelopeRates::new(
+            self.params.attack.get() * MAX_ATTACK,
+            self.params.decay.get() * MAX_DECAY,
+            self.params.sustain.get(),
+            self.params.release.get() * MAX_RELEASE,
+            BASE_SAMPLE_RATE as f32,
+        );
+        let looping = self.params.looping.get() > 0.5;
+
+        if self.sample_rate as i32 != BASE_SAMPLE_RATE {
+            for i in 0..samples {
+                // The converter runs ahead of the output, so resampled notes
+                // can land a few samples late, but not at the block start
+                while let Some(data) = self.midi_queue.pop(i) {
+                    self.process_midi_event(data);
+                }
+                // Keep the converter topped up so it never reads past what was rendered,
+                // it pulls a varying number of source samples for each output sample
+                while !self.sample_rate_converters[0].source_producer.is_full() {
+                    let (l, r) = self.process_sample(&envelope_rates, looping);
+                    self.sample_rate_converters[0].push(l);
+                    self.sample_rate_converters[1].push(r);
+                }
+
+                let amplitude = self.amplitude.next();
+                self.samples_out[0][i] = self.sample_rate_converters[0].pop() * amplitude;
+                self.samples_out[1][i] = self.sample_rate_converters[1].pop() * amplitude;
+            }
         } else {
-            let wav_data_ring = RingBuffer::<WavData>::new(64);
+            //No need for sample rate conversion
+            for sample_idx in 0..samples {
+                while let Some(data) = self.midi_queue.pop(sample_idx) {
+                    self.process_midi_event(data);
+                }
+                let (l, r) = self.process_sample(&envelope_rates, looping);
+                let amplitude = self.amplitude.next();
+                self.samples_out[0][sample_idx] = l * amplitude;
+                self.samples_out[1][sample_idx] = r * amplitude;
+            }
+        }
 
-            let (wav_data_producer, wav_data_consumer) = wav_data_ring.split();
-            self.wav_data_consumer = Some(wav_data_consumer);
+        // Anything timed past the end of the block starts now, before the next one
+        while let Some(data) = self.midi_queue.pop(usize::MAX) {
+            self.process_midi_event(data);
+        }
+
+        let voices = self
+            .notes
+            .iter()
+            .filter(|voice| voice.state != NoteState::NONE)
+            .count();
+        self.params.voices.set(voices as f32);
+    }
 
-            start_file_load_thread(wav_data_producer);
+    fn reset_sample_rate_converters(&mut self) {
+        for (converter, samples_out) in self
+            .sample_rate_converters
+            .iter_mut()
+            .zip(self.samples_out.iter_mut())
+        {
+            *converter = SampleRateConverter::new(
+                BASE_SAMPLE_RATE as f64,
+                self.sample_rate,
+                self.block_size,
+                self.quality,
+            );
+
+            *samples_out = vec![0.0; converter.target_buffer_size as usize];
         }
     }
+
+    /// The zone that plays `note`. Where zones overlap the one with the
+    /// closest root wins, then the narrowest.
+    fn find_zone(&self, note: u8) -> Option<usize> {
+        self.zones
+            .iter()
+            .enumerate()
+            .filter(|(_, zone)| !zone.data.layers.is_empty() && zone.contains(note))
+            .min_by_key(|(_, zone)| {
+                (
+                    (i32::from(zone.root) - i32::from(note)).abs(),
+                    zone.hi - zone.lo,
+                )
+            })
+            .map(|(index, _)| index)
+    }
 }
 
-fn start_file_load_thread(mut producer: Producer<WavData>) {
+/// Start a load thread for every new kit and hand its zones to the audio
+/// thread, which never spawns threads or copies the sample paths itself.
+/// Runs until the plugin is dropped.
+fn start_kit_loader(params: Weak<SamplerSynthParameters>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        while let Some(params) = params.upgrade() {
+            if params.sample_paths_changed.swap(false, Ordering::Relaxed) {
+                // One slot per mapping so the load thread never has to drop a sample
+                let sample_paths = params.sample_paths();
+                let zone_ring = RingBuffer::<Zone>::new(sample_paths.len().max(1));
+
+                let (zone_producer, zone_consumer) = zone_ring.split();
+                start_file_load_thread(zone_producer, sample_paths);
+                if let Ok(mut loaded_kit) = params.loaded_kit.lock() {
+                    *loaded_kit = Some(zone_consumer);
+                }
+            }
+            drop(params);
+            thread::sleep(KIT_POLL_INTERVAL);
+        }
+    })
+}
+
+fn start_file_load_thread(
+    mut producer: Producer<Zone>,
+    sample_paths: Vec<SampleMapping>,
+) -> thread::JoinHandle<()> {
     //Start up a thread to load the wav files form disk
     thread::spawn(move || {
         ::log::info!("init thread");
-        producer
-            .push(WavData {
-                audio: load_wav("C:/dev/vst/dgriffin/assets/kick.wav"),
-                note: 36,
-            })
-            .unwrap();
-        producer
-            .push(WavData {
-                audio: load_wav("C:/dev/vst/dgriffin/assets/snare.wav"),
-                note: 38,
-            })
-            .unwrap();
-        producer
-            .push(WavData {
-                audio: load_wav("C:/dev/vst/dgriffin/assets/floor.wav"),
-                note: 41,
-            })
-            .unwrap();
-        producer
-            .push(WavData {
-                audio: load_wav("C:/dev/vst/dgriffin/assets/rack.wav"),
-                note: 43,
-            })
-            .unwrap();
-        producer
-            .push(WavData {
-                audio: load_wav("C:/dev/vst/dgriffin/assets/sweep.wav"),
-                note: 2,
-            })
-            .unwrap();
+        // Rows for the same zone are gathered into one set of velocity layers
+        let mut loaded: Vec<Zone> = Vec::new();
+        for mapping in sample_paths {
+            let mut buffers = Vec::new();
+            for path in &mapping.paths {
+                let resolved_path = resolve_sample_path(path);
+                // A decoder bug on one file shouldn't stop the rest of the kit
+                // loading, the panic itself is logged by the panic hook
+                match panic::catch_unwind(|| load_wav(&resolved_path)) {
+                    Ok(Ok(buffer)) => buffers.push(buffer),
+                    Ok(Err(err)) => {
+                        ::log::error!("failed to load {}: {}", resolved_path.display(), err);
+                    }
+                    Err(_) => ::log::error!("panicked loading {}", resolved_path.display()),
+                }
+            }
+
+            if buffers.is_empty() {
+                continue;
+            }
+
+            let layer = VelocityLayer {
+                buffers,
+                velocity_range: mapping.velocity_range,
+            };
+            // Notes and ranges are checked to be below 128 when parsed
+            let (lo, hi, root) = (
+                mapping.key_range.0 as u8,
+                mapping.key_range.1 as u8,
+                mapping.note as u8,
+            );
+            match loaded
+                .iter_mut()
+                .find(|zone| (zone.lo, zone.hi, zone.root) == (lo, hi, root))
+            {
+                Some(zone) => zone.data.layers.push(layer),
+                None => loaded.push(Zone {
+                    lo,
+                    hi,
+                    root,
+                    tune: mapping.tune,
+                    data: WavData {
+                        layers: vec![layer],
+                        choke_group: mapping.choke_group,
+                    },
+                }),
+            }
+        }
+
+        for zone in loaded {
+            let root = zone.root;
+            if producer.push(zone).is_err() {
+                ::log::error!("zone queue full, skipping note {}", root);
+            }
+        }
 
         ::log::info!("init thread done loading");
-    });
+    })
 }
 
 // All plugins using `vst` also need to implement the `Plugin` trait.  Here, we
@@ -375,14 +1591,17 @@ impl Plugin for SamplerSynth {
             outputs: 2,
             // This `parameters` bit is important; without it, none of our
             // parameters will be shown!
-            parameters: 1,
+            parameters: 14,
             category: Category::Synth,
+            presets: PROGRAM_COUNT as i32,
+            preset_chunks: true,
             ..Default::default()
         }
     }
 
     fn init(&mut self) {
         setup_logging("WAVSampler.log");
+        start_kit_loader(Arc::downgrade(&self.params));
 
         //let path = env::current_dir().unwrap();
         //::log::info!("The current directory is {}", path.display());
@@ -393,32 +1612,15 @@ impl Plugin for SamplerSynth {
     fn process(&mut self, buffer: &mut AudioBuffer<f32>) {
         self.handle_wav_loading();
 
-        let amplitude = self.params.amplitude.get();
-
         let samples = buffer.samples();
         let (_, mut outputs) = buffer.split();
 
-        if self.sample_rate as i32 != BASE_SAMPLE_RATE {
-            while !self.sample_rate_converter.source_producer.is_full() {
-                let sample = self.process_sample();
-                self.sample_rate_converter.push(sample * amplitude);
-            }
-
-            for i in 0..samples {
-                self.samples_out[i] = self.sample_rate_converter.pop();
-            }
-        } else {
-            //No need for sample rate conversion
-            for sample_idx in 0..self.sample_rate_converter.source_buffer_size {
-                let sample = self.process_sample();
-                self.samples_out[sample_idx] = sample * amplitude
-            }
-        }
+        self.render(samples);
 
         for i in 0..samples {
             for buf_idx in 0..outputs.len() {
                 let buff = outputs.get_mut(buf_idx);
-                buff[i] = self.samples_out[i];
+                buff[i] = self.samples_out[buf_idx.min(1)][i];
             }
         }
     }
@@ -428,8 +1630,11 @@ impl Plugin for SamplerSynth {
     fn process_events(&mut self, events: &Events) {
         for event in events.events() {
             match event {
-                Event::Midi(ev) => self.process_midi_event(ev.data),
-                // More events can be handled here.
+                // Applied in `render` at their own sample, not the block start
+                Event::Midi(ev) => self.queue_midi_event(ev.delta_frames, ev.data),
+                // More events can be handled here. VST 2.4 has no parameter
+                // events, automation comes through `set_parameter` and is
+                // ramped across the block in `render`.
                 _ => (),
             }
         }
@@ -449,15 +1654,44 @@ impl Plugin for SamplerSynth {
     }
 
     fn set_sample_rate(&mut self, rate: f32) {
+        // Converters that are running follow the new rate without dropping
+        // what they hold. Ones that sat idle at the base rate hold stale audio
+        // and start over
+        let converting = self.sample_rate as i32 != BASE_SAMPLE_RATE;
         self.sample_rate = rate as f64;
         self.time_per_sample = (1.0 / self.sample_rate) as f64;
+        if converting {
+            for converter in self.sample_rate_converters.iter_mut() {
+                converter.set_target_hz(self.sample_rate);
+            }
+        } else {
+            self.reset_sample_rate_converters();
+        }
     }
 
-    fn set_block_size(&mut self, size: i64) {
-        self.sample_rate_converter =
-            SampleRateConverter::new(BASE_SAMPLE_RATE as f64, self.sample_rate, size as usize);
+    fn resume(&mut self) {
+        // The interpolator is fixed when a converter is built, so a new quality
+        // starts the converters over. Building them allocates, so it waits for
+        // the host to resume the plugin instead of happening in `process`
+        let quality = Quality::from_parameter(self.params.interpolation.get());
+        if quality != self.quality {
+            self.quality = quality;
+            self.reset_sample_rate_converters();
+        }
+    }
 
-        self.samples_out = vec![0.0; self.sample_rate_converter.target_buffer_size as usize];
+    fn set_block_size(&mut self, size: i64) {
+        self.block_size = size as usize;
+        // Only the sample rate invalidates what the converters hold, a new
+        // block size just needs room for it
+        for (converter, samples_out) in self
+            .sample_rate_converters
+            .iter_mut()
+            .zip(self.samples_out.iter_mut())
+        {
+            converter.set_target_buffer_size(self.block_size);
+            samples_out.resize(self.block_size, 0.0);
+        }
     }
 }
 
@@ -466,24 +1700,91 @@ impl PluginParameters for SamplerSynthParameters {
     fn get_parameter(&self, index: i32) -> f32 {
         match index {
             0 => self.amplitude.get(),
+            1 => self.attack.get(),
+            2 => self.decay.get(),
+            3 => self.sustain.get(),
+            4 => self.release.get(),
+            5 => self.looping.get(),
+            6 => self.polyphony.get(),
+            7 => self.reverse.get(),
+            8 => self.start_offset.get(),
+            9 => self.retrigger.get(),
+            10 => self.voices.get() / POLY as f32,
+            11 => self.loop_crossfade.get(),
+            12 => self.interpolation.get(),
+            13 => self.release_vel_amount.get(),
             _ => 0.0,
         }
     }
 
     // the `set_parameter` function sets the value of a parameter.
     fn set_parameter(&self, index: i32, val: f32) {
+        let val = match clamp_parameter(val) {
+            Some(val) => val,
+            None => return,
+        };
         #[allow(clippy::single_match)]
         match index {
             0 => self.amplitude.set(val),
+            1 => self.attack.set(val),
+            2 => self.decay.set(val),
+            3 => self.sustain.set(val),
+            4 => self.release.set(val),
+            5 => self.looping.set(val),
+            6 => self.polyphony.set(val),
+            7 => self.reverse.set(val),
+            8 => self.start_offset.set(val),
+            9 => self.retrigger.set(val),
+            11 => self.loop_crossfade.set(val),
+            12 => self.interpolation.set(val),
+            13 => self.release_vel_amount.set(val),
+            // The voice count is written by `render` only
             _ => (),
         }
     }
 
+    fn can_be_automated(&self, index: i32) -> bool {
+        index != 10 && index != 12
+    }
+
     // This is what will display underneath our control.  We can
     // format it into a string that makes the most since.
     fn get_parameter_text(&self, index: i32) -> String {
         match index {
             0 => format!("{:.2}", (self.amplitude.get() - 0.5) * 2f32),
+            1 => format!("{:.2}", self.attack.get() * MAX_ATTACK),
+            2 => format!("{:.2}", self.decay.get() * MAX_DECAY),
+            3 => format!("{:.2}", self.sustain.get()),
+            4 => format!("{:.2}", self.release.get() * MAX_RELEASE),
+            5 => (if self.looping.get() > 0.5 {
+                "On"
+            } else {
+                "Off"
+            })
+            .to_string(),
+            6 => format!("{}", self.polyphony()),
+            7 => (if self.reverse.get() > 0.5 {
+                "On"
+            } else {
+                "Off"
+            })
+            .to_string(),
+            8 => format!("{:.0}%", self.start_offset.get() * 100.0),
+            9 => (if self.retrigger.get() > 0.5 {
+                "On"
+            } else {
+                "Off"
+            })
+            .to_string(),
+            10 => format!("{}", self.voices.get() as usize),
+            11 => format!(
+                "{:.1} ms",
+                self.loop_crossfade() as f32 * 1000.0 / BASE_SAMPLE_RATE as f32
+            ),
+            12 => Quality::from_parameter(self.interpolation.get())
+                .name()
+                .to_string(),
+            13 => format!("{:.2}", self.release_vel_amount.get()),
             _ => "".to_string(),
         }
     }
@@ -492,11 +1793,1059 @@ impl PluginParameters for SamplerSynthParameters {
     fn get_parameter_name(&self, index: i32) -> String {
         match index {
             0 => "Amplitude",
+            1 => "Attack",
+            2 => "Decay",
+            3 => "Sustain",
+            4 => "Release",
+            5 => "Loop",
+            6 => "Polyphony",
+            7 => "Reverse",
+            8 => "Start offset",
+            9 => "Retrigger",
+            10 => "Voices",
+            11 => "Loop Xfade",
+            12 => "Interpolation",
+            13 => "Rel Vel > Release",
             _ => "",
         }
         .to_string()
     }
+
+    // Switch kits, keeping the edits made to the one being left. The new kit
+    // loads in the background like any other change to the sample paths.
+    fn change_preset(&self, preset: i32) {
+        let preset = preset as usize;
+        if preset >= PROGRAM_COUNT || preset == self.program.load(Ordering::Relaxed) {
+            return;
+        }
+        if let (Ok(mut kits), Ok(mut sample_paths)) = (self.kits.lock(), self.sample_paths.lock()) {
+            self.switch_kit(&mut kits, &mut sample_paths, preset);
+        }
+    }
+
+    fn get_preset_num(&self) -> i32 {
+        self.program.load(Ordering::Relaxed) as i32
+    }
+
+    fn get_preset_name(&self, preset: i32) -> String {
+        format!("Kit {}", preset + 1)
+    }
+
+    fn get_preset_data(&self) -> Vec<u8> {
+        self.serialize_sample_paths()
+    }
+
+    fn get_bank_data(&self) -> Vec<u8> {
+        self.serialize_bank()
+    }
+
+    fn load_preset_data(&self, data: &[u8]) {
+        self.deserialize_sample_paths(data);
+    }
+
+    fn load_bank_data(&self, data: &[u8]) {
+        self.deserialize_bank(data);
+    }
 }
 
 // This part is important!  Without it, our plugin won't work.
 plugin_main!(SamplerSynth);
+
+#[cfg(test)]
+mod tests {
+    use ringbuf::RingBuffer;
+    use std::path::PathBuf;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use vst::plugin::{Plugin, PluginParameters};
+    use vsts::buffer::process_buffer;
+    use {load_wav, open_log_file, parse_sample_mapping, read_smpl_loop, SampleMapping};
+    use {select_layers, start_file_load_thread, start_kit_loader};
+    use {EnvelopePhase, EnvelopeRates, LoopMode, Note, NoteState, VelocityLayer, Zone, POLY};
+    use {Interpolated, Quality, DEFAULT_QUALITY, QUALITIES};
+    use {SampleBuffer, SamplerSynth, WavData};
+
+    /// Mono audio looped over its whole length.
+    fn sample_buffer(audio: Vec<f32>, sample_rate: u32) -> SampleBuffer {
+        SampleBuffer {
+            loop_end: audio.len(),
+            audio,
+            channels: 1,
+            loop_start: 0,
+            loop_mode: LoopMode::Forward,
+            sample_rate,
+        }
+    }
+
+    /// A zone one key wide, as in a drum map.
+    fn drum_zone(note: u8, data: WavData) -> Zone {
+        Zone {
+            lo: note,
+            hi: note,
+            root: note,
+            tune: 0,
+            data,
+        }
+    }
+
+    /// A layer that plays at every velocity.
+    fn full_layer(buffers: Vec<SampleBuffer>) -> VelocityLayer {
+        VelocityLayer {
+            buffers,
+            velocity_range: (0, 127),
+        }
+    }
+
+    #[test]
+    fn test_parse_sample_mapping() {
+        assert_eq!(
+            parse_sample_mapping("36=kick.wav"),
+            Some(SampleMapping::new(36, "kick.wav"))
+        );
+        assert_eq!(
+            parse_sample_mapping("48:40-60=C:/samples/piano=c3.wav"),
+            Some(SampleMapping {
+                note: 48,
+                key_range: (40, 60),
+                tune: 0,
+                choke_group: None,
+                velocity_range: (0, 127),
+                paths: vec!["C:/samples/piano=c3.wav".to_string()],
+            })
+        );
+        assert_eq!(
+            parse_sample_mapping("38=snare_1.wav|snare_2.wav").map(|mapping| mapping.paths),
+            Some(vec!["snare_1.wav".to_string(), "snare_2.wav".to_string()])
+        );
+        assert_eq!(
+            parse_sample_mapping("46:44-46@1=open_hat.wav").map(|mapping| mapping.choke_group),
+            Some(Some(1))
+        );
+        assert_eq!(
+            parse_sample_mapping("42@0=closed_hat.wav").map(|mapping| mapping.choke_group),
+            Some(None)
+        );
+        assert_eq!(
+            parse_sample_mapping("38@2v0-63=snare_soft.wav").map(|m| m.velocity_range),
+            Some((0, 63))
+        );
+        assert_eq!(
+            parse_sample_mapping("60:48-72@3t-25v0-90=piano.wav").map(|m| (m.tune, m.choke_group)),
+            Some((-25, Some(3)))
+        );
+        assert_eq!(
+            parse_sample_mapping("100=ride.wav").map(|m| m.key_range),
+            Some((100, 100))
+        );
+        assert_eq!(parse_sample_mapping("38v64-20=snare.wav"), None);
+        assert_eq!(parse_sample_mapping("60:48-128=piano.wav"), None);
+        assert_eq!(parse_sample_mapping("48:50-60=piano.wav"), None);
+        assert_eq!(parse_sample_mapping("not a mapping"), None);
+    }
+
+    #[test]
+    fn test_round_robin_alternates() {
+        let buffer = sample_buffer(vec![0.5; 16], 44100);
+        let mut synth = SamplerSynth::default();
+        synth.zones.push(drum_zone(
+            38,
+            WavData {
+                layers: vec![full_layer(vec![buffer.clone(), buffer])],
+                choke_group: None,
+            },
+        ));
+
+        synth.note_on(38, 127);
+        synth.note_on(38, 127);
+        synth.note_on(38, 127);
+        assert_eq!(synth.notes[0].buffer_index, 0);
+        assert_eq!(synth.notes[1].buffer_index, 1);
+        assert_eq!(synth.notes[2].buffer_index, 0);
+    }
+
+    #[test]
+    fn test_sample_paths_round_trip() {
+        let synth = SamplerSynth::default();
+        let data = b"60:48-72@2t15v0-90=piano_soft.wav\n60:48-72v91-127=piano_hard.wav\n";
+        synth.params.load_preset_data(data);
+        assert_eq!(synth.params.serialize_sample_paths(), data.to_vec());
+    }
+
+    #[test]
+    fn test_program_change() {
+        let mut synth = SamplerSynth::default();
+        synth.params.load_preset_data(b"36=kick.wav\n");
+        synth.process_midi_event([192, 5, 0]);
+        assert_eq!(synth.params.get_preset_num(), 5);
+        // A fresh program has the default kit
+        assert_eq!(synth.params.sample_paths().len(), 4);
+        synth.params.load_preset_data(b"38=snare.wav\n");
+        let data = synth.params.get_bank_data();
+
+        let loaded = SamplerSynth::default();
+        loaded.params.load_bank_data(&data);
+        assert_eq!(loaded.params.serialize_sample_paths(), b"36=kick.wav\n");
+        loaded
+            .params
+            .sample_paths_changed
+            .store(false, Ordering::Relaxed);
+        loaded.params.change_preset(5);
+        assert!(loaded.params.sample_paths_changed.load(Ordering::Relaxed));
+        assert_eq!(loaded.params.serialize_sample_paths(), b"38=snare.wav\n");
+        loaded.params.change_preset(0);
+        assert_eq!(loaded.params.serialize_sample_paths(), b"36=kick.wav\n");
+    }
+
+    #[test]
+    fn test_midi_program_change_never_waits() {
+        let mut synth = SamplerSynth::default();
+        // The GUI thread is saving the bank, the change is dropped
+        let params = synth.params.clone();
+        let kits = params.kits.lock().unwrap();
+        synth.process_midi_event([192, 3, 0]);
+        assert_eq!(synth.params.get_preset_num(), 0);
+        drop(kits);
+
+        synth.process_midi_event([192, 3, 0]);
+        assert_eq!(synth.params.get_preset_num(), 3);
+    }
+
+    #[test]
+    fn test_kit_loader() {
+        let path = ::std::env::temp_dir().join("wav_sampler_test_kit_loader.wav");
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 44100,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for _ in 0..441 {
+            writer.write_sample(i16::MAX / 2).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let mut synth = SamplerSynth::default();
+        let data = format!("36={}\n", path.display());
+        synth.params.load_preset_data(data.as_bytes());
+        start_kit_loader(Arc::downgrade(&synth.params));
+        // The audio thread only ever picks up what the loader hands it
+        for _ in 0..200 {
+            synth.handle_wav_loading();
+            if !synth.zones.is_empty() {
+                break;
+            }
+            ::std::thread::sleep(::std::time::Duration::from_millis(10));
+        }
+        assert_eq!(synth.zones.len(), 1);
+        assert_eq!(synth.zones[0].root, 36);
+    }
+
+    #[test]
+    fn test_high_notes() {
+        let mut synth = SamplerSynth::default();
+        synth.set_block_size(64);
+        // One zone over the whole keyboard, and one a bad kit stretched past it
+        for &(lo, hi) in [(0, 127), (128, 255)].iter() {
+            synth.zones.push(Zone {
+                lo,
+                hi,
+                root: 60,
+                tune: 0,
+                data: WavData {
+                    layers: vec![full_layer(vec![sample_buffer(vec![0.5; 4410], 44100)])],
+                    choke_group: None,
+                },
+            });
+        }
+        synth.process_midi_event([144, 100, 127]);
+        synth.process_midi_event([144, 127, 127]);
+        synth.process_midi_event([144, 200, 127]);
+        synth.render(64);
+        assert!(synth.samples_out[0].iter().all(|&x| x != 0.0));
+        assert_eq!(
+            synth
+                .notes
+                .iter()
+                .filter(|note| note.state == NoteState::ON)
+                .count(),
+            2
+        );
+        synth.process_midi_event([128, 100, 0]);
+        synth.process_midi_event([128, 200, 0]);
+    }
+
+    #[test]
+    fn test_zones() {
+        let rates = EnvelopeRates::new(0.0, 0.0, 1.0, 0.0, 44100.0);
+        let data = |level| WavData {
+            layers: vec![full_layer(vec![sample_buffer(vec![level; 16], 44100)])],
+            choke_group: None,
+        };
+        let mut synth = SamplerSynth::default();
+        synth.zones.push(Zone {
+            lo: 48,
+            hi: 59,
+            root: 54,
+            tune: 0,
+            data: data(0.25),
+        });
+        synth.zones.push(Zone {
+            lo: 60,
+            hi: 72,
+            root: 60,
+            tune: 100,
+            data: data(0.5),
+        });
+        // A one key zone sharing a root with a wider one
+        synth.zones.push(drum_zone(54, data(1.0)));
+
+        assert_eq!(synth.find_zone(47), None);
+        assert_eq!(synth.find_zone(48), Some(0));
+        assert_eq!(synth.find_zone(54), Some(2));
+        assert_eq!(synth.find_zone(72), Some(1));
+        assert_eq!(synth.find_zone(73), None);
+
+        // An octave up from the root plays twice as fast, plus the tuning
+        synth.note_on(72, 255);
+        assert!((synth.notes[0].speed - 2.0 * (1.0f64 / 12.0).exp2()).abs() < 1e-9);
+        assert_eq!(synth.process_sample(&rates, false).0, 0.5);
+        synth.note_on(48, 255);
+        assert!((synth.notes[1].speed - (-0.5f64).exp2()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_release_keeps_pitch() {
+        let rates = EnvelopeRates::new(0.0, 0.0, 1.0, 1.0, 44100.0);
+        let mut synth = SamplerSynth::default();
+        let ramp: Vec<f32> = (0..1000).map(|frame| frame as f32 / 1000.0).collect();
+        synth.zones.push(Zone {
+            lo: 36,
+            hi: 84,
+            root: 60,
+            tune: 0,
+            data: WavData {
+                layers: vec![full_layer(vec![sample_buffer(ramp, 44100)])],
+                choke_group: None,
+            },
+        });
+
+        // An octave down reads half a frame per sample, between frames the
+        // output is interpolated
+        synth.note_on(48, 255);
+        synth.process_sample(&rates, false);
+        assert!((synth.process_sample(&rates, false).0 - 0.0005).abs() < 1e-6);
+
+        // The release carries on from the same fractional position at the same rate
+        let mut positions = vec![synth.notes[0].position];
+        for sample in 0..20 {
+            if sample == 10 {
+                synth.note_off(48, 0.0);
+            }
+            synth.process_sample(&rates, false);
+            positions.push(synth.notes[0].position);
+        }
+        assert!(synth.notes[0].state == NoteState::OFF);
+        for pair in positions.windows(2) {
+            assert_eq!(pair[1] - pair[0], 0.5);
+        }
+    }
+
+    #[test]
+    fn test_velocity_layers() {
+        let layer = |velocity_range| VelocityLayer {
+            buffers: Vec::new(),
+            velocity_range,
+        };
+        let layers = vec![layer((0, 70)), layer((60, 127))];
+        assert_eq!(select_layers(&layers, 10), Some((0, 0, 0.0)));
+        assert_eq!(select_layers(&layers, 100), Some((1, 1, 0.0)));
+
+        // Crossfades across the overlap
+        let (lower, upper, low_fade) = select_layers(&layers, 60).unwrap();
+        assert_eq!((lower, upper), (0, 1));
+        let (_, _, high_fade) = select_layers(&layers, 70).unwrap();
+        assert!(low_fade > 0.0 && low_fade < 0.5);
+        assert!(high_fade > 0.5 && high_fade < 1.0);
+
+        // A gap between layers is silent
+        let layers = vec![layer((0, 40)), layer((80, 127))];
+        assert_eq!(select_layers(&layers, 60), None);
+    }
+
+    #[test]
+    fn test_note_on_picks_layer() {
+        let rates = EnvelopeRates::new(0.0, 0.0, 1.0, 0.0, 44100.0);
+        let buffer = |level| sample_buffer(vec![level; 16], 44100);
+        let mut synth = SamplerSynth::default();
+        synth.zones.push(drum_zone(
+            38,
+            WavData {
+                layers: vec![
+                    VelocityLayer {
+                        buffers: vec![buffer(0.25)],
+                        velocity_range: (0, 63),
+                    },
+                    VelocityLayer {
+                        buffers: vec![buffer(0.5)],
+                        velocity_range: (64, 127),
+                    },
+                ],
+                choke_group: None,
+            },
+        ));
+
+        synth.note_on(38, 127);
+        let (l, _) = synth.process_sample(&rates, false);
+        assert_eq!(l, 0.5 * 127.0 / 255.0);
+
+        synth.notes[0] = Note::default();
+        synth.note_on(38, 20);
+        let (l, _) = synth.process_sample(&rates, false);
+        assert_eq!(l, 0.25 * 20.0 / 255.0);
+    }
+
+    #[test]
+    fn test_reverse_playback() {
+        let rates = EnvelopeRates::new(0.0, 0.0, 1.0, 0.0, 44100.0);
+        let mut synth = SamplerSynth::default();
+        synth.params.reverse.set(1.0);
+        synth.zones.push(drum_zone(
+            36,
+            WavData {
+                layers: vec![full_layer(vec![sample_buffer(
+                    vec![0.0, 0.25, 0.5, 1.0],
+                    44100,
+                )])],
+                choke_group: None,
+            },
+        ));
+
+        synth.note_on(36, 255);
+        for expected in &[1.0, 0.5, 0.25, 0.0] {
+            assert_eq!(synth.process_sample(&rates, false).0, *expected);
+        }
+        // Frees the voice once it runs past the start
+        synth.process_sample(&rates, false);
+        assert!(synth.notes[0].state == NoteState::NONE);
+    }
+
+    #[test]
+    fn test_zero_velocity_is_note_off() {
+        let mut synth = SamplerSynth::default();
+        synth.zones.push(drum_zone(
+            36,
+            WavData {
+                layers: vec![full_layer(vec![sample_buffer(vec![0.5; 64], 44100)])],
+                choke_group: None,
+            },
+        ));
+
+        synth.process_midi_event([144, 36, 100]);
+        assert!(synth.notes[0].state == NoteState::ON);
+        synth.process_midi_event([144, 36, 0]);
+        assert!(synth.notes[0].state != NoteState::ON);
+        assert!(synth.notes[1].state == NoteState::NONE);
+    }
+
+    #[test]
+    fn test_events_start_at_their_sample() {
+        let mut synth = SamplerSynth::default();
+        synth.set_block_size(256);
+        synth.zones.push(drum_zone(
+            36,
+            WavData {
+                layers: vec![full_layer(vec![sample_buffer(vec![0.5; 4410], 44100)])],
+                choke_group: None,
+            },
+        ));
+
+        synth.queue_midi_event(100, [144, 36, 127]);
+        synth.render(256);
+        let output = &synth.samples_out[0];
+        assert!(output[..100].iter().all(|&x| x == 0.0));
+        assert!(output[100] != 0.0);
+    }
+
+    #[test]
+    fn test_start_offset_and_retrigger() {
+        let rates = EnvelopeRates::new(0.0, 0.0, 1.0, 0.0, 44100.0);
+        let mut synth = SamplerSynth::default();
+        synth.params.start_offset.set(0.5);
+        synth.params.retrigger.set(1.0);
+        synth.zones.push(drum_zone(
+            36,
+            WavData {
+                layers: vec![full_layer(vec![sample_buffer(
+                    vec![0.0, 0.25, 0.5, 0.75, 1.0],
+                    44100,
+                )])],
+                choke_group: None,
+            },
+        ));
+
+        synth.note_on(36, 255);
+        assert_eq!(synth.process_sample(&rates, false).0, 0.5);
+        assert_eq!(synth.process_sample(&rates, false).0, 0.75);
+
+        // Restarts from the offset in the same voice
+        synth.note_on(36, 255);
+        assert!(synth.notes[1].state == NoteState::NONE);
+        assert_eq!(synth.process_sample(&rates, false).0, 0.5);
+    }
+
+    #[test]
+    fn test_note_off_releases_latest_voice() {
+        let mut synth = SamplerSynth::default();
+        synth.zones.push(drum_zone(
+            60,
+            WavData {
+                layers: vec![full_layer(vec![sample_buffer(vec![0.5; 64], 44100)])],
+                choke_group: None,
+            },
+        ));
+
+        synth.note_on(60, 127);
+        synth.note_on(60, 127);
+        synth.note_off(60, 0.0);
+        assert!(synth.notes[0].state == NoteState::ON);
+        assert!(synth.notes[1].state == NoteState::OFF);
+    }
+
+    #[test]
+    fn test_voice_stealing() {
+        let mut synth = SamplerSynth::default();
+        synth.params.polyphony.set(0.0);
+        synth.zones.push(drum_zone(
+            42,
+            WavData {
+                layers: vec![full_layer(vec![sample_buffer(vec![0.5; 16], 44100)])],
+                choke_group: None,
+            },
+        ));
+
+        synth.note_on(42, 127);
+        synth.notes[0].time = 1.0;
+        synth.note_on(42, 127);
+
+        // The first hit fades out while the second one starts
+        assert!(synth.notes[0].stolen);
+        assert!(synth.notes[0].envelope_phase == EnvelopePhase::Release);
+        assert!(synth.notes[1].state == NoteState::ON);
+        assert!(!synth.notes[1].stolen);
+    }
+
+    #[test]
+    fn test_polyphony() {
+        let mut synth = SamplerSynth::default();
+        assert_eq!(synth.params.get_parameter_text(6), "3");
+        synth.zones.push(drum_zone(
+            42,
+            WavData {
+                layers: vec![full_layer(vec![sample_buffer(vec![0.5; 128], 44100)])],
+                choke_group: None,
+            },
+        ));
+        for _ in 0..4 {
+            synth.note_on(42, 127);
+        }
+        let playing = |synth: &SamplerSynth| {
+            synth
+                .notes
+                .iter()
+                .filter(|voice| voice.state == NoteState::ON && !voice.stolen)
+                .count()
+        };
+        assert_eq!(playing(&synth), 3);
+
+        synth.params.set_parameter(6, 1.0);
+        assert_eq!(synth.params.get_parameter_text(6), "16");
+        for _ in 0..20 {
+            synth.note_on(42, 127);
+        }
+        assert_eq!(playing(&synth), 16);
+        synth.params.set_parameter(6, 0.0);
+        assert_eq!(synth.params.get_parameter_text(6), "1");
+    }
+
+    #[test]
+    fn test_voice_count() {
+        let mut synth = SamplerSynth::default();
+        synth.zones.push(drum_zone(
+            42,
+            WavData {
+                layers: vec![full_layer(vec![sample_buffer(vec![0.5; 128], 44100)])],
+                choke_group: None,
+            },
+        ));
+        synth.note_on(42, 127);
+        synth.note_on(42, 127);
+        synth.render(64);
+        assert_eq!(synth.params.get_parameter_text(10), "2");
+        assert_eq!(synth.params.get_parameter(10), 2.0 / POLY as f32);
+
+        // Read-only, and back to none once the hits have played out
+        synth.params.set_parameter(10, 1.0);
+        assert_eq!(synth.params.voices.get(), 2.0);
+        synth.render(256);
+        assert_eq!(synth.params.get_parameter_text(10), "0");
+    }
+
+    #[test]
+    fn test_note_one_plays_its_sample() {
+        let rates = EnvelopeRates::new(0.0, 0.0, 1.0, 0.0, 44100.0);
+        let mut synth = SamplerSynth::default();
+
+        // Nothing is loaded on note 1, so it stays silent
+        synth.note_on(1, 255);
+        assert_eq!(synth.process_sample(&rates, false), (0.0, 0.0));
+
+        synth.zones.push(drum_zone(
+            1,
+            WavData {
+                layers: vec![full_layer(vec![sample_buffer(vec![0.25; 16], 44100)])],
+                choke_group: None,
+            },
+        ));
+        synth.note_on(1, 255);
+        for _ in 0..16 {
+            assert_eq!(synth.process_sample(&rates, false), (0.25, 0.25));
+        }
+    }
+
+    #[test]
+    fn test_choke_group() {
+        let rates = EnvelopeRates::new(0.0, 0.0, 1.0, 1.0, 44100.0);
+        let buffer = sample_buffer(vec![0.5; 4410], 44100);
+        let mut synth = SamplerSynth::default();
+        for &note in &[42, 46] {
+            synth.zones.push(drum_zone(
+                note,
+                WavData {
+                    layers: vec![full_layer(vec![buffer.clone()])],
+                    choke_group: Some(1),
+                },
+            ));
+        }
+
+        synth.note_on(46, 255);
+        synth.process_sample(&rates, false);
+        synth.note_on(42, 255);
+
+        // The open hat fades out within a few milliseconds, the closed one keeps playing
+        for _ in 0..441 {
+            synth.process_sample(&rates, false);
+        }
+        assert!(synth.notes[0].state == NoteState::NONE);
+        assert!(synth.notes[1].state == NoteState::ON);
+        assert_eq!(synth.notes[1].key, 42);
+    }
+
+    #[test]
+    fn test_render_at_48_khz() {
+        for &quality in QUALITIES.iter() {
+            let mut synth = SamplerSynth::default();
+            synth.set_sample_rate(48000.0);
+            synth.set_block_size(512);
+            synth.params.amplitude.set(1.0);
+            synth.params.set_parameter(12, quality.to_parameter());
+            assert_eq!(synth.params.get_parameter_text(12), quality.name());
+            synth.resume();
+            synth.zones.push(drum_zone(
+                36,
+                WavData {
+                    layers: vec![full_layer(vec![sample_buffer(vec![0.5; 44100], 44100)])],
+                    choke_group: None,
+                },
+            ));
+            synth.note_on(36, 255);
+
+            // The first block includes the converter's latency, after that the
+            // output should never drop out
+            synth.render(512);
+            assert_eq!(synth.quality, quality);
+            for _ in 0..20 {
+                synth.render(512);
+                assert_eq!(synth.samples_out[0].len(), 512);
+                for sample in &synth.samples_out[0] {
+                    assert!(
+                        (sample - 0.5).abs() < 0.05,
+                        "{:?} dropout: {}",
+                        quality,
+                        sample
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_interpolation_waits_for_resume() {
+        let mut synth = SamplerSynth::default();
+        synth.set_sample_rate(48000.0);
+        synth.set_block_size(512);
+        synth.render(512);
+
+        // Rebuilding the converters allocates, `process` leaves it to `resume`
+        synth
+            .params
+            .set_parameter(12, Quality::Linear.to_parameter());
+        synth.render(512);
+        assert_eq!(synth.quality, DEFAULT_QUALITY);
+        synth.resume();
+        assert_eq!(synth.quality, Quality::Linear);
+        match synth.sample_rate_converters[0].source_signal {
+            Interpolated::Linear(_) => (),
+            _ => panic!("converter not rebuilt"),
+        }
+    }
+
+    #[test]
+    fn test_set_block_size_keeps_converter() {
+        let mut synth = SamplerSynth::default();
+        synth.set_sample_rate(48000.0);
+        synth.set_block_size(512);
+        synth.params.amplitude.set(1.0);
+        synth.zones.push(drum_zone(
+            36,
+            WavData {
+                layers: vec![full_layer(vec![sample_buffer(vec![0.5; 44100], 44100)])],
+                choke_group: None,
+            },
+        ));
+        synth.note_on(36, 255);
+
+        // Past the converter's latency, then hosts that repeat the call, or
+        // change the size mid stream, shouldn't put a gap in the output
+        synth.render(512);
+        for &size in [512, 512, 512, 256, 1024, 64].iter() {
+            synth.set_block_size(size as i64);
+            synth.render(size);
+            for sample in &synth.samples_out[0][..size] {
+                assert!((sample - 0.5).abs() < 0.05, "discontinuity: {}", sample);
+            }
+        }
+    }
+
+    #[test]
+    fn test_set_sample_rate_while_playing() {
+        let mut synth = SamplerSynth::default();
+        synth.set_sample_rate(48000.0);
+        synth.set_block_size(512);
+        synth.params.amplitude.set(1.0);
+        synth.zones.push(drum_zone(
+            36,
+            WavData {
+                layers: vec![full_layer(vec![sample_buffer(vec![0.5; 44100], 44100)])],
+                choke_group: None,
+            },
+        ));
+        synth.note_on(36, 255);
+
+        // Past the converter's latency, a new rate keeps the note going
+        synth.render(512);
+        for &rate in [96000.0, 88200.0, 48000.0].iter() {
+            synth.set_sample_rate(rate);
+            synth.render(512);
+            for sample in &synth.samples_out[0][..512] {
+                assert!((sample - 0.5).abs() < 0.05, "discontinuity: {}", sample);
+            }
+        }
+    }
+
+    #[test]
+    fn test_process_without_set_block_size() {
+        let mut synth = SamplerSynth::default();
+        synth.zones.push(drum_zone(
+            36,
+            WavData {
+                layers: vec![full_layer(vec![sample_buffer(vec![0.5; 4096], 44100)])],
+                choke_group: None,
+            },
+        ));
+        synth.note_on(36, 255);
+
+        let outputs = process_buffer(&[], 2, 256, |buffer| synth.process(buffer));
+        assert!(outputs[0].iter().any(|&x| x != 0.0));
+
+        // A block bigger than the one announced
+        synth.set_block_size(64);
+        let outputs = process_buffer(&[], 2, 1024, |buffer| synth.process(buffer));
+        assert!(outputs[1].iter().all(|&x| x != 0.0));
+    }
+
+    #[test]
+    fn test_missing_wav_is_silent() {
+        let mut synth = SamplerSynth::default();
+        synth.set_block_size(64);
+        synth
+            .params
+            .load_preset_data(b"36=/does/not/exist/missing.wav\n");
+        let sample_paths = synth.params.sample_paths();
+        assert_eq!(sample_paths.len(), 1);
+
+        let (producer, mut consumer) = RingBuffer::<Zone>::new(1).split();
+        start_file_load_thread(producer, sample_paths)
+            .join()
+            .unwrap();
+        assert!(consumer.pop().is_none());
+
+        synth.note_on(36, 255);
+        synth.render(64);
+        for sample in synth.samples_out[0].iter().chain(&synth.samples_out[1]) {
+            assert_eq!(*sample, 0.0);
+        }
+    }
+
+    #[test]
+    fn test_bad_files_keep_playing() {
+        // No log file is no reason to take the host down
+        assert!(open_log_file("missing_folder/WAVSampler.log").is_err());
+
+        // A file that isn't a wav, next to one that loads
+        let bad = ::std::env::temp_dir().join("wav_sampler_test_bad.wav");
+        ::std::fs::write(&bad, b"RIFF\x10\0\0\0WAVEfmt garbage").unwrap();
+        let good = ::std::env::temp_dir().join("wav_sampler_test_good.wav");
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 44100,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&good, spec).unwrap();
+        for _ in 0..4410 {
+            writer.write_sample(i16::max_value() / 2).unwrap();
+        }
+        writer.finalize().unwrap();
+        let data = format!("36={}\n38={}\n", bad.display(), good.display());
+
+        let mut synth = SamplerSynth::default();
+        synth.params.load_preset_data(data.as_bytes());
+        let (producer, mut consumer) = RingBuffer::<Zone>::new(2).split();
+        start_file_load_thread(producer, synth.params.sample_paths())
+            .join()
+            .unwrap();
+        let zone = consumer.pop().unwrap();
+        assert_eq!(zone.root, 38);
+        assert!(consumer.pop().is_none());
+        synth.zones.push(zone);
+
+        synth.note_on(36, 127);
+        synth.note_on(38, 127);
+        synth.set_block_size(64);
+        synth.render(64);
+        assert!(synth.samples_out[0].iter().all(|x| x.is_finite()));
+        assert!(synth.samples_out[0].iter().any(|&x| x != 0.0));
+    }
+
+    #[test]
+    fn test_release_frees_voice() {
+        let rates = EnvelopeRates::new(0.0, 0.0, 1.0, 0.001, 1000.0);
+        let mut note = Note::default();
+        assert_eq!(note.advance_envelope(&rates), 1.0);
+
+        note.envelope_phase = EnvelopePhase::Release;
+        assert_eq!(note.advance_envelope(&rates), 0.0);
+    }
+
+    #[test]
+    fn test_release_velocity() {
+        // Samples of release left after a note off with `release_velocity`
+        let tail = |release_velocity: u8| {
+            let mut synth = SamplerSynth::default();
+            synth.params.release_vel_amount.set(1.0);
+            synth.zones.push(drum_zone(
+                36,
+                WavData {
+                    layers: vec![full_layer(vec![sample_buffer(vec![0.5; 64], 44100)])],
+                    choke_group: None,
+                },
+            ));
+            synth.process_midi_event([144, 36, 127]);
+            synth.notes[0].envelope = 1.0;
+            synth.process_midi_event([128, 36, release_velocity]);
+            let rates = EnvelopeRates::new(0.0, 0.0, 1.0, 0.01, 44100.0);
+            let mut samples = 0;
+            while synth.notes[0].advance_envelope(&rates) > 0.0 {
+                samples += 1;
+            }
+            samples
+        };
+        // Keyboards without release velocity send 64, which leaves the 10ms alone
+        let near = |samples: usize, expected: usize| (samples as i32 - expected as i32).abs() <= 2;
+        assert!(near(tail(64), 441), "{}", tail(64));
+        assert!(near(tail(127), 441 / 4), "{}", tail(127));
+        assert!(near(tail(0), 441 * 4), "{}", tail(0));
+    }
+
+    #[test]
+    fn test_load_wav_24_bit() {
+        let path = ::std::env::temp_dir().join("wav_sampler_test_24_bit.wav");
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 44100,
+            bits_per_sample: 24,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for sample in &[0, 1 << 22, (1 << 23) - 1, -(1 << 23)] {
+            writer.write_sample(*sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let audio = load_wav(&path).unwrap().audio;
+        assert_eq!(audio.len(), 4);
+        assert_eq!(audio[1], 0.5);
+        assert_eq!(audio[3], -1.0);
+        for sample in audio {
+            assert!(sample >= -1.0 && sample <= 1.0);
+        }
+    }
+
+    #[test]
+    fn test_load_wav_float() {
+        let path = ::std::env::temp_dir().join("wav_sampler_test_float.wav");
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 44100,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for sample in &[0.0f32, 0.25, -0.75] {
+            writer.write_sample(*sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        assert_eq!(load_wav(&path).unwrap().audio, vec![0.0, 0.25, -0.75]);
+    }
+
+    #[test]
+    fn test_load_wav_stereo_frames() {
+        let path = ::std::env::temp_dir().join("wav_sampler_test_stereo.wav");
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for sample in &[0.25f32, -0.25, 0.5, -0.5] {
+            writer.write_sample(*sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let wav_data = load_wav(&path).unwrap();
+        assert_eq!(wav_data.frames(), 2);
+        assert_eq!(wav_data.frame(1), (0.5, -0.5));
+    }
+
+    #[test]
+    fn test_file_sample_rate() {
+        // A tenth of a second at 48kHz
+        let path = ::std::env::temp_dir().join("wav_sampler_test_48k.wav");
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 48000,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for _ in 0..4800 {
+            writer.write_sample(0.5f32).unwrap();
+        }
+        writer.finalize().unwrap();
+        let buffer = load_wav(&path).unwrap();
+        assert_eq!(buffer.sample_rate, 48000);
+
+        // Still a tenth of a second at a 44.1kHz host
+        let mut synth = SamplerSynth::default();
+        synth.zones.push(drum_zone(
+            36,
+            WavData {
+                layers: vec![full_layer(vec![buffer])],
+                choke_group: None,
+            },
+        ));
+        synth.note_on(36, 255);
+        let outputs = process_buffer(&[], 2, 8820, |buffer| synth.process(buffer));
+        let length = outputs[0].iter().filter(|&&x| x != 0.0).count();
+        assert!((length as i32 - 4410).abs() <= 2, "{}", length);
+    }
+
+    /// Write `frames` of 16 bit mono audio to a wav in the temp folder, with a
+    /// `smpl` chunk holding a single loop over `start` to `end` inclusive.
+    fn write_looped_wav(name: &str, frames: &[i16], start: u8, end: u8, kind: u8) -> PathBuf {
+        let path = ::std::env::temp_dir().join(name);
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 44100,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for &frame in frames {
+            writer.write_sample(frame).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let mut smpl = vec![0u8; 36 + 24];
+        smpl[28] = 1;
+        smpl[36 + 4] = kind;
+        smpl[36 + 8] = start;
+        smpl[36 + 12] = end;
+        let mut bytes = ::std::fs::read(&path).unwrap();
+        bytes.extend_from_slice(b"smpl");
+        bytes.extend_from_slice(&(smpl.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&smpl);
+        let riff_size = (bytes.len() - 8) as u32;
+        bytes[4..8].copy_from_slice(&riff_size.to_le_bytes());
+        ::std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_smpl_loop_points() {
+        // A single forward loop over frames 100 to 149
+        let path = write_looped_wav("wav_sampler_test_smpl.wav", &[0; 200], 100, 149, 0);
+        assert_eq!(read_smpl_loop(&path), Some((100, 150, LoopMode::Forward)));
+
+        let wav_data = load_wav(&path).unwrap();
+        assert_eq!((wav_data.loop_start, wav_data.loop_end), (100, 150));
+
+        let path = write_looped_wav("wav_sampler_test_smpl_alt.wav", &[0; 200], 100, 149, 1);
+        assert_eq!(load_wav(&path).unwrap().loop_mode, LoopMode::PingPong);
+    }
+
+    #[test]
+    fn test_ping_pong_loop() {
+        let rates = EnvelopeRates::new(0.0, 0.0, 1.0, 0.0, 44100.0);
+        // A rising ramp, one step per frame, bouncing between frames 100 and 149
+        let ramp: Vec<i16> = (0..200).map(|frame| frame * 100).collect();
+        let path = write_looped_wav("wav_sampler_test_ping_pong.wav", &ramp, 100, 149, 1);
+        let mut synth = SamplerSynth::default();
+        synth.zones.push(drum_zone(
+            36,
+            WavData {
+                layers: vec![full_layer(vec![load_wav(&path).unwrap()])],
+                choke_group: None,
+            },
+        ));
+        synth.note_on(36, 255);
+
+        let step = 100.0 / 32768.0;
+        let output: Vec<f32> = (0..500)
+            .map(|_| synth.process_sample(&rates, true).0)
+            .collect();
+        // Carries on long past the loop end, never jumping more than a step
+        assert!(synth.notes[0].state == NoteState::ON);
+        for pair in output.windows(2) {
+            assert!((pair[1] - pair[0]).abs() <= step * 1.001);
+        }
+        assert!(output[100..].iter().all(|&x| x >= 100.0 * step - 1e-6));
+        assert!(output.iter().all(|&x| x <= 149.0 * step + 1e-6));
+        // Turned around at the end and back again at the start
+        assert!((output[160] - 138.0 * step).abs() < 1e-6);
+        assert!((output[210] - 112.0 * step).abs() < 1e-6);
+    }
+}
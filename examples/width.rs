@@ -0,0 +1,354 @@
+#[macro_use]
+extern crate vst;
+extern crate vsts;
+
+use vst::api::Supported;
+use vst::buffer::AudioBuffer;
+use vst::plugin::{CanDo, Category, Info, Plugin, PluginParameters};
+use vst::util::AtomicFloat;
+use vsts::buffer::{bypass_pairs, process_stereo, MAX_PAIRS};
+use vsts::dsp::{flush_denormal_f64, ms_decode, ms_encode, time_coeff, LinkwitzRiley};
+use vsts::smoothing::BypassFade;
+use vsts::util::{clamp_parameter, effect_can_do, from_range, to_range_log};
+
+use std::sync::Arc;
+
+/// Stereo width. Scales the side signal against the mid, and can fold the
+/// lows to mono so bass stays centred whatever the width.
+///
+/// The mono crossover runs on mid and side alike, the Linkwitz-Riley bands
+/// add back up to an allpass so the mid only picks up the same phase shift as
+/// the side, and the mono sum keeps a flat response through the crossover.
+struct Width {
+    params: Arc<WidthParameters>,
+    sample_rate: f32,
+    pairs: [PairState; MAX_PAIRS],
+}
+
+/// Filters and meter of one channel pair.
+#[derive(Copy, Clone)]
+struct PairState {
+    /// Crossovers for the mid and the side.
+    splits: [LinkwitzRiley; 2],
+    correlation: Correlation,
+    /// Crossfade in and out of bypass.
+    bypass: BypassFade,
+}
+
+/// The plugin's parameter object contains the values of parameters that can be
+/// adjusted from the host.
+///
+/// The parameters object is shared between the processing and GUI threads.
+/// For this reason, all mutable state in the object has to be represented
+/// through thread-safe interior mutability.
+struct WidthParameters {
+    width: AtomicFloat,
+    /// Crossover below which the signal is summed to mono, 0 for off.
+    mono_below: AtomicFloat,
+    bypass: AtomicFloat,
+    /// Read-only meter, the output correlation from -1 to 1. Not a control,
+    /// the host only reads it back.
+    correlation: AtomicFloat,
+}
+
+/// Running average of L·R against the channel powers. +1 when the channels
+/// match, 0 when they're unrelated and -1 when one is the other inverted.
+#[derive(Copy, Clone, Default)]
+struct Correlation {
+    product: f64,
+    power_l: f64,
+    power_r: f64,
+}
+
+impl Correlation {
+    fn process(&mut self, l: f32, r: f32, cte: f64) {
+        let (l, r) = (f64::from(l), f64::from(r));
+        let average = |prev: f64, x: f64| flush_denormal_f64(x + cte * (prev - x));
+        self.product = average(self.product, l * r);
+        self.power_l = average(self.power_l, l * l);
+        self.power_r = average(self.power_r, r * r);
+    }
+
+    fn value(&self) -> f32 {
+        let power = (self.power_l * self.power_r).sqrt();
+        // Silence reads as unrelated rather than dividing by zero
+        if power > CORRELATION_FLOOR {
+            (self.product / power).clamp(-1.0, 1.0) as f32
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Default for Width {
+    fn default() -> Width {
+        Width {
+            params: Arc::new(WidthParameters::default()),
+            sample_rate: 44100.0,
+            pairs: [PairState {
+                splits: [LinkwitzRiley::new(MONO_BELOW_RANGE.0, 44100.0); 2],
+                correlation: Correlation::default(),
+                bypass: BypassFade::new(44100.0),
+            }; MAX_PAIRS],
+        }
+    }
+}
+
+impl Default for WidthParameters {
+    fn default() -> WidthParameters {
+        WidthParameters {
+            width: AtomicFloat::new(1.0 / MAX_WIDTH),
+            mono_below: AtomicFloat::new(0.0),
+            bypass: AtomicFloat::new(0.0),
+            correlation: AtomicFloat::new(0.0),
+        }
+    }
+}
+
+/// Widest setting, as a multiple of the input's side level.
+const MAX_WIDTH: f32 = 2.0;
+/// Range of the mono crossover in Hz.
+const MONO_BELOW_RANGE: (f32, f32) = (20.0, 500.0);
+/// Averaging time of the correlation meter, in the units of `time_coeff`.
+const CORRELATION_TIME: f64 = 300.0;
+/// Channel power product below which the correlation isn't measured.
+const CORRELATION_FLOOR: f64 = 1e-12;
+
+impl WidthParameters {
+    fn width(&self) -> f32 {
+        self.width.get() * MAX_WIDTH
+    }
+
+    /// Exponential so the knob moves evenly in octaves.
+    fn mono_below_hz(&self) -> Option<f32> {
+        let mono_below = self.mono_below.get();
+        if mono_below > 0.0 {
+            Some(to_range_log(
+                mono_below,
+                MONO_BELOW_RANGE.0,
+                MONO_BELOW_RANGE.1,
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+impl Plugin for Width {
+    fn get_info(&self) -> Info {
+        Info {
+            name: "Width".to_string(),
+            vendor: "DGriffin".to_string(),
+            unique_id: 543923476,
+            version: 1,
+            inputs: 2,
+            outputs: 2,
+            parameters: 4,
+            category: Category::Mastering,
+            ..Default::default()
+        }
+    }
+
+    fn set_sample_rate(&mut self, rate: f32) {
+        self.sample_rate = rate;
+        for pair in self.pairs.iter_mut() {
+            pair.bypass.set_sample_rate(rate);
+            for split in pair.splits.iter_mut() {
+                split.reset();
+            }
+        }
+    }
+
+    fn process(&mut self, buffer: &mut AudioBuffer<f32>) {
+        let bypass = self.params.bypass.get() > 0.5;
+        let width = self.params.width();
+        let mono_below = self.params.mono_below_hz();
+        for pair in self.pairs.iter_mut() {
+            if let Some(freq) = mono_below {
+                for split in pair.splits.iter_mut() {
+                    split.set_freq(freq, self.sample_rate);
+                }
+            }
+        }
+        if bypass_pairs(buffer, &mut self.pairs, bypass, |pair| &mut pair.bypass) {
+            return;
+        }
+
+        let cte = time_coeff(CORRELATION_TIME, f64::from(self.sample_rate));
+        process_stereo(buffer, &mut self.pairs, |pair, input_l, input_r| {
+            let [split_mid, split_side] = &mut pair.splits;
+            let (mid, side) = ms_encode(input_l, input_r);
+            let (mid, side) = if mono_below.is_some() {
+                let (mid_low, mid_high) = split_mid.split(mid);
+                let (_, side_high) = split_side.split(side);
+                (mid_low + mid_high, side_high)
+            } else {
+                (mid, side)
+            };
+            let (output_l, output_r) = ms_decode(mid, side * width);
+            pair.correlation.process(output_l, output_r, cte);
+            pair.bypass.mix((input_l, input_r), (output_l, output_r))
+        });
+        // The meter shows the main pair
+        self.params
+            .correlation
+            .set(self.pairs[0].correlation.value());
+    }
+
+    fn get_parameter_object(&mut self) -> Arc<dyn PluginParameters> {
+        Arc::clone(&self.params) as Arc<dyn PluginParameters>
+    }
+
+    fn can_do(&self, can_do: CanDo) -> Supported {
+        effect_can_do(can_do)
+    }
+}
+
+impl PluginParameters for WidthParameters {
+    fn get_parameter(&self, index: i32) -> f32 {
+        match index {
+            0 => self.width.get(),
+            1 => self.mono_below.get(),
+            2 => self.bypass.get(),
+            3 => from_range(self.correlation.get(), -1.0, 1.0),
+            _ => 0.0,
+        }
+    }
+
+    fn set_parameter(&self, index: i32, val: f32) {
+        let val = match clamp_parameter(val) {
+            Some(val) => val,
+            None => return,
+        };
+        match index {
+            0 => self.width.set(val),
+            1 => self.mono_below.set(val),
+            2 => self.bypass.set(val),
+            // The correlation meter is written by `process` only
+            _ => (),
+        }
+    }
+
+    fn can_be_automated(&self, index: i32) -> bool {
+        index != 3
+    }
+
+    fn get_parameter_text(&self, index: i32) -> String {
+        match index {
+            0 => format!("{:.0}%", self.width() * 100.0),
+            1 => match self.mono_below_hz() {
+                Some(freq) => format!("{:.0} Hz", freq),
+                None => "Off".to_string(),
+            },
+            2 => (if self.bypass.get() > 0.5 { "On" } else { "Off" }).to_string(),
+            3 => format!("{:+.2}", self.correlation.get()),
+            _ => "".to_string(),
+        }
+    }
+
+    fn get_parameter_name(&self, index: i32) -> String {
+        match index {
+            0 => "Width",
+            1 => "Mono Below",
+            2 => "Bypass",
+            3 => "Correlation",
+            _ => "",
+        }
+        .to_string()
+    }
+}
+
+plugin_main!(Width);
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+    use vst::plugin::{Plugin, PluginParameters};
+    use vsts::buffer::process_buffer;
+    use vsts::util::from_range_log;
+    use {Width, WidthParameters};
+
+    /// `freq` Hz sines scaled by `gains` on each channel.
+    fn sines(freq: f32, gains: (f32, f32)) -> Vec<Vec<f32>> {
+        let sine: Vec<f32> = (0..44100)
+            .map(|n| (n as f32 * 2.0 * PI * freq / 44100.0).sin())
+            .collect();
+        vec![
+            sine.iter().map(|x| x * gains.0).collect(),
+            sine.iter().map(|x| x * gains.1).collect(),
+        ]
+    }
+
+    /// Run `sines` through `width`.
+    fn process_sines(width: &mut Width, freq: f32, gains: (f32, f32)) -> Vec<Vec<f32>> {
+        process_buffer(&sines(freq, gains), 2, 44100, |buffer| {
+            width.process(buffer)
+        })
+    }
+
+    fn peak(signal: &[f32]) -> f32 {
+        signal.iter().fold(0.0f32, |peak, x| peak.max(x.abs()))
+    }
+
+    #[test]
+    fn test_default_passes_through() {
+        // Full width with the mono crossover off
+        let mut width = Width::default();
+        let outputs = process_sines(&mut width, 440.0, (1.0, -0.3));
+        let inputs = sines(440.0, (1.0, -0.3));
+        for (output, input) in outputs.iter().zip(inputs.iter()) {
+            for (x, y) in output.iter().zip(input.iter()) {
+                assert!((x - y).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mono_below() {
+        let mut width = Width::default();
+        // Crossover at 100Hz
+        width
+            .params
+            .set_parameter(1, from_range_log(100.0, 20.0, 500.0));
+        // Side only bass is folded away
+        let outputs = process_sines(&mut width, 30.0, (0.5, -0.5));
+        assert!(peak(&outputs[0][22050..]) < 0.05);
+        // One sided signals at the crossover keep their level in the mono sum
+        width.pairs[0]
+            .splits
+            .iter_mut()
+            .for_each(|split| split.reset());
+        let outputs = process_sines(&mut width, 100.0, (1.0, 0.0));
+        let sum: Vec<f32> = outputs[0]
+            .iter()
+            .zip(&outputs[1])
+            .map(|(l, r)| l + r)
+            .collect();
+        assert!((peak(&sum[22050..]) - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_correlation() {
+        let mut width = Width::default();
+        process_sines(&mut width, 440.0, (0.5, 0.5));
+        assert!(width.params.correlation.get() > 0.99);
+        process_sines(&mut width, 440.0, (0.5, -0.5));
+        assert!(width.params.correlation.get() < -0.99);
+        // No width leaves only the mid
+        width.params.set_parameter(0, 0.0);
+        process_sines(&mut width, 440.0, (0.5, -0.2));
+        assert!(width.params.correlation.get() > 0.99);
+        assert!(width.params.get_parameter(3) > 0.99);
+        width.params.set_parameter(3, 0.0);
+        assert!(width.params.correlation.get() > 0.99);
+    }
+
+    #[test]
+    fn test_parameter_text() {
+        let params = WidthParameters::default();
+        let text: Vec<String> = (0..4).map(|i| params.get_parameter_text(i)).collect();
+        assert_eq!(text, ["100%", "Off", "Off", "+0.00"]);
+        params.set_parameter(1, 1.0);
+        assert_eq!(params.get_parameter_text(1), "500 Hz");
+    }
+}
@@ -0,0 +1,211 @@
+use smoothing::{set_bypass, BypassFade};
+use vst::buffer::{AudioBuffer, Inputs, Outputs};
+use vst::host::HostBuffer;
+
+/// Copy every input channel to the matching output channel unchanged.
+///
+/// Used by the effects when bypassed. Outputs without a matching input are
+/// silenced.
+pub fn copy_through(buffer: &mut AudioBuffer<f32>) {
+    let (inputs, mut outputs) = buffer.split();
+    copy_channels(&inputs, &mut outputs, 0);
+}
+
+/// Fade every pair's bypass towards `bypass` with `set_bypass`, and once the
+/// fade has finished copy `buffer` through. Returns whether it did, leaving
+/// the effect nothing to process.
+pub fn bypass_pairs<S, F>(
+    buffer: &mut AudioBuffer<f32>,
+    pairs: &mut [S],
+    bypass: bool,
+    fade: F,
+) -> bool
+where
+    F: FnMut(&mut S) -> &mut BypassFade,
+{
+    if set_bypass(pairs, bypass, fade) {
+        copy_through(buffer);
+        return true;
+    }
+    false
+}
+
+/// `copy_through` for the outputs from `first` on.
+fn copy_channels(inputs: &Inputs<f32>, outputs: &mut Outputs<f32>, first: usize) {
+    for i in first..outputs.len() {
+        let output = outputs.get_mut(i);
+        if i < inputs.len() {
+            output.copy_from_slice(inputs.get(i));
+        } else {
+            for sample in output.iter_mut() {
+                *sample = 0.0;
+            }
+        }
+    }
+}
+
+/// Most channel pairs the effects keep state for, enough for a 7.1 bus.
+pub const MAX_PAIRS: usize = 4;
+
+/// Run a stereo effect over every channel pair of `buffer`.
+///
+/// Each pair has its own state in `pairs`, so the filters and envelopes of
+/// one pair never see another's audio. `process` gets that state and the
+/// left and right input of each frame and returns the output pair. An odd
+/// channel left over after the last pair, and pairs past the end of `pairs`,
+/// are copied through instead of being indexed blindly.
+pub fn process_stereo<S, F>(buffer: &mut AudioBuffer<f32>, pairs: &mut [S], mut process: F)
+where
+    F: FnMut(&mut S, f32, f32) -> (f32, f32),
+{
+    let count = (buffer.input_count() / 2)
+        .min(buffer.output_count() / 2)
+        .min(pairs.len());
+
+    let (inputs, mut outputs) = buffer.split();
+    for (pair, state) in pairs.iter_mut().enumerate().take(count) {
+        let (input_l, input_r) = (inputs.get(pair * 2), inputs.get(pair * 2 + 1));
+        let output_l = outputs.get_mut(pair * 2);
+        let output_r = outputs.get_mut(pair * 2 + 1);
+        for i in 0..output_l.len() {
+            let (l, r) = process(state, input_l[i], input_r[i]);
+            output_l[i] = l;
+            output_r[i] = r;
+        }
+    }
+    copy_channels(&inputs, &mut outputs, count * 2);
+}
+
+/// Run `process` on a buffer reading `inputs` and writing `output_count`
+/// silent channels of `samples` samples, and return what it wrote.
+///
+/// Meant for the plugins' tests. `vst`'s `HostBuffer` builds the channel
+/// pointer arrays an `AudioBuffer` is made from, so no unsafe code is needed.
+pub fn process_buffer<F>(
+    inputs: &[Vec<f32>],
+    output_count: usize,
+    samples: usize,
+    process: F,
+) -> Vec<Vec<f32>>
+where
+    F: FnOnce(&mut AudioBuffer<f32>),
+{
+    let mut host_buffer: HostBuffer<f32> = HostBuffer::new(inputs.len(), output_count);
+    let mut outputs = vec![vec![0.0; samples]; output_count];
+    process(&mut host_buffer.bind(inputs, &mut outputs));
+    outputs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run `process_stereo` with a gain of 2 on `inputs`.
+    fn double(inputs: &[Vec<f32>], output_count: usize) -> Vec<Vec<f32>> {
+        let mut host_buffer: HostBuffer<f32> = HostBuffer::new(inputs.len(), output_count);
+        let mut outputs = vec![vec![9.0; 4]; output_count];
+        {
+            let mut buffer = host_buffer.bind(inputs, &mut outputs);
+            process_stereo(&mut buffer, &mut [(); MAX_PAIRS], |_, l, r| {
+                (l * 2.0, r * 2.0)
+            });
+        }
+        outputs
+    }
+
+    #[test]
+    fn test_process_buffer() {
+        let outputs = process_buffer(&[vec![0.5; 4]], 2, 4, |buffer| {
+            assert_eq!((buffer.input_count(), buffer.output_count()), (1, 2));
+            assert_eq!(buffer.samples(), 4);
+            copy_through(buffer);
+        });
+        assert_eq!(outputs, vec![vec![0.5; 4], vec![0.0; 4]]);
+
+        // Synths have no inputs
+        let outputs = process_buffer(&[], 2, 8, |buffer| {
+            for output in buffer.split().1.into_iter() {
+                output[7] = 1.0;
+            }
+        });
+        assert_eq!(outputs[1][7], 1.0);
+    }
+
+    #[test]
+    fn test_stereo_is_processed() {
+        let outputs = double(&[vec![0.5; 4], vec![0.25; 4]], 2);
+        assert_eq!(outputs, vec![vec![1.0; 4], vec![0.5; 4]]);
+    }
+
+    #[test]
+    fn test_mono_passes_through() {
+        let outputs = double(&[vec![0.5; 4]], 1);
+        assert_eq!(outputs, vec![vec![0.5; 4]]);
+    }
+
+    #[test]
+    fn test_every_pair_is_processed() {
+        let inputs = vec![vec![0.5; 4], vec![0.25; 4], vec![0.125; 4], vec![1.0; 4]];
+        let outputs = double(&inputs, 4);
+        assert_eq!(outputs[2], vec![0.25; 4]);
+        assert_eq!(outputs[3], vec![2.0; 4]);
+
+        // Each pair keeps its own state
+        let mut host_buffer: HostBuffer<f32> = HostBuffer::new(4, 4);
+        let mut outputs = vec![vec![0.0; 4]; 4];
+        let mut frames = [0; MAX_PAIRS];
+        process_stereo(
+            &mut host_buffer.bind(&inputs, &mut outputs),
+            &mut frames,
+            |frames, _, _| {
+                *frames += 1;
+                (*frames as f32, 0.0)
+            },
+        );
+        assert_eq!(frames, [4, 4, 0, 0]);
+        assert_eq!(outputs[2], vec![1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn test_odd_channel_passes_through() {
+        let outputs = double(&[vec![0.5; 4], vec![0.25; 4], vec![0.125; 4]], 4);
+        assert_eq!(outputs[0], vec![1.0; 4]);
+        assert_eq!(outputs[1], vec![0.5; 4]);
+        assert_eq!(outputs[2], vec![0.125; 4]);
+        // No input to copy, so it's silenced
+        assert_eq!(outputs[3], vec![0.0; 4]);
+    }
+
+    #[test]
+    fn test_bypass_waits_for_fade() {
+        let inputs = vec![vec![0.5; 10]; 2];
+        // 10 samples to fade at 1khz
+        let mut fades = [BypassFade::new(1000.0); MAX_PAIRS];
+        let outputs = process_buffer(&inputs, 2, 10, |buffer| {
+            assert!(!bypass_pairs(buffer, &mut fades, true, |fade| fade));
+            process_stereo(buffer, &mut fades, |fade, l, r| {
+                fade.mix((l, r), (0.0, 0.0))
+            });
+        });
+        assert!(outputs[0][0] < 0.5);
+        let outputs = process_buffer(&inputs, 2, 10, |buffer| {
+            assert!(bypass_pairs(buffer, &mut fades, true, |fade| fade));
+        });
+        assert_eq!(outputs, inputs);
+    }
+
+    #[test]
+    fn test_pairs_without_state_pass_through() {
+        let inputs = vec![vec![0.5; 4]; 4];
+        let mut host_buffer: HostBuffer<f32> = HostBuffer::new(4, 4);
+        let mut outputs = vec![vec![0.0; 4]; 4];
+        let mut frames = [0; 1];
+        process_stereo(
+            &mut host_buffer.bind(&inputs, &mut outputs),
+            &mut frames,
+            |_, l, r| (l * 2.0, r * 2.0),
+        );
+        assert_eq!(outputs[1], vec![1.0; 4]);
+        assert_eq!(outputs[2], vec![0.5; 4]);
+    }
+}
@@ -0,0 +1,874 @@
+use std::f32::consts::{FRAC_1_SQRT_2, PI};
+use std::f64::consts::PI as PI_F64;
+
+/// Values smaller than this are treated as silence in recursive state.
+const DENORMAL_THRESHOLD: f32 = 1e-15;
+
+/// Snap tiny values to zero so decaying feedback never ends up in the
+/// denormal range, which is very slow on x86.
+pub fn flush_denormal(x: f32) -> f32 {
+    if x.abs() < DENORMAL_THRESHOLD {
+        0.0
+    } else {
+        x
+    }
+}
+
+/// `flush_denormal` for the plugins that keep their state in f64.
+pub fn flush_denormal_f64(x: f64) -> f64 {
+    if x.abs() < f64::from(DENORMAL_THRESHOLD) {
+        0.0
+    } else {
+        x
+    }
+}
+
+/// Polynomial band limited step, subtract from a naive waveform around each
+/// downward jump of 2 to smooth it out. `t` is the phase (0 to 1) and `dt`
+/// the phase increment per sample.
+pub fn poly_blep(t: f64, dt: f64) -> f64 {
+    if t < dt {
+        let t = t / dt;
+        t + t - t * t - 1.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt;
+        t * t + t + t + 1.0
+    } else {
+        0.0
+    }
+}
+
+/// Oversampling factor of `TruePeak`.
+const TRUE_PEAK_FACTOR: usize = 4;
+/// Taps per oversampled phase, the detector lags the input by half of this.
+const TRUE_PEAK_TAPS: usize = 8;
+
+/// Inter-sample peak detector. Upsamples 4x with a short windowed sinc FIR
+/// and returns the largest magnitude, catching peaks that land between samples
+/// after reconstruction.
+#[derive(Copy, Clone, Debug)]
+pub struct TruePeak {
+    coeffs: [[f64; TRUE_PEAK_TAPS]; TRUE_PEAK_FACTOR],
+    history: [f64; TRUE_PEAK_TAPS],
+    pos: usize,
+}
+
+impl Default for TruePeak {
+    fn default() -> TruePeak {
+        let half = (TRUE_PEAK_TAPS / 2) as f64;
+        let mut coeffs = [[0.0; TRUE_PEAK_TAPS]; TRUE_PEAK_FACTOR];
+        for (phase, taps) in coeffs.iter_mut().enumerate() {
+            let offset = phase as f64 / TRUE_PEAK_FACTOR as f64;
+            for (k, tap) in taps.iter_mut().enumerate() {
+                let u = k as f64 - half + offset;
+                let sinc = if u == 0.0 {
+                    1.0
+                } else {
+                    (PI_F64 * u).sin() / (PI_F64 * u)
+                };
+                let window = 0.5 * (1.0 + (PI_F64 * u / half).cos());
+                *tap = sinc * window;
+            }
+            // Unity gain at DC for every phase
+            let sum: f64 = taps.iter().sum();
+            for tap in taps.iter_mut() {
+                *tap /= sum;
+            }
+        }
+        TruePeak {
+            coeffs,
+            history: [0.0; TRUE_PEAK_TAPS],
+            pos: 0,
+        }
+    }
+}
+
+impl TruePeak {
+    /// Push a sample and return the peak magnitude of the 4 upsampled points.
+    pub fn process(&mut self, x: f64) -> f64 {
+        self.pos = (self.pos + 1) % TRUE_PEAK_TAPS;
+        self.history[self.pos] = x;
+
+        let mut peak: f64 = 0.0;
+        for taps in self.coeffs.iter() {
+            let mut y = 0.0;
+            for (k, tap) in taps.iter().enumerate() {
+                y += tap * self.history[(self.pos + TRUE_PEAK_TAPS - k) % TRUE_PEAK_TAPS];
+            }
+            peak = peak.max(y.abs());
+        }
+        peak
+    }
+
+    pub fn reset(&mut self) {
+        self.history = [0.0; TRUE_PEAK_TAPS];
+    }
+}
+
+/// One pole, one zero highpass that removes DC offset.
+#[derive(Copy, Clone, Debug)]
+pub struct DcBlocker {
+    r: f32,
+    x1: f32,
+    y1: f32,
+}
+
+impl DcBlocker {
+    pub fn new(cutoff: f32, sample_rate: f32) -> DcBlocker {
+        DcBlocker {
+            r: (-2.0 * PI * cutoff / sample_rate).exp(),
+            x1: 0.0,
+            y1: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        let y = x - self.x1 + self.r * self.y1;
+        self.x1 = x;
+        self.y1 = flush_denormal(y);
+        y
+    }
+
+    pub fn reset(&mut self) {
+        self.x1 = 0.0;
+        self.y1 = 0.0;
+    }
+}
+
+/// Split a left/right pair into mid and side.
+pub fn ms_encode(l: f32, r: f32) -> (f32, f32) {
+    ((l + r) * 0.5, (l - r) * 0.5)
+}
+
+/// Back to left and right, the inverse of `ms_encode`.
+pub fn ms_decode(mid: f32, side: f32) -> (f32, f32) {
+    (mid + side, mid - side)
+}
+
+/// Fraction of the ceiling where `soft_limit` starts rounding peaks off.
+const SOFT_LIMIT_KNEE: f32 = 0.5;
+/// Range of the plugins' limiter ceiling parameter in dBFS.
+pub const LIMITER_CEILING_RANGE: (f32, f32) = (-24.0, 0.0);
+
+/// Output protection limiter. Linear up to half the ceiling, above that the
+/// peaks are bent over with a `tanh` curve that approaches but never passes
+/// `ceiling`. The slope is continuous at the knee so nothing clicks on the way
+/// in.
+pub fn soft_limit(x: f32, ceiling: f32) -> f32 {
+    soft_clip(x, ceiling, SOFT_LIMIT_KNEE)
+}
+
+/// `soft_limit` with the knee at `knee` times the ceiling, from 0 to 1.
+pub fn soft_clip(x: f32, ceiling: f32, knee: f32) -> f32 {
+    let knee = ceiling * knee;
+    let magnitude = x.abs();
+    if magnitude <= knee {
+        x
+    } else {
+        let headroom = ceiling - knee;
+        let limited = knee + headroom * ((magnitude - knee) / headroom).tanh();
+        limited.min(ceiling).copysign(x)
+    }
+}
+
+/// Crossfade from `x` to `y`, `a` from 0 to 1. Use this when the two signals
+/// are strongly correlated, like a saturated copy of the dry signal, where the
+/// gains need to sum to one to keep the level steady.
+pub fn mix(x: f32, y: f32, a: f32) -> f32 {
+    x * (1.0 - a) + y * a
+}
+
+/// Constant power crossfade from `x` to `y`, `a` from 0 to 1. The gains follow
+/// a quarter cosine and sine so their squares sum to one. Use this for
+/// uncorrelated signals, like a dry signal against a delay or reverb tail,
+/// where a linear crossfade dips by 3dB in the middle.
+pub fn mix_equal_power(x: f32, y: f32, a: f32) -> f32 {
+    let angle = a * PI * 0.5;
+    x * angle.cos() + y * angle.sin()
+}
+
+/// The Saturate plugin's curve. `movement` is how far the input moved since
+/// the last sample, it sets how quickly curve A catches up with the input.
+pub fn saturate(
+    output_prev: f32,
+    input_prev: f32,
+    input: f32,
+    movement: f32,
+    a: f32,
+    b: f32,
+    ab_mix: f32,
+) -> f32 {
+    let delta_input = input - input_prev;
+    let dist_a = ((a * input).tanh() - output_prev) * a * movement;
+    let dist_b = b * delta_input / (b * input).cosh().powi(2);
+    mix(
+        (output_prev + dist_a).tanh(),
+        (output_prev + dist_b).tanh() * 12.0,
+        ab_mix.clamp(0.0, 1.0),
+    )
+}
+
+/// Circular buffer of the most recent input, read back at a delay.
+#[derive(Clone, Debug)]
+pub struct DelayLine {
+    buffer: Vec<f32>,
+    /// Where the next sample is written.
+    pos: usize,
+}
+
+impl DelayLine {
+    /// A line that can be read back up to `max_delay` samples.
+    pub fn new(max_delay: usize) -> DelayLine {
+        DelayLine {
+            // Room for the sample after the longest delay, which it interpolates towards
+            buffer: vec![0.0; max_delay + 2],
+            pos: 0,
+        }
+    }
+
+    /// Longest delay in samples that can be read back.
+    pub fn max_delay(&self) -> f32 {
+        (self.buffer.len() - 2) as f32
+    }
+
+    /// Read `delay` samples back from the next write, linearly interpolating
+    /// between samples. Delays are kept between 1 sample and `max_delay`.
+    pub fn read(&self, delay: f32) -> f32 {
+        let delay = delay.max(1.0).min(self.max_delay());
+        let len = self.buffer.len();
+        let whole = delay as usize;
+        let frac = delay - whole as f32;
+        let a = self.buffer[(self.pos + len - whole) % len];
+        let b = self.buffer[(self.pos + len - whole - 1) % len];
+        a + (b - a) * frac
+    }
+
+    pub fn write(&mut self, x: f32) {
+        self.buffer[self.pos] = x;
+        self.pos = (self.pos + 1) % self.buffer.len();
+    }
+
+    /// Fill the line with silence.
+    pub fn reset(&mut self) {
+        for sample in self.buffer.iter_mut() {
+            *sample = 0.0;
+        }
+    }
+}
+
+/// How long a delay line of length `delay` feeding back `feedback` of its
+/// output keeps ringing once its input stops, until it has dropped by 60 dB.
+/// In the same unit as `delay`, and counting the first pass through the line,
+/// so no feedback still gives one `delay`. Infinite if it never dies out.
+pub fn feedback_tail(delay: f32, feedback: f32) -> f32 {
+    let feedback = feedback.abs();
+    if feedback >= 1.0 {
+        f32::INFINITY
+    } else if feedback == 0.0 {
+        delay
+    } else {
+        delay * (1.0 + (0.001f32).ln() / feedback.ln())
+    }
+}
+
+/// Fourth order Linkwitz-Riley crossover, two Butterworth sections on each
+/// side. The low and high outputs add back up to an allpass, so a signal split
+/// into bands and summed keeps a flat magnitude.
+#[derive(Copy, Clone, Debug)]
+pub struct LinkwitzRiley {
+    lowpass: [Biquad; 2],
+    highpass: [Biquad; 2],
+}
+
+impl LinkwitzRiley {
+    pub fn new(freq: f32, sample_rate: f32) -> LinkwitzRiley {
+        let lowpass = Biquad::new(freq, FRAC_1_SQRT_2, sample_rate, FilterKind::Lowpass);
+        let highpass = Biquad::new(freq, FRAC_1_SQRT_2, sample_rate, FilterKind::Highpass);
+        LinkwitzRiley {
+            lowpass: [lowpass; 2],
+            highpass: [highpass; 2],
+        }
+    }
+
+    /// Move the crossover point, the filter state is kept.
+    pub fn set_freq(&mut self, freq: f32, sample_rate: f32) {
+        for lowpass in self.lowpass.iter_mut() {
+            lowpass.set_coeffs(freq, FRAC_1_SQRT_2, sample_rate, FilterKind::Lowpass);
+        }
+        for highpass in self.highpass.iter_mut() {
+            highpass.set_coeffs(freq, FRAC_1_SQRT_2, sample_rate, FilterKind::Highpass);
+        }
+    }
+
+    /// Split `x` into its low and high bands.
+    pub fn split(&mut self, x: f32) -> (f32, f32) {
+        let low = self.lowpass[0].process(x);
+        let low = self.lowpass[1].process(low);
+        let high = self.highpass[0].process(x);
+        let high = self.highpass[1].process(high);
+        (low, high)
+    }
+
+    pub fn reset(&mut self) {
+        for biquad in self.lowpass.iter_mut().chain(self.highpass.iter_mut()) {
+            biquad.reset();
+        }
+    }
+}
+
+/// Low, mid and high bands from two `LinkwitzRiley` crossovers. The low band
+/// goes through an allpass at the high crossover so it stays in phase with the
+/// mid and high bands, and the three still add back up to an allpass.
+#[derive(Copy, Clone, Debug)]
+pub struct ThreeBandSplit {
+    low_split: LinkwitzRiley,
+    high_split: LinkwitzRiley,
+    low_allpass: LinkwitzRiley,
+}
+
+impl ThreeBandSplit {
+    pub fn new(low_freq: f32, high_freq: f32, sample_rate: f32) -> ThreeBandSplit {
+        ThreeBandSplit {
+            low_split: LinkwitzRiley::new(low_freq, sample_rate),
+            high_split: LinkwitzRiley::new(high_freq, sample_rate),
+            low_allpass: LinkwitzRiley::new(high_freq, sample_rate),
+        }
+    }
+
+    /// Move the crossover points, the filter state is kept.
+    pub fn set_freqs(&mut self, low_freq: f32, high_freq: f32, sample_rate: f32) {
+        self.low_split.set_freq(low_freq, sample_rate);
+        self.high_split.set_freq(high_freq, sample_rate);
+        self.low_allpass.set_freq(high_freq, sample_rate);
+    }
+
+    /// Split `x` into its low, mid and high bands.
+    pub fn split(&mut self, x: f32) -> [f32; 3] {
+        let (low, rest) = self.low_split.split(x);
+        let (mid, high) = self.high_split.split(rest);
+        let (low_low, low_high) = self.low_allpass.split(low);
+        [low_low + low_high, mid, high]
+    }
+
+    /// Split `x` at the low crossover only, for two bands.
+    pub fn split_low(&mut self, x: f32) -> (f32, f32) {
+        self.low_split.split(x)
+    }
+
+    pub fn reset(&mut self) {
+        self.low_split.reset();
+        self.high_split.reset();
+        self.low_allpass.reset();
+    }
+}
+
+/// Filter response of a `Biquad`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FilterKind {
+    Lowpass,
+    Highpass,
+    Bandpass,
+    Peaking { gain_db: f32 },
+    LowShelf { gain_db: f32 },
+    HighShelf { gain_db: f32 },
+}
+
+/// Second order IIR filter using the RBJ audio EQ cookbook coefficients.
+#[derive(Copy, Clone, Debug)]
+pub struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl Default for Biquad {
+    /// A filter that passes the signal through unchanged.
+    fn default() -> Biquad {
+        Biquad {
+            b0: 1.0,
+            b1: 0.0,
+            b2: 0.0,
+            a1: 0.0,
+            a2: 0.0,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+}
+
+impl Biquad {
+    pub fn new(cutoff: f32, q: f32, sample_rate: f32, kind: FilterKind) -> Biquad {
+        let mut biquad = Biquad::default();
+        biquad.set_coeffs(cutoff, q, sample_rate, kind);
+        biquad
+    }
+
+    /// Recalculate the coefficients, the filter state is kept so this can be
+    /// called while audio is running.
+    pub fn set_coeffs(&mut self, cutoff: f32, q: f32, sample_rate: f32, kind: FilterKind) {
+        // Keep the cutoff just below nyquist and the q positive so the filter stays stable
+        let cutoff = cutoff.max(1.0).min(sample_rate * 0.499);
+        let q = q.max(0.01);
+
+        let w0 = 2.0 * PI * cutoff / sample_rate;
+        let cos_w0 = w0.cos();
+        let alpha = w0.sin() / (2.0 * q);
+
+        let (b0, b1, b2, a0, a1, a2) = match kind {
+            FilterKind::Lowpass => (
+                (1.0 - cos_w0) / 2.0,
+                1.0 - cos_w0,
+                (1.0 - cos_w0) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos_w0,
+                1.0 - alpha,
+            ),
+            FilterKind::Highpass => (
+                (1.0 + cos_w0) / 2.0,
+                -(1.0 + cos_w0),
+                (1.0 + cos_w0) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos_w0,
+                1.0 - alpha,
+            ),
+            FilterKind::Bandpass => (alpha, 0.0, -alpha, 1.0 + alpha, -2.0 * cos_w0, 1.0 - alpha),
+            FilterKind::Peaking { gain_db } => {
+                let a = (10.0f32).powf(gain_db / 40.0);
+                (
+                    1.0 + alpha * a,
+                    -2.0 * cos_w0,
+                    1.0 - alpha * a,
+                    1.0 + alpha / a,
+                    -2.0 * cos_w0,
+                    1.0 - alpha / a,
+                )
+            }
+            FilterKind::LowShelf { gain_db } => {
+                let a = (10.0f32).powf(gain_db / 40.0);
+                let shelf = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) - (a - 1.0) * cos_w0 + shelf),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos_w0),
+                    a * ((a + 1.0) - (a - 1.0) * cos_w0 - shelf),
+                    (a + 1.0) + (a - 1.0) * cos_w0 + shelf,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos_w0),
+                    (a + 1.0) + (a - 1.0) * cos_w0 - shelf,
+                )
+            }
+            FilterKind::HighShelf { gain_db } => {
+                let a = (10.0f32).powf(gain_db / 40.0);
+                let shelf = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) + (a - 1.0) * cos_w0 + shelf),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos_w0),
+                    a * ((a + 1.0) + (a - 1.0) * cos_w0 - shelf),
+                    (a + 1.0) - (a - 1.0) * cos_w0 + shelf,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos_w0),
+                    (a + 1.0) - (a - 1.0) * cos_w0 - shelf,
+                )
+            }
+        };
+
+        self.b0 = b0 / a0;
+        self.b1 = b1 / a0;
+        self.b2 = b2 / a0;
+        self.a1 = a1 / a0;
+        self.a2 = a2 / a0;
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.b1 * self.x1 + self.b2 * self.x2
+            - self.a1 * self.y1
+            - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = flush_denormal(y);
+        y
+    }
+
+    /// Clear the filter history.
+    pub fn reset(&mut self) {
+        self.x1 = 0.0;
+        self.x2 = 0.0;
+        self.y1 = 0.0;
+        self.y2 = 0.0;
+    }
+
+    /// Linear gain of the filter at `freq`.
+    pub fn magnitude(&self, freq: f32, sample_rate: f32) -> f32 {
+        let w = 2.0 * PI * freq / sample_rate;
+        // Evaluate H(z) on the unit circle, z^-1 = cos(w) - i sin(w)
+        let (cos_w, sin_w) = (w.cos(), w.sin());
+        let (cos_2w, sin_2w) = ((2.0 * w).cos(), (2.0 * w).sin());
+        let num_re = self.b0 + self.b1 * cos_w + self.b2 * cos_2w;
+        let num_im = -self.b1 * sin_w - self.b2 * sin_2w;
+        let den_re = 1.0 + self.a1 * cos_w + self.a2 * cos_2w;
+        let den_im = -self.a1 * sin_w - self.a2 * sin_2w;
+        ((num_re * num_re + num_im * num_im) / (den_re * den_re + den_im * den_im)).sqrt()
+    }
+}
+
+/// Furthest the plugins' tilt knobs lean, in dB between the lows and highs.
+pub const MAX_TILT_DB: f32 = 12.0;
+
+/// Tilt EQ, a low and a high shelf at the same pivot moving in opposite
+/// directions, so one knob brightens or darkens the whole signal.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Tilt {
+    low: Biquad,
+    high: Biquad,
+    tilt_db: f32,
+}
+
+impl Tilt {
+    /// Lean the response by `tilt_db` from the lows to the highs around
+    /// `pivot`, positive is brighter. The filter state is kept.
+    pub fn set(&mut self, tilt_db: f32, pivot: f32, sample_rate: f32) {
+        let low = FilterKind::LowShelf {
+            gain_db: -tilt_db * 0.5,
+        };
+        let high = FilterKind::HighShelf {
+            gain_db: tilt_db * 0.5,
+        };
+        self.low.set_coeffs(pivot, FRAC_1_SQRT_2, sample_rate, low);
+        self.high
+            .set_coeffs(pivot, FRAC_1_SQRT_2, sample_rate, high);
+        self.tilt_db = tilt_db;
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        // The shelves keep running when flat, so moving off 0 doesn't click
+        let y = self.high.process(self.low.process(x));
+        if self.tilt_db == 0.0 {
+            x
+        } else {
+            y
+        }
+    }
+
+    pub fn magnitude(&self, freq: f32, sample_rate: f32) -> f32 {
+        self.low.magnitude(freq, sample_rate) * self.high.magnitude(freq, sample_rate)
+    }
+
+    pub fn reset(&mut self) {
+        self.low.reset();
+        self.high.reset();
+    }
+}
+
+/// One pole coefficient for an envelope follower's attack or release time,
+/// roughly in ms.
+pub fn time_coeff(time: f64, sample_rate: f64) -> f64 {
+    (-2.0 * PI_F64 * 1000.0 / time / sample_rate).exp()
+}
+
+/// One step of the envelope follower's ballistics filter.
+pub fn envelope(prev_env: f64, detector_input: f64, cte_attack: f64, cte_release: f64) -> f64 {
+    let cte = if detector_input >= prev_env {
+        cte_attack
+    } else {
+        cte_release
+    };
+    detector_input + cte * (prev_env - detector_input)
+}
+
+/// `envelope` with a hold stage, while `hold_counter` is above zero the
+/// envelope stays put instead of releasing.
+pub fn held_envelope(
+    prev_env: f64,
+    detector_input: f64,
+    cte_attack: f64,
+    cte_release: f64,
+    hold_counter: &mut usize,
+) -> f64 {
+    if detector_input < prev_env && *hold_counter > 0 {
+        *hold_counter -= 1;
+        prev_env
+    } else {
+        envelope(prev_env, detector_input, cte_attack, cte_release)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noise::XorShift;
+
+    #[test]
+    fn test_mid_side() {
+        // Mono has no side
+        assert_eq!(ms_encode(0.3, 0.3), (0.3, 0.0));
+        assert_eq!(ms_encode(0.5, -0.5), (0.0, 0.5));
+        let (mid, side) = ms_encode(0.75, -0.25);
+        assert_eq!(ms_decode(mid, side), (0.75, -0.25));
+    }
+
+    #[test]
+    fn test_mix_equal_power_keeps_power() {
+        fn power(a: f32, crossfade: fn(f32, f32, f32) -> f32) -> f32 {
+            let mut x_noise = XorShift::new(1);
+            let mut y_noise = XorShift::new(2);
+            let n = 48000;
+            let sum: f32 = (0..n)
+                .map(|_| crossfade(x_noise.next_f32(), y_noise.next_f32(), a).powi(2))
+                .sum();
+            sum / n as f32
+        }
+
+        // Uniform noise between -1 and 1 has a power of 1/3
+        for &a in [0.0, 0.25, 0.5, 0.75, 1.0].iter() {
+            let ratio = power(a, mix_equal_power) * 3.0;
+            assert!((ratio - 1.0).abs() < 0.05, "a {} ratio {}", a, ratio);
+        }
+        // A linear crossfade loses half the power in the middle
+        let ratio = power(0.5, mix) * 3.0;
+        assert!((ratio - 0.5).abs() < 0.05, "ratio {}", ratio);
+    }
+
+    #[test]
+    fn test_soft_limit_holds_ceiling() {
+        let ceiling = 0.5;
+        // Quiet signal passes through untouched
+        assert_eq!(soft_limit(0.2, ceiling), 0.2);
+        assert_eq!(soft_limit(-0.25, ceiling), -0.25);
+
+        // A sine driven 12dB over the ceiling stays under it
+        let mut peak: f32 = 0.0;
+        for n in 0..1000 {
+            let x = (n as f32 * 0.05).sin() * ceiling * 4.0;
+            let y = soft_limit(x, ceiling);
+            assert_eq!(y.signum(), x.signum());
+            peak = peak.max(y.abs());
+        }
+        assert!(peak <= ceiling);
+        assert!(peak > ceiling * 0.95);
+    }
+
+    #[test]
+    fn test_lowpass_response() {
+        let sample_rate = 44100.0;
+        let lowpass = Biquad::new(1000.0, 0.707, sample_rate, FilterKind::Lowpass);
+        assert!((lowpass.magnitude(0.0, sample_rate) - 1.0).abs() < 1e-4);
+        assert!(lowpass.magnitude(sample_rate / 2.0, sample_rate) < 1e-4);
+        // Butterworth q is 3 dB down at the cutoff
+        assert!((lowpass.magnitude(1000.0, sample_rate) - 0.707).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_highpass_response() {
+        let sample_rate = 44100.0;
+        let highpass = Biquad::new(1000.0, 0.707, sample_rate, FilterKind::Highpass);
+        assert!(highpass.magnitude(0.0, sample_rate) < 1e-4);
+        assert!((highpass.magnitude(sample_rate / 2.0, sample_rate) - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_lowpass_settles_to_dc() {
+        let mut lowpass = Biquad::new(1000.0, 0.707, 44100.0, FilterKind::Lowpass);
+        let mut y = 0.0;
+        for _ in 0..4410 {
+            y = lowpass.process(1.0);
+        }
+        assert!((y - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_poly_blep() {
+        // Only touches the samples either side of the wrap
+        assert_eq!(poly_blep(0.5, 0.01), 0.0);
+        assert_eq!(poly_blep(0.0, 0.01), -1.0);
+        assert!(poly_blep(0.999, 0.01) > 0.0);
+        assert!(poly_blep(0.005, 0.01) < 0.0);
+    }
+
+    #[test]
+    fn test_true_peak_finds_inter_sample_peak() {
+        use std::f64::consts::{FRAC_PI_2, FRAC_PI_4};
+
+        // A quarter sample rate sine 45 degrees off, every sample lands on 0.707
+        let mut detector = TruePeak::default();
+        let mut peak: f64 = 0.0;
+        for n in 0..64 {
+            let x = (FRAC_PI_2 * n as f64 + FRAC_PI_4).sin();
+            assert!(x.abs() < 0.71);
+            peak = peak.max(detector.process(x));
+        }
+        assert!(peak > 0.95 && peak < 1.05);
+    }
+
+    #[test]
+    fn test_true_peak_passes_dc() {
+        let mut detector = TruePeak::default();
+        let mut peak = 0.0;
+        for _ in 0..16 {
+            peak = detector.process(0.5);
+        }
+        assert!((peak - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_flush_denormal() {
+        assert_eq!(flush_denormal(1e-20), 0.0);
+        assert_eq!(flush_denormal(-1e-20), 0.0);
+        assert_eq!(flush_denormal(0.5), 0.5);
+    }
+
+    #[test]
+    fn test_state_reaches_zero_after_burst() {
+        let mut lowpass = Biquad::new(1000.0, 0.707, 44100.0, FilterKind::Lowpass);
+        for i in 0..441 {
+            lowpass.process(if i % 2 == 0 { 1.0 } else { -1.0 });
+        }
+        let mut settled = false;
+        for _ in 0..44100 {
+            lowpass.process(0.0);
+            if lowpass.y1 == 0.0 && lowpass.y2 == 0.0 {
+                settled = true;
+                break;
+            }
+        }
+        assert!(settled);
+    }
+
+    #[test]
+    fn test_dc_blocker_removes_offset() {
+        let mut blocker = DcBlocker::new(10.0, 44100.0);
+        let mut y = 0.0;
+        for n in 0..44100 {
+            y = blocker.process(0.5 + if n % 2 == 0 { 0.1 } else { -0.1 });
+        }
+        // Only the alternating part is left
+        assert!((y.abs() - 0.1).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_linkwitz_riley_sums_flat() {
+        let sample_rate = 44100.0;
+        for &freq in [100.0, 1000.0, 5000.0].iter() {
+            let mut crossover = LinkwitzRiley::new(1000.0, sample_rate);
+            let mut peak: f32 = 0.0;
+            for n in 0..8820 {
+                let x = (2.0 * PI * freq * n as f32 / sample_rate).sin();
+                let (low, high) = crossover.split(x);
+                if n > 4410 {
+                    peak = peak.max((low + high).abs());
+                }
+            }
+            assert!((peak - 1.0).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_three_band_split_sums_flat() {
+        let sample_rate = 44100.0;
+        for &freq in [100.0, 1000.0, 5000.0].iter() {
+            let mut crossover = ThreeBandSplit::new(300.0, 3000.0, sample_rate);
+            let mut peak: f32 = 0.0;
+            for n in 0..8820 {
+                let x = (2.0 * PI * freq * n as f32 / sample_rate).sin();
+                let bands = crossover.split(x);
+                if n > 4410 {
+                    peak = peak.max(bands.iter().sum::<f32>().abs());
+                }
+            }
+            assert!((peak - 1.0).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_peaking_gain() {
+        let sample_rate = 44100.0;
+        let kind = FilterKind::Peaking { gain_db: 6.0 };
+        let peaking = Biquad::new(1000.0, 1.0, sample_rate, kind);
+        assert!((peaking.magnitude(1000.0, sample_rate) - 1.995).abs() < 0.01);
+        assert!((peaking.magnitude(0.0, sample_rate) - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_shelf_gain() {
+        let sample_rate = 44100.0;
+        let low = Biquad::new(
+            1000.0,
+            0.707,
+            sample_rate,
+            FilterKind::LowShelf { gain_db: 6.0 },
+        );
+        assert!((low.magnitude(10.0, sample_rate) - 1.995).abs() < 0.01);
+        assert!((low.magnitude(20000.0, sample_rate) - 1.0).abs() < 0.01);
+        let high = Biquad::new(
+            1000.0,
+            0.707,
+            sample_rate,
+            FilterKind::HighShelf { gain_db: 6.0 },
+        );
+        assert!((high.magnitude(10.0, sample_rate) - 1.0).abs() < 0.01);
+        assert!((high.magnitude(20000.0, sample_rate) - 1.995).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_tilt() {
+        let sample_rate = 44100.0;
+        let mut tilt = Tilt::default();
+        tilt.set(12.0, 1000.0, sample_rate);
+        // Half the tilt either side, the pivot stays put
+        assert!((tilt.magnitude(10.0, sample_rate) - 0.501).abs() < 0.01);
+        assert!((tilt.magnitude(1000.0, sample_rate) - 1.0).abs() < 1e-3);
+        assert!((tilt.magnitude(20000.0, sample_rate) - 1.995).abs() < 0.02);
+
+        // Flat is exactly the input
+        tilt.set(0.0, 1000.0, sample_rate);
+        for n in 0..100 {
+            let x = (n as f32 * 0.3).sin();
+            assert_eq!(tilt.process(x), x);
+        }
+    }
+
+    #[test]
+    fn test_delay_line() {
+        let mut line = DelayLine::new(100);
+        for n in 0..10 {
+            line.write(n as f32);
+        }
+        assert_eq!(line.read(1.0), 9.0);
+        assert_eq!(line.read(3.0), 7.0);
+        assert_eq!(line.read(2.5), 7.5);
+        // Longer than the line reads the oldest sample there is
+        assert_eq!(line.read(1000.0), line.read(100.0));
+    }
+
+    #[test]
+    fn test_feedback_tail() {
+        assert_eq!(feedback_tail(100.0, 0.0), 100.0);
+        // Every pass loses 20 dB, so 3 more passes after the first one
+        assert!((feedback_tail(100.0, 0.1) - 400.0).abs() < 1e-3);
+        assert_eq!(feedback_tail(100.0, -0.1), feedback_tail(100.0, 0.1));
+        assert!(feedback_tail(100.0, 0.9) > feedback_tail(100.0, 0.5));
+        assert_eq!(feedback_tail(100.0, 1.0), f32::INFINITY);
+    }
+
+    #[test]
+    fn test_hold_delays_release() {
+        let cte_release = time_coeff(100.0, 44100.0);
+        let mut hold_counter = 10;
+        let mut env = 1.0;
+        for _ in 0..10 {
+            env = held_envelope(env, 0.0, 0.0, cte_release, &mut hold_counter);
+            assert_eq!(env, 1.0);
+        }
+        env = held_envelope(env, 0.0, 0.0, cte_release, &mut hold_counter);
+        assert!(env < 1.0);
+
+        // No hold releases straight away
+        let mut hold_counter = 0;
+        assert!(held_envelope(1.0, 0.0, 0.0, cte_release, &mut hold_counter) < 1.0);
+    }
+}
@@ -0,0 +1,171 @@
+use noise::XorShift;
+
+use std::f32::consts::PI;
+
+/// Waveform of an `Lfo`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LfoShape {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+    /// A new random value every cycle, held until the next one.
+    SampleAndHold,
+}
+
+/// Every shape, in the order a shape parameter steps through them.
+const SHAPES: [LfoShape; 5] = [
+    LfoShape::Sine,
+    LfoShape::Triangle,
+    LfoShape::Saw,
+    LfoShape::Square,
+    LfoShape::SampleAndHold,
+];
+
+impl LfoShape {
+    /// Shape for a 0 - 1 parameter value.
+    pub fn from_parameter(x: f32) -> LfoShape {
+        // Negative values saturate to 0 in the cast
+        let index = (x * (SHAPES.len() - 1) as f32).round() as usize;
+        SHAPES[index.min(SHAPES.len() - 1)]
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LfoShape::Sine => "Sine",
+            LfoShape::Triangle => "Triangle",
+            LfoShape::Saw => "Saw",
+            LfoShape::Square => "Square",
+            LfoShape::SampleAndHold => "S&H",
+        }
+    }
+}
+
+/// Low frequency oscillator for modulating parameters. Every shape is bipolar,
+/// from -1 to 1. The waveforms aren't band limited, which is fine at
+/// modulation rates.
+#[derive(Copy, Clone, Debug)]
+pub struct Lfo {
+    /// Position in the cycle, from 0 to 1.
+    phase: f32,
+    /// Phase increment per sample.
+    step: f32,
+    shape: LfoShape,
+    held: f32,
+    rng: XorShift,
+}
+
+impl Lfo {
+    pub fn new(shape: LfoShape) -> Lfo {
+        let mut rng = XorShift::default();
+        Lfo {
+            phase: 0.0,
+            step: 0.0,
+            shape,
+            held: rng.next_f32(),
+            rng,
+        }
+    }
+
+    pub fn set_rate_hz(&mut self, rate: f32, sample_rate: f32) {
+        self.step = rate / sample_rate;
+    }
+
+    pub fn set_shape(&mut self, shape: LfoShape) {
+        self.shape = shape;
+    }
+
+    pub fn phase(&self) -> f32 {
+        self.phase
+    }
+
+    /// Restart the cycle, for syncing to a note or the host transport.
+    pub fn reset(&mut self) {
+        self.phase = 0.0;
+    }
+
+    /// Jump to `phase`, wrapped into 0 to 1.
+    pub fn set_phase(&mut self, phase: f32) {
+        let phase = phase.rem_euclid(1.0);
+        // A tiny negative phase rounds up to exactly 1
+        self.phase = if phase < 1.0 { phase } else { 0.0 };
+    }
+
+    /// Value at the current phase, then advance one sample.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> f32 {
+        let phase = self.phase;
+        let value = match self.shape {
+            LfoShape::Sine => (phase * 2.0 * PI).sin(),
+            // Starts at zero and rises, like the sine
+            LfoShape::Triangle => 4.0 * ((phase + 0.75) % 1.0 - 0.5).abs() - 1.0,
+            LfoShape::Saw => 2.0 * phase - 1.0,
+            LfoShape::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            LfoShape::SampleAndHold => self.held,
+        };
+
+        self.phase += self.step;
+        if self.phase >= 1.0 {
+            self.phase %= 1.0;
+            self.held = self.rng.next_f32();
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sine_matches_phase() {
+        let mut lfo = Lfo::new(LfoShape::Sine);
+        lfo.set_rate_hz(3.0, 1000.0);
+        for _ in 0..1000 {
+            let phase = lfo.phase();
+            assert!((lfo.next() - (phase * 2.0 * PI).sin()).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_sample_and_hold_changes_on_wrap() {
+        let mut lfo = Lfo::new(LfoShape::SampleAndHold);
+        lfo.set_rate_hz(10.0, 1000.0);
+        let mut prev = lfo.next();
+        let mut changes = 0;
+        // Ten cycles, with a little slack for rounding in the phase
+        for _ in 0..1050 {
+            let wrapped = lfo.phase() < 0.01;
+            let value = lfo.next();
+            if value != prev {
+                assert!(wrapped);
+                changes += 1;
+            }
+            assert!(value >= -1.0 && value <= 1.0);
+            prev = value;
+        }
+        assert_eq!(changes, 10);
+    }
+
+    #[test]
+    fn test_shapes_are_bipolar() {
+        for &shape in SHAPES.iter() {
+            let mut lfo = Lfo::new(shape);
+            lfo.set_rate_hz(1.0, 100.0);
+            let values: Vec<f32> = (0..100).map(|_| lfo.next()).collect();
+            assert!(values.iter().all(|x| x.abs() <= 1.0));
+            if shape != LfoShape::SampleAndHold {
+                assert!(values.iter().cloned().fold(-1.0, f32::max) >= 0.97);
+                assert!(values.iter().cloned().fold(1.0, f32::min) <= -0.98);
+            }
+        }
+        assert_eq!(LfoShape::from_parameter(0.0), LfoShape::Sine);
+        assert_eq!(LfoShape::from_parameter(1.0), LfoShape::SampleAndHold);
+    }
+}
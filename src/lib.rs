@@ -0,0 +1,12 @@
+//! Shared building blocks for the example plugins.
+
+extern crate vst;
+
+pub mod buffer;
+pub mod dsp;
+pub mod lfo;
+pub mod midi;
+pub mod noise;
+pub mod smoothing;
+pub mod tempo;
+pub mod util;
@@ -0,0 +1,120 @@
+/// Midi events a plugin can hold for one block. Past this `MidiQueue::push`
+/// refuses them rather than allocating on the audio thread.
+pub const MAX_QUEUED_EVENTS: usize = 1024;
+
+/// Release velocity from a note off's third byte, from -1 to 1. Keyboards that
+/// don't measure it send 64, which comes out as 0 so it changes nothing.
+pub fn release_velocity(data: u8) -> f32 {
+    ((f32::from(data) - 64.0) / 63.0).clamp(-1.0, 1.0)
+}
+
+/// Midi events from `process_events`, held until `process` reaches the sample
+/// each one is timed for. Hosts hand over a whole block of events before the
+/// block is processed, applying them straight away snaps them all to its start.
+#[derive(Clone, Debug)]
+pub struct MidiQueue {
+    /// Sample offset into the block and midi data, sorted by offset.
+    events: Vec<(usize, [u8; 3])>,
+    /// Index of the next event `pop` hands out.
+    next: usize,
+}
+
+impl Default for MidiQueue {
+    fn default() -> MidiQueue {
+        MidiQueue {
+            events: Vec::with_capacity(MAX_QUEUED_EVENTS),
+            next: 0,
+        }
+    }
+}
+
+impl MidiQueue {
+    /// Hold `data` until `delta_frames` into the next block. Returns false if
+    /// the queue is full, the caller should apply the event straight away.
+    pub fn push(&mut self, delta_frames: i32, data: [u8; 3]) -> bool {
+        if self.events.len() == MAX_QUEUED_EVENTS {
+            return false;
+        }
+        let delta_frames = delta_frames.max(0) as usize;
+        // Hosts send events in order, but if one doesn't, events on the same
+        // sample still keep the order they arrived in
+        let index = self.events.len()
+            - self
+                .events
+                .iter()
+                .rev()
+                .take_while(|&&(queued, _)| queued > delta_frames)
+                .count();
+        self.events.insert(index, (delta_frames, data));
+        true
+    }
+
+    /// The next event due at or before `sample_idx`, if there is one. Call it
+    /// until it returns `None` before rendering each sample, then with
+    /// `usize::MAX` after the block to catch events timed past its end.
+    pub fn pop(&mut self, sample_idx: usize) -> Option<[u8; 3]> {
+        match self.events.get(self.next) {
+            Some(&(delta_frames, data)) if delta_frames <= sample_idx => {
+                self.next += 1;
+                if self.next == self.events.len() {
+                    self.events.clear();
+                    self.next = 0;
+                }
+                Some(data)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.next == self.events.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_come_out_at_their_sample() {
+        let mut queue = MidiQueue::default();
+        assert!(queue.push(100, [144, 60, 100]));
+        assert!(queue.push(10, [144, 62, 100]));
+        assert!(queue.push(10, [128, 62, 0]));
+        assert!(queue.push(-5, [144, 64, 100]));
+
+        // Sorted by sample, events on the same sample stay in order
+        assert_eq!(queue.pop(0), Some([144, 64, 100]));
+        assert_eq!(queue.pop(0), None);
+        assert_eq!(queue.pop(9), None);
+        assert_eq!(queue.pop(10), Some([144, 62, 100]));
+        assert_eq!(queue.pop(10), Some([128, 62, 0]));
+        assert_eq!(queue.pop(99), None);
+        assert!(!queue.is_empty());
+        assert_eq!(queue.pop(usize::MAX), Some([144, 60, 100]));
+        assert!(queue.is_empty());
+        assert_eq!(queue.pop(usize::MAX), None);
+    }
+
+    #[test]
+    fn test_release_velocity() {
+        assert_eq!(release_velocity(64), 0.0);
+        assert_eq!(release_velocity(127), 1.0);
+        assert_eq!(release_velocity(0), -1.0);
+        // Out of range data bytes are held to the ends
+        assert_eq!(release_velocity(255), 1.0);
+    }
+
+    #[test]
+    fn test_full_queue_refuses_events() {
+        let mut queue = MidiQueue::default();
+        for _ in 0..MAX_QUEUED_EVENTS {
+            assert!(queue.push(0, [144, 60, 100]));
+        }
+        assert!(!queue.push(0, [128, 60, 0]));
+        // Empties once everything has been handed out
+        while queue.pop(0).is_some() {}
+        assert!(queue.is_empty());
+        assert!(queue.push(0, [128, 60, 0]));
+    }
+}
@@ -0,0 +1,106 @@
+/// Xorshift random number generator, cheap enough to run per sample and
+/// doesn't allocate.
+#[derive(Copy, Clone, Debug)]
+pub struct XorShift {
+    state: u32,
+}
+
+impl Default for XorShift {
+    fn default() -> XorShift {
+        XorShift::new(0x9E37_79B9)
+    }
+}
+
+impl XorShift {
+    pub fn new(seed: u32) -> XorShift {
+        // Zero is a fixed point of xorshift
+        XorShift {
+            state: if seed == 0 { 0x9E37_79B9 } else { seed },
+        }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// White noise sample between -1 and 1.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u32() as f64 / u32::MAX as f64 * 2.0 - 1.0) as f32
+    }
+}
+
+/// Add triangular (TPDF) dither of one least significant bit either side
+/// before `x` is rounded to `bit_depth` bits. It turns the rounding error into
+/// a steady noise floor, instead of distortion that follows the signal, so
+/// quiet tails fade into the noise rather than breaking up.
+pub fn tpdf_dither(x: f32, bit_depth: u32, rng: &mut XorShift) -> f32 {
+    let lsb = 1.0 / (1u64 << (bit_depth - 1)) as f32;
+    x + (rng.next_f32() + rng.next_f32()) * 0.5 * lsb
+}
+
+/// Paul Kellet's pink noise filter, turns white noise into a -3 dB/octave
+/// slope.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PinkFilter {
+    b: [f32; 7],
+}
+
+impl PinkFilter {
+    pub fn process(&mut self, white: f32) -> f32 {
+        let b = &mut self.b;
+        b[0] = 0.99886 * b[0] + white * 0.055_517_9;
+        b[1] = 0.99332 * b[1] + white * 0.075_075_9;
+        b[2] = 0.96900 * b[2] + white * 0.153_852;
+        b[3] = 0.86650 * b[3] + white * 0.310_485_6;
+        b[4] = 0.55000 * b[4] + white * 0.532_952_2;
+        b[5] = -0.7616 * b[5] - white * 0.016_898;
+        let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+        b[6] = white * 0.115_926;
+        // Roughly the same level as the white noise going in
+        pink * 0.11
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_white_noise_range() {
+        let mut rng = XorShift::default();
+        let mut sum = 0.0;
+        for _ in 0..100_000 {
+            let x = rng.next_f32();
+            assert!(x >= -1.0 && x <= 1.0);
+            sum += f64::from(x);
+        }
+        assert!((sum / 100_000.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_tpdf_dither_range() {
+        let mut rng = XorShift::new(1);
+        let lsb = 1.0 / 32768.0;
+        let mut sum = 0.0;
+        for _ in 0..100_000 {
+            let d = tpdf_dither(0.0, 16, &mut rng);
+            assert!(d.abs() <= lsb);
+            sum += f64::from(d);
+        }
+        assert!((sum / 100_000.0).abs() < 0.01 * lsb as f64);
+    }
+
+    #[test]
+    fn test_pink_noise_is_bounded() {
+        let mut rng = XorShift::new(1);
+        let mut pink = PinkFilter::default();
+        for _ in 0..100_000 {
+            assert!(pink.process(rng.next_f32()).abs() < 1.5);
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use dsp::mix_equal_power;
+
+/// Smoothing time used by the plugins when a parameter doesn't need its own.
+pub const DEFAULT_SMOOTHING_MS: f32 = 10.0;
+
+/// One pole smoother for parameter values, removes the zipper noise caused by
+/// applying a new value in a single step at the start of a block.
+#[derive(Copy, Clone, Debug)]
+pub struct SmoothedValue {
+    current: f32,
+    target: f32,
+    coefficient: f32,
+}
+
+impl SmoothedValue {
+    /// Start settled at `value`. Without a call to `set_time` the value jumps
+    /// straight to the target.
+    pub fn new(value: f32) -> SmoothedValue {
+        SmoothedValue {
+            current: value,
+            target: value,
+            coefficient: 0.0,
+        }
+    }
+
+    /// Set how long it takes to get within 1% of a new target.
+    pub fn set_time(&mut self, ms: f32, sample_rate: f32) {
+        let samples = ms * 0.001 * sample_rate;
+        self.coefficient = if samples < 1.0 {
+            0.0
+        } else {
+            // The remaining distance is multiplied by the coefficient each sample
+            (0.01f32.ln() / samples).exp()
+        };
+    }
+
+    pub fn set_target(&mut self, target: f32) {
+        self.target = target;
+    }
+
+    /// Jump to `value` without smoothing.
+    pub fn reset(&mut self, value: f32) {
+        self.current = value;
+        self.target = value;
+    }
+
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+
+    /// Advance one sample and return the smoothed value.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> f32 {
+        self.current = self.target + self.coefficient * (self.current - self.target);
+        self.current
+    }
+}
+
+/// Linear ramp across a block, from the value a parameter had at the end of the
+/// last block to the value it has now.
+///
+/// VST 2.4 has no parameter change event, the vst crate's `Event` only carries
+/// MIDI and SysEx, so no host sends automation through `process_events`. Every
+/// host delivers it as `set_parameter` calls between blocks instead, which
+/// leaves one value per block. Ramping between those values follows fast
+/// automation at the resolution the host gives rather than stepping once per
+/// block.
+#[derive(Copy, Clone, Debug)]
+pub struct BlockRamp {
+    current: f32,
+    target: f32,
+    step: f32,
+    remaining: usize,
+}
+
+impl BlockRamp {
+    pub fn new(value: f32) -> BlockRamp {
+        BlockRamp {
+            current: value,
+            target: value,
+            step: 0.0,
+            remaining: 0,
+        }
+    }
+
+    /// Start a block of `samples` samples that ends on `target`.
+    pub fn start(&mut self, target: f32, samples: usize) {
+        self.target = target;
+        self.remaining = samples;
+        self.step = if samples > 0 {
+            (target - self.current) / samples as f32
+        } else {
+            0.0
+        };
+    }
+
+    /// Jump to `value` without ramping.
+    pub fn reset(&mut self, value: f32) {
+        self.current = value;
+        self.target = value;
+        self.remaining = 0;
+    }
+
+    /// Advance one sample and return the ramped value. The last sample of the
+    /// block lands exactly on the target.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> f32 {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            self.current = if self.remaining == 0 {
+                self.target
+            } else {
+                self.current + self.step
+            };
+        }
+        self.current
+    }
+}
+
+/// How long the effects take to crossfade in or out of bypass.
+pub const BYPASS_FADE_MS: f32 = 10.0;
+
+/// Crossfade between an effect's output and its dry input when its bypass
+/// parameter is switched. Jumping straight between the two clicks whenever
+/// the effect has changed the signal. The effect has to keep processing
+/// while the fade runs so the wet side is there to fade from, `is_bypassed`
+/// says when it can stop.
+#[derive(Copy, Clone, Debug)]
+pub struct BypassFade {
+    /// 0 is all effect and 1 all dry input.
+    position: f32,
+    target: f32,
+    /// Per sample change of `position` while fading.
+    step: f32,
+}
+
+impl BypassFade {
+    /// Start on the effect, not bypassed.
+    pub fn new(sample_rate: f32) -> BypassFade {
+        let mut fade = BypassFade {
+            position: 0.0,
+            target: 0.0,
+            step: 1.0,
+        };
+        fade.set_sample_rate(sample_rate);
+        fade
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.step = 1.0 / (BYPASS_FADE_MS * 0.001 * sample_rate).max(1.0);
+    }
+
+    /// Fade towards the dry input if `bypass`, back to the effect if not.
+    pub fn set(&mut self, bypass: bool) {
+        self.target = if bypass { 1.0 } else { 0.0 };
+    }
+
+    /// Whether the fade to the dry input has finished, so the effect can
+    /// copy its input through without processing it.
+    pub fn is_bypassed(&self) -> bool {
+        self.target == 1.0 && self.position == 1.0
+    }
+
+    /// Advance one sample and blend the effect's `wet` output with the `dry`
+    /// input, both as left and right. Equal power, so the level holds up
+    /// through the fade when the effect has moved the signal away from its
+    /// input, as a delay or reverb does.
+    pub fn mix(&mut self, dry: (f32, f32), wet: (f32, f32)) -> (f32, f32) {
+        if self.position < self.target {
+            self.position = (self.position + self.step).min(self.target);
+        } else if self.position > self.target {
+            self.position = (self.position - self.step).max(self.target);
+        }
+        if self.position == 0.0 {
+            return wet;
+        } else if self.position == 1.0 {
+            return dry;
+        }
+        (
+            mix_equal_power(wet.0, dry.0, self.position),
+            mix_equal_power(wet.1, dry.1, self.position),
+        )
+    }
+}
+
+/// Fade the bypass of every channel pair in `pairs` towards `bypass`, `fade`
+/// picks a pair's `BypassFade` out of its state. Every pair fades together,
+/// so the first speaks for all of them when it returns whether the fade to
+/// the dry input has finished.
+pub fn set_bypass<S, F>(pairs: &mut [S], bypass: bool, mut fade: F) -> bool
+where
+    F: FnMut(&mut S) -> &mut BypassFade,
+{
+    for pair in pairs.iter_mut() {
+        fade(pair).set(bypass);
+    }
+    match pairs.first_mut() {
+        Some(pair) => fade(pair).is_bypassed(),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_1_SQRT_2;
+
+    #[test]
+    fn test_reaches_target_after_time() {
+        let sample_rate = 48000.0;
+        let mut value = SmoothedValue::new(0.0);
+        value.set_time(20.0, sample_rate);
+        value.set_target(1.0);
+
+        let samples = (0.02 * sample_rate) as usize;
+        let mut out = 0.0;
+        for i in 0..samples {
+            out = value.next();
+            if i == samples / 2 {
+                // Still moving halfway through
+                assert!(out < 0.99);
+            }
+        }
+        // Allow for rounding in the coefficient
+        assert!((out - 1.0).abs() < 0.01 + 1e-5);
+    }
+
+    #[test]
+    fn test_no_time_is_instant() {
+        let mut value = SmoothedValue::new(0.5);
+        value.set_target(2.0);
+        assert_eq!(value.next(), 2.0);
+    }
+
+    #[test]
+    fn test_ramp_ends_on_target() {
+        let mut ramp = BlockRamp::new(0.0);
+        ramp.start(1.0, 4);
+        let values: Vec<f32> = (0..6).map(|_| ramp.next()).collect();
+        assert_eq!(values, vec![0.25, 0.5, 0.75, 1.0, 1.0, 1.0]);
+
+        // A new block starts from wherever the last one got to
+        ramp.start(0.0, 4);
+        ramp.next();
+        ramp.start(0.5, 2);
+        assert_eq!(ramp.next(), 0.625);
+        assert_eq!(ramp.next(), 0.5);
+    }
+
+    #[test]
+    fn test_bypass_fade() {
+        let mut fade = BypassFade::new(1000.0);
+        assert_eq!(fade.mix((1.0, 1.0), (0.0, -1.0)), (0.0, -1.0));
+
+        // 10 samples to get across at 1khz
+        fade.set(true);
+        let left: Vec<f32> = (0..12)
+            .map(|_| fade.mix((1.0, 1.0), (0.0, 0.0)).0)
+            .collect();
+        assert!(left.windows(2).all(|pair| pair[1] >= pair[0]));
+        assert!(left[8] < 1.0);
+        assert_eq!(left[9], 1.0);
+        assert!(fade.is_bypassed());
+        // Equal power, halfway back each side is 3 dB down
+        fade.set(false);
+        for _ in 0..4 {
+            fade.mix((0.0, 0.0), (0.0, 0.0));
+        }
+        let (dry, wet) = fade.mix((1.0, 0.0), (0.0, 1.0));
+        assert!(!fade.is_bypassed());
+        assert!((dry - FRAC_1_SQRT_2).abs() < 1e-6 && (wet - FRAC_1_SQRT_2).abs() < 1e-6);
+        // The powers of the two sides always add up to one
+        for _ in 0..6 {
+            let (dry, wet) = fade.mix((1.0, 0.0), (0.0, 1.0));
+            assert!((dry * dry + wet * wet - 1.0).abs() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use vst::api::TimeInfoFlags;
+use vst::host::Host;
+
+/// Tempo used for a synced time or rate when the host doesn't report one.
+pub const DEFAULT_BPM: f64 = 120.0;
+
+/// Synced divisions as (label, length in beats).
+pub const DIVISIONS: [(&str, f64); 7] = [
+    ("1/1", 4.0),
+    ("1/2", 2.0),
+    ("1/4", 1.0),
+    ("1/8", 0.5),
+    ("1/16", 0.25),
+    ("1/32", 0.125),
+    ("1/64", 0.0625),
+];
+
+/// Synced divisions with dotted notes between the straight ones, a trailing
+/// dot marks a dotted note.
+pub const DOTTED_DIVISIONS: [(&str, f64); 9] = [
+    ("1/1", 4.0),
+    ("1/2", 2.0),
+    ("1/4.", 1.5),
+    ("1/4", 1.0),
+    ("1/8.", 0.75),
+    ("1/8", 0.5),
+    ("1/16.", 0.375),
+    ("1/16", 0.25),
+    ("1/32", 0.125),
+];
+
+/// Division of `divisions` for a 0 - 1 parameter value.
+pub fn division(divisions: &[(&'static str, f64)], x: f32) -> (&'static str, f64) {
+    // Negative values saturate to 0 in the cast
+    let index = (x * (divisions.len() - 1) as f32).round() as usize;
+    divisions[index.min(divisions.len() - 1)]
+}
+
+/// Tempo reported by the host, if it has one.
+pub fn host_bpm<H: Host>(host: &H) -> Option<f64> {
+    let tempo_valid = TimeInfoFlags::TEMPO_VALID.bits();
+    host.get_time_info(tempo_valid)
+        .filter(|info| info.flags & tempo_valid != 0 && info.tempo > 0.0)
+        .map(|info| info.tempo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_division_steps_through_in_order() {
+        assert_eq!(division(&DIVISIONS, 0.0), ("1/1", 4.0));
+        assert_eq!(division(&DIVISIONS, 2.0 / 6.0), ("1/4", 1.0));
+        assert_eq!(division(&DIVISIONS, 1.0), ("1/64", 0.0625));
+        assert_eq!(division(&DOTTED_DIVISIONS, 0.25), ("1/4.", 1.5));
+        // Out of range values stay on the ends
+        assert_eq!(division(&DIVISIONS, -1.0), ("1/1", 4.0));
+        assert_eq!(division(&DIVISIONS, 2.0), ("1/64", 0.0625));
+    }
+}
@@ -0,0 +1,177 @@
+use std::ptr;
+use vst::api::Supported;
+use vst::plugin::{CanDo, HostCallback};
+
+/// Lowest level `db_from_gain` returns, stands in for silence.
+pub const MIN_DB: f32 = -144.0;
+
+pub fn gain_from_db(decibels: f32) -> f32 {
+    (10.0f32).powf(decibels * 0.05)
+}
+
+/// Level in dB, a gain of 0 or below is clamped to `MIN_DB` instead of -inf.
+pub fn db_from_gain(gain: f32) -> f32 {
+    (gain.max(0.0).log(10.0) * 20.0).max(MIN_DB)
+}
+
+/// Clamp a parameter value from the host to 0 - 1.
+///
+/// Returns `None` for NaN and infinities, which `set_parameter` ignores so a
+/// bad value from the host can't reach the DSP.
+pub fn clamp_parameter(val: f32) -> Option<f32> {
+    if val.is_finite() {
+        Some(val.clamp(0.0, 1.0))
+    } else {
+        None
+    }
+}
+
+/// Map a 0 - 1 parameter value onto `bottom` - `top`. `x` is clamped to 0 - 1
+/// first, so the result never leaves the range.
+pub fn to_range(x: f32, bottom: f32, top: f32) -> f32 {
+    x.clamp(0.0, 1.0) * (top - bottom) + bottom
+}
+
+/// Map a value in `bottom` - `top` back to 0 - 1, the inverse of `to_range`.
+/// Values outside the range are clamped to 0 - 1.
+pub fn from_range(x: f32, bottom: f32, top: f32) -> f32 {
+    ((x - bottom) / (top - bottom)).clamp(0.0, 1.0)
+}
+
+/// Map a 0 - 1 parameter value onto `bottom` - `top` exponentially, so equal
+/// knob travel covers equal ratios. Meant for frequencies and times, where an
+/// octave or a doubling sounds the same size wherever it is. Both ends of the
+/// range have to be above 0.
+pub fn to_range_log(x: f32, bottom: f32, top: f32) -> f32 {
+    bottom * (top / bottom).powf(x.clamp(0.0, 1.0))
+}
+
+/// Map a value in `bottom` - `top` back to 0 - 1, the inverse of
+/// `to_range_log`. Values outside the range are clamped to 0 - 1.
+pub fn from_range_log(x: f32, bottom: f32, top: f32) -> f32 {
+    ((x / bottom).ln() / (top / bottom).ln()).clamp(0.0, 1.0)
+}
+
+/// `Plugin::can_do` for the stereo effects. They don't take or send midi and
+/// process a stereo pair, and say so instead of leaving the host to guess.
+/// Their bypass is a parameter rather than the host's soft bypass, so that's
+/// refused too and the host bypasses them itself. Plugins that need more,
+/// like host tempo, answer that first and hand everything else on to this.
+pub fn effect_can_do(can_do: CanDo) -> Supported {
+    match can_do {
+        CanDo::SendEvents
+        | CanDo::SendMidiEvent
+        | CanDo::ReceiveEvents
+        | CanDo::ReceiveMidiEvent
+        | CanDo::ReceiveSysExEvent
+        | CanDo::MidiProgramNames
+        | CanDo::MidiSingleNoteTuningChange
+        | CanDo::MidiKeyBasedInstrumentControl
+        | CanDo::Bypass => Supported::No,
+        CanDo::Other(ref name) if name == "2in2out" => Supported::Yes,
+        _ => Supported::Maybe,
+    }
+}
+
+/// `audioMasterIOChanged`, asks the host to read the plugin's IO setup and
+/// latency again.
+const AUDIO_MASTER_IO_CHANGED: i32 = 13;
+
+/// Tells the host the plugin's latency is now `samples`. Hosts only read the
+/// latency off the effect, so it's written there before asking them to look
+/// again. Does nothing until the plugin has been loaded by a host.
+pub fn notify_latency(host: &HostCallback, samples: usize) {
+    let effect = host.raw_effect();
+    if let Some(callback) = host.raw_callback() {
+        if !effect.is_null() {
+            unsafe {
+                (*effect).initialDelay = samples as i32;
+            }
+            callback(effect, AUDIO_MASTER_IO_CHANGED, 0, 0, ptr::null_mut(), 0.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_db_round_trip() {
+        for &x in [1e-6, 0.001, 0.5, 1.0, 2.0, 100.0].iter() {
+            let y = gain_from_db(db_from_gain(x));
+            assert!((y - x).abs() / x < 1e-4);
+        }
+        assert_eq!(db_from_gain(1.0), 0.0);
+        assert!((gain_from_db(-6.0) - 0.501).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_silence_is_clamped() {
+        assert_eq!(db_from_gain(0.0), MIN_DB);
+        assert_eq!(db_from_gain(-1.0), MIN_DB);
+    }
+
+    #[test]
+    fn test_range_round_trip() {
+        assert_eq!(to_range(0.0, -24.0, 24.0), -24.0);
+        assert_eq!(to_range(0.5, -24.0, 24.0), 0.0);
+        assert_eq!(from_range(24.0, -24.0, 24.0), 1.0);
+        assert!((from_range(to_range(0.3, 0.6, 1.5), 0.6, 1.5) - 0.3).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_log_range() {
+        // The midpoint is the geometric mean, 632Hz rather than 10kHz
+        assert!((to_range_log(0.5, 20.0, 20000.0) - 632.46).abs() < 0.01);
+        assert!((to_range_log(0.0, 20.0, 20000.0) - 20.0).abs() < 1e-3);
+        assert!((to_range_log(1.0, 20.0, 20000.0) - 20000.0).abs() < 0.1);
+        // Each tenth of the knob is an octave
+        assert!(
+            (to_range_log(0.2, 20.0, 20480.0) / to_range_log(0.1, 20.0, 20480.0) - 2.0).abs()
+                < 1e-4
+        );
+        assert!((from_range_log(632.46, 20.0, 20000.0) - 0.5).abs() < 1e-4);
+        assert!((from_range_log(to_range_log(0.3, 1.0, 1000.0), 1.0, 1000.0) - 0.3).abs() < 1e-5);
+        assert_eq!(
+            to_range_log(2.0, 20.0, 20000.0),
+            to_range_log(1.0, 20.0, 20000.0)
+        );
+        assert_eq!(from_range_log(10.0, 20.0, 20000.0), 0.0);
+        assert_eq!(from_range_log(40000.0, 20.0, 20000.0), 1.0);
+    }
+
+    #[test]
+    fn test_parameter_clamping() {
+        assert_eq!(clamp_parameter(0.25), Some(0.25));
+        assert_eq!(clamp_parameter(2.0), Some(1.0));
+        assert_eq!(clamp_parameter(-1.0), Some(0.0));
+        assert_eq!(clamp_parameter(std::f32::NAN), None);
+        assert_eq!(clamp_parameter(std::f32::INFINITY), None);
+        assert_eq!(clamp_parameter(std::f32::NEG_INFINITY), None);
+    }
+
+    #[test]
+    fn test_range_stays_in_bounds() {
+        assert_eq!(to_range(2.0, -24.0, 24.0), 24.0);
+        assert_eq!(to_range(-1.0, -24.0, 24.0), -24.0);
+        assert_eq!(from_range(48.0, -24.0, 24.0), 1.0);
+        assert_eq!(from_range(-48.0, -24.0, 24.0), 0.0);
+        // dB of silence is far below the bottom of the range
+        assert_eq!(from_range(db_from_gain(0.0), -24.0, 24.0), 0.0);
+    }
+
+    #[test]
+    fn test_effect_can_do() {
+        assert_eq!(effect_can_do(CanDo::ReceiveMidiEvent), Supported::No);
+        assert_eq!(effect_can_do(CanDo::Bypass), Supported::No);
+        assert_eq!(
+            effect_can_do(CanDo::Other("2in2out".to_string())),
+            Supported::Yes
+        );
+        assert_eq!(
+            effect_can_do(CanDo::Other("1in1out".to_string())),
+            Supported::Maybe
+        );
+    }
+}